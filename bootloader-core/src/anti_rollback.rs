@@ -0,0 +1,59 @@
+//! Refusing new images that are older than the confirmed one
+//!
+//! With the `anti-rollback` feature, the security counter of a new image (see [shared::security_counter]) must be at
+//! least the stored counter, which the binary reads from the UICR and sets with [set_security_counter] at every boot.
+//! Otherwise the image is refused as part of the [header check](crate::header_check), so an attacker can't bring back
+//! an old image with a known vulnerability. An image without a security counter has counter 0.
+//!
+//! The stored counter only follows an image once it's confirmed, so a test swap can still be reverted: when the
+//! goal is back at jumping to the application, [counter_to_store] tells the binary which counter to program.
+//!
+//! The counter is taken from a protected TLV, so it can only be trusted as far as the digest of the image can.
+//! That makes this feature most useful together with `secure-boot`.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use shared::{
+    mcuboot::McubootHeader,
+    slots::{self, SlotDescriptor, SlotRole, APPLICATION_IMAGE},
+    state::{BootloaderGoal, BootloaderState},
+    Flash,
+};
+
+/// The security counter that is stored in the UICR
+static SECURITY_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// Sets the security counter that new images must at least have
+pub fn set_security_counter(value: u32) {
+    SECURITY_COUNTER.store(value, Ordering::Relaxed);
+}
+
+/// The security counter that new images must at least have
+pub fn security_counter() -> u32 {
+    SECURITY_COUNTER.load(Ordering::Relaxed)
+}
+
+/// Reads the security counter of the image in the slot, which is 0 if it has none
+pub fn image_security_counter(flash: &dyn Flash, slot: &SlotDescriptor) -> u32 {
+    McubootHeader::load(flash, slot.address())
+        .and_then(|header| {
+            header.security_counter(
+                flash,
+                slot.address(),
+                slot.address() + slot.image_capacity(),
+            )
+        })
+        .unwrap_or(0)
+}
+
+/// Returns the security counter the binary must store, if the image in the primary slot is confirmed and has a
+/// higher counter than the stored one
+pub fn counter_to_store(flash: &dyn Flash) -> Option<u32> {
+    let state = BootloaderState::load(flash);
+    if state.is_valid() && state.goal() != BootloaderGoal::JumpToApplication {
+        return None;
+    }
+
+    let layout = slots::default_layout();
+    let primary = slots::find(&layout, SlotRole::Primary, APPLICATION_IMAGE)?;
+    Some(image_security_counter(flash, primary)).filter(|counter| *counter > security_counter())
+}
@@ -0,0 +1,373 @@
+//! Verification of the application image in the primary slot, or in another executable slot for a direct boot
+
+use crate::LogSink;
+#[cfg(feature = "verification")]
+use crate::{
+    events,
+    hardening::Decision,
+    report::{self, Verification},
+    uprintln,
+};
+use core::{
+    ops::{Range, RangeInclusive},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use shared::{
+    image_header::ImageHeader,
+    mcuboot::McubootHeader,
+    slots::{self, SlotDescriptor, SlotRole},
+    Flash,
+};
+
+/// What to do when slot A doesn't pass the verification
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VerificationPolicy {
+    /// Refuse to boot by panicking. This is the default.
+    Strict,
+    /// Boot the start of slot A anyway, like a bootloader without verification would
+    Lenient,
+}
+
+/// The range the initial stack pointer of a vector table must lie in. The stack grows down, so it may start right at
+/// the end of RAM.
+const INITIAL_STACK_POINTER_RANGE: RangeInclusive<u32> = 0x2000_0000..=0x2004_0000;
+
+/// Set when the [VerificationPolicy::Lenient] policy is used
+static LENIENT_VERIFICATION: AtomicBool = AtomicBool::new(false);
+
+/// Sets what to do when slot A doesn't pass the verification
+pub fn set_verification_policy(policy: VerificationPolicy) {
+    LENIENT_VERIFICATION.store(policy == VerificationPolicy::Lenient, Ordering::Relaxed);
+}
+
+/// Finds the vector table of the application in slot A and returns its address.
+///
+/// The vector table is checked at the [vector table offset](SlotDescriptor::vector_table_offset) of the slot, or
+/// right after the [ImageHeader] or [McubootHeader] at the start of the slot. An image with neither must start with
+/// its vector table, because the slot isn't scanned for one.
+///
+/// If no valid vector table can be found, this is recorded as a [SecurityEvent::VerificationFailed](shared::event_log::SecurityEvent::VerificationFailed).
+/// The function then panics, unless the [VerificationPolicy::Lenient] policy is set without the `secure-boot` feature.
+/// With the `fi-hardening` feature, the check is done twice with random delays around it,
+/// and the address is only returned if both checks agree.
+pub fn find_application_address(flash: &mut dyn Flash, log: &mut dyn LogSink) -> u32 {
+    let [primary, _] = slots::default_layout();
+    find_application_address_in(flash, log, &primary)
+}
+
+/// Finds the vector table of the application in the given slot and returns its address,
+/// like [find_application_address] does for slot A.
+#[cfg(feature = "verification")]
+pub fn find_application_address_in(
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+    slot: &SlotDescriptor,
+) -> u32 {
+    if let Some(application_address) = check_slot(flash, slot, slot.range.clone()) {
+        report::set_verification(Verification::Passed);
+        return application_address;
+    }
+
+    events::record(
+        flash,
+        log,
+        shared::event_log::SecurityEvent::VerificationFailed,
+        0,
+    )
+    .ok();
+    report::set_verification(Verification::Failed);
+
+    // With secure boot, an image that can't be verified is never started
+    if LENIENT_VERIFICATION.load(Ordering::Relaxed) && !cfg!(feature = "secure-boot") {
+        slot.address()
+    } else {
+        panic!("Could not find a reset vector in the firmware")
+    }
+}
+
+/// Returns true if the slot has a valid image that is linked to run from the `run_slot`, so it can be copied there
+#[cfg(feature = "verification")]
+pub fn has_valid_image_for(
+    flash: &dyn Flash,
+    slot: &SlotDescriptor,
+    run_slot: &SlotDescriptor,
+) -> bool {
+    check_slot(flash, slot, run_slot.range.clone()).is_some()
+}
+
+/// Searches the golden slot for the vector table of the golden image, for when the primary slot has no valid image.
+///
+/// Returns `None` if the primary slot has a valid image, or if the golden slot isn't executable or has no valid
+/// image either, so the caller goes on with the primary slot.
+#[cfg(feature = "verification")]
+pub fn find_golden_address(
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+    primary: &SlotDescriptor,
+    golden: &SlotDescriptor,
+) -> Option<u32> {
+    if !golden.executable || check_slot(flash, primary, primary.range.clone()).is_some() {
+        return None;
+    }
+
+    let application_address = check_slot(flash, golden, golden.range.clone())?;
+
+    uprintln!(
+        log,
+        "The primary slot has no valid image, starting the golden image"
+    );
+    events::record(
+        flash,
+        log,
+        shared::event_log::SecurityEvent::GoldenImageStarted,
+        0,
+    )
+    .ok();
+    report::set_verification(Verification::Passed);
+
+    Some(application_address)
+}
+
+/// Searches a slot other than the primary one, like slot B, for the vector table of an application that is
+/// linked to run from there.
+///
+/// Returns `None` if the slot isn't executable or no vector table can be found, so the caller can fall back
+/// to the primary slot.
+#[cfg(all(feature = "direct-boot", feature = "verification"))]
+pub fn find_direct_boot_address(
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+    slot: &SlotDescriptor,
+) -> Option<u32> {
+    if !slot.executable {
+        return None;
+    }
+
+    let application_address = check_slot(flash, slot, slot.range.clone());
+
+    if application_address.is_some() {
+        report::set_verification(Verification::Passed);
+    } else {
+        // The fallback to the primary slot reports its own verification
+        events::record(
+            flash,
+            log,
+            shared::event_log::SecurityEvent::VerificationFailed,
+            1,
+        )
+        .ok();
+    }
+
+    application_address
+}
+
+/// Returns the address of the vector table of the application, if the slot is executable.
+///
+/// The verification is compiled out, so the application must be placed at the start of its slot or at its
+/// [vector table offset](SlotDescriptor::vector_table_offset).
+#[cfg(all(feature = "direct-boot", not(feature = "verification")))]
+pub fn find_direct_boot_address(
+    flash: &mut dyn Flash,
+    _log: &mut dyn LogSink,
+    slot: &SlotDescriptor,
+) -> Option<u32> {
+    slot.executable
+        .then(|| unverified_vector_table_address(flash, slot))
+}
+
+/// Checks the vector table at the [vector table offset](SlotDescriptor::vector_table_offset) of the slot and returns
+/// its address if it belongs to an application that is linked to run from there, for a direct-XIP boot.
+#[cfg(all(feature = "direct-xip", feature = "verification"))]
+pub(crate) fn find_in_place_address(flash: &dyn Flash, slot: &SlotDescriptor) -> Option<u32> {
+    if !slot.executable {
+        return None;
+    }
+
+    check_slot(flash, slot, slot.range.clone())
+}
+
+/// Returns the address at the [vector table offset](SlotDescriptor::vector_table_offset) of the slot, if the slot is
+/// executable.
+#[cfg(all(feature = "direct-xip", not(feature = "verification")))]
+pub(crate) fn find_in_place_address(_flash: &dyn Flash, slot: &SlotDescriptor) -> Option<u32> {
+    slot.vector_table_address().filter(|_| slot.executable)
+}
+
+/// Checks the vector table of the slot and returns its address if both the decision and the address are valid.
+/// A slot with a vector table offset is checked at that offset, and otherwise an image with an [ImageHeader] or a
+/// [McubootHeader] right after the header. Without either, the vector table is checked at the start of the slot, like
+/// the unverified boot jumps there. The reset vector must lie in the run range, which is the slot itself unless the
+/// image is linked to run from another slot.
+///
+/// An image that doesn't run on the hardware revision of the board (see [revision_check](crate::revision_check)) is
+/// never started.
+///
+/// With the `fi-hardening` feature, the check is done twice with random delays around it.
+/// With the `secure-boot` feature, the image must be signed as well.
+#[cfg(feature = "verification")]
+fn check_slot(flash: &dyn Flash, slot: &SlotDescriptor, run_range: Range<u32>) -> Option<u32> {
+    if !crate::revision_check::runs_on_this_revision(
+        crate::revision_check::image_compatible_revisions(flash, slot),
+    ) {
+        return None;
+    }
+
+    // An image that isn't signed has no vector table as far as the bootloader is concerned
+    #[cfg(feature = "secure-boot")]
+    if crate::secure_boot::verify_signature(flash, slot).is_err() {
+        return None;
+    }
+
+    // A header isn't a vector table, so an image with one is checked right after it.
+    // An image without one starts with its vector table.
+    let vector_table_address = slot
+        .vector_table_address()
+        .or_else(|| header_vector_table_address(flash, slot))
+        .unwrap_or(slot.address());
+
+    let check = || check_vector_table(flash, slot, run_range.clone(), vector_table_address);
+
+    #[cfg(not(feature = "fi-hardening"))]
+    let (decision, application_address) = {
+        let (decision, application_address) = check();
+        (decision, Some(application_address))
+    };
+
+    #[cfg(feature = "fi-hardening")]
+    let (decision, application_address) = crate::hardening::decide_twice(check);
+
+    application_address.filter(|_| decision.is_valid())
+}
+
+/// Checks that there is a vector table at the given address in the slot.
+///
+/// Returns [Decision::VALID] with the address if it's aligned for the VTOR and lies in the slot, the initial stack
+/// pointer lies in RAM and the reset vector lies in the run range.
+#[cfg(feature = "verification")]
+fn check_vector_table(
+    flash: &dyn Flash,
+    slot: &SlotDescriptor,
+    run_range: Range<u32>,
+    vector_table_address: u32,
+) -> (Decision, u32) {
+    if vector_table_address & (slots::VECTOR_TABLE_ALIGNMENT - 1) != 0
+        || vector_table_address < slot.range.start
+        || vector_table_address.saturating_add(8) > slot.range.end
+    {
+        return (Decision::INVALID, 0);
+    }
+
+    #[cfg_attr(not(feature = "encrypted-images"), allow(unused_mut))]
+    let mut vector_table: [u32; 2] = flash
+        .read_u32(vector_table_address..vector_table_address + 8)
+        .try_into()
+        .unwrap();
+
+    // An encrypted image is decrypted when it's copied to the run range, so that's the vector table it will have
+    #[cfg(feature = "encrypted-images")]
+    if slot.range != run_range {
+        if let Some(cipher) = crate::image_encryption::ImageCipher::for_slot(flash, slot) {
+            cipher.apply_to_words(vector_table_address, &mut vector_table);
+        }
+    }
+
+    let [initial_stack_pointer, reset_vector] = vector_table;
+    // The lowest bit of the reset vector is the thumb bit
+    if !INITIAL_STACK_POINTER_RANGE.contains(&initial_stack_pointer)
+        || !run_range.contains(&(reset_vector & !1))
+    {
+        return (Decision::INVALID, 0);
+    }
+
+    (Decision::VALID, vector_table_address)
+}
+
+/// Checks that the application can be started with the vector table at the given address.
+///
+/// The vector table must lie in a bootable slot of the standard layout and its reset vector must point into that
+/// same slot, see [is_valid_bootload_target_in] for which slots are bootable.
+/// This is checked right before the jump, whatever the verification or the [VerificationPolicy] decided,
+/// so the bootloader never starts code in a slot that isn't bootable, the scratch area or its own flash.
+pub fn is_valid_bootload_target(flash: &dyn Flash, vector_table_address: u32) -> bool {
+    is_valid_bootload_target_in(flash, &slots::default_layout(), vector_table_address)
+}
+
+/// Checks that the application can be started with the vector table at the given address, like
+/// [is_valid_bootload_target] does for the standard layout.
+///
+/// The vector table and its reset vector must lie in the same primary slot or executable golden slot.
+/// With the `direct-boot` or `direct-xip` feature, they may also lie in the same executable slot with another role.
+pub fn is_valid_bootload_target_in(
+    flash: &dyn Flash,
+    slots: &[SlotDescriptor],
+    vector_table_address: u32,
+) -> bool {
+    slots
+        .iter()
+        .filter(|slot| {
+            slot.role == SlotRole::Primary
+                || (slot.executable
+                    && (slot.role == SlotRole::Golden
+                        || cfg!(feature = "direct-boot")
+                        || cfg!(feature = "direct-xip")))
+        })
+        .any(|slot| is_in_slot(flash, slot.range.clone(), vector_table_address))
+}
+
+/// Checks that the vector table at the given address is aligned for the VTOR and lies in the slot, that its initial
+/// stack pointer lies in RAM and that its reset vector lies in the slot
+fn is_in_slot(flash: &dyn Flash, slot: Range<u32>, vector_table_address: u32) -> bool {
+    // The vector table must at least have the initial stack pointer and the reset vector
+    let vector_table_end = match vector_table_address.checked_add(8) {
+        Some(vector_table_end) => vector_table_end,
+        None => return false,
+    };
+    if vector_table_address & (slots::VECTOR_TABLE_ALIGNMENT - 1) != 0
+        || vector_table_address < slot.start
+        || vector_table_end > slot.end
+    {
+        return false;
+    }
+
+    let [initial_stack_pointer, reset_vector] = flash
+        .read_u32(vector_table_address..vector_table_end)
+        .try_into()
+        .unwrap();
+    // The lowest bit of the reset vector is the thumb bit
+    INITIAL_STACK_POINTER_RANGE.contains(&initial_stack_pointer)
+        && slot.contains(&(reset_vector & !1))
+}
+
+/// Returns the address of the vector table of the application, which is the start of the slot or its
+/// [vector table offset](SlotDescriptor::vector_table_offset).
+///
+/// The verification is compiled out, so the application must be placed at the very start of its slot if there
+/// is no offset, or right after its [ImageHeader] or [McubootHeader].
+#[cfg(not(feature = "verification"))]
+pub fn find_application_address_in(
+    flash: &mut dyn Flash,
+    _log: &mut dyn LogSink,
+    slot: &SlotDescriptor,
+) -> u32 {
+    unverified_vector_table_address(flash, slot)
+}
+
+/// Returns the vector table offset of the slot, or the vector table behind the header at the start of the slot,
+/// or the start of the slot
+#[cfg(not(feature = "verification"))]
+fn unverified_vector_table_address(flash: &dyn Flash, slot: &SlotDescriptor) -> u32 {
+    slot.vector_table_address()
+        .or_else(|| header_vector_table_address(flash, slot))
+        .unwrap_or(slot.address())
+}
+
+/// Returns the address of the vector table behind the [ImageHeader] or [McubootHeader] at the start of the slot,
+/// if there is one and the vector table lies in the slot
+fn header_vector_table_address(flash: &dyn Flash, slot: &SlotDescriptor) -> Option<u32> {
+    let vector_table_offset = match ImageHeader::load(flash, slot.address()) {
+        Some(_) => ImageHeader::VECTOR_TABLE_OFFSET,
+        None => McubootHeader::load(flash, slot.address())?.vector_table_offset(),
+    };
+
+    (vector_table_offset < slot.size()).then(|| slot.address() + vector_table_offset)
+}
@@ -0,0 +1,56 @@
+//! Refusing new images that are built for another board
+//!
+//! The binary reads the board ID from the UICR (see [shared::board_id]) and sets it with [set_board_id] at every
+//! boot. A new image with another board ID is then refused as part of the [header check](crate::header_check), and
+//! its board ID is kept in the state (see [BootloaderState::refused_board_id]), so the application can tell why
+//! its update didn't happen.
+//!
+//! [BootloaderState::refused_board_id]: shared::state::BootloaderState::refused_board_id
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use shared::{
+    board_id::ANY_BOARD, image_header::ImageHeader, mcuboot::McubootHeader, slots::SlotDescriptor,
+    Flash,
+};
+
+/// The value of [BOARD_ID] when the board ID isn't known
+const UNKNOWN_BOARD: u32 = u32::MAX;
+
+/// The board ID of this board, or [UNKNOWN_BOARD]
+static BOARD_ID: AtomicU32 = AtomicU32::new(UNKNOWN_BOARD);
+
+/// Sets the board ID of this board. With `None`, images for every board are accepted.
+pub fn set_board_id(board_id: Option<u16>) {
+    BOARD_ID.store(board_id.map_or(UNKNOWN_BOARD, u32::from), Ordering::Relaxed);
+}
+
+/// The board ID of this board, if it's known
+pub fn board_id() -> Option<u16> {
+    match BOARD_ID.load(Ordering::Relaxed) {
+        UNKNOWN_BOARD => None,
+        board_id => Some(board_id as u16),
+    }
+}
+
+/// Reads the board ID of the image in the slot from its image header or its MCUboot trailer.
+/// An image without one runs on [ANY_BOARD].
+pub fn image_board_id(flash: &dyn Flash, slot: &SlotDescriptor) -> u16 {
+    if let Some(header) = ImageHeader::load(flash, slot.address()) {
+        return header.board_id;
+    }
+
+    McubootHeader::load(flash, slot.address())
+        .and_then(|header| {
+            header.board_id(
+                flash,
+                slot.address(),
+                slot.address() + slot.image_capacity(),
+            )
+        })
+        .unwrap_or(ANY_BOARD)
+}
+
+/// Returns true if an image with the given board ID may run on this board
+pub fn runs_on_this_board(image_board_id: u16) -> bool {
+    image_board_id == ANY_BOARD || board_id().unwrap_or(image_board_id) == image_board_id
+}
@@ -0,0 +1,74 @@
+//! Erasing the old image after an update
+//!
+//! After a swap, slot B has the previous image and the scratch area has copies of its pages.
+//! For data protection, they shouldn't stay readable once the new image is confirmed.
+//!
+//! At the end of every swap, [mark_old_image] stores a CRC of slot B in the state and marks the old image as
+//! [OldImageStatus::Pending]. When the goal is [JumpToApplication](BootloaderGoal::JumpToApplication) at the end
+//! of a boot, the new image is confirmed and [erase_old_image] erases slot B and the scratch area.
+//! That only happens if slot B still has the stored CRC: if the application already staged a new image there,
+//! it's left alone. The erase is tracked in the state, so it's resumed after a reset and not repeated after that.
+
+use crate::{uprintln, watchdog, LogSink};
+use shared::{
+    flash_addresses::{
+        bootloader_scratch_page_range, program_slot_b_page_range, program_slot_b_range, PAGE_SIZE,
+    },
+    state::{BootloaderGoal, BootloaderState, OldImageStatus},
+    Flash, FlashError,
+};
+
+/// Calculates the CRC-32/MPEG-2 of slot B
+fn slot_b_crc(flash: &dyn Flash) -> u32 {
+    crc::Crc::<u32>::new(&crc::CRC_32_MPEG_2).checksum(flash.read_u8(program_slot_b_range()))
+}
+
+/// Marks the image that a swap just left in slot B for erasing. The state must be stored after this.
+pub fn mark_old_image(state: &mut BootloaderState, flash: &dyn Flash) {
+    state.set_old_image_crc(slot_b_crc(flash));
+    state.set_old_image_status(OldImageStatus::Pending);
+}
+
+/// Erases the old image if it's marked for erasing and the new image is confirmed.
+///
+/// When a flash operation fails, the error is returned and the erase is resumed at the next boot.
+pub fn erase_old_image(
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> Result<(), FlashError> {
+    if state.goal() != BootloaderGoal::JumpToApplication {
+        return Ok(());
+    }
+
+    match state.old_image_status() {
+        OldImageStatus::None => return Ok(()),
+        OldImageStatus::Pending if slot_b_crc(flash) != state.old_image_crc() => {
+            uprintln!(log, "Slot B has changed since the swap, not erasing it");
+        }
+        OldImageStatus::Pending | OldImageStatus::Erasing => {
+            uprintln!(
+                log,
+                "Erasing the old image from slot B and the scratch area"
+            );
+            state.set_old_image_status(OldImageStatus::Erasing);
+            state.store(flash)?;
+
+            for page in program_slot_b_page_range().chain(bootloader_scratch_page_range()) {
+                watchdog::feed();
+                let page_address = page * PAGE_SIZE;
+                // Pages that are already erased don't need to wear the flash again
+                if flash
+                    .read_u32(page_address..page_address + PAGE_SIZE)
+                    .iter()
+                    .any(|word| *word != 0xFFFF_FFFF)
+                {
+                    flash.erase_page(page_address)?;
+                }
+            }
+        }
+    }
+
+    state.set_old_image_status(OldImageStatus::None);
+    state.store(flash)
+}
@@ -0,0 +1,222 @@
+//! Cryptographic building blocks of the bootloader
+//!
+//! The image encryption key is never stored anywhere. It's derived at boot from a hardware-unique key (HUK) and the
+//! metadata of the image, with the KDF in counter mode of NIST SP 800-108 and AES-256-CMAC as the PRF.
+//! The HUK itself is behind the [HardwareUniqueKey] trait, so on the nRF9160 it stays inside the KMU and the
+//! CryptoCell derives the keys from it. The binary hands its derivation to the core with [set_device_huk], after
+//! which [DeviceHuk] uses it. [SoftwareHuk] does the same derivation in software with a known key, for host tooling
+//! and tests with `std-compat`.
+//!
+//! The same derivation gives the tokens that authenticate a wipe of the device (see [derive_wipe_token])
+//! and the revocation of a signing key (see [derive_revocation_token]), the keystream that encrypts the event log
+//! (see [EventLogKeystream]) and the maintenance key that unlocks the shell (see [derive_maintenance_key]).
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+use shared::event_log::RecordKeystream;
+
+/// The length of the derived keys in bytes
+pub const KEY_SIZE: usize = 32;
+
+/// The label that separates the image encryption key from other keys derived from the same HUK
+const IMAGE_KEY_LABEL: &[u8] = b"dis-bootloader image key";
+
+/// A key that is unique to the device and that can only be used, not read
+pub trait HardwareUniqueKey {
+    /// Derives a key from the HUK with the KDF in counter mode of NIST SP 800-108, with AES-256-CMAC as the PRF.
+    ///
+    /// This is the derivation of the CryptoCell: block `i` of the key is the CMAC of the counter `i` as one byte,
+    /// the label, a zero byte, the context and the length of the key in bits as two big-endian bytes.
+    /// Returns `None` if the HUK can't be used, like when it was never provisioned or has been destroyed.
+    fn derive(&self, label: &[u8], context: &[u8]) -> Option<[u8; KEY_SIZE]>;
+}
+
+/// Derives a key from the HUK of the device, see [HardwareUniqueKey::derive]
+pub type KeyDerivation = fn(label: &[u8], context: &[u8]) -> Option<[u8; KEY_SIZE]>;
+
+/// The [KeyDerivation] as a pointer, or null if there is none
+static KEY_DERIVATION: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Makes [DeviceHuk] derive its keys with the given derivation, which should keep the HUK in hardware
+pub fn set_device_huk(derivation: KeyDerivation) {
+    KEY_DERIVATION.store(derivation as *mut (), Ordering::Relaxed);
+}
+
+/// The HUK of the device, with the derivation that the binary set with [set_device_huk].
+/// Without one, it can't derive any key.
+pub struct DeviceHuk;
+
+impl HardwareUniqueKey for DeviceHuk {
+    fn derive(&self, label: &[u8], context: &[u8]) -> Option<[u8; KEY_SIZE]> {
+        let derivation = KEY_DERIVATION.load(Ordering::Relaxed);
+        if derivation.is_null() {
+            return None;
+        }
+
+        // Safety: only a KeyDerivation is ever stored
+        let derivation = unsafe { core::mem::transmute::<*mut (), KeyDerivation>(derivation) };
+        derivation(label, context)
+    }
+}
+
+/// Derives the key that decrypts the image with the given metadata.
+///
+/// The metadata should contain everything that identifies the image, like its version,
+/// so that every image gets its own key.
+pub fn derive_image_key(
+    huk: &dyn HardwareUniqueKey,
+    image_metadata: &[u8],
+) -> Option<[u8; KEY_SIZE]> {
+    huk.derive(IMAGE_KEY_LABEL, image_metadata)
+}
+
+/// The label of the token that authenticates a wipe of the device
+const WIPE_TOKEN_LABEL: &[u8] = b"dis-bootloader wipe token";
+
+/// Derives the token that authenticates a wipe of the device with the given ID.
+///
+/// Whoever provisioned the device knows the key and can create the token, the application can't.
+pub fn derive_wipe_token(huk: &dyn HardwareUniqueKey, device_id: &[u8]) -> Option<[u8; KEY_SIZE]> {
+    huk.derive(WIPE_TOKEN_LABEL, device_id)
+}
+
+/// The label of the token that authenticates the revocation of a signing key
+const REVOCATION_TOKEN_LABEL: &[u8] = b"dis-bootloader revocation token";
+
+/// Derives the token that authenticates the revocation of the signing key with the given ID
+/// on the device with the given ID.
+///
+/// Like the wipe token, only whoever provisioned the device can create it.
+pub fn derive_revocation_token(
+    huk: &dyn HardwareUniqueKey,
+    device_id: &[u8; 16],
+    key_id: u32,
+) -> Option<[u8; KEY_SIZE]> {
+    // The context is the device ID followed by the little-endian key ID
+    let mut context = [0; 20];
+    context[..16].copy_from_slice(device_id);
+    context[16..].copy_from_slice(&key_id.to_le_bytes());
+
+    huk.derive(REVOCATION_TOKEN_LABEL, &context)
+}
+
+/// The label of the maintenance key
+const MAINTENANCE_KEY_LABEL: &[u8] = b"dis-bootloader maintenance key";
+
+/// The size of a challenge of the shell in bytes
+pub const CHALLENGE_SIZE: usize = 16;
+
+/// Derives the maintenance key of the device with the given ID, which answers the challenges of the shell
+/// (see [maintenance_response]).
+///
+/// Like the tokens, only whoever provisioned the device can derive it.
+pub fn derive_maintenance_key(
+    huk: &dyn HardwareUniqueKey,
+    device_id: &[u8; 16],
+) -> Option<[u8; KEY_SIZE]> {
+    huk.derive(MAINTENANCE_KEY_LABEL, device_id)
+}
+
+/// Computes the response to a challenge of the shell, which is the HMAC-SHA256 of the challenge with the maintenance
+/// key of the device
+#[cfg(feature = "maintenance-key")]
+pub fn maintenance_response(
+    maintenance_key: &[u8; KEY_SIZE],
+    challenge: &[u8; CHALLENGE_SIZE],
+) -> [u8; 32] {
+    use hmac::{Hmac, Mac};
+
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(maintenance_key).unwrap();
+    mac.update(challenge);
+    mac.finalize().into_bytes().into()
+}
+
+/// The label of the keystream of an event log record
+const EVENT_LOG_RECORD_LABEL: &[u8] = b"dis-bootloader event log record";
+
+/// The keystream of the encrypted event log records.
+///
+/// Every record gets its own keystream, derived from the HUK with the device ID and the index of the record as the
+/// context. Whoever provisioned the device can derive them too: on a host, pass this with a [SoftwareHuk] with the
+/// device secret to [shared::event_log::decrypted_records].
+pub struct EventLogKeystream<'a> {
+    /// The key of the device
+    pub huk: &'a dyn HardwareUniqueKey,
+    /// The ID of the device
+    pub device_id: [u8; 16],
+}
+
+impl EventLogKeystream<'_> {
+    /// Derives the keystream of the record with the given index
+    fn derive(&self, record_index: u32) -> Option<[u8; KEY_SIZE]> {
+        // The context is the device ID followed by the little-endian record index
+        let mut context = [0; 20];
+        context[..16].copy_from_slice(&self.device_id);
+        context[16..].copy_from_slice(&record_index.to_le_bytes());
+
+        self.huk.derive(EVENT_LOG_RECORD_LABEL, &context)
+    }
+
+    /// Returns true if the HUK can derive the keystreams, so the records can be encrypted.
+    /// After the HUK is destroyed, nobody can read encrypted records anymore.
+    pub fn is_available(&self) -> bool {
+        self.derive(0).is_some()
+    }
+}
+
+impl RecordKeystream for EventLogKeystream<'_> {
+    /// Returns the keystream of the record, or zeroes if the HUK is gone. Check [Self::is_available] first.
+    fn keystream(&self, record_index: u32) -> [u8; 6] {
+        let block = self.derive(record_index).unwrap_or_default();
+        block[..6].try_into().unwrap()
+    }
+}
+
+/// Compares two byte slices in a time that only depends on their length.
+///
+/// Signatures, MACs, digests and tokens must be compared with this instead of `==`, which stops at the first
+/// difference and so tells an attacker that measures the boot time how many bytes were right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let difference = a
+        .iter()
+        .zip(b)
+        .fold(0u8, |difference, (a, b)| difference | (a ^ b));
+
+    // Keep the compiler from turning the loop back into an early exit
+    core::hint::black_box(difference) == 0
+}
+
+/// A software stand-in for the hardware-unique key, for host tooling and tests.
+///
+/// It derives the same keys as the CryptoCell does from a KMU slot with the same key, so whoever provisioned a
+/// device can create its tokens and decrypt its event log and images. The bootloader itself never uses it.
+#[cfg(feature = "std-compat")]
+pub struct SoftwareHuk {
+    /// The value of the key
+    pub key: [u8; KEY_SIZE],
+}
+
+#[cfg(feature = "std-compat")]
+impl HardwareUniqueKey for SoftwareHuk {
+    fn derive(&self, label: &[u8], context: &[u8]) -> Option<[u8; KEY_SIZE]> {
+        use cmac::{Cmac, Mac};
+
+        let output_bits = (KEY_SIZE as u16 * 8).to_be_bytes();
+
+        let mut key = [0; KEY_SIZE];
+        for (counter, block) in (1u8..).zip(key.as_chunks_mut::<16>().0) {
+            let mut mac = Cmac::<aes::Aes256>::new_from_slice(&self.key).unwrap();
+            mac.update(&[counter]);
+            mac.update(label);
+            mac.update(&[0]);
+            mac.update(context);
+            mac.update(&output_bits);
+            *block = mac.finalize().into_bytes().into();
+        }
+
+        Some(key)
+    }
+}
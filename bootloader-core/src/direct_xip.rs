@@ -0,0 +1,86 @@
+//! Starting the newest image in place from whichever slot has it
+//!
+//! With the [DirectXip](shared::state::BootloaderGoal::DirectXip) goal, the [SlotRole::Primary] and
+//! [SlotRole::Secondary] slot of the application each have an image that is linked to run from that slot, behind an
+//! [ImageHeader]. At every boot, the bootloader starts the valid image with the highest version without copying
+//! anything, so an update is written into the slot that isn't running and a reset starts it.
+//! When both images have the same version, the primary slot wins.
+//!
+//! The vector table is only checked at the vector table offset of the slot, or at
+//! [ImageHeader::VECTOR_TABLE_OFFSET] if the slot has none, so the header is never mistaken for it.
+
+use crate::{application, uprintln, LogSink};
+#[cfg(feature = "verification")]
+use crate::{
+    events,
+    report::{self, Verification},
+};
+use shared::{
+    image_header::{ImageHeader, ImageVersion},
+    slots::{self, SlotDescriptor, SlotRole, APPLICATION_IMAGE},
+    Flash,
+};
+
+/// Finds the valid image with the highest version in the primary and secondary slot of the application and returns
+/// the address of its vector table.
+///
+/// Returns `None` if neither slot has an image header with a valid image behind it, so the caller can fall back to
+/// the primary slot. With the `verification` feature, this is recorded as a
+/// [SecurityEvent::VerificationFailed](shared::event_log::SecurityEvent::VerificationFailed) with detail 2.
+pub fn find_newest_image(
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+    slots: &[SlotDescriptor],
+) -> Option<u32> {
+    let mut newest: Option<(ImageVersion, u32)> = None;
+
+    for role in [SlotRole::Primary, SlotRole::Secondary] {
+        let slot = match slots::find(slots, role, APPLICATION_IMAGE) {
+            Some(slot) => slot,
+            None => continue,
+        };
+
+        let header = match ImageHeader::load(flash, slot.address()) {
+            Some(header) => header,
+            None => {
+                uprintln!(log, "The {:?} slot has no image header", role);
+                continue;
+            }
+        };
+
+        let slot = SlotDescriptor {
+            vector_table_offset: Some(
+                slot.vector_table_offset
+                    .unwrap_or(ImageHeader::VECTOR_TABLE_OFFSET),
+            ),
+            ..slot.clone()
+        };
+
+        match application::find_in_place_address(flash, &slot) {
+            Some(application_address) => {
+                uprintln!(log, "The {:?} slot has version {:?}", role, header.version);
+                if newest.is_none_or(|(version, _)| header.version > version) {
+                    newest = Some((header.version, application_address));
+                }
+            }
+            None => uprintln!(log, "The {:?} slot has no valid image", role),
+        }
+    }
+
+    #[cfg(feature = "verification")]
+    match newest {
+        Some(_) => report::set_verification(Verification::Passed),
+        // The fallback to the primary slot reports its own verification
+        None => {
+            events::record(
+                flash,
+                log,
+                shared::event_log::SecurityEvent::VerificationFailed,
+                2,
+            )
+            .ok();
+        }
+    }
+
+    newest.map(|(_, application_address)| application_address)
+}
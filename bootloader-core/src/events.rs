@@ -0,0 +1,58 @@
+//! Recording of security events
+//!
+//! The events are written with [record]. It first hands the event to [LogSink::security_event], which logs it and
+//! is the hook for passing it on, and then appends it to the event log in flash.
+//! The event log is encrypted once [set_event_log_encryption] is called.
+//! Without it, or when the [DeviceHuk] can't derive keys, the events are written in plain text.
+//! Every record is encrypted with its own [EventLogKeystream], derived from the HUK and the device ID,
+//! so host tooling that knows the device secret can read the log with [shared::event_log::decrypted_records].
+
+use crate::{
+    crypto::{DeviceHuk, EventLogKeystream},
+    LogSink,
+};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use shared::{
+    event_log::{self, AppendError, SecurityEvent},
+    Flash,
+};
+
+/// Set when the events must be encrypted for the device with the [DEVICE_ID]
+static ENCRYPTED: AtomicBool = AtomicBool::new(false);
+/// The ID of the device, as little-endian words
+static DEVICE_ID: [AtomicU32; 4] = [const { AtomicU32::new(0) }; 4];
+
+/// Makes [record] encrypt all events with the keystreams of the device with the given ID
+pub fn set_event_log_encryption(device_id: [u8; 16]) {
+    for (word, bytes) in DEVICE_ID.iter().zip(device_id.as_chunks::<4>().0) {
+        word.store(u32::from_le_bytes(*bytes), Ordering::Relaxed);
+    }
+    ENCRYPTED.store(true, Ordering::Relaxed);
+}
+
+/// Reports the event to the log sink and appends it to the event log, encrypted if there is a key
+pub fn record(
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+    event: SecurityEvent,
+    detail: u32,
+) -> Result<(), AppendError> {
+    log.security_event(event, detail);
+
+    if ENCRYPTED.load(Ordering::Relaxed) {
+        let mut device_id = [0; 16];
+        for (bytes, word) in device_id.as_chunks_mut::<4>().0.iter_mut().zip(&DEVICE_ID) {
+            *bytes = word.load(Ordering::Relaxed).to_le_bytes();
+        }
+
+        let keystream = EventLogKeystream {
+            huk: &DeviceHuk,
+            device_id,
+        };
+        if keystream.is_available() {
+            return event_log::append_encrypted(flash, &keystream, event, detail);
+        }
+    }
+
+    event_log::append(flash, event, detail)
+}
@@ -0,0 +1,75 @@
+//! Countermeasures against fault injection around security decisions
+//!
+//! A glitch on the supply or the clock can make the CPU skip an instruction or take the wrong side of a branch.
+//! To make that harder, a decision is not a `bool` but a [Decision] with two values that are far apart,
+//! it is taken twice, and it's surrounded by random delays so an attacker can't time the glitch.
+//! The binary must seed the delays with [seed] from a true random source.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// The outcome of a security decision
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Decision(u32);
+
+impl Decision {
+    /// The check passed. It has a large Hamming distance to [Self::INVALID], 0 and all ones.
+    pub const VALID: Self = Self(0x3CA5_965A);
+    /// The check failed
+    pub const INVALID: Self = Self(0xC35A_69A5);
+
+    /// Returns true if the decision is [Self::VALID].
+    ///
+    /// The value is read from memory twice, so a single skipped instruction can't make this return true.
+    #[inline(always)]
+    pub fn is_valid(&self) -> bool {
+        let first = unsafe { core::ptr::read_volatile(&self.0) };
+        random_delay();
+        let second = unsafe { core::ptr::read_volatile(&self.0) };
+
+        first == Self::VALID.0 && second == Self::VALID.0 && first ^ second == 0
+    }
+}
+
+/// The state of the pseudo random generator of the delays
+static DELAY_STATE: AtomicU32 = AtomicU32::new(0x9E37_79B9);
+
+/// Seeds the random delays. This should come from a true random source.
+pub fn seed(entropy: u32) {
+    // A zero state would make xorshift get stuck
+    DELAY_STATE.store(entropy | 1, Ordering::Relaxed);
+}
+
+/// Waits for a random amount of time of up to 255 iterations
+#[inline(never)]
+pub fn random_delay() {
+    // Xorshift32
+    let mut state = DELAY_STATE.load(Ordering::Relaxed);
+    state ^= state << 13;
+    state ^= state >> 17;
+    state ^= state << 5;
+    DELAY_STATE.store(state, Ordering::Relaxed);
+
+    for _ in 0..state & 0xFF {
+        core::hint::spin_loop();
+    }
+}
+
+/// Takes a decision twice with random delays around it.
+///
+/// Returns [Decision::VALID] with the result only if both decisions are valid and both results are the same.
+pub fn decide_twice<T: PartialEq + Copy>(
+    decide: impl Fn() -> (Decision, T),
+) -> (Decision, Option<T>) {
+    random_delay();
+    let (first_decision, first_result) = decide();
+    random_delay();
+    let (second_decision, second_result) = decide();
+    random_delay();
+
+    if first_decision.is_valid() && second_decision.is_valid() && first_result == second_result {
+        (Decision::VALID, Some(first_result))
+    } else {
+        (Decision::INVALID, None)
+    }
+}
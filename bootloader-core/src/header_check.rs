@@ -0,0 +1,139 @@
+//! Checking the image header of a new image before it replaces the running one
+//!
+//! A swap or an overwrite starts destroying the image in the primary slot with its first page, so a new image that
+//! was only partially downloaded, or corrupted on its way into the secondary slot, leaves the device with two broken
+//! images. Before the first page is touched, the image in the secondary slot of the application is checked with
+//! [verify_image]: an image with an [ImageHeader](shared::image_header::ImageHeader) must match the length and CRC in
+//! it. With the `image-header` feature, an image without a header is refused as well.
+//!
+//! An image that is built for another board is refused and its board ID is kept in the state, see
+//! [board_check](crate::board_check). An image that doesn't run on the hardware revision of the board is refused too,
+//! see [revision_check](crate::revision_check).
+//!
+//! An MCUboot image with a critical TLV that the bootloader doesn't know is refused too, see
+//! [McubootHeader::unknown_critical_tlv]. With the `encrypted-images` feature, the bootloader knows the
+//! [TLV_DEVICE_ENCRYPTION](shared::mcuboot::TLV_DEVICE_ENCRYPTION) TLV.
+//!
+//! With the `image-digest` feature, the image must also have an MCUboot trailer with a matching SHA-256 digest
+//! (see [image_digest](crate::image_digest)).
+//!
+//! With the `secure-boot` feature, it must be signed with the signing key (see [secure_boot](crate::secure_boot)).
+//!
+//! With the `anti-rollback` feature, its security counter must not be lower than the stored one (see
+//! [anti_rollback](crate::anti_rollback)).
+//!
+//! A refused image is recorded as a [SecurityEvent::VerificationFailed] with detail 3 for the header, 4 for the
+//! digest, 5 for the signature, 6 for the security counter, 7 for a critical TLV, 8 for the board ID and 10 for the
+//! hardware revision, and the image in the primary slot keeps running. A swap also refuses an encrypted image with
+//! detail 9, because only an overwrite can decrypt it.
+
+use crate::{board_check, events, revision_check, uprintln, LogSink};
+use shared::{
+    event_log::SecurityEvent,
+    image_header::{verify_image, ImageError},
+    mcuboot::McubootHeader,
+    slots::SlotDescriptor,
+    state::BootloaderState,
+    Flash,
+};
+
+/// The TLVs that this build knows next to the [KNOWN_TLVS](shared::mcuboot::KNOWN_TLVS) of every build
+#[cfg(feature = "encrypted-images")]
+const OPTIONAL_TLVS: &[u16] = &[shared::mcuboot::TLV_DEVICE_ENCRYPTION];
+#[cfg(not(feature = "encrypted-images"))]
+const OPTIONAL_TLVS: &[u16] = &[];
+
+/// Checks the new image in the given slot and returns true if it may replace the image in the primary slot.
+///
+/// The refused board ID in the state is updated, so the state must be stored after this.
+pub(crate) fn new_image_is_intact(
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+    state: &mut BootloaderState,
+    slot: &SlotDescriptor,
+) -> bool {
+    state.set_refused_board_id(None);
+
+    match verify_image(&*flash, slot) {
+        Ok(header) => uprintln!(log, "The new image has version {:?}", header.version),
+        Err(ImageError::NoHeader) if !cfg!(feature = "image-header") => {}
+        Err(error) => {
+            uprintln!(log, "The new image is refused: {:?}", error);
+            events::record(flash, log, SecurityEvent::VerificationFailed, 3).ok();
+            return false;
+        }
+    }
+
+    let board_id = board_check::image_board_id(&*flash, slot);
+    if !board_check::runs_on_this_board(board_id) {
+        uprintln!(
+            log,
+            "The new image is refused, it's built for board {} instead of board {:?}",
+            board_id,
+            board_check::board_id()
+        );
+        state.set_refused_board_id(Some(board_id));
+        events::record(flash, log, SecurityEvent::VerificationFailed, 8).ok();
+        return false;
+    }
+
+    let compatible_revisions = revision_check::image_compatible_revisions(&*flash, slot);
+    if !revision_check::runs_on_this_revision(compatible_revisions) {
+        uprintln!(
+            log,
+            "The new image is refused, it runs on revisions {:#010X} instead of revision {:?}",
+            compatible_revisions,
+            revision_check::hardware_revision()
+        );
+        events::record(flash, log, SecurityEvent::VerificationFailed, 10).ok();
+        return false;
+    }
+
+    let slot_end = slot.address() + slot.image_capacity();
+    if let Some(tlv_type) = McubootHeader::load(&*flash, slot.address()).and_then(|header| {
+        header.unknown_critical_tlv_except(&*flash, slot.address(), slot_end, OPTIONAL_TLVS)
+    }) {
+        uprintln!(
+            log,
+            "The new image is refused, it has an unknown critical TLV {:#06X}",
+            tlv_type
+        );
+        events::record(flash, log, SecurityEvent::VerificationFailed, 7).ok();
+        return false;
+    }
+
+    #[cfg(feature = "image-digest")]
+    if let Err(error) = crate::image_digest::verify_digest(flash, slot) {
+        uprintln!(log, "The digest of the new image is refused: {:?}", error);
+        events::record(flash, log, SecurityEvent::VerificationFailed, 4).ok();
+        return false;
+    }
+
+    #[cfg(feature = "secure-boot")]
+    if let Err(error) = crate::secure_boot::verify_signature(flash, slot) {
+        uprintln!(
+            log,
+            "The signature of the new image is refused: {:?}",
+            error
+        );
+        events::record(flash, log, SecurityEvent::VerificationFailed, 5).ok();
+        return false;
+    }
+
+    #[cfg(feature = "anti-rollback")]
+    {
+        let counter = crate::anti_rollback::image_security_counter(flash, slot);
+        if counter < crate::anti_rollback::security_counter() {
+            uprintln!(
+                log,
+                "The new image is refused, its security counter {} is lower than {}",
+                counter,
+                crate::anti_rollback::security_counter()
+            );
+            events::record(flash, log, SecurityEvent::VerificationFailed, 6).ok();
+            return false;
+        }
+    }
+
+    true
+}
@@ -0,0 +1,82 @@
+//! The health check of a test-swapped image
+//!
+//! Normally a test-swapped image is reverted at the first boot in which it isn't confirmed. With a failed boot
+//! threshold, the image gets more boots, as long as it starts properly. The binary tells the core whether the
+//! application of the previous boot completed the [handshake](shared::mailbox::complete_handshake). A boot without
+//! the handshake counts as failed, and the image is reverted once the threshold is reached. This catches images
+//! that start, but never reach their main loop.
+
+use crate::{events, uprintln, LogSink};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use shared::{event_log::SecurityEvent, state::BootloaderState, Flash};
+
+/// The stored threshold when the handshake isn't used
+const NO_THRESHOLD: u32 = 0xFFFF_FFFF;
+
+/// The number of failed boots after which a test image is reverted
+static FAILED_BOOT_THRESHOLD: AtomicU32 = AtomicU32::new(NO_THRESHOLD);
+/// Set when the application of the previous boot completed the handshake
+static HANDSHAKE_COMPLETED: AtomicBool = AtomicBool::new(false);
+
+/// Sets the number of failed boots after which a test image is reverted.
+/// With `None`, the handshake isn't used and the image is reverted at the first boot it isn't confirmed.
+pub fn set_failed_boot_threshold(threshold: Option<u32>) {
+    FAILED_BOOT_THRESHOLD.store(threshold.unwrap_or(NO_THRESHOLD), Ordering::Relaxed);
+}
+
+/// Tells the core whether the application of the previous boot completed the handshake
+pub fn set_handshake_completed(completed: bool) {
+    HANDSHAKE_COMPLETED.store(completed, Ordering::Relaxed);
+}
+
+/// Decides whether the unconfirmed test image gets another boot instead of being reverted.
+///
+/// A failed boot is counted in the state, and the rollback is recorded when the threshold is reached.
+pub(crate) fn keep_test_image(
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> bool {
+    let threshold = FAILED_BOOT_THRESHOLD.load(Ordering::Relaxed);
+    let failed_boots = match state.failed_test_boots() {
+        Some(failed_boots) if threshold != NO_THRESHOLD => failed_boots,
+        _ => return false,
+    };
+
+    if HANDSHAKE_COMPLETED.load(Ordering::Relaxed) {
+        uprintln!(
+            log,
+            "The test image completed the handshake, it may boot until it's confirmed"
+        );
+        return true;
+    }
+
+    let failed_boots = failed_boots + 1;
+    if failed_boots >= threshold {
+        uprintln!(
+            log,
+            "The test image failed {} boots, reverting it",
+            failed_boots
+        );
+        events::record(flash, log, SecurityEvent::RollbackTriggered, failed_boots).ok();
+        return false;
+    }
+
+    uprintln!(
+        log,
+        "The test image failed {} of {} boots",
+        failed_boots,
+        threshold
+    );
+    state.set_failed_test_boots(Some(failed_boots));
+    if let Err(error) = state.store(flash) {
+        // Without the count in flash, the image could fail boots forever
+        uprintln!(
+            log,
+            "Could not count the failed boot, reverting: {:?}",
+            error
+        );
+        return false;
+    }
+    true
+}
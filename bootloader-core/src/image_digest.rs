@@ -0,0 +1,80 @@
+//! Checking the SHA-256 digest of a new image
+//!
+//! An image that is signed for MCUboot has the SHA-256 digest of its header, the image itself and its protected TLVs
+//! in the [TLV_SHA256] TLV of its trailer. With the `image-digest` feature, the bootloader computes the digest of the
+//! image in the secondary slot in software and only swaps it in if it matches, as part of the
+//! [header check](crate::header_check). Unlike the CRC of our own image header, this also catches an image that was
+//! changed on purpose along with its CRC, as long as the trailer can be trusted.
+//!
+//! Like with MCUboot, the digest of an encrypted image covers the decrypted image, so it's decrypted while it's hashed
+//! (see [image_encryption](crate::image_encryption)).
+
+use crate::crypto::constant_time_eq;
+use core::ops::Range;
+use sha2::{Digest, Sha256};
+use shared::{
+    mcuboot::{McubootHeader, TLV_SHA256},
+    slots::SlotDescriptor,
+    Flash,
+};
+
+/// The size of a SHA-256 digest in bytes
+pub const DIGEST_SIZE: usize = 32;
+
+/// Why an image doesn't pass [verify_digest]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DigestError {
+    /// There is no MCUboot header at the start of the slot
+    NoHeader,
+    /// The trailer has no SHA-256 TLV, or it isn't valid
+    NoDigest,
+    /// The digest of the image doesn't match the one in the trailer
+    Mismatch,
+}
+
+/// Computes the SHA-256 digest of the image in the slot, compares it with the digest in its trailer and returns it
+pub fn verify_digest(
+    flash: &dyn Flash,
+    slot: &SlotDescriptor,
+) -> Result<[u8; DIGEST_SIZE], DigestError> {
+    let header = McubootHeader::load(flash, slot.address()).ok_or(DigestError::NoHeader)?;
+
+    // The image must end before the first excluded page
+    let image_end = slot.address() + slot.image_capacity();
+    let stored = header
+        .find_tlv(flash, slot.address(), image_end, TLV_SHA256)
+        .filter(|stored| stored.len() == DIGEST_SIZE)
+        .ok_or(DigestError::NoDigest)?;
+
+    let digest = hash(flash, slot, header.hashed_range(slot.address()));
+    if !constant_time_eq(&digest, flash.read_u8(stored)) {
+        return Err(DigestError::Mismatch);
+    }
+
+    Ok(digest)
+}
+
+/// Computes the SHA-256 digest of the address range in the slot
+#[cfg(not(feature = "encrypted-images"))]
+fn hash(flash: &dyn Flash, _slot: &SlotDescriptor, range: Range<u32>) -> [u8; DIGEST_SIZE] {
+    Sha256::digest(flash.read_u8(range)).into()
+}
+
+/// Computes the SHA-256 digest of the address range in the slot, decrypting it first if it's encrypted
+#[cfg(feature = "encrypted-images")]
+fn hash(flash: &dyn Flash, slot: &SlotDescriptor, range: Range<u32>) -> [u8; DIGEST_SIZE] {
+    let Some(cipher) = crate::image_encryption::ImageCipher::for_slot(flash, slot) else {
+        return Sha256::digest(flash.read_u8(range)).into();
+    };
+
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 256];
+    for address in range.clone().step_by(buffer.len()) {
+        let length = (range.end - address).min(buffer.len() as u32) as usize;
+        let chunk = &mut buffer[..length];
+        chunk.copy_from_slice(flash.read_u8(address..address + chunk.len() as u32));
+        cipher.apply(address, chunk);
+        hasher.update(chunk);
+    }
+    hasher.finalize().into()
+}
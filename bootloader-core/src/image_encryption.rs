@@ -0,0 +1,144 @@
+//! Decrypting images that are encrypted for one device
+//!
+//! An MCUboot image can be encrypted for a single device. It then has a critical [TLV_DEVICE_ENCRYPTION] TLV with a
+//! random 16 byte nonce, and the image itself, from the end of the header up to the TLVs, is encrypted with AES-256 in
+//! counter mode. Like with MCUboot, the counter starts at zero at the start of the image. The key is never sent or
+//! stored: it's derived from the HUK of the device with [derive_image_key], with the 32 bytes of the header followed
+//! by the nonce as the metadata. So only the device and whoever provisioned it know the key, and every image gets its
+//! own.
+//!
+//! Only the [SlotRole::Secondary] slot holds an encrypted image. The overwrite decrypts it while it copies it into
+//! the primary slot (see [overwrite](crate::overwrite)). Its vector table is checked and its digest is computed on the
+//! decrypted image, so the signature covers the image that ends up in the primary slot. A swap would put the image in
+//! the primary slot as it is, so a swap refuses an encrypted image.
+
+use crate::crypto::{derive_image_key, DeviceHuk, HardwareUniqueKey, KEY_SIZE};
+use aes::Aes256;
+use core::ops::Range;
+use ctr::{
+    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+    Ctr128BE,
+};
+use shared::{
+    mcuboot::{McubootHeader, TLV_DEVICE_ENCRYPTION},
+    slots::{SlotDescriptor, SlotRole},
+    Flash,
+};
+
+/// The size of the nonce in the [TLV_DEVICE_ENCRYPTION] TLV in bytes
+pub const NONCE_SIZE: usize = 16;
+
+/// Returns true if the image in the slot is encrypted for a device, whether this device can decrypt it or not
+pub fn is_encrypted(flash: &dyn Flash, slot: &SlotDescriptor) -> bool {
+    nonce_range(
+        flash,
+        slot.address(),
+        slot.address() + slot.image_capacity(),
+    )
+    .is_some()
+}
+
+/// Finds the value of the [TLV_DEVICE_ENCRYPTION] TLV of the image in the slot with the given address
+fn nonce_range(flash: &dyn Flash, slot_address: u32, slot_end: u32) -> Option<Range<u32>> {
+    McubootHeader::load(flash, slot_address)?.find_tlv(
+        flash,
+        slot_address,
+        slot_end,
+        TLV_DEVICE_ENCRYPTION,
+    )
+}
+
+/// The cipher of an encrypted image, which both encrypts and decrypts it
+pub struct ImageCipher {
+    /// The key of the image
+    key: [u8; KEY_SIZE],
+    /// The address range of the encrypted part of the image
+    image: Range<u32>,
+}
+
+impl ImageCipher {
+    /// Returns the cipher of the image in the slot, if the slot is a [SlotRole::Secondary] slot with an encrypted
+    /// image and the [DeviceHuk] can derive its key
+    pub fn for_slot(flash: &dyn Flash, slot: &SlotDescriptor) -> Option<Self> {
+        if slot.role != SlotRole::Secondary {
+            return None;
+        }
+
+        Self::load(
+            &DeviceHuk,
+            flash,
+            slot.address(),
+            slot.address() + slot.image_capacity(),
+        )
+    }
+
+    /// Derives the cipher of the image in the slot with the given address from the given HUK.
+    ///
+    /// Returns `None` if the image isn't encrypted, its nonce doesn't have [NONCE_SIZE] bytes or the HUK can't
+    /// derive the key.
+    pub fn load(
+        huk: &dyn HardwareUniqueKey,
+        flash: &dyn Flash,
+        slot_address: u32,
+        slot_end: u32,
+    ) -> Option<Self> {
+        let header = McubootHeader::load(flash, slot_address)?;
+        let nonce =
+            nonce_range(flash, slot_address, slot_end).filter(|nonce| nonce.len() == NONCE_SIZE)?;
+
+        let mut metadata = [0; McubootHeader::SIZE as usize + NONCE_SIZE];
+        let (header_bytes, nonce_bytes) = metadata.split_at_mut(McubootHeader::SIZE as usize);
+        header_bytes
+            .copy_from_slice(flash.read_u8(slot_address..slot_address + McubootHeader::SIZE));
+        nonce_bytes.copy_from_slice(flash.read_u8(nonce));
+
+        Some(Self {
+            key: derive_image_key(huk, &metadata)?,
+            image: header.image_range(slot_address),
+        })
+    }
+
+    /// Encrypts or decrypts the bytes at the given address in place. Only the bytes inside the image are changed.
+    pub fn apply(&self, address: u32, data: &mut [u8]) {
+        let Some((mut cipher, range)) = self.cipher_for(address..address + data.len() as u32)
+        else {
+            return;
+        };
+
+        cipher.apply_keystream(
+            &mut data[(range.start - address) as usize..(range.end - address) as usize],
+        );
+    }
+
+    /// Encrypts or decrypts the little-endian words at the given address in place, like [Self::apply] does
+    pub fn apply_to_words(&self, address: u32, words: &mut [u32]) {
+        let Some((mut cipher, range)) = self.cipher_for(address..address + words.len() as u32 * 4)
+        else {
+            return;
+        };
+
+        for (word_address, word) in (address..).step_by(4).zip(words) {
+            // The image may start or end in the middle of a word
+            let start = range.start.saturating_sub(word_address).min(4) as usize;
+            let end = range.end.saturating_sub(word_address).min(4) as usize;
+            if start < end {
+                let mut bytes = word.to_le_bytes();
+                cipher.apply_keystream(&mut bytes[start..end]);
+                *word = u32::from_le_bytes(bytes);
+            }
+        }
+    }
+
+    /// Returns the keystream at the start of the part of the address range that lies in the image, and that part.
+    /// Returns `None` if no part of the range lies in the image.
+    fn cipher_for(&self, range: Range<u32>) -> Option<(Ctr128BE<Aes256>, Range<u32>)> {
+        let range = range.start.max(self.image.start)..range.end.min(self.image.end);
+        if range.is_empty() {
+            return None;
+        }
+
+        let mut cipher = Ctr128BE::<Aes256>::new(&self.key.into(), &[0; 16].into());
+        cipher.seek(range.start - self.image.start);
+        Some((cipher, range))
+    }
+}
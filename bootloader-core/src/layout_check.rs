@@ -0,0 +1,142 @@
+//! The sanity check of the flash layout at boot
+//!
+//! The layout is checked when it's generated at build time, but the binary can pass its own slots to
+//! [run_with_slots](crate::run_with_slots) and the part can have another flash than the layout was made for.
+//! A swap between mismatched ranges would corrupt the images, so [run_with_slots](crate::run_with_slots) checks
+//! the layout before it performs any goal. Every problem is reported, and with a broken layout the bootloader
+//! doesn't swap, but starts the application that's there.
+//!
+//! Only a secondary slot may lie on the [external flash](shared::external_flash), and only if the flash reports one
+//! that's big enough.
+
+use crate::{uprintln, LogSink};
+use core::ops::Range;
+use shared::{
+    external_flash::external_range,
+    flash_addresses::{
+        bootloader_event_log_range, bootloader_flash_range, bootloader_flash_trace_range,
+        bootloader_panic_log_range, bootloader_revocations_range, bootloader_scratch_range,
+        bootloader_state_log_range, bootloader_state_range, user_data_range, PAGE_SIZE,
+    },
+    flash_geometry::FlashGeometry,
+    slots::{self, SlotDescriptor, SlotRole},
+    state::BootloaderState,
+};
+
+/// Checks the regions of the bootloader and the given slots against each other and against the flash.
+/// Prints every problem it finds and returns true if there are none.
+pub fn check(slots: &[SlotDescriptor], geometry: FlashGeometry, log: &mut dyn LogSink) -> bool {
+    let mut is_valid = true;
+    let mut report = |log: &mut dyn LogSink, problem: core::fmt::Arguments| {
+        uprintln!(log, "Layout error: {}", problem);
+        is_valid = false;
+    };
+
+    if geometry.page_size != PAGE_SIZE {
+        report(
+            log,
+            format_args!(
+                "the layout is made for pages of {} bytes, the flash has pages of {} bytes",
+                PAGE_SIZE, geometry.page_size
+            ),
+        );
+    }
+
+    let regions = [
+        ("bootloader flash", bootloader_flash_range()),
+        ("bootloader scratch", bootloader_scratch_range()),
+        ("bootloader state log", bootloader_state_log_range()),
+        ("bootloader panic log", bootloader_panic_log_range()),
+        ("bootloader revocations", bootloader_revocations_range()),
+        ("bootloader event log", bootloader_event_log_range()),
+        ("bootloader flash trace", bootloader_flash_trace_range()),
+        ("bootloader state", bootloader_state_range()),
+        ("user data", user_data_range()),
+    ];
+    let slot_regions = slots.iter().map(|slot| ("slot", slot.range.clone()));
+    let all_regions = || regions.iter().cloned().chain(slot_regions.clone());
+
+    for (index, (name, range)) in all_regions().enumerate() {
+        if range.start % PAGE_SIZE != 0 || range.end % PAGE_SIZE != 0 || range.start > range.end {
+            report(
+                log,
+                format_args!("the {} {:08X?} isn't whole pages", name, range),
+            );
+        }
+        if external_range(range.clone()).is_some() {
+            // Only the secondary slots can be on the external flash, the rest is read and run in place
+            if index < regions.len() || slots[index - regions.len()].role != SlotRole::Secondary {
+                report(
+                    log,
+                    format_args!("the {} {:08X?} can't be on the external flash", name, range),
+                );
+            } else if !geometry.contains(&range) {
+                report(
+                    log,
+                    format_args!(
+                        "the {} {:08X?} doesn't fit in the external flash of {} bytes",
+                        name, range, geometry.external_size
+                    ),
+                );
+            }
+        } else if range.end > geometry.size {
+            report(
+                log,
+                format_args!(
+                    "the {} {:08X?} doesn't fit in the flash of {} bytes",
+                    name, range, geometry.size
+                ),
+            );
+        }
+
+        for (other_name, other_range) in all_regions().skip(index + 1) {
+            if overlaps(&range, &other_range) {
+                report(
+                    log,
+                    format_args!(
+                        "the {} {:08X?} overlaps the {} {:08X?}",
+                        name, range, other_name, other_range
+                    ),
+                );
+            }
+        }
+    }
+
+    if bootloader_scratch_range().len() < PAGE_SIZE as usize {
+        report(log, format_args!("the bootloader scratch has no pages"));
+    }
+    if bootloader_state_range().len() != 2 * PAGE_SIZE as usize {
+        report(
+            log,
+            format_args!(
+                "the bootloader state {:08X?} isn't two pages",
+                bootloader_state_range()
+            ),
+        );
+    }
+
+    for primary in slots.iter().filter(|slot| slot.role == SlotRole::Primary) {
+        let Some(secondary) = slots::find(slots, SlotRole::Secondary, primary.image_id) else {
+            continue;
+        };
+        let largest_slot_pages = primary.page_range().len().max(secondary.page_range().len());
+        if largest_slot_pages > BootloaderState::MAX_SWAP_PAGES {
+            report(
+                log,
+                format_args!(
+                    "the slots of image {} have more than the {} pages the state can swap",
+                    primary.image_id,
+                    BootloaderState::MAX_SWAP_PAGES
+                ),
+            );
+        }
+    }
+
+    is_valid
+}
+
+/// Returns true if the ranges have at least one address in common. An empty range, like a state log without
+/// pages, has none.
+fn overlaps(a: &Range<u32>, b: &Range<u32>) -> bool {
+    !a.is_empty() && !b.is_empty() && a.start < b.end && b.start < a.end
+}
@@ -0,0 +1,489 @@
+//! The core logic of the bootloader
+//!
+//! Nothing in here knows about the HAL, the chip or an executor. All hardware access goes through
+//! the [shared::Flash] and [LogSink] trait objects, so a binary using this crate only has to be a thin shell
+//! that sets up the peripherals and performs the actual jump.
+
+#![no_std]
+#![warn(missing_docs)]
+
+use shared::{
+    event_log::SecurityEvent,
+    flash_addresses::{
+        bootloader_flash_page_range, bootloader_flash_range, bootloader_scratch_page_range,
+        bootloader_scratch_range, bootloader_state_log_range, bootloader_state_page_range,
+        bootloader_state_range, user_data_page_range, user_data_range,
+    },
+    modem_update::{self, ModemUpdateStatus},
+    slots::{self, SlotDescriptor, SlotRole, APPLICATION_IMAGE},
+    state::{BootloaderGoal, BootloaderState, GoalChangeError, RollbackReason},
+    Flash, FlashError,
+};
+
+#[cfg(feature = "anti-rollback")]
+pub mod anti_rollback;
+pub mod application;
+pub mod board_check;
+#[cfg(feature = "erase-old-image")]
+pub mod cleanup;
+pub mod crypto;
+#[cfg(feature = "direct-xip")]
+pub mod direct_xip;
+pub mod events;
+pub mod hardening;
+pub mod header_check;
+#[cfg(feature = "test-swap")]
+pub mod health;
+#[cfg(feature = "image-digest")]
+pub mod image_digest;
+#[cfg(feature = "encrypted-images")]
+pub mod image_encryption;
+pub mod layout_check;
+pub mod logging;
+#[cfg(feature = "measured-boot")]
+pub mod measurement;
+pub mod overwrite;
+pub mod report;
+pub mod restore;
+pub mod revision_check;
+pub mod rollback;
+#[cfg(feature = "secure-boot")]
+pub mod secure_boot;
+pub mod swap;
+#[cfg(feature = "flash-trace")]
+pub mod trace;
+pub mod watchdog;
+pub mod wipe;
+
+pub use application::{
+    find_application_address, find_application_address_in, is_valid_bootload_target,
+    is_valid_bootload_target_in, set_verification_policy, VerificationPolicy,
+};
+pub use logging::{Level, LogSink, NullLog};
+pub use swap::{perform_multi_image_swap, perform_swap, perform_swap_between};
+pub use wipe::wipe;
+
+/// Runs the bootloader logic.
+///
+/// Loads the state, performs the goal that is stored in it and returns the address
+/// of the vector table of the application that should be jumped to.
+///
+/// After a wipe, there is no application anymore, so this function doesn't return in that case.
+///
+/// When a flash operation fails while images are copied or erased, the error is returned. The state still has the
+/// goal to finish the operation, so the binary should reset to let it resume. Other failed flash operations are
+/// only logged and the goal is performed again at the next boot.
+pub fn run(flash: &mut dyn Flash, log: &mut dyn LogSink) -> Result<u32, FlashError> {
+    run_with_slots(flash, log, &slots::default_layout())
+}
+
+/// Runs the bootloader logic with the given slot layout, like [run] does with the standard layout.
+///
+/// The layout must have a [SlotRole::Primary] slot for the application. Swaps go between the primary and the
+/// [SlotRole::Secondary] slot of every image in the swap (see [perform_multi_image_swap]).
+/// A direct boot starts the [SlotRole::Test] slot of the application, or its secondary slot if there is no test slot.
+/// A direct-XIP boot starts the primary or the secondary slot of the application, whichever has the newest image.
+/// If the primary slot has no valid image, an executable [SlotRole::Golden] slot of the application is started.
+/// When the [layout check](layout_check::check) fails, no goal is performed and the application is started.
+pub fn run_with_slots(
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+    slots: &[SlotDescriptor],
+) -> Result<u32, FlashError> {
+    let primary = slots::find(slots, SlotRole::Primary, APPLICATION_IMAGE)
+        .expect("The slot layout must have a primary slot for the application");
+
+    // Print the memory regions we're using, just for convenience
+    udebug!(log, "\nDefined memory regions:");
+    udebug!(
+        log,
+        "\tbootloader flash:   {:08X?} ({:03?})",
+        bootloader_flash_range(),
+        bootloader_flash_page_range()
+    );
+    udebug!(
+        log,
+        "\tbootloader scratch: {:08X?} ({:03?})",
+        bootloader_scratch_range(),
+        bootloader_scratch_page_range()
+    );
+    udebug!(
+        log,
+        "\tbootloader state:   {:08X?} ({:03?})",
+        bootloader_state_range(),
+        bootloader_state_page_range()
+    );
+    udebug!(
+        log,
+        "\tbootloader log:     {:08X?}",
+        bootloader_state_log_range()
+    );
+    udebug!(
+        log,
+        "\tuser data:          {:08X?} ({:03?})",
+        user_data_range(),
+        user_data_page_range()
+    );
+    for slot in slots {
+        udebug!(
+            log,
+            "\timage {} {:9?}: {:08X?} ({:03?})",
+            slot.image_id,
+            slot.role,
+            slot.range,
+            slot.page_range()
+        );
+    }
+
+    // Swapping with a broken layout would corrupt the images, so the application that's there is started instead
+    if !layout_check::check(slots, flash.geometry(), log) {
+        uerror!(
+            log,
+            "The memory layout is broken, the goal is not performed"
+        );
+        return Ok(find_bootable_address(flash, log, slots, primary));
+    }
+
+    // Let's check what we need to do by loading the state
+    let mut state = BootloaderState::load(flash);
+
+    // The state must be valid or we will just jump to the application
+    if !state.is_valid() {
+        uwarn!(log, "State is invalid, jumping to application");
+
+        // An erased state is normal for a device that was just programmed, anything else is suspicious
+        let state_is_erased = flash
+            .read_u32(bootloader_state_range())
+            .iter()
+            .chain(flash.read_u32(bootloader_state_log_range()))
+            .all(|word| *word == 0xFFFF_FFFF);
+        if !state_is_erased {
+            events::record(flash, log, SecurityEvent::StateCrcFailure, 0).ok();
+        }
+
+        return Ok(find_bootable_address(flash, log, slots, primary));
+    }
+
+    // The goal changes below expect this goal, so they can't overwrite a goal that was set in the meantime
+    let goal = state.goal();
+    uinfo!(log, "Goal: {:?}", goal);
+    report::set_goal(goal);
+
+    match goal {
+        BootloaderGoal::JumpToApplication => {
+            // An image that keeps failing to boot is swapped back like a test swap would be
+            if rollback::revert_failing_image(slots, &mut state, flash, log)
+                && prepare_swap(
+                    slots,
+                    &mut state,
+                    goal,
+                    false,
+                    RollbackReason::BootAttempts,
+                    flash,
+                    log,
+                )
+            {
+                perform_multi_image_swap(slots, false, &mut state, flash, log)?;
+            }
+        }
+        BootloaderGoal::StartSwap => {
+            // A test image that completes the handshake isn't reverted until it fails too many boots
+            #[cfg(feature = "test-swap")]
+            if health::keep_test_image(&mut state, flash, log) {
+                return Ok(find_bootable_address(flash, log, slots, primary));
+            }
+
+            if prepare_swap(
+                slots,
+                &mut state,
+                goal,
+                false,
+                RollbackReason::None,
+                flash,
+                log,
+            ) {
+                // TODO: think about reset here
+                perform_multi_image_swap(slots, false, &mut state, flash, log)?;
+            }
+        }
+        BootloaderGoal::FinishSwap => {
+            perform_multi_image_swap(slots, false, &mut state, flash, log)?;
+        }
+        #[cfg(feature = "test-swap")]
+        BootloaderGoal::StartTestSwap => {
+            if prepare_swap(
+                slots,
+                &mut state,
+                goal,
+                true,
+                RollbackReason::None,
+                flash,
+                log,
+            ) {
+                perform_multi_image_swap(slots, true, &mut state, flash, log)?;
+            }
+        }
+        #[cfg(feature = "test-swap")]
+        BootloaderGoal::FinishTestSwap => {
+            perform_multi_image_swap(slots, true, &mut state, flash, log)?;
+        }
+        #[cfg(not(feature = "test-swap"))]
+        BootloaderGoal::StartTestSwap => {
+            uwarn!(
+                log,
+                "Test swaps are not supported, performing a normal swap"
+            );
+            if prepare_swap(
+                slots,
+                &mut state,
+                goal,
+                false,
+                RollbackReason::None,
+                flash,
+                log,
+            ) {
+                perform_multi_image_swap(slots, false, &mut state, flash, log)?;
+            }
+        }
+        #[cfg(not(feature = "test-swap"))]
+        BootloaderGoal::FinishTestSwap => {
+            perform_multi_image_swap(slots, false, &mut state, flash, log)?;
+        }
+        BootloaderGoal::StartModemUpdate => {
+            let status = match modem_update::validate(flash) {
+                Ok(descriptor) => {
+                    uinfo!(log, "Staged modem update is valid: {:08X?}", descriptor);
+                    ModemUpdateStatus::Validated
+                }
+                Err(error) => {
+                    uwarn!(log, "Staged modem update is invalid: {:?}", error);
+                    ModemUpdateStatus::Invalid
+                }
+            };
+
+            // The application performs the actual update
+            state.set_modem_update_status(status);
+            state.set_goal(goal, BootloaderGoal::JumpToApplication).ok();
+            store_state(&state, flash, log);
+        }
+        BootloaderGoal::FinishModemUpdate => {
+            state.set_modem_update_status(ModemUpdateStatus::Done);
+            state.set_goal(goal, BootloaderGoal::JumpToApplication).ok();
+            store_state(&state, flash, log);
+        }
+        BootloaderGoal::UpdateBootloader => {
+            // The binary replaces the bootloader before the core runs, so it doesn't support it if we get here
+            uwarn!(log, "Bootloader updates are not supported");
+            state.set_goal(goal, BootloaderGoal::JumpToApplication).ok();
+            store_state(&state, flash, log);
+        }
+        #[cfg(feature = "direct-boot")]
+        BootloaderGoal::BootSlotB | BootloaderGoal::TestBootSlotB => {
+            let direct_boot_slot = slots::find(slots, SlotRole::Test, APPLICATION_IMAGE)
+                .or_else(|| slots::find(slots, SlotRole::Secondary, APPLICATION_IMAGE));
+            match direct_boot_slot
+                .and_then(|slot| application::find_direct_boot_address(flash, log, slot))
+            {
+                Some(application_address) => {
+                    // Without a confirmation by the application, the next boot rolls back to slot A
+                    if goal == BootloaderGoal::TestBootSlotB {
+                        state.set_goal(goal, BootloaderGoal::JumpToApplication).ok();
+                        // Without the rollback in flash, the test image could be started forever
+                        if let Err(error) = state.store(flash) {
+                            uerror!(
+                                log,
+                                "Could not store the rollback, starting the primary slot: {:?}",
+                                error
+                            );
+                            return Ok(find_bootable_address(flash, log, slots, primary));
+                        }
+                    }
+                    return Ok(application_address);
+                }
+                None => {
+                    uwarn!(
+                        log,
+                        "The direct boot slot has no valid image, falling back to the primary slot"
+                    );
+                    state.set_goal(goal, BootloaderGoal::JumpToApplication).ok();
+                    store_state(&state, flash, log);
+                }
+            }
+        }
+        #[cfg(not(feature = "direct-boot"))]
+        BootloaderGoal::BootSlotB | BootloaderGoal::TestBootSlotB => {
+            uwarn!(log, "Direct boots of slot B are not supported");
+            state.set_goal(goal, BootloaderGoal::JumpToApplication).ok();
+            store_state(&state, flash, log);
+        }
+        BootloaderGoal::FactoryRestore => {
+            if restore::start_factory_restore(slots, &mut state, flash, log)? {
+                restore::finish_factory_restore(slots, &mut state, flash, log)?;
+            }
+        }
+        BootloaderGoal::FinishFactoryRestore => {
+            restore::finish_factory_restore(slots, &mut state, flash, log)?;
+        }
+        BootloaderGoal::Overwrite => {
+            if overwrite::start_overwrite(slots, &mut state, flash, log)? {
+                // Stored with the first copied page
+                state.set_rollback_reason(RollbackReason::None);
+                overwrite::finish_overwrite(slots, &mut state, flash, log)?;
+            }
+        }
+        BootloaderGoal::FinishOverwrite => {
+            overwrite::finish_overwrite(slots, &mut state, flash, log)?;
+        }
+        #[cfg(feature = "direct-xip")]
+        BootloaderGoal::DirectXip => {
+            // The goal stays, so the newest image is chosen again at every boot
+            match direct_xip::find_newest_image(flash, log, slots) {
+                Some(application_address) => return Ok(application_address),
+                None => uwarn!(
+                    log,
+                    "Neither slot has a valid image with a header, falling back to the primary slot"
+                ),
+            }
+        }
+        #[cfg(not(feature = "direct-xip"))]
+        BootloaderGoal::DirectXip => {
+            uwarn!(log, "Direct-XIP boots are not supported");
+            state.set_goal(goal, BootloaderGoal::JumpToApplication).ok();
+            store_state(&state, flash, log);
+        }
+        BootloaderGoal::Revert => {
+            if rollback::can_revert(slots, &mut state, flash, log)
+                && prepare_swap(
+                    slots,
+                    &mut state,
+                    goal,
+                    false,
+                    RollbackReason::Requested,
+                    flash,
+                    log,
+                )
+            {
+                perform_multi_image_swap(slots, false, &mut state, flash, log)?;
+            }
+        }
+        BootloaderGoal::Wipe => {
+            wipe(flash, log)?;
+            uwarn!(
+                log,
+                "The device has been wiped, it must be reprogrammed with a debugger"
+            );
+            loop {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    // The new image is confirmed once the goal is back at jumping to the application
+    // A failed erase is resumed at the next boot, it doesn't have to keep the application from starting
+    #[cfg(feature = "erase-old-image")]
+    if let Err(error) = cleanup::erase_old_image(&mut state, flash, log) {
+        uwarn!(log, "Could not erase the old image: {:?}", error);
+    }
+
+    Ok(find_bootable_address(flash, log, slots, primary))
+}
+
+/// Prepares the state for a swap and returns true if the swap can start.
+///
+/// If the application takes part in the swap, the image in its secondary slot must pass the header check first
+/// (see [header_check]) and, with the `encrypted-images` feature, must not be encrypted. The images of every swapped
+/// pair must fit in the smaller slot of the pair (see [swap::swap_page_count]). Otherwise, the goal is set back to
+/// jumping to the application.
+/// The rollback reason is stored with it, so the application can tell whether the swap reverted the image.
+/// A failed store is only logged, the goal then stays and the swap is tried again at the next boot.
+fn prepare_swap(
+    slots: &[SlotDescriptor],
+    state: &mut BootloaderState,
+    goal: BootloaderGoal,
+    test_swap: bool,
+    reason: RollbackReason,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> bool {
+    let new_image = slots::find(slots, SlotRole::Secondary, APPLICATION_IMAGE)
+        .filter(|_| state.swap_images() & 1 << APPLICATION_IMAGE != 0);
+    if let Some(new_image) = new_image {
+        if !header_check::new_image_is_intact(flash, log, state, new_image) {
+            state.set_goal(goal, BootloaderGoal::JumpToApplication).ok();
+            store_state(state, flash, log);
+            return false;
+        }
+
+        // A swap would put the image in the primary slot as it is, only the overwrite decrypts it
+        #[cfg(feature = "encrypted-images")]
+        if image_encryption::is_encrypted(flash, new_image) {
+            uwarn!(
+                log,
+                "The new image is encrypted, it can only be installed with an overwrite"
+            );
+            events::record(flash, log, SecurityEvent::VerificationFailed, 9).ok();
+            state.set_goal(goal, BootloaderGoal::JumpToApplication).ok();
+            store_state(state, flash, log);
+            return false;
+        }
+    }
+
+    for image_id in (0..u32::BITS as u8).filter(|id| state.swap_images() & 1 << id != 0) {
+        let (Some(primary), Some(secondary)) = (
+            slots::find(slots, SlotRole::Primary, image_id),
+            slots::find(slots, SlotRole::Secondary, image_id),
+        ) else {
+            continue;
+        };
+        if swap::swap_page_count(flash, primary, secondary).is_none() {
+            uwarn!(
+                log,
+                "The image {} doesn't fit in the other slot, the swap is refused",
+                image_id
+            );
+            state.set_goal(goal, BootloaderGoal::JumpToApplication).ok();
+            store_state(state, flash, log);
+            return false;
+        }
+    }
+
+    state.set_rollback_reason(reason);
+    match state.prepare_swap(goal, test_swap, flash) {
+        Ok(()) => true,
+        Err(GoalChangeError::Mismatch(_)) => false,
+        Err(GoalChangeError::Flash(error)) => {
+            uerror!(log, "Could not prepare the swap: {:?}", error);
+            false
+        }
+    }
+}
+
+/// Stores the state after a goal change that doesn't move any images.
+///
+/// A failed store is only logged, so the application still starts and the goal is performed again at the next boot.
+fn store_state(state: &BootloaderState, flash: &mut dyn Flash, log: &mut dyn LogSink) {
+    if let Err(error) = state.store(flash) {
+        uerror!(log, "Could not store the state: {:?}", error);
+    }
+}
+
+/// Finds the vector table of the application in the primary slot.
+///
+/// With the `verification` feature, the image in an executable [SlotRole::Golden] slot is started instead if the
+/// primary slot has no valid image.
+#[cfg_attr(not(feature = "verification"), allow(unused_variables))]
+fn find_bootable_address(
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+    slots: &[SlotDescriptor],
+    primary: &SlotDescriptor,
+) -> u32 {
+    #[cfg(feature = "verification")]
+    if let Some(application_address) = slots::find(slots, SlotRole::Golden, APPLICATION_IMAGE)
+        .and_then(|golden| application::find_golden_address(flash, log, primary, golden))
+    {
+        return application_address;
+    }
+
+    application::find_application_address_in(flash, log, primary)
+}
@@ -0,0 +1,228 @@
+//! The log output of the bootloader
+//!
+//! Everything the bootloader logs goes to a [LogSink], so the swap code doesn't know whether that's the UART, RTT or
+//! [nothing](NullLog). Messages are written with [uprintln] or, with a [Level], with [uerror], [uwarn], [uinfo] and
+//! [udebug]. Without the `logging` feature or with the `silent` feature all of them compile to nothing, so the format
+//! strings don't take up flash. The [udebug] messages, like every page of a swap, also need the `debug-log` feature.
+
+use core::fmt::{self, Write};
+use shared::event_log::SecurityEvent;
+
+/// A print macro that takes a [LogSink] and then the print expression like println!.
+///
+/// The output is formatted straight into the sink in small chunks, so no big buffer is needed and messages can have any length.
+#[cfg(all(feature = "logging", not(feature = "silent")))]
+#[macro_export]
+macro_rules! uprintln {
+    ($uart:expr, $($arg:tt)*) => {
+        {
+            #[allow(unused_imports)]
+            use $crate::LogSink as _;
+            $uart.write_line(format_args!($($arg)*));
+        }
+    };
+}
+
+/// A print macro that takes a [LogSink] and then the print expression like println!.
+///
+/// Logging is disabled, so this compiles to nothing. The arguments are still type checked.
+#[cfg(any(not(feature = "logging"), feature = "silent"))]
+#[macro_export]
+macro_rules! uprintln {
+    ($uart:expr, $($arg:tt)*) => {
+        {
+            let _ = &$uart;
+            if false {
+                let _ = format_args!($($arg)*);
+            }
+        }
+    };
+}
+
+/// Logs a message with the given [Level] to a [LogSink]. This is used by [uerror], [uwarn], [uinfo] and [udebug].
+#[cfg(all(feature = "logging", not(feature = "silent")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __ulog {
+    ($level:expr, $log:expr, $($arg:tt)*) => {
+        {
+            #[allow(unused_imports)]
+            use $crate::LogSink as _;
+            $log.log($level, format_args!($($arg)*));
+        }
+    };
+}
+
+/// Logging is disabled, so this compiles to nothing. The arguments are still type checked.
+#[cfg(any(not(feature = "logging"), feature = "silent"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __ulog {
+    ($level:expr, $log:expr, $($arg:tt)*) => {
+        $crate::uprintln!($log, $($arg)*)
+    };
+}
+
+/// Logs an error, something that went wrong and may leave the device without a working image, to a [LogSink]
+#[macro_export]
+macro_rules! uerror {
+    ($log:expr, $($arg:tt)*) => {
+        $crate::__ulog!($crate::Level::Error, $log, $($arg)*)
+    };
+}
+
+/// Logs a warning, something that went wrong but that the bootloader recovers from, to a [LogSink]
+#[macro_export]
+macro_rules! uwarn {
+    ($log:expr, $($arg:tt)*) => {
+        $crate::__ulog!($crate::Level::Warn, $log, $($arg)*)
+    };
+}
+
+/// Logs what the bootloader does to a [LogSink], like [uprintln]
+#[macro_export]
+macro_rules! uinfo {
+    ($log:expr, $($arg:tt)*) => {
+        $crate::__ulog!($crate::Level::Info, $log, $($arg)*)
+    };
+}
+
+/// Logs the details of what the bootloader does to a [LogSink]
+#[cfg(feature = "debug-log")]
+#[macro_export]
+macro_rules! udebug {
+    ($log:expr, $($arg:tt)*) => {
+        $crate::__ulog!($crate::Level::Debug, $log, $($arg)*)
+    };
+}
+
+/// Logs the details of what the bootloader does to a [LogSink].
+///
+/// The `debug-log` feature is disabled, so this compiles to nothing. The arguments are still type checked.
+#[cfg(not(feature = "debug-log"))]
+#[macro_export]
+macro_rules! udebug {
+    ($log:expr, $($arg:tt)*) => {
+        {
+            let _ = &$log;
+            if false {
+                let _ = format_args!($($arg)*);
+            }
+        }
+    };
+}
+
+/// How important a log message is
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Level {
+    /// Something went wrong and the device may be left without a working image
+    Error,
+    /// Something went wrong, but the bootloader recovers from it
+    Warn,
+    /// What the bootloader does
+    Info,
+    /// The details of what the bootloader does
+    Debug,
+}
+
+impl Level {
+    /// The text in front of the messages of this level in a line based sink
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Level::Error => "Error: ",
+            Level::Warn => "Warning: ",
+            Level::Info => "",
+            Level::Debug => "Debug: ",
+        }
+    }
+}
+
+/// Something the bootloader can write its log output to
+pub trait LogSink {
+    /// Write all bytes to the sink. This function returns when everything has been written.
+    ///
+    /// When called by [uprintln], the bytes are always in RAM, so they can be used for DMA directly.
+    fn write_bytes(&mut self, bytes: &[u8]);
+
+    /// Called for every security event that the bootloader records (see [crate::events]).
+    ///
+    /// The default implementation logs the event. Sinks can override it to also pass the event on,
+    /// like the bootloader does with the boot info block for the application.
+    fn security_event(&mut self, event: SecurityEvent, detail: u32) {
+        crate::uprintln!(
+            self,
+            "Security event {:?} with detail {:#010X}",
+            event,
+            detail
+        );
+    }
+
+    /// Writes a message of the given level, used by [uerror], [uwarn], [uinfo] and [udebug].
+    ///
+    /// The default implementation writes it as a line with the [prefix](Level::prefix) of the level in front.
+    /// Sinks with levels of their own, like defmt, can override it.
+    fn log(&mut self, level: Level, args: fmt::Arguments) {
+        self.write_line(format_args!("{}{}", level.prefix(), args));
+    }
+
+    /// Writes the formatted arguments and a newline to the sink.
+    ///
+    /// The output is written in small chunks, so no big buffer is needed. This is used by [uprintln].
+    fn write_line(&mut self, args: fmt::Arguments) {
+        let mut writer = ChunkWriter {
+            sink: self,
+            buffer: [0; CHUNK_SIZE],
+            length: 0,
+        };
+
+        // The writer itself never fails, so the only errors come from faulty formatting implementations
+        if writer.write_fmt(args).is_err() {
+            writer.flush();
+            writer.sink.write_bytes(b"<formatting error>");
+        }
+        writer.write_str("\n").ok();
+        writer.flush();
+    }
+}
+
+/// A sink that drops everything, for when there's nowhere to log to
+pub struct NullLog;
+
+impl LogSink for NullLog {
+    fn write_bytes(&mut self, _bytes: &[u8]) {}
+}
+
+/// The size of the chunks the log output is written in
+const CHUNK_SIZE: usize = 64;
+
+/// Collects the output in a small buffer and writes it to the sink when the buffer is full
+struct ChunkWriter<'a, S: LogSink + ?Sized> {
+    sink: &'a mut S,
+    buffer: [u8; CHUNK_SIZE],
+    length: usize,
+}
+
+impl<'a, S: LogSink + ?Sized> ChunkWriter<'a, S> {
+    /// Writes everything that is in the buffer to the sink
+    fn flush(&mut self) {
+        if self.length > 0 {
+            self.sink.write_bytes(&self.buffer[..self.length]);
+            self.length = 0;
+        }
+    }
+}
+
+impl<'a, S: LogSink + ?Sized> Write for ChunkWriter<'a, S> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.length == CHUNK_SIZE {
+                self.flush();
+            }
+
+            self.buffer[self.length] = byte;
+            self.length += 1;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,19 @@
+//! Measuring the flash for attestation
+
+use sha2::{Digest, Sha256};
+use shared::{
+    flash_addresses::{bootloader_flash_range, program_slot_a_range, program_slot_b_range},
+    measurements::BootMeasurements,
+    Flash,
+};
+
+/// Hashes the bootloader flash and both program slots
+pub fn measure(flash: &dyn Flash) -> BootMeasurements {
+    let hash = |address_range| -> [u8; 32] { Sha256::digest(flash.read_u8(address_range)).into() };
+
+    BootMeasurements::new(
+        hash(bootloader_flash_range()),
+        hash(program_slot_a_range()),
+        hash(program_slot_b_range()),
+    )
+}
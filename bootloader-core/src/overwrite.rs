@@ -0,0 +1,184 @@
+//! Overwriting the primary slot with the image in the secondary slot
+//!
+//! With the [Overwrite](BootloaderGoal::Overwrite) goal, the image in the [SlotRole::Secondary] slot of the
+//! application is copied into the primary slot, page by page. Unlike a swap, the old image isn't kept, so there is
+//! no rollback. In return, every page is erased and programmed once instead of three times, which halves the wear
+//! of the flash and the time the update takes. The secondary slot is only read, and the excluded pages of both
+//! slots are left alone like in a swap.
+//!
+//! Like a swap, the overwrite marks every copied page in the page states of the state, so an overwrite that is
+//! interrupted by a reset continues where it was with the [FinishOverwrite](BootloaderGoal::FinishOverwrite) goal.
+//!
+//! With the `encrypted-images` feature, an image that is encrypted for this device is decrypted on its way into the
+//! primary slot (see [image_encryption](crate::image_encryption)). The secondary slot keeps the encrypted image, so
+//! the key can be derived again to resume the overwrite after a reset.
+
+#[cfg(feature = "verification")]
+use crate::application;
+#[cfg(not(feature = "encrypted-images"))]
+use crate::swap::copy_page;
+#[cfg(feature = "encrypted-images")]
+use crate::swap::copy_transformed_page;
+use crate::{header_check, report, uprintln, watchdog, LogSink};
+use shared::{
+    flash_addresses::PAGE_SIZE,
+    slots::{self, SlotDescriptor, SlotRole, APPLICATION_IMAGE},
+    state::{BootloaderGoal, BootloaderState, PageState},
+    Flash, FlashError,
+};
+
+/// Starts the overwrite of the primary slot by resetting the page states.
+///
+/// Returns false if the overwrite can't be done, because the layout has no secondary slot with the size of the
+/// primary slot or, with the `verification` feature, the secondary slot has no image that is linked for the primary
+/// slot. The new image must also pass the [header check](crate::header_check), and an encrypted image must be one
+/// that this device can decrypt. The goal is then set back to [BootloaderGoal::JumpToApplication].
+/// It also returns false if the goal isn't [BootloaderGoal::Overwrite] anymore.
+pub fn start_overwrite(
+    slots: &[SlotDescriptor],
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> Result<bool, FlashError> {
+    let primary = slots::find(slots, SlotRole::Primary, APPLICATION_IMAGE);
+    let secondary = slots::find(slots, SlotRole::Secondary, APPLICATION_IMAGE);
+
+    let possible = match (primary, secondary) {
+        (Some(primary), Some(secondary)) => {
+            secondary.size() == primary.size()
+                && can_be_decrypted(flash, secondary)
+                && new_image_is_valid(flash, secondary, primary)
+                && header_check::new_image_is_intact(flash, log, state, secondary)
+        }
+        _ => false,
+    };
+
+    if !possible {
+        uprintln!(log, "There is no image that can overwrite the primary slot");
+        state
+            .set_goal(BootloaderGoal::Overwrite, BootloaderGoal::JumpToApplication)
+            .ok();
+        state.store(flash)?;
+        return Ok(false);
+    }
+
+    if state
+        .set_goal(BootloaderGoal::Overwrite, BootloaderGoal::FinishOverwrite)
+        .is_err()
+    {
+        return Ok(false);
+    }
+    for page in 0..BootloaderState::MAX_SWAP_PAGES as u32 {
+        state.set_page_state(page, PageState::Original);
+    }
+    state.store(flash)?;
+    Ok(true)
+}
+
+/// Copies the pages of the secondary slot that haven't been copied yet into the primary slot and sets the goal
+/// back to [BootloaderGoal::JumpToApplication] when it's done.
+///
+/// When a flash operation fails, the overwrite stops and the error is returned, so it resumes at the next boot.
+pub fn finish_overwrite(
+    slots: &[SlotDescriptor],
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> Result<(), FlashError> {
+    if let (Some(primary), Some(secondary)) = (
+        slots::find(slots, SlotRole::Primary, APPLICATION_IMAGE),
+        slots::find(slots, SlotRole::Secondary, APPLICATION_IMAGE),
+    ) {
+        uprintln!(log, "Overwriting the primary slot with the secondary slot");
+
+        #[cfg(feature = "flash-trace")]
+        crate::trace::traced(flash, log, |flash, _| {
+            copy_pages(secondary, primary, state, flash)
+        })?;
+        #[cfg(not(feature = "flash-trace"))]
+        copy_pages(secondary, primary, state, flash)?;
+    }
+
+    state
+        .set_goal(
+            BootloaderGoal::FinishOverwrite,
+            BootloaderGoal::JumpToApplication,
+        )
+        .ok();
+    state.store(flash)
+}
+
+/// Copies the secondary slot into the primary slot page by page, skipping the pages that are already done
+fn copy_pages(
+    secondary: &SlotDescriptor,
+    primary: &SlotDescriptor,
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+) -> Result<(), FlashError> {
+    #[cfg(feature = "encrypted-images")]
+    let cipher = crate::image_encryption::ImageCipher::for_slot(flash, secondary);
+
+    for page in 0..primary.size() / PAGE_SIZE {
+        watchdog::feed();
+
+        if primary.is_page_excluded(page) || secondary.is_page_excluded(page) {
+            continue;
+        }
+
+        let copied = state.get_page_state(page) == PageState::Swapped;
+        report::count_page(copied);
+        if copied {
+            continue;
+        }
+
+        let from = secondary.address() + page * PAGE_SIZE;
+        let to = primary.address() + page * PAGE_SIZE;
+        #[cfg(feature = "encrypted-images")]
+        copy_transformed_page(flash, from, to, |data| {
+            if let Some(cipher) = &cipher {
+                cipher.apply_to_words(from, data);
+            }
+        })?;
+        #[cfg(not(feature = "encrypted-images"))]
+        copy_page(flash, from, to)?;
+
+        state.set_page_state(page, PageState::Swapped);
+        state.burn_store(flash)?;
+    }
+
+    Ok(())
+}
+
+/// Checks that the secondary slot has an image that can run from the primary slot
+#[cfg(feature = "verification")]
+fn new_image_is_valid(
+    flash: &dyn Flash,
+    secondary: &SlotDescriptor,
+    primary: &SlotDescriptor,
+) -> bool {
+    application::has_valid_image_for(flash, secondary, primary)
+}
+
+/// Without the verification, the new image is copied as it is
+#[cfg(not(feature = "verification"))]
+fn new_image_is_valid(
+    _flash: &dyn Flash,
+    _secondary: &SlotDescriptor,
+    _primary: &SlotDescriptor,
+) -> bool {
+    true
+}
+
+/// Checks that the image in the secondary slot isn't encrypted, or that this device can decrypt it
+#[cfg(feature = "encrypted-images")]
+fn can_be_decrypted(flash: &dyn Flash, secondary: &SlotDescriptor) -> bool {
+    use crate::image_encryption::{is_encrypted, ImageCipher};
+
+    !is_encrypted(flash, secondary) || ImageCipher::for_slot(flash, secondary).is_some()
+}
+
+/// Without the encryption, an encrypted image is refused by the header check for its critical TLV
+#[cfg(not(feature = "encrypted-images"))]
+fn can_be_decrypted(_flash: &dyn Flash, _secondary: &SlotDescriptor) -> bool {
+    true
+}
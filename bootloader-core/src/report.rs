@@ -0,0 +1,137 @@
+//! The summary of a boot for automated fixtures
+//!
+//! While it runs, the core notes the goal it performed, the result of the verification and what the swap did.
+//! Right before the jump, the binary calls [emit] to write all of it as a single line, so factory and HIL fixtures
+//! don't have to parse the human readable log. The line starts with [PREFIX] and has `key=value` fields separated
+//! by spaces:
+//!
+//! ```text
+//! BOOT-REPORT v1 goal=FinishSwap verification=passed swapped=104 skipped=0 jump=0x00018000
+//! ```
+//!
+//! The goal is `none` when the state was invalid or wasn't loaded. Unlike the rest of the log, the report is also
+//! written without the `logging` feature.
+
+use crate::LogSink;
+use core::sync::atomic::{AtomicU32, Ordering};
+use shared::state::BootloaderGoal;
+
+/// The start of the report line
+pub const PREFIX: &str = "BOOT-REPORT v1";
+
+/// The stored goal when no goal was performed
+const NO_GOAL: u32 = 0xFFFF_FFFF;
+
+/// The goal that was performed
+static GOAL: AtomicU32 = AtomicU32::new(NO_GOAL);
+/// The [Verification] of the image that is started
+static VERIFICATION: AtomicU32 = AtomicU32::new(Verification::Skipped as u32);
+/// The number of pages that were swapped in this run
+static SWAPPED_PAGES: AtomicU32 = AtomicU32::new(0);
+/// The number of pages that a resumed swap had already swapped before this run or that were identical in both slots
+static SKIPPED_PAGES: AtomicU32 = AtomicU32::new(0);
+/// The number of pages that the swap erased in this run
+static ERASES: AtomicU32 = AtomicU32::new(0);
+
+/// The result of the search for the vector table of the image that is started
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Verification {
+    /// The image wasn't verified, because the `verification` feature is disabled
+    Skipped = 0,
+    /// A valid vector table was found
+    Passed = 1,
+    /// No valid vector table was found and the start of the slot is used anyway
+    Failed = 2,
+}
+
+/// The summary of the current boot
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BootReport {
+    /// The goal that was performed, or `None` if the state was invalid or wasn't loaded
+    pub goal: Option<BootloaderGoal>,
+    /// The result of the verification of the image that is started
+    pub verification: Verification,
+    /// The number of pages that were swapped in this run
+    pub swapped_pages: u32,
+    /// The number of pages that a resumed swap had already swapped before this run or that were identical in both
+    /// slots
+    pub skipped_pages: u32,
+    /// The number of pages that the swap erased in this run
+    pub erases: u32,
+    /// The address of the vector table that is jumped to
+    pub jump_address: u32,
+}
+
+/// Notes the goal that is performed
+pub(crate) fn set_goal(goal: BootloaderGoal) {
+    GOAL.store(goal.into(), Ordering::Relaxed);
+}
+
+/// Notes the result of the verification
+#[cfg_attr(not(feature = "verification"), allow(dead_code))]
+pub(crate) fn set_verification(verification: Verification) {
+    VERIFICATION.store(verification as u32, Ordering::Relaxed);
+}
+
+/// Counts a page that was swapped, or that didn't have to be swapped if `skipped` is true
+pub(crate) fn count_page(skipped: bool) {
+    let counter = if skipped {
+        &SKIPPED_PAGES
+    } else {
+        &SWAPPED_PAGES
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a page erase of the swap
+pub(crate) fn count_erase() {
+    ERASES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the summary of the boot so far, with the given jump address
+pub fn current(jump_address: u32) -> BootReport {
+    BootReport {
+        goal: match GOAL.load(Ordering::Relaxed) {
+            NO_GOAL => None,
+            goal => goal.try_into().ok(),
+        },
+        verification: match VERIFICATION.load(Ordering::Relaxed) {
+            1 => Verification::Passed,
+            2 => Verification::Failed,
+            _ => Verification::Skipped,
+        },
+        swapped_pages: SWAPPED_PAGES.load(Ordering::Relaxed),
+        skipped_pages: SKIPPED_PAGES.load(Ordering::Relaxed),
+        erases: ERASES.load(Ordering::Relaxed),
+        jump_address,
+    }
+}
+
+/// Writes the summary of the boot as a single line to the log sink
+pub fn emit(log: &mut dyn LogSink, jump_address: u32) {
+    let report = current(jump_address);
+
+    let verification = match report.verification {
+        Verification::Skipped => "skipped",
+        Verification::Passed => "passed",
+        Verification::Failed => "failed",
+    };
+
+    // Not through uprintln, so the report is there even when the rest of the log is compiled out
+    match report.goal {
+        Some(goal) => log.write_line(format_args!(
+            "{} goal={:?} verification={} swapped={} skipped={} jump={:#010X}",
+            PREFIX,
+            goal,
+            verification,
+            report.swapped_pages,
+            report.skipped_pages,
+            report.jump_address
+        )),
+        None => log.write_line(format_args!(
+            "{} goal=none verification={} swapped={} skipped={} jump={:#010X}",
+            PREFIX, verification, report.swapped_pages, report.skipped_pages, report.jump_address
+        )),
+    }
+}
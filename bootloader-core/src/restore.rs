@@ -0,0 +1,152 @@
+//! Restoring the factory image from the golden slot
+//!
+//! With the [FactoryRestore](BootloaderGoal::FactoryRestore) goal, the image in the [SlotRole::Golden] slot of the
+//! application is copied into the primary slot, so support can reset a device to known good firmware remotely.
+//! For this, the golden image must be linked to run from the primary slot. The primary slot is erased where the
+//! golden image is smaller. The golden slot itself is only read, and the excluded pages of the primary slot are
+//! left alone like in a swap.
+//!
+//! Like a swap, the restore marks every copied page in the page states of the state, so a restore that is
+//! interrupted by a reset continues where it was with the
+//! [FinishFactoryRestore](BootloaderGoal::FinishFactoryRestore) goal.
+
+#[cfg(feature = "verification")]
+use crate::application;
+use crate::{uprintln, watchdog, LogSink};
+use core::mem::size_of;
+use shared::{
+    flash_addresses::PAGE_SIZE,
+    slots::{self, SlotDescriptor, SlotRole, APPLICATION_IMAGE},
+    state::{BootloaderGoal, BootloaderState, PageState},
+    Flash, FlashError,
+};
+
+/// Starts the restore of the golden image into the primary slot by resetting the page states.
+///
+/// Returns false if the restore can't be done, because the layout has no golden slot that fits in the primary
+/// slot or, with the `verification` feature, the golden slot has no image that is linked for the primary slot.
+/// The goal is then set back to [BootloaderGoal::JumpToApplication].
+/// It also returns false if the goal isn't [BootloaderGoal::FactoryRestore] anymore.
+pub fn start_factory_restore(
+    slots: &[SlotDescriptor],
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> Result<bool, FlashError> {
+    let primary = slots::find(slots, SlotRole::Primary, APPLICATION_IMAGE);
+    let golden = slots::find(slots, SlotRole::Golden, APPLICATION_IMAGE);
+
+    let restorable = match (primary, golden) {
+        (Some(primary), Some(golden)) => {
+            golden.size() <= primary.size() && golden_image_is_valid(flash, golden, primary)
+        }
+        _ => false,
+    };
+
+    if !restorable {
+        uprintln!(log, "There is no golden image that can be restored");
+        state
+            .set_goal(
+                BootloaderGoal::FactoryRestore,
+                BootloaderGoal::JumpToApplication,
+            )
+            .ok();
+        state.store(flash)?;
+        return Ok(false);
+    }
+
+    if state
+        .set_goal(
+            BootloaderGoal::FactoryRestore,
+            BootloaderGoal::FinishFactoryRestore,
+        )
+        .is_err()
+    {
+        return Ok(false);
+    }
+    for page in 0..BootloaderState::MAX_SWAP_PAGES as u32 {
+        state.set_page_state(page, PageState::Original);
+    }
+    state.store(flash)?;
+    Ok(true)
+}
+
+/// Copies the pages of the golden image that haven't been copied yet into the primary slot and sets the goal back
+/// to [BootloaderGoal::JumpToApplication] when it's done.
+///
+/// When a flash operation fails, the restore stops and the error is returned, so it resumes at the next boot.
+pub fn finish_factory_restore(
+    slots: &[SlotDescriptor],
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> Result<(), FlashError> {
+    if let (Some(primary), Some(golden)) = (
+        slots::find(slots, SlotRole::Primary, APPLICATION_IMAGE),
+        slots::find(slots, SlotRole::Golden, APPLICATION_IMAGE),
+    ) {
+        uprintln!(log, "Restoring the golden image into the primary slot");
+        copy_pages(golden, primary, state, flash)?;
+    }
+
+    state
+        .set_goal(
+            BootloaderGoal::FinishFactoryRestore,
+            BootloaderGoal::JumpToApplication,
+        )
+        .ok();
+    state.store(flash)
+}
+
+/// Copies the golden slot into the primary slot page by page, skipping the pages that are already done
+fn copy_pages(
+    golden: &SlotDescriptor,
+    primary: &SlotDescriptor,
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+) -> Result<(), FlashError> {
+    let mut buffer = [0; PAGE_SIZE as usize / size_of::<u32>()];
+
+    for page in 0..primary.size() / PAGE_SIZE {
+        watchdog::feed();
+
+        if state.get_page_state(page) == PageState::Swapped || primary.is_page_excluded(page) {
+            continue;
+        }
+
+        let to = primary.address() + page * PAGE_SIZE;
+        flash.erase_page(to)?;
+
+        // The rest of the primary slot stays erased
+        if page < golden.size() / PAGE_SIZE {
+            let from = golden.address() + page * PAGE_SIZE;
+            buffer.copy_from_slice(flash.read_u32(from..from + PAGE_SIZE));
+            flash.program_page(to, &buffer)?;
+        }
+
+        state.set_page_state(page, PageState::Swapped);
+        state.burn_store(flash)?;
+    }
+
+    Ok(())
+}
+
+/// Checks that the golden slot has an image that can run from the primary slot
+#[cfg(feature = "verification")]
+fn golden_image_is_valid(
+    flash: &dyn Flash,
+    golden: &SlotDescriptor,
+    primary: &SlotDescriptor,
+) -> bool {
+    application::has_valid_image_for(flash, golden, primary)
+}
+
+/// Without the verification, the golden image is restored as it is
+#[cfg(not(feature = "verification"))]
+fn golden_image_is_valid(
+    _flash: &dyn Flash,
+    _golden: &SlotDescriptor,
+    _primary: &SlotDescriptor,
+) -> bool {
+    true
+}
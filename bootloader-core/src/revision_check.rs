@@ -0,0 +1,51 @@
+//! Refusing images that don't run on the hardware revision of the board
+//!
+//! The binary reads the hardware revision from the UICR (see [shared::hardware_revision]) and sets it with
+//! [set_hardware_revision] at every boot. A new image that doesn't run on this revision is then refused as part of
+//! the [header check](crate::header_check), and an image in a slot that doesn't run on it is never started
+//! (see [application](crate::application)).
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use shared::{hardware_revision, mcuboot::McubootHeader, slots::SlotDescriptor, Flash};
+
+/// The value of [HARDWARE_REVISION] when the revision isn't known. It's the erased UICR word, so it's never a
+/// programmed revision.
+const UNKNOWN_REVISION: u32 = u32::MAX;
+
+/// The hardware revision of this board, or [UNKNOWN_REVISION]
+static HARDWARE_REVISION: AtomicU32 = AtomicU32::new(UNKNOWN_REVISION);
+
+/// Sets the hardware revision of this board. With `None`, only images that run on all revisions are accepted.
+pub fn set_hardware_revision(hardware_revision: Option<u32>) {
+    HARDWARE_REVISION.store(
+        hardware_revision.unwrap_or(UNKNOWN_REVISION),
+        Ordering::Relaxed,
+    );
+}
+
+/// The hardware revision of this board, if it's known
+pub fn hardware_revision() -> Option<u32> {
+    match HARDWARE_REVISION.load(Ordering::Relaxed) {
+        UNKNOWN_REVISION => None,
+        hardware_revision => Some(hardware_revision),
+    }
+}
+
+/// Reads the bitmask of the hardware revisions the image in the slot runs on from its MCUboot trailer.
+/// An image without one runs on all revisions, which is a mask of 0.
+pub fn image_compatible_revisions(flash: &dyn Flash, slot: &SlotDescriptor) -> u32 {
+    McubootHeader::load(flash, slot.address())
+        .and_then(|header| {
+            header.compatible_revisions(
+                flash,
+                slot.address(),
+                slot.address() + slot.image_capacity(),
+            )
+        })
+        .unwrap_or(0)
+}
+
+/// Returns true if an image with the given bitmask of compatible revisions may run on this board
+pub fn runs_on_this_revision(compatible_revisions: u32) -> bool {
+    hardware_revision::is_compatible(compatible_revisions, hardware_revision())
+}
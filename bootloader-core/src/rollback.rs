@@ -0,0 +1,126 @@
+//! The automatic rollback of an image that keeps failing to boot
+//!
+//! With a boot attempt threshold, the bootloader counts every start of the application in the state. The application
+//! [clears the count](shared::state::BootloaderState::clear_boot_attempts) once it's running properly. When the count
+//! goes over the threshold, the image in the primary slot is swapped with the previous image in the secondary slot,
+//! which is recorded as a [SecurityEvent::RollbackTriggered] with the number of boot attempts.
+//!
+//! Unlike the health check of a test swap, this also covers images that were confirmed and broke later, for example
+//! on a configuration they didn't expect.
+//!
+//! The application can revert the image itself with the [Revert](shared::state::BootloaderGoal::Revert) goal.
+//! Either way, the [rollback reason](shared::state::BootloaderState::rollback_reason) in the state tells the previous
+//! image why it's running again.
+
+#[cfg(feature = "verification")]
+use crate::application;
+use crate::{events, uprintln, LogSink};
+use core::sync::atomic::{AtomicU32, Ordering};
+use shared::{
+    event_log::SecurityEvent,
+    slots::{self, SlotDescriptor, SlotRole, APPLICATION_IMAGE},
+    state::{BootloaderGoal, BootloaderState},
+    Flash,
+};
+
+/// The stored threshold when the boot attempts aren't counted
+const NO_THRESHOLD: u32 = 0xFFFF_FFFF;
+
+/// The number of boot attempts after which the image is reverted
+static BOOT_ATTEMPT_THRESHOLD: AtomicU32 = AtomicU32::new(NO_THRESHOLD);
+
+/// Sets the number of boots without the application clearing the boot attempts after which the image is reverted.
+/// With `None`, the boot attempts aren't counted.
+pub fn set_boot_attempt_threshold(threshold: Option<u32>) {
+    BOOT_ATTEMPT_THRESHOLD.store(threshold.unwrap_or(NO_THRESHOLD), Ordering::Relaxed);
+}
+
+/// Counts a boot attempt and decides whether the image should be reverted instead of started.
+///
+/// The state must have the [JumpToApplication](shared::state::BootloaderGoal::JumpToApplication) goal. When this
+/// returns true, the boot attempts are reset in the state, which is stored by the swap that follows.
+/// With the `verification` feature, the image is only reverted if the secondary slot has an image that is linked for
+/// the primary slot, so there is something to go back to.
+pub(crate) fn revert_failing_image(
+    slots: &[SlotDescriptor],
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> bool {
+    let threshold = BOOT_ATTEMPT_THRESHOLD.load(Ordering::Relaxed);
+    if threshold == NO_THRESHOLD {
+        return false;
+    }
+
+    let boot_attempts = state.boot_attempts().saturating_add(1);
+    if boot_attempts > threshold {
+        if !has_previous_image(slots, flash) {
+            // Counting on would only wear the state pages
+            uprintln!(
+                log,
+                "The image wasn't confirmed in {} boots, but there is no image to revert to",
+                threshold
+            );
+            return false;
+        }
+
+        uprintln!(
+            log,
+            "The image wasn't confirmed in {} boots, reverting it",
+            threshold
+        );
+        events::record(flash, log, SecurityEvent::RollbackTriggered, threshold).ok();
+        state.set_boot_attempts(0);
+        return true;
+    }
+
+    uprintln!(log, "Boot attempt {} of {}", boot_attempts, threshold);
+    state.set_boot_attempts(boot_attempts);
+    if let Err(error) = state.store(flash) {
+        // The attempt is counted again at the next boot
+        uprintln!(log, "Could not count the boot attempt: {:?}", error);
+    }
+    false
+}
+
+/// Checks whether the image can be reverted for the [Revert](shared::state::BootloaderGoal::Revert) goal.
+///
+/// With the `verification` feature, the secondary slot must have an image that is linked for the primary slot.
+/// If it doesn't, the goal is set back to [JumpToApplication](shared::state::BootloaderGoal::JumpToApplication).
+pub(crate) fn can_revert(
+    slots: &[SlotDescriptor],
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> bool {
+    if has_previous_image(slots, flash) {
+        uprintln!(log, "Reverting to the previous image");
+        return true;
+    }
+
+    uprintln!(log, "There is no image to revert to");
+    state
+        .set_goal(BootloaderGoal::Revert, BootloaderGoal::JumpToApplication)
+        .ok();
+    if let Err(error) = state.store(flash) {
+        uprintln!(log, "Could not store the state: {:?}", error);
+    }
+    false
+}
+
+/// Returns true if the secondary slot of the application has an image to revert to
+#[cfg_attr(not(feature = "verification"), allow(unused_variables))]
+fn has_previous_image(slots: &[SlotDescriptor], flash: &dyn Flash) -> bool {
+    let primary = slots::find(slots, SlotRole::Primary, APPLICATION_IMAGE);
+    let secondary = slots::find(slots, SlotRole::Secondary, APPLICATION_IMAGE);
+
+    match (primary, secondary) {
+        #[cfg(feature = "verification")]
+        (Some(primary), Some(secondary)) => {
+            application::has_valid_image_for(flash, secondary, primary)
+        }
+        #[cfg(not(feature = "verification"))]
+        (Some(_), Some(_)) => true,
+        _ => false,
+    }
+}
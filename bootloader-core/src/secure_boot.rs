@@ -0,0 +1,182 @@
+//! Refusing images that aren't signed with the signing key
+//!
+//! With the `secure-boot` feature, every image must be an MCUboot image with a valid SHA-256 digest (see
+//! [image_digest](crate::image_digest)) and a signature of that digest in its trailer, like MCUboot's own signed
+//! images. The signature is checked with the public key that the binary sets with [set_public_key], before a new
+//! image is swapped in and whenever the vector table of an image is looked for.
+//! An image that isn't signed is never started, not even with the [VerificationPolicy::Lenient] policy.
+//!
+//! A [PublicKey::Ed25519] key checks the signature in the [TLV_ED25519] TLV. With the `ecdsa-p256` feature, a
+//! [PublicKey::P256] key checks the DER encoded ECDSA signature in the [TLV_ECDSA_SIG] TLV instead.
+//! Both are done in software, which takes a while for P-256. The binary can hand the P-256 verification to hardware
+//! like the CryptoCell with [set_p256_accelerator], and the software is used if the accelerator can't do it.
+//!
+//! A bootloader update is signed like an application image and checked with the same key by
+//! [verify_bootloader_update]. Stage 0 does this with a key of its own before it installs the update.
+//!
+//! [VerificationPolicy::Lenient]: crate::VerificationPolicy::Lenient
+//! [TLV_ECDSA_SIG]: shared::mcuboot::TLV_ECDSA_SIG
+
+#[cfg(feature = "ecdsa-p256")]
+use crate::image_digest::DIGEST_SIZE;
+use crate::image_digest::{verify_digest, DigestError};
+use core::sync::atomic::{AtomicU32, Ordering};
+use shared::{
+    bootloader_update::StagedBootloader,
+    mcuboot::{McubootHeader, TLV_ED25519},
+    slots::SlotDescriptor,
+    Flash,
+};
+
+/// The public key of the signing key
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PublicKey {
+    /// An Ed25519 key
+    Ed25519([u8; 32]),
+    /// A P-256 key for ECDSA, as the big-endian x and y coordinates
+    #[cfg(feature = "ecdsa-p256")]
+    P256([u8; 64]),
+}
+
+/// The kind of key in [PUBLIC_KEY]
+const NO_KEY: u32 = 0;
+const ED25519_KEY: u32 = 1;
+#[cfg(feature = "ecdsa-p256")]
+const P256_KEY: u32 = 2;
+
+/// The kind of the public key that is set
+static PUBLIC_KEY_KIND: AtomicU32 = AtomicU32::new(NO_KEY);
+/// The public key of the signing key, as little-endian words. An Ed25519 key only uses the first half.
+static PUBLIC_KEY: [AtomicU32; 16] = [const { AtomicU32::new(0) }; 16];
+
+/// Sets the public key that the signatures of the images are checked with
+pub fn set_public_key(key: PublicKey) {
+    let (kind, bytes): (u32, &[u8]) = match &key {
+        PublicKey::Ed25519(bytes) => (ED25519_KEY, bytes),
+        #[cfg(feature = "ecdsa-p256")]
+        PublicKey::P256(bytes) => (P256_KEY, bytes),
+    };
+
+    for (word, bytes) in PUBLIC_KEY.iter().zip(bytes.as_chunks::<4>().0) {
+        word.store(u32::from_le_bytes(*bytes), Ordering::Relaxed);
+    }
+    PUBLIC_KEY_KIND.store(kind, Ordering::Relaxed);
+}
+
+/// Reads the public key back, if one is set
+fn public_key() -> Option<PublicKey> {
+    let mut bytes = [0; 64];
+    for (bytes, word) in bytes.as_chunks_mut::<4>().0.iter_mut().zip(&PUBLIC_KEY) {
+        *bytes = word.load(Ordering::Relaxed).to_le_bytes();
+    }
+
+    match PUBLIC_KEY_KIND.load(Ordering::Relaxed) {
+        ED25519_KEY => Some(PublicKey::Ed25519(bytes[..32].try_into().unwrap())),
+        #[cfg(feature = "ecdsa-p256")]
+        P256_KEY => Some(PublicKey::P256(bytes)),
+        _ => None,
+    }
+}
+
+/// Verifies an ECDSA P-256 signature of a SHA-256 digest in hardware, with the public key as the big-endian x and y
+/// coordinates and the signature as the big-endian r and s.
+///
+/// Returns `None` if the hardware can't do the verification, so the software does it instead.
+#[cfg(feature = "ecdsa-p256")]
+pub type P256Accelerator =
+    fn(public_key: &[u8; 64], digest: &[u8; DIGEST_SIZE], signature: &[u8; 64]) -> Option<bool>;
+
+/// The [P256Accelerator] as a pointer, or null if there is none
+#[cfg(feature = "ecdsa-p256")]
+static P256_ACCELERATOR: core::sync::atomic::AtomicPtr<()> =
+    core::sync::atomic::AtomicPtr::new(core::ptr::null_mut());
+
+/// Makes the P-256 signatures be verified with the given accelerator first
+#[cfg(feature = "ecdsa-p256")]
+pub fn set_p256_accelerator(accelerator: P256Accelerator) {
+    P256_ACCELERATOR.store(accelerator as *mut (), Ordering::Relaxed);
+}
+
+/// Why an image doesn't pass [verify_signature]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SignatureError {
+    /// No public key was set, so no image can be trusted
+    NoPublicKey,
+    /// The digest of the image isn't valid
+    Digest(DigestError),
+    /// The trailer has no signature TLV for the kind of public key
+    NoSignature,
+    /// The signature doesn't match the digest and the public key
+    Invalid,
+}
+
+/// Checks that the image in the slot is signed with the signing key
+pub fn verify_signature(flash: &dyn Flash, slot: &SlotDescriptor) -> Result<(), SignatureError> {
+    let public_key = public_key().ok_or(SignatureError::NoPublicKey)?;
+
+    let digest = verify_digest(flash, slot).map_err(SignatureError::Digest)?;
+
+    // The digest was found, so the header and the trailer are valid
+    let header = McubootHeader::load(flash, slot.address()).ok_or(SignatureError::NoSignature)?;
+    let image_end = slot.address() + slot.image_capacity();
+    let find_signature = |tlv_type| {
+        header
+            .find_tlv(flash, slot.address(), image_end, tlv_type)
+            .map(|signature| flash.read_u8(signature))
+            .ok_or(SignatureError::NoSignature)
+    };
+
+    match public_key {
+        PublicKey::Ed25519(key) => {
+            let signature = ed25519_compact::Signature::from_slice(find_signature(TLV_ED25519)?)
+                .map_err(|_| SignatureError::Invalid)?;
+            ed25519_compact::PublicKey::new(key)
+                .verify(digest, &signature)
+                .map_err(|_| SignatureError::Invalid)
+        }
+        #[cfg(feature = "ecdsa-p256")]
+        PublicKey::P256(key) => {
+            let signature =
+                p256::ecdsa::Signature::from_der(find_signature(shared::mcuboot::TLV_ECDSA_SIG)?)
+                    .map_err(|_| SignatureError::Invalid)?;
+            verify_p256(&key, &digest, &signature)
+                .then_some(())
+                .ok_or(SignatureError::Invalid)
+        }
+    }
+}
+
+/// Checks that the bootloader update that is staged in slot B is signed with the signing key
+pub fn verify_bootloader_update(
+    flash: &dyn Flash,
+    update: &StagedBootloader,
+) -> Result<(), SignatureError> {
+    verify_signature(flash, &update.signed_image())
+}
+
+/// Verifies the P-256 signature with the accelerator, or in software if there is none or it can't do it
+#[cfg(feature = "ecdsa-p256")]
+fn verify_p256(
+    key: &[u8; 64],
+    digest: &[u8; DIGEST_SIZE],
+    signature: &p256::ecdsa::Signature,
+) -> bool {
+    use p256::ecdsa::{signature::hazmat::PrehashVerifier, VerifyingKey};
+
+    let accelerator = P256_ACCELERATOR.load(Ordering::Relaxed);
+    if !accelerator.is_null() {
+        // Safety: only a P256Accelerator is ever stored
+        let accelerator = unsafe { core::mem::transmute::<*mut (), P256Accelerator>(accelerator) };
+        if let Some(is_valid) = accelerator(key, digest, &signature.to_bytes().into()) {
+            return is_valid;
+        }
+    }
+
+    // The uncompressed SEC1 encoding of the key
+    let mut point = [0x04; 65];
+    point[1..].copy_from_slice(key);
+
+    VerifyingKey::from_sec1_bytes(&point)
+        .and_then(|key| key.verify_prehash(digest, signature))
+        .is_ok()
+}
@@ -0,0 +1,407 @@
+//! The swap engine that exchanges the images in a primary and a secondary slot, slot A and slot B by default
+//!
+//! A swap can cover multiple images, like the application and the firmware of a co-processor.
+//! Their slot pairs are swapped one after the other, in the order of their image IDs, and the goal is only changed
+//! once all of them are done. Every pair that is done is marked in the state, so after a reset the swap resumes
+//! at the pair it was in.
+//!
+//! The slots of a pair don't need to have the same size. Only the pages up to the end of the longer of the two
+//! images are swapped, as the [ImageHeader] or the [McubootHeader] tells, and the rest of both slots is erased.
+//! Both images must fit in the smaller slot then. An image without a header may fill its whole slot.
+
+use crate::{report, udebug, uerror, uinfo, uwarn, watchdog, LogSink};
+use core::mem::size_of;
+use shared::{
+    flash_addresses::{bootloader_scratch_page_range, PAGE_SIZE},
+    image_header::ImageHeader,
+    mcuboot::McubootHeader,
+    slots::{self, SlotDescriptor, SlotRole},
+    state::{BootloaderGoal, BootloaderState, PageState},
+    user_data, Flash, FlashError,
+};
+
+/// Actually performs the swapping procedure between slot A and slot B.
+///
+/// If the state has been prepared for a swap, all pages will be swapped.
+/// If not, then it will resume a previous swap.
+///
+/// When a flash operation fails, the swap stops and the error is returned. The state still has the goal to finish
+/// the swap, so it resumes at the next boot.
+pub fn perform_swap(
+    test_swap: bool,
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> Result<(), FlashError> {
+    perform_multi_image_swap(&slots::default_layout(), test_swap, state, flash, log)
+}
+
+/// Swaps the primary and the secondary slot of every image in [BootloaderState::swap_images], like [perform_swap].
+///
+/// Images that are already swapped are skipped, so an interrupted swap resumes at the image it was in.
+/// Images without both a primary and a secondary slot in the layout are skipped as well.
+pub fn perform_multi_image_swap(
+    slots: &[SlotDescriptor],
+    test_swap: bool,
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> Result<(), FlashError> {
+    #[cfg(feature = "flash-trace")]
+    return crate::trace::traced(flash, log, |flash, log| {
+        swap_images(slots, test_swap, state, flash, log)
+    });
+    #[cfg(not(feature = "flash-trace"))]
+    swap_images(slots, test_swap, state, flash, log)
+}
+
+/// The body of [perform_multi_image_swap]
+fn swap_images(
+    slots: &[SlotDescriptor],
+    test_swap: bool,
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> Result<(), FlashError> {
+    for image_id in 0..u32::BITS as u8 {
+        if !state.is_image_swap_pending(image_id) {
+            continue;
+        }
+
+        match (
+            slots::find(slots, SlotRole::Primary, image_id),
+            slots::find(slots, SlotRole::Secondary, image_id),
+        ) {
+            (Some(primary), Some(secondary)) => {
+                uinfo!(log, "Swapping image {}", image_id);
+                swap_pages(primary, secondary, state, flash, log)?;
+            }
+            _ => uwarn!(
+                log,
+                "The slot layout has no slot pair for image {}, skipping it",
+                image_id
+            ),
+        }
+
+        // The next image starts with fresh page states
+        state.finish_image_swap(image_id);
+        state.store(flash)?;
+    }
+
+    finish_swap(test_swap, state, flash)
+}
+
+/// Performs the swapping procedure between the given slots, like [perform_swap].
+///
+/// Both slots must have at most [BootloaderState::MAX_SWAP_PAGES] pages, both images must fit in the smaller slot
+/// (see [swap_page_count]) and neither slot may overlap the [user data](shared::user_data).
+/// The swap fails with [FlashError::OutOfRange] if the memory layout doesn't fit the
+/// [geometry](Flash::geometry) of the flash.
+pub fn perform_swap_between(
+    primary: &SlotDescriptor,
+    secondary: &SlotDescriptor,
+    test_swap: bool,
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> Result<(), FlashError> {
+    #[cfg(feature = "flash-trace")]
+    return crate::trace::traced(flash, log, |flash, log| {
+        swap_pages(primary, secondary, state, flash, log)?;
+        finish_swap(test_swap, state, flash)
+    });
+    #[cfg(not(feature = "flash-trace"))]
+    {
+        swap_pages(primary, secondary, state, flash, log)?;
+        finish_swap(test_swap, state, flash)
+    }
+}
+
+/// Swaps all pages of the given slots that aren't swapped yet
+fn swap_pages(
+    primary: &SlotDescriptor,
+    secondary: &SlotDescriptor,
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> Result<(), FlashError> {
+    assert!(
+        primary.page_range().len() <= BootloaderState::MAX_SWAP_PAGES
+            && secondary.page_range().len() <= BootloaderState::MAX_SWAP_PAGES,
+        "The swapped slots must fit in the state"
+    );
+    assert!(
+        !user_data::overlaps(&(primary.address()..primary.address() + primary.size()))
+            && !user_data::overlaps(&(secondary.address()..secondary.address() + secondary.size())),
+        "The swapped slots must not overlap the user data"
+    );
+
+    // The layout is made for pages of PAGE_SIZE, swapping with other pages would mix up the images
+    let geometry = flash.geometry();
+    if !geometry.fits_layout() {
+        uerror!(
+            log,
+            "The memory layout doesn't fit the flash of {} pages of {} bytes",
+            geometry.page_count(),
+            geometry.page_size
+        );
+        return Err(FlashError::OutOfRange);
+    }
+
+    // The pages to swap are decided before the first page is touched, because the headers move with the pages
+    let total_program_pages = match state.swap_page_count() {
+        Some(pages) => pages,
+        None => {
+            let pages = swap_page_count(&*flash, primary, secondary).expect(
+                "The images of the swapped slots must fit in the smaller slot, see swap_page_count",
+            );
+            state.set_swap_page_count(Some(pages));
+            state.store(flash)?;
+            pages
+        }
+    };
+    let total_scratch_pages = bootloader_scratch_page_range().len() as u32;
+
+    udebug!(log, "total_program_pages: {}", total_program_pages);
+    udebug!(log, "total_scratch_pages: {}", total_scratch_pages);
+
+    // We're doing a round-robin for scratch page usage, so we need to keep track of the used index
+    let mut scratch_page_index = 0;
+
+    // We need to swap every page
+    for page in 0..total_program_pages {
+        watchdog::feed();
+
+        // Get the addresses of the A and B page slot
+        let slot_a_page = primary.page_range().start + page;
+        let slot_a_address = slot_a_page * PAGE_SIZE;
+        let slot_b_page = secondary.page_range().start + page;
+        let slot_b_address = slot_b_page * PAGE_SIZE;
+
+        // Data that belongs to the slot instead of the image stays where it is
+        if primary.is_page_excluded(page) || secondary.is_page_excluded(page) {
+            udebug!(log, "Page {} is excluded from the swap", page);
+            continue;
+        }
+
+        // Identical pages don't have to move. Nothing has touched a page that is still in its original state,
+        // so this holds when a swap is resumed as well.
+        if state.get_page_state(page) == PageState::Original
+            && flash.read_u32(slot_a_address..slot_a_address + PAGE_SIZE)
+                == flash.read_u32(slot_b_address..slot_b_address + PAGE_SIZE)
+        {
+            udebug!(log, "Page {} is identical in both slots", page);
+            state.set_page_state(page, PageState::Swapped);
+        }
+
+        report::count_page(state.get_page_state(page).is_swapped());
+
+        // We run a small statemachine that needs to continue until the page is swapped.
+        // If we resume a swap due to a reset, then it is possible that a lot of pages have already been swapped
+        while !state.get_page_state(page).is_swapped() {
+            udebug!(
+                log,
+                "Swapping page {}: {:?}",
+                page,
+                state.get_page_state(page)
+            );
+            // Depending on the state, we need to swap certain pages
+            match state.get_page_state(page) {
+                PageState::Original => {
+                    // We need to copy the A page to a scratch page
+
+                    // Decide which scratch page to use
+                    let scratch_page = bootloader_scratch_page_range().start + scratch_page_index;
+                    let scratch_address = scratch_page * PAGE_SIZE;
+
+                    udebug!(
+                        log,
+                        "Moving page @{:#010X} to page {:#010X}",
+                        slot_a_address,
+                        scratch_address
+                    );
+
+                    // Copy the data from slot A into the scratch slot
+                    copy_page(flash, slot_a_address, scratch_address)?;
+                    // Update the state
+                    state.set_page_state(page, PageState::InScratch { scratch_page });
+                    state.burn_store(flash)?;
+                }
+                PageState::InScratch { scratch_page } => {
+                    // We need to copy the B page to the A slot
+
+                    udebug!(
+                        log,
+                        "Moving page @{:#010X} to page {:#010X}",
+                        slot_b_address,
+                        slot_a_address
+                    );
+
+                    // Copy the data from slot B into the A slot
+                    copy_page(flash, slot_b_address, slot_a_address)?;
+                    // Update the state
+                    state.set_page_state(page, PageState::InScratchOverwritten { scratch_page });
+                    state.burn_store(flash)?;
+                }
+                PageState::InScratchOverwritten { scratch_page } => {
+                    // We need to copy the scratch page to the B slot
+
+                    let scratch_address = scratch_page * PAGE_SIZE;
+
+                    udebug!(
+                        log,
+                        "Moving page @{:#010X} to page {:#010X}",
+                        scratch_address,
+                        slot_b_address
+                    );
+
+                    // Copy the data from the scratch slot into the B slot
+                    copy_page(flash, scratch_address, slot_b_address)?;
+                    // Update the state
+                    state.set_page_state(page, PageState::Swapped);
+
+                    state.burn_store(flash)?;
+                }
+                PageState::Swapped => {
+                    // We're done and shouldn't be able to get here
+                    unreachable!()
+                }
+            }
+        }
+
+        // Go to the next scratch page or start over if we were on the last one
+        scratch_page_index = (scratch_page_index + 1) % total_scratch_pages;
+    }
+
+    // The rest of the slots only has what's left of older images
+    for slot in [primary, secondary] {
+        erase_pages_after(slot, total_program_pages, flash, log)?;
+    }
+
+    Ok(())
+}
+
+/// Gives the number of bytes at the start of the slot that the image in it takes up, as the [ImageHeader] or the
+/// [McubootHeader] tells. Without a header, or with one that doesn't fit, that's the whole slot.
+fn image_length(flash: &dyn Flash, slot: &SlotDescriptor) -> u32 {
+    ImageHeader::load(flash, slot.address())
+        .map(|header| ImageHeader::SIZE.saturating_add(header.length))
+        .or_else(|| {
+            let header = McubootHeader::load(flash, slot.address())?;
+            let tlvs = header.tlv_range(flash, slot.address(), slot.range.end)?;
+            Some(tlvs.end - slot.address())
+        })
+        .filter(|length| *length <= slot.size())
+        .unwrap_or(slot.size())
+}
+
+/// Gives the number of pages from the start of both slots that must be swapped to exchange their images,
+/// or `None` if one of the images doesn't fit in the other slot.
+pub fn swap_page_count(
+    flash: &dyn Flash,
+    primary: &SlotDescriptor,
+    secondary: &SlotDescriptor,
+) -> Option<u32> {
+    let pages = image_length(flash, primary)
+        .max(image_length(flash, secondary))
+        .div_ceil(PAGE_SIZE);
+    let smaller_slot_pages = primary.page_range().len().min(secondary.page_range().len());
+
+    (pages as usize <= smaller_slot_pages).then_some(pages)
+}
+
+/// Erases the pages of the slot from the given page on, except the excluded ones and the ones that are erased already
+fn erase_pages_after(
+    slot: &SlotDescriptor,
+    first_page: u32,
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+) -> Result<(), FlashError> {
+    for page in first_page..slot.page_range().len() as u32 {
+        let address = slot.address() + page * PAGE_SIZE;
+        if slot.is_page_excluded(page)
+            || flash
+                .read_u32(address..address + PAGE_SIZE)
+                .iter()
+                .all(|word| *word == 0xFFFF_FFFF)
+        {
+            continue;
+        }
+
+        watchdog::feed();
+        udebug!(log, "Erasing page @{:#010X} after the images", address);
+        flash.erase_page(address)?;
+        report::count_erase();
+    }
+
+    Ok(())
+}
+
+/// Sets the goal after a finished swap and stores the state
+fn finish_swap(
+    test_swap: bool,
+    state: &mut BootloaderState,
+    flash: &mut dyn Flash,
+) -> Result<(), FlashError> {
+    // We're done, so we should change the state
+    if test_swap {
+        // Swapping back is the goal until the new image is confirmed, but it may get more boots
+        state
+            .set_goal(BootloaderGoal::FinishTestSwap, BootloaderGoal::StartSwap)
+            .ok();
+        state.set_failed_test_boots(Some(0));
+    } else {
+        state
+            .set_goal(
+                BootloaderGoal::FinishSwap,
+                BootloaderGoal::JumpToApplication,
+            )
+            .ok();
+    }
+
+    // Slot B now has the old image, which is erased when the new one is confirmed
+    #[cfg(feature = "erase-old-image")]
+    crate::cleanup::mark_old_image(state, flash);
+
+    // We've changed the goal, so we need to store that
+    state.store(flash)
+}
+
+/// How often a page is erased and programmed when it doesn't read back like the data that was written to it
+const COPY_ATTEMPTS: u32 = 3;
+
+/// Erases the page at `to` and programs it with the data of the page at `from`.
+///
+/// The data goes through a buffer in RAM, so the flash doesn't have to be memory mapped.
+/// Afterwards, the page is read back and compared with the buffer, so a flash that doesn't check its own writes
+/// can't corrupt the image without anyone noticing. A page that doesn't match is written again, and after
+/// [COPY_ATTEMPTS] attempts [FlashError::VerifyFailed] is returned. The page state then still has the step that
+/// was being done, so it's done again after the reset.
+pub(crate) fn copy_page(flash: &mut dyn Flash, from: u32, to: u32) -> Result<(), FlashError> {
+    copy_transformed_page(flash, from, to, |_| {})
+}
+
+/// Copies the page like [copy_page], but lets `transform` change the data in the buffer before it's programmed,
+/// like the overwrite does to decrypt an image
+pub(crate) fn copy_transformed_page(
+    flash: &mut dyn Flash,
+    from: u32,
+    to: u32,
+    transform: impl FnOnce(&mut [u32]),
+) -> Result<(), FlashError> {
+    let mut buffer = [0; PAGE_SIZE as usize / size_of::<u32>()];
+    buffer.copy_from_slice(flash.read_u32(from..from + PAGE_SIZE));
+    transform(&mut buffer);
+
+    for _ in 0..COPY_ATTEMPTS {
+        flash.erase_page(to)?;
+        report::count_erase();
+        match flash.program_page(to, &buffer) {
+            Ok(()) if flash.read_u32(to..to + PAGE_SIZE) == buffer => return Ok(()),
+            Ok(()) | Err(FlashError::VerifyFailed) => continue,
+            Err(error) => return Err(error),
+        }
+    }
+
+    Err(FlashError::VerifyFailed)
+}
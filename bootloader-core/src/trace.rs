@@ -0,0 +1,174 @@
+//! The trace of the flash operations of a swap
+//!
+//! With the `flash-trace` feature, the swap runs on a [TracingFlash] that writes a line for every erase and program
+//! to the log sink, with the address, the length and the place in the source that did it:
+//!
+//! ```text
+//! FLASH-TRACE erase 0x000F8000 len 4096 at bootloader-core/src/swap.rs:259
+//! FLASH-TRACE program 0x000F8000 len 4096 at bootloader-core/src/swap.rs:261
+//! ```
+//!
+//! The binary can also give a region that the trace is mirrored into with [set_mirror_region], so it can be read
+//! out of a unit that failed in the field. Every operation is a record of [MIRROR_RECORD_WORDS] words: the
+//! [MIRROR_MARKER] with the operation, the address, the length in bytes and the line of the caller.
+//! The records are programmed into erased flash one after the other, like the event log. When the region is full,
+//! the rest of the trace is only written to the log sink. Whoever reads out the trace erases the region.
+
+use crate::LogSink;
+use core::{
+    cell::RefCell,
+    mem::{size_of, size_of_val},
+    ops::Range,
+    panic::Location,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use shared::{
+    event_log::SecurityEvent, flash_addresses::PAGE_SIZE, flash_geometry::FlashGeometry, Flash,
+    FlashError,
+};
+
+/// The start of every trace line
+pub const PREFIX: &str = "FLASH-TRACE";
+
+/// The upper half of the first word of a mirror record. The lower half is the [Operation].
+pub const MIRROR_MARKER: u32 = 0x7ACE_0000;
+/// The number of words of a mirror record
+pub const MIRROR_RECORD_WORDS: u32 = 4;
+
+/// The start of the mirror region, or 0 without one
+static MIRROR_START: AtomicU32 = AtomicU32::new(0);
+/// The end of the mirror region, or 0 without one
+static MIRROR_END: AtomicU32 = AtomicU32::new(0);
+
+/// A flash operation in the trace
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Operation {
+    /// A page erase
+    Erase = 1,
+    /// A program of (a part of) a page
+    Program = 2,
+}
+
+/// Sets the page aligned flash region the trace is mirrored into, or stops mirroring with `None`.
+///
+/// The region must not be used for anything else.
+pub fn set_mirror_region(region: Option<Range<u32>>) {
+    let region = region.unwrap_or(0..0);
+    MIRROR_START.store(region.start, Ordering::Relaxed);
+    MIRROR_END.store(region.end, Ordering::Relaxed);
+}
+
+/// A [Flash] that traces the erases and programs to a log sink before passing them on
+pub struct TracingFlash<'a, 'l> {
+    flash: &'a mut dyn Flash,
+    log: &'a RefCell<&'l mut dyn LogSink>,
+}
+
+impl TracingFlash<'_, '_> {
+    fn trace(&mut self, operation: Operation, address: u32, length: u32, caller: &Location) {
+        let name = match operation {
+            Operation::Erase => "erase",
+            Operation::Program => "program",
+        };
+        self.log.borrow_mut().write_line(format_args!(
+            "{} {} {:#010X} len {} at {}:{}",
+            PREFIX,
+            name,
+            address,
+            length,
+            caller.file(),
+            caller.line()
+        ));
+
+        self.mirror([
+            MIRROR_MARKER | operation as u32,
+            address,
+            length,
+            caller.line(),
+        ]);
+    }
+
+    /// Programs the record into the first free place of the mirror region, if there is one.
+    ///
+    /// The trace must not change the outcome of the swap, so a failed program only loses the record.
+    fn mirror(&mut self, record: [u32; MIRROR_RECORD_WORDS as usize]) {
+        let region = MIRROR_START.load(Ordering::Relaxed)..MIRROR_END.load(Ordering::Relaxed);
+        let record_size = MIRROR_RECORD_WORDS * size_of::<u32>() as u32;
+
+        let free_address = region
+            .step_by(record_size as usize)
+            .find(|address| self.flash.read_u32(*address..*address + 4)[0] == 0xFFFF_FFFF);
+        let record_address = match free_address {
+            Some(record_address) => record_address,
+            None => return,
+        };
+
+        // Only the words that differ from what is in flash are written, so we pass the contents before the record
+        let page_address = record_address - record_address % PAGE_SIZE;
+        let word_index = (record_address - page_address) as usize / size_of::<u32>();
+        let mut buffer = [0; PAGE_SIZE as usize / size_of::<u32>()];
+        buffer[..word_index].copy_from_slice(self.flash.read_u32(page_address..record_address));
+        buffer[word_index..][..record.len()].copy_from_slice(&record);
+
+        self.flash
+            .program_page(page_address, &buffer[..word_index + record.len()])
+            .ok();
+    }
+}
+
+impl Flash for TracingFlash<'_, '_> {
+    #[track_caller]
+    fn erase_page(&mut self, page_address: u32) -> Result<(), FlashError> {
+        self.trace(
+            Operation::Erase,
+            page_address,
+            PAGE_SIZE,
+            Location::caller(),
+        );
+        self.flash.erase_page(page_address)
+    }
+
+    #[track_caller]
+    fn program_page(&mut self, page_address: u32, data: &[u32]) -> Result<(), FlashError> {
+        let length = size_of_val(data) as u32;
+        self.trace(Operation::Program, page_address, length, Location::caller());
+        self.flash.program_page(page_address, data)
+    }
+
+    fn read_u8(&self, address_range: Range<u32>) -> &[u8] {
+        self.flash.read_u8(address_range)
+    }
+
+    fn read_u32(&self, address_range: Range<u32>) -> &[u32] {
+        self.flash.read_u32(address_range)
+    }
+
+    fn geometry(&self) -> FlashGeometry {
+        self.flash.geometry()
+    }
+}
+
+/// The log sink that the traced code writes to. It shares the log sink with the [TracingFlash].
+pub struct SharedLog<'a, 'l>(&'a RefCell<&'l mut dyn LogSink>);
+
+impl LogSink for SharedLog<'_, '_> {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.0.borrow_mut().write_bytes(bytes);
+    }
+
+    fn security_event(&mut self, event: SecurityEvent, detail: u32) {
+        self.0.borrow_mut().security_event(event, detail);
+    }
+}
+
+/// Runs `f` with a flash that traces all erases and programs to the log sink and the mirror region
+pub fn traced<R>(
+    flash: &mut dyn Flash,
+    log: &mut dyn LogSink,
+    f: impl FnOnce(&mut dyn Flash, &mut dyn LogSink) -> R,
+) -> R {
+    let log = RefCell::new(log);
+    let mut flash = TracingFlash { flash, log: &log };
+    f(&mut flash, &mut SharedLog(&log))
+}
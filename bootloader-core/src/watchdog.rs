@@ -0,0 +1,28 @@
+//! Feeding the watchdog during long flash operations
+//!
+//! A swap, an overwrite or a wipe can take many seconds. The binary may start a watchdog before it runs the core, so a
+//! hang doesn't leave the device dead. The core doesn't know the hardware, so the binary sets how the watchdog is fed
+//! with [set_feeder], and the core feeds it for every page it copies or erases.
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// A function that feeds the watchdog
+pub type Feeder = fn();
+
+/// The [Feeder] as a pointer, or null if there is none
+static FEEDER: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Makes the core feed the watchdog with the given function during long flash operations
+pub fn set_feeder(feeder: Feeder) {
+    FEEDER.store(feeder as *mut (), Ordering::Relaxed);
+}
+
+/// Feeds the watchdog, if there is a feeder
+pub(crate) fn feed() {
+    let feeder = FEEDER.load(Ordering::Relaxed);
+    if !feeder.is_null() {
+        // Safety: only a Feeder is ever stored
+        let feeder = unsafe { core::mem::transmute::<*mut (), Feeder>(feeder) };
+        feeder();
+    }
+}
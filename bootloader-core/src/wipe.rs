@@ -0,0 +1,41 @@
+//! Wiping the device for a return or refurbishment
+//!
+//! A wipe erases everything that belongs to the user of the device. It's done in a fixed order:
+//! the device secrets (by the binary, because they live outside of the flash regions the core knows about),
+//! program slot A, program slot B, the scratch area and at last the state and its log.
+//! Because the state is erased last, its [Wipe](shared::state::BootloaderGoal::Wipe) goal stays until
+//! the whole wipe is done, so a wipe that is interrupted by a reset starts over at the next boot.
+//! The [user data](shared::user_data) holds the calibration of the device, so it's kept.
+
+use crate::{uprintln, watchdog, LogSink};
+use shared::{
+    flash_addresses::{
+        bootloader_scratch_page_range, program_slot_a_page_range, program_slot_b_page_range,
+        PAGE_SIZE,
+    },
+    state::BootloaderState,
+    Flash, FlashError,
+};
+
+/// Erases the program slots, the scratch area and the state.
+///
+/// When an erase fails, the wipe stops and the error is returned. The state is erased last, so the wipe starts
+/// over at the next boot.
+pub fn wipe(flash: &mut dyn Flash, log: &mut dyn LogSink) -> Result<(), FlashError> {
+    for (name, pages) in [
+        ("program slot a", program_slot_a_page_range()),
+        ("program slot b", program_slot_b_page_range()),
+        ("bootloader scratch", bootloader_scratch_page_range()),
+    ] {
+        uprintln!(log, "Erasing {}", name);
+        for page in pages {
+            watchdog::feed();
+            flash.erase_page(page * PAGE_SIZE)?;
+        }
+    }
+
+    // The state keeps its newest record until last, so the goal stays until the very end
+    uprintln!(log, "Erasing bootloader state");
+    watchdog::feed();
+    BootloaderState::erase(flash)
+}
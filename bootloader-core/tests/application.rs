@@ -0,0 +1,104 @@
+//! Host tests of finding the vector table of the application
+
+mod common;
+
+use common::RamFlash;
+use dis_bootloader_core::{
+    application::has_valid_image_for, find_application_address, revision_check, NullLog,
+};
+use shared::{
+    flash_addresses::{program_slot_a_range, PAGE_SIZE},
+    mcuboot::{McubootHeader, TlvInfo, TLV_HARDWARE_REVISIONS},
+    slots::{self, SlotRole, APPLICATION_IMAGE},
+    Flash,
+};
+
+/// Programs a vector table that runs from slot A at the start of the page, like an image without a header has
+fn write_vector_table(flash: &mut RamFlash, page_address: u32) {
+    let mut page = [0xFFFF_FFFF; PAGE_SIZE as usize / 4];
+    page[0] = 0x2000_8000;
+    page[1] = program_slot_a_range().start + 0x101;
+    flash.erase_page(page_address).unwrap();
+    flash.program_page(page_address, &page).unwrap();
+}
+
+/// Programs an MCUboot image that runs from slot A on the given hardware revisions at the start of the page
+fn write_mcuboot_image(flash: &mut RamFlash, page_address: u32, compatible_revisions: u32) {
+    const HEADER_SIZE: usize = 0x200;
+    const IMAGE_SIZE: usize = 0x200;
+
+    let mut image = vec![0xFF; PAGE_SIZE as usize];
+    image[..4].copy_from_slice(&McubootHeader::MAGIC.to_le_bytes());
+    image[4..8].copy_from_slice(&0u32.to_le_bytes());
+    image[8..10].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    image[10..12].copy_from_slice(&0u16.to_le_bytes());
+    image[12..16].copy_from_slice(&(IMAGE_SIZE as u32).to_le_bytes());
+    image[16..20].copy_from_slice(&0u32.to_le_bytes());
+    image[HEADER_SIZE..HEADER_SIZE + 4].copy_from_slice(&0x2000_8000u32.to_le_bytes());
+    image[HEADER_SIZE + 4..HEADER_SIZE + 8].copy_from_slice(
+        &(program_slot_a_range().start + HEADER_SIZE as u32 + 0x101).to_le_bytes(),
+    );
+
+    let mut trailer = Vec::new();
+    trailer.extend(TlvInfo::MAGIC.to_le_bytes());
+    trailer.extend((TlvInfo::SIZE as u16 + 4 + 4).to_le_bytes());
+    trailer.extend(TLV_HARDWARE_REVISIONS.to_le_bytes());
+    trailer.extend(4u16.to_le_bytes());
+    trailer.extend(compatible_revisions.to_le_bytes());
+    let trailer_start = HEADER_SIZE + IMAGE_SIZE;
+    image[trailer_start..trailer_start + trailer.len()].copy_from_slice(&trailer);
+
+    let words = image
+        .chunks(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    flash.erase_page(page_address).unwrap();
+    flash.program_page(page_address, &words).unwrap();
+}
+
+#[test]
+fn image_without_header_starts_at_the_slot() {
+    let mut flash = RamFlash::new();
+    write_vector_table(&mut flash, program_slot_a_range().start);
+
+    assert_eq!(
+        find_application_address(&mut flash, &mut NullLog),
+        program_slot_a_range().start
+    );
+}
+
+#[test]
+fn image_without_header_can_be_copied_from_slot_b() {
+    let layout = slots::default_layout();
+    let primary = slots::find(&layout, SlotRole::Primary, APPLICATION_IMAGE).unwrap();
+    let secondary = slots::find(&layout, SlotRole::Secondary, APPLICATION_IMAGE).unwrap();
+
+    let mut flash = RamFlash::new();
+    assert!(!has_valid_image_for(&flash, secondary, primary));
+
+    write_vector_table(&mut flash, secondary.address());
+    assert!(has_valid_image_for(&flash, secondary, primary));
+}
+
+#[test]
+fn image_for_another_hardware_revision_is_not_started() {
+    let layout = slots::default_layout();
+    let primary = slots::find(&layout, SlotRole::Primary, APPLICATION_IMAGE).unwrap();
+    let secondary = slots::find(&layout, SlotRole::Secondary, APPLICATION_IMAGE).unwrap();
+
+    // The image runs on revisions 1 and 3
+    let mut flash = RamFlash::new();
+    write_mcuboot_image(&mut flash, secondary.address(), 0b1010);
+
+    revision_check::set_hardware_revision(Some(3));
+    assert!(has_valid_image_for(&flash, secondary, primary));
+
+    revision_check::set_hardware_revision(Some(2));
+    assert!(!has_valid_image_for(&flash, secondary, primary));
+
+    // Without a known revision, only images for all revisions run
+    revision_check::set_hardware_revision(None);
+    assert!(!has_valid_image_for(&flash, secondary, primary));
+    write_mcuboot_image(&mut flash, secondary.address(), 0);
+    assert!(has_valid_image_for(&flash, secondary, primary));
+}
@@ -0,0 +1,154 @@
+//! Host tests of the signature check of staged bootloader updates
+
+mod common;
+
+use common::RamFlash;
+use dis_bootloader_core::secure_boot::{
+    set_public_key, verify_bootloader_update, PublicKey, SignatureError,
+};
+use ed25519_compact::{KeyPair, Seed};
+use sha2::{Digest, Sha256};
+use shared::{
+    bootloader_update::{self, is_installed, BootloaderUpdateError},
+    build_info::BuildInfo,
+    flash_addresses::{
+        bootloader_descriptor_range, bootloader_flash_range, program_slot_b_range, PAGE_SIZE,
+    },
+    mcuboot::{McubootHeader, TlvInfo, TLV_ED25519, TLV_SHA256},
+    staged_image::StagedImageHeader,
+    Flash,
+};
+
+/// The size of the MCUboot header in front of the bootloader
+const HEADER_SIZE: u32 = 0x200;
+
+/// The signing key of these tests
+fn signing_key() -> KeyPair {
+    KeyPair::from_seed(Seed::new([0x5E; 32]))
+}
+
+/// Creates a bootloader image for the bootloader flash, with a build info in its descriptor block
+fn bootloader_image() -> Vec<u8> {
+    let mut image = (0..bootloader_flash_range().len())
+        .map(|index| (index * 13 + index / 241) as u8)
+        .collect::<Vec<_>>();
+
+    let descriptor =
+        (bootloader_descriptor_range().start - bootloader_flash_range().start) as usize;
+    let mut build_info = Vec::new();
+    build_info.extend(BuildInfo::MAGIC.to_le_bytes());
+    build_info.extend([2, 0, 3, 0, 1, 0, 0, 0]);
+    build_info.extend(0u32.to_le_bytes());
+    build_info.extend(0u64.to_le_bytes());
+    build_info.extend(*b"0123abcd\0\0\0\0\0\0\0\0");
+    image[descriptor..descriptor + BuildInfo::SIZE].copy_from_slice(&build_info);
+
+    image
+}
+
+/// Signs the bootloader image like `imgtool sign` does, with a SHA-256 TLV and an Ed25519 TLV
+fn sign(bootloader: &[u8], key: &KeyPair) -> Vec<u8> {
+    let mut image = vec![0; HEADER_SIZE as usize];
+    image[..4].copy_from_slice(&McubootHeader::MAGIC.to_le_bytes());
+    image[8..10].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    image[12..16].copy_from_slice(&(bootloader.len() as u32).to_le_bytes());
+    image[20..24].copy_from_slice(&[2, 3, 1, 0]);
+    image.extend(bootloader);
+
+    let digest = Sha256::digest(&image);
+    let signature = key.sk.sign(digest, None);
+
+    image.extend(TlvInfo::MAGIC.to_le_bytes());
+    image.extend((TlvInfo::SIZE as u16 + 4 + 32 + 4 + 64).to_le_bytes());
+    image.extend(TLV_SHA256.to_le_bytes());
+    image.extend(32u16.to_le_bytes());
+    image.extend(digest);
+    image.extend(TLV_ED25519.to_le_bytes());
+    image.extend(64u16.to_le_bytes());
+    image.extend(*signature);
+
+    image
+}
+
+/// Stages the signed image in slot B behind the header of a bootloader update
+fn stage(flash: &mut RamFlash, signed: &[u8]) {
+    let mut staged = StagedImageHeader::new(bootloader_update::MAGIC, signed)
+        .to_words()
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect::<Vec<_>>();
+    staged.extend(signed);
+    staged.resize(staged.len().next_multiple_of(PAGE_SIZE as usize), 0xFF);
+
+    for (page_address, page) in (program_slot_b_range().start..)
+        .step_by(PAGE_SIZE as usize)
+        .zip(staged.chunks(PAGE_SIZE as usize))
+    {
+        let words = page
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        flash.erase_page(page_address).unwrap();
+        flash.program_page(page_address, &words).unwrap();
+    }
+}
+
+#[test]
+fn signed_update_is_accepted() {
+    let key = signing_key();
+    set_public_key(PublicKey::Ed25519(*key.pk));
+
+    let mut flash = RamFlash::new();
+    let bootloader = bootloader_image();
+    stage(&mut flash, &sign(&bootloader, &key));
+
+    let update = bootloader_update::validate(&flash, None).unwrap();
+    assert_eq!(update.build_info.version_major, 2);
+    assert_eq!(
+        flash.read_u8(update.bootloader_range()),
+        bootloader.as_slice()
+    );
+    assert!(!is_installed(&flash, &update));
+    assert_eq!(verify_bootloader_update(&flash, &update), Ok(()));
+}
+
+#[test]
+fn update_signed_with_another_key_is_refused() {
+    set_public_key(PublicKey::Ed25519(*signing_key().pk));
+
+    let mut flash = RamFlash::new();
+    let other_key = KeyPair::from_seed(Seed::new([0xA1; 32]));
+    stage(&mut flash, &sign(&bootloader_image(), &other_key));
+
+    let update = bootloader_update::validate(&flash, None).unwrap();
+    assert_eq!(
+        verify_bootloader_update(&flash, &update),
+        Err(SignatureError::Invalid)
+    );
+}
+
+#[test]
+fn update_without_signature_is_refused() {
+    let mut flash = RamFlash::new();
+
+    // The bootloader binary itself, like it was staged before updates had to be signed
+    let bootloader = bootloader_image();
+    stage(&mut flash, &bootloader);
+    assert_eq!(
+        bootloader_update::validate(&flash, None),
+        Err(BootloaderUpdateError::NoMcubootImage)
+    );
+
+    // A signed image of which the bootloader was changed after signing, with the CRC of the staged image fixed
+    let key = signing_key();
+    set_public_key(PublicKey::Ed25519(*key.pk));
+    let mut signed = sign(&bootloader, &key);
+    signed[HEADER_SIZE as usize + 0x100] ^= 0x01;
+    stage(&mut flash, &signed);
+
+    let update = bootloader_update::validate(&flash, None).unwrap();
+    assert!(matches!(
+        verify_bootloader_update(&flash, &update),
+        Err(SignatureError::Digest(_))
+    ));
+}
@@ -0,0 +1,205 @@
+//! The glue the host tests share
+//!
+//! The host tests run the core with the `std-compat` feature, on a flash in RAM and with the layouts of [layouts].
+//! The flash has an external flash behind the window of [external_flash](shared::external_flash) as well.
+//! Every test runs in its own thread, so each one can pick its layout with
+//! [with_layout](shared::flash_addresses::with_layout).
+
+#![allow(dead_code)] // Not every test uses every function
+
+use core::{
+    mem::{size_of, size_of_val},
+    ops::Range,
+};
+use shared::{
+    external_flash::{external_address, EXTERNAL_FLASH_ADDRESS},
+    flash_addresses::{FlashLayout, PAGE_SIZE},
+    flash_geometry::FlashGeometry,
+    Flash, FlashError,
+};
+
+/// The size of the flash of the nRF9160
+pub const FLASH_SIZE: u32 = 0x0010_0000;
+
+/// The size of the external flash
+pub const EXTERNAL_FLASH_SIZE: u32 = 0x0010_0000;
+
+/// A flash that lives in RAM.
+///
+/// It behaves like NOR flash, so programming can only change bits from 1 to 0.
+/// It can also simulate a power cut, after which all erase and program operations are ignored.
+pub struct RamFlash {
+    memory: Vec<u32>,
+    external_memory: Vec<u32>,
+    operations_left: Option<u32>,
+}
+
+impl RamFlash {
+    /// Creates an erased flash with the size of the nRF9160 flash, and an erased external flash
+    pub fn new() -> Self {
+        Self {
+            memory: vec![0xFFFF_FFFF; FLASH_SIZE as usize / size_of::<u32>()],
+            external_memory: vec![0xFFFF_FFFF; EXTERNAL_FLASH_SIZE as usize / size_of::<u32>()],
+            operations_left: None,
+        }
+    }
+
+    /// Cuts the power after the given amount of erase and program operations.
+    /// With `None`, the power is restored and stays on.
+    pub fn cut_power_after(&mut self, operations: Option<u32>) {
+        self.operations_left = operations;
+    }
+
+    /// Returns true if the power has been cut
+    pub fn power_is_cut(&self) -> bool {
+        self.operations_left == Some(0)
+    }
+
+    /// Uses up one operation and returns whether there was still power to perform it
+    fn take_operation(&mut self) -> bool {
+        match &mut self.operations_left {
+            None => true,
+            Some(0) => false,
+            Some(operations_left) => {
+                *operations_left -= 1;
+                true
+            }
+        }
+    }
+
+    fn page_words(&mut self, page_address: u32) -> Result<&mut [u32], FlashError> {
+        if !page_address.is_multiple_of(PAGE_SIZE) {
+            return Err(FlashError::Alignment);
+        }
+        let (memory, page_address) = match external_address(page_address) {
+            Some(page_address) => (&mut self.external_memory, page_address),
+            None => (&mut self.memory, page_address),
+        };
+        let start = page_address as usize / size_of::<u32>();
+        memory
+            .get_mut(start..start + PAGE_SIZE as usize / size_of::<u32>())
+            .ok_or(FlashError::OutOfRange)
+    }
+
+    /// The memory of the flash that has the address, and the address in that memory
+    fn memory_of(&self, address: u32) -> (&[u32], u32) {
+        match external_address(address) {
+            Some(address) => (&self.external_memory, address),
+            None => (&self.memory, address),
+        }
+    }
+}
+
+impl Flash for RamFlash {
+    fn erase_page(&mut self, page_address: u32) -> Result<(), FlashError> {
+        let has_power = self.take_operation();
+        let page = self.page_words(page_address)?;
+
+        if has_power {
+            page.fill(0xFFFF_FFFF);
+        }
+
+        Ok(())
+    }
+
+    fn program_page(&mut self, page_address: u32, data: &[u32]) -> Result<(), FlashError> {
+        if data.len() > PAGE_SIZE as usize / size_of::<u32>() {
+            return Err(FlashError::OutOfRange);
+        }
+
+        let has_power = self.take_operation();
+        let page = self.page_words(page_address)?;
+
+        if has_power {
+            // Programming can only clear bits
+            for (flash_word, data_word) in page.iter_mut().zip(data) {
+                *flash_word &= *data_word;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_u8(&self, address_range: Range<u32>) -> &[u8] {
+        let (memory, start) = self.memory_of(address_range.start);
+        let end = start + address_range.len() as u32;
+        let memory_bytes = unsafe {
+            core::slice::from_raw_parts(memory.as_ptr() as *const u8, size_of_val(memory))
+        };
+
+        &memory_bytes[start as usize..end as usize]
+    }
+
+    fn read_u32(&self, address_range: Range<u32>) -> &[u32] {
+        assert!(address_range.start.is_multiple_of(4));
+        assert!(address_range.end.is_multiple_of(4));
+
+        let (memory, start) = self.memory_of(address_range.start);
+        let end = start + address_range.len() as u32;
+        &memory[start as usize / 4..end as usize / 4]
+    }
+
+    fn geometry(&self) -> FlashGeometry {
+        FlashGeometry {
+            page_size: PAGE_SIZE,
+            size: FLASH_SIZE,
+            external_size: EXTERNAL_FLASH_SIZE,
+        }
+    }
+}
+
+/// The layouts the swap and the state are tested with
+pub fn layouts() -> [(&'static str, FlashLayout); 4] {
+    [
+        ("nRF9160", FlashLayout::NRF9160),
+        // Slots of a few pages, a single scratch page and no state log
+        (
+            "small",
+            FlashLayout {
+                program_slot_a: 0x0001_8000..0x0001_C000,
+                program_slot_b: 0x0001_C000..0x0002_0000,
+                modem_staging: 0x0001_C000..0x0002_0000,
+                bootloader_scratch: 0x000F_8000..0x000F_9000,
+                bootloader_state_log: 0x000F_A000..0x000F_A000,
+                ..FlashLayout::NRF9160
+            },
+        ),
+        // Slots that don't follow each other, more scratch pages than usual and a state log of three pages
+        (
+            "spread",
+            FlashLayout {
+                program_slot_a: 0x0002_0000..0x0003_0000,
+                program_slot_b: 0x0006_0000..0x0007_0000,
+                modem_staging: 0x0006_0000..0x0007_0000,
+                bootloader_scratch: 0x0008_0000..0x0008_4000,
+                bootloader_state_log: 0x0008_4000..0x0008_7000,
+                ..FlashLayout::NRF9160
+            },
+        ),
+        // Slot B on the external flash
+        (
+            "external",
+            FlashLayout {
+                program_slot_b: EXTERNAL_FLASH_ADDRESS..EXTERNAL_FLASH_ADDRESS + 0x0006_8000,
+                modem_staging: EXTERNAL_FLASH_ADDRESS..EXTERNAL_FLASH_ADDRESS + 0x0006_8000,
+                ..FlashLayout::NRF9160
+            },
+        ),
+    ]
+}
+
+/// Creates the contents of a page that is unique for the given seed
+pub fn pattern(seed: u32) -> [u32; PAGE_SIZE as usize / 4] {
+    core::array::from_fn(|index| seed.rotate_left(index as u32 % 32) ^ index as u32)
+}
+
+/// Erases the page and programs the pattern of the seed into it
+pub fn fill_page(flash: &mut RamFlash, page_address: u32, seed: u32) {
+    flash.erase_page(page_address).unwrap();
+    flash.program_page(page_address, &pattern(seed)).unwrap();
+}
+
+/// Returns true if the page contains the pattern of the seed
+pub fn page_has_pattern(flash: &RamFlash, page_address: u32, seed: u32) -> bool {
+    flash.read_u32(page_address..page_address + PAGE_SIZE) == pattern(seed)
+}
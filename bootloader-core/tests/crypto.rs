@@ -0,0 +1,277 @@
+//! Host tests of the key derivations and of the images that are encrypted for one device
+
+mod common;
+
+use common::{fill_page, RamFlash};
+use dis_bootloader_core::{
+    crypto::{
+        derive_image_key, derive_maintenance_key, derive_revocation_token, derive_wipe_token,
+        maintenance_response, set_device_huk, EventLogKeystream, HardwareUniqueKey, SoftwareHuk,
+        KEY_SIZE,
+    },
+    image_encryption::ImageCipher,
+    overwrite, NullLog,
+};
+use shared::{
+    event_log::{self, SecurityEvent},
+    flash_addresses::PAGE_SIZE,
+    mcuboot::{McubootHeader, TlvInfo, TLV_CRITICAL, TLV_DEVICE_ENCRYPTION},
+    slots::{self, SlotRole, APPLICATION_IMAGE},
+    state::{BootloaderGoal, BootloaderState},
+    Flash,
+};
+
+/// The secret of the device in these tests
+const SECRET: [u8; KEY_SIZE] = {
+    let mut secret = [0; KEY_SIZE];
+    let mut index = 0;
+    while index < KEY_SIZE {
+        secret[index] = index as u8;
+        index += 1;
+    }
+    secret
+};
+
+/// The ID of the device in these tests
+const DEVICE_ID: [u8; 16] = [
+    0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x1F,
+];
+
+/// The derivation of the device, like the bootloader sets the one of the CryptoCell
+fn derive_on_device(label: &[u8], context: &[u8]) -> Option<[u8; KEY_SIZE]> {
+    SoftwareHuk { key: SECRET }.derive(label, context)
+}
+
+#[test]
+fn software_huk_matches_the_cryptocell_derivation() {
+    // Computed with the AES-CMAC of a reference implementation, with the input of the CryptoCell KDF
+    let expected = [
+        0x0E, 0x96, 0xE3, 0xA9, 0x94, 0x2B, 0xA8, 0x96, 0x54, 0x17, 0xA7, 0x8E, 0xB7, 0xD6, 0xF3,
+        0x81, 0x87, 0x6B, 0x21, 0x07, 0x80, 0xF6, 0x23, 0x56, 0xAA, 0xCC, 0x7B, 0xBD, 0xBD, 0xBC,
+        0x40, 0x03,
+    ];
+
+    let huk = SoftwareHuk { key: SECRET };
+    assert_eq!(derive_wipe_token(&huk, &DEVICE_ID), Some(expected));
+}
+
+#[test]
+fn derivations_give_different_keys() {
+    let huk = SoftwareHuk { key: SECRET };
+    let other_huk = SoftwareHuk { key: [0xA5; 32] };
+
+    let keys = [
+        derive_image_key(&huk, b"image 1"),
+        derive_image_key(&huk, b"image 2"),
+        derive_image_key(&other_huk, b"image 1"),
+        derive_wipe_token(&huk, &DEVICE_ID),
+        derive_wipe_token(&other_huk, &DEVICE_ID),
+        derive_revocation_token(&huk, &DEVICE_ID, 0),
+        derive_revocation_token(&huk, &DEVICE_ID, 1),
+        derive_maintenance_key(&huk, &DEVICE_ID),
+    ];
+
+    for (index, key) in keys.iter().enumerate() {
+        assert!(key.is_some());
+        assert!(
+            !keys[index + 1..].contains(key),
+            "key {} is repeated",
+            index
+        );
+    }
+}
+
+#[test]
+fn maintenance_response_is_the_hmac_of_the_challenge() {
+    // Computed with the HMAC-SHA256 of a reference implementation, keyed with the derived maintenance key
+    let expected = [
+        0x74, 0x02, 0x28, 0x34, 0xE2, 0xDD, 0x6D, 0x51, 0xB7, 0x16, 0x58, 0xE7, 0x96, 0x55, 0x5C,
+        0xDF, 0x29, 0x51, 0x45, 0xBC, 0xBE, 0xA7, 0xF2, 0xFD, 0x41, 0x14, 0x30, 0xDE, 0x2F, 0x9D,
+        0x89, 0x9D,
+    ];
+    let challenge = core::array::from_fn(|index| 0xC0 + index as u8);
+
+    let key = derive_maintenance_key(&SoftwareHuk { key: SECRET }, &DEVICE_ID).unwrap();
+    assert_eq!(maintenance_response(&key, &challenge), expected);
+
+    let other_key = derive_maintenance_key(&SoftwareHuk { key: [0xA5; 32] }, &DEVICE_ID).unwrap();
+    assert_ne!(maintenance_response(&other_key, &challenge), expected);
+}
+
+#[test]
+fn event_log_keystream_decrypts_the_records() {
+    let mut flash = RamFlash::new();
+    let huk = SoftwareHuk { key: SECRET };
+    let keystream = EventLogKeystream {
+        huk: &huk,
+        device_id: DEVICE_ID,
+    };
+    assert!(keystream.is_available());
+
+    let events = [
+        (SecurityEvent::Provisioned, 0),
+        (SecurityEvent::VerificationFailed, 5),
+        (SecurityEvent::VerificationFailed, 5),
+    ];
+    for (event, detail) in events {
+        event_log::append_encrypted(&mut flash, &keystream, event, detail).unwrap();
+    }
+
+    // Without the keystream, the records can't be read
+    assert_eq!(event_log::records(&flash).count(), 0);
+
+    let records = event_log::decrypted_records(&flash, &keystream)
+        .map(|record| (record.event, record.detail))
+        .collect::<Vec<_>>();
+    assert_eq!(records, events);
+
+    // The same event gives another record at every index
+    let words = flash.read_u32(shared::flash_addresses::bootloader_event_log_range());
+    assert_ne!(words[2..4], words[4..6]);
+}
+
+/// The size of the header of the test image, the vector table follows it
+const HEADER_SIZE: u32 = 0x200;
+/// The size of the test image, without the header and the TLVs
+const IMAGE_SIZE: u32 = 3 * PAGE_SIZE + 0x100;
+
+/// Writes an MCUboot image with the [TLV_DEVICE_ENCRYPTION] TLV into the slot at the given address, and returns the
+/// image as it must end up in the primary slot
+fn write_plain_image(flash: &mut RamFlash, slot_address: u32, primary_address: u32) -> Vec<u8> {
+    let mut image = vec![0; (HEADER_SIZE + IMAGE_SIZE) as usize];
+
+    let mut header = Vec::new();
+    header.extend(McubootHeader::MAGIC.to_le_bytes());
+    header.extend(0u32.to_le_bytes());
+    header.extend((HEADER_SIZE as u16).to_le_bytes());
+    header.extend(0u16.to_le_bytes());
+    header.extend(IMAGE_SIZE.to_le_bytes());
+    header.extend(0u32.to_le_bytes());
+    header.extend([1, 2, 3, 0, 4, 0, 0, 0, 0, 0, 0, 0]);
+    image[..header.len()].copy_from_slice(&header);
+
+    // A vector table that runs from the primary slot, followed by something that isn't all the same
+    let body = &mut image[HEADER_SIZE as usize..];
+    for (index, byte) in body.iter_mut().enumerate() {
+        *byte = (index * 7 + index / 251) as u8;
+    }
+    body[..4].copy_from_slice(&0x2000_8000u32.to_le_bytes());
+    body[4..8].copy_from_slice(&(primary_address + HEADER_SIZE + 0x201).to_le_bytes());
+
+    let mut trailer = Vec::new();
+    trailer.extend(TlvInfo::MAGIC.to_le_bytes());
+    trailer.extend(24u16.to_le_bytes());
+    trailer.extend((TLV_CRITICAL | TLV_DEVICE_ENCRYPTION).to_le_bytes());
+    trailer.extend(16u16.to_le_bytes());
+    trailer.extend([0x4E; 16]);
+
+    let mut slot_contents = image.clone();
+    slot_contents.extend(&trailer);
+    write_bytes(flash, slot_address, &slot_contents);
+
+    image
+}
+
+/// Erases the pages at the address and programs the bytes into them
+fn write_bytes(flash: &mut RamFlash, address: u32, bytes: &[u8]) {
+    for (page_address, page) in (address..)
+        .step_by(PAGE_SIZE as usize)
+        .zip(bytes.chunks(PAGE_SIZE as usize))
+    {
+        let mut words = vec![0xFFFF_FFFF; PAGE_SIZE as usize / 4];
+        for (word, chunk) in words.iter_mut().zip(page.chunks(4)) {
+            let mut word_bytes = [0xFF; 4];
+            word_bytes[..chunk.len()].copy_from_slice(chunk);
+            *word = u32::from_le_bytes(word_bytes);
+        }
+        flash.erase_page(page_address).unwrap();
+        flash.program_page(page_address, &words).unwrap();
+    }
+}
+
+/// Encrypts the image in the slot in place for the device with the given secret, like the update server does
+fn encrypt_image(flash: &mut RamFlash, slot_address: u32, slot_end: u32, secret: [u8; KEY_SIZE]) {
+    let cipher =
+        ImageCipher::load(&SoftwareHuk { key: secret }, flash, slot_address, slot_end).unwrap();
+
+    let length = (HEADER_SIZE + IMAGE_SIZE + 24) as usize;
+    let mut bytes = flash
+        .read_u8(slot_address..slot_address + length as u32)
+        .to_vec();
+    cipher.apply(slot_address, &mut bytes);
+    write_bytes(flash, slot_address, &bytes);
+}
+
+#[test]
+fn overwrite_decrypts_an_encrypted_image() {
+    set_device_huk(derive_on_device);
+
+    let layout = slots::default_layout();
+    let primary = slots::find(&layout, SlotRole::Primary, APPLICATION_IMAGE).unwrap();
+    let secondary = slots::find(&layout, SlotRole::Secondary, APPLICATION_IMAGE).unwrap();
+
+    let mut flash = RamFlash::new();
+    fill_page(&mut flash, primary.address(), 0x1234);
+    let image = write_plain_image(&mut flash, secondary.address(), primary.address());
+    encrypt_image(&mut flash, secondary.address(), secondary.range.end, SECRET);
+
+    // The header stays readable, the image itself doesn't
+    let stored = flash.read_u8(secondary.address()..secondary.address() + image.len() as u32);
+    assert_eq!(
+        stored[..HEADER_SIZE as usize],
+        image[..HEADER_SIZE as usize]
+    );
+    assert_ne!(
+        stored[HEADER_SIZE as usize..],
+        image[HEADER_SIZE as usize..]
+    );
+
+    let mut state = BootloaderState::load(&flash);
+    state
+        .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::Overwrite)
+        .unwrap();
+    state.set_valid(true);
+    state.store(&mut flash).unwrap();
+
+    assert!(overwrite::start_overwrite(&layout, &mut state, &mut flash, &mut NullLog).unwrap());
+    overwrite::finish_overwrite(&layout, &mut state, &mut flash, &mut NullLog).unwrap();
+
+    assert_eq!(
+        flash.read_u8(primary.address()..primary.address() + image.len() as u32),
+        image
+    );
+    assert_eq!(
+        BootloaderState::load(&flash).goal(),
+        BootloaderGoal::JumpToApplication
+    );
+}
+
+#[test]
+fn overwrite_refuses_an_image_for_another_device() {
+    set_device_huk(derive_on_device);
+
+    let layout = slots::default_layout();
+    let primary = slots::find(&layout, SlotRole::Primary, APPLICATION_IMAGE).unwrap();
+    let secondary = slots::find(&layout, SlotRole::Secondary, APPLICATION_IMAGE).unwrap();
+
+    let mut flash = RamFlash::new();
+    fill_page(&mut flash, primary.address(), 0x1234);
+    write_plain_image(&mut flash, secondary.address(), primary.address());
+
+    encrypt_image(
+        &mut flash,
+        secondary.address(),
+        secondary.range.end,
+        [0xA5; 32],
+    );
+
+    let mut state = BootloaderState::load(&flash);
+    state
+        .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::Overwrite)
+        .unwrap();
+    state.set_valid(true);
+    state.store(&mut flash).unwrap();
+
+    assert!(!overwrite::start_overwrite(&layout, &mut state, &mut flash, &mut NullLog).unwrap());
+    assert!(common::page_has_pattern(&flash, primary.address(), 0x1234));
+}
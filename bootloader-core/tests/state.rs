@@ -0,0 +1,103 @@
+//! Host tests of the state with the layouts of [common::layouts]
+
+mod common;
+
+use common::{layouts, RamFlash};
+use shared::{
+    flash_addresses::with_layout,
+    state::{BootloaderGoal, BootloaderState},
+};
+
+/// Stores the state with the goal changed from the expected one to the given one
+fn store_goal(flash: &mut RamFlash, expected: BootloaderGoal, goal: BootloaderGoal) {
+    let mut state = BootloaderState::load(flash);
+    state.set_goal(expected, goal).unwrap();
+    state.set_valid(true);
+    state.store(flash).unwrap();
+}
+
+#[test]
+fn state_survives_many_stores() {
+    for (name, layout) in layouts() {
+        with_layout(&layout, || {
+            let mut flash = RamFlash::new();
+            let mut state = BootloaderState::load(&flash);
+            state
+                .set_goal(
+                    BootloaderGoal::JumpToApplication,
+                    BootloaderGoal::JumpToApplication,
+                )
+                .unwrap();
+            state.set_valid(true);
+            state.store(&mut flash).unwrap();
+
+            // Enough stores to fill every page of the log a few times over
+            let goals = [BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap];
+            for index in 0..300 {
+                store_goal(&mut flash, goals[index % 2], goals[(index + 1) % 2]);
+
+                let state = BootloaderState::load(&flash);
+                assert!(state.is_valid(), "layout {}, store {}", name, index);
+                assert_eq!(
+                    state.goal(),
+                    goals[(index + 1) % 2],
+                    "layout {}, store {}",
+                    name,
+                    index
+                );
+            }
+        });
+    }
+}
+
+#[test]
+fn state_survives_power_cuts() {
+    for (name, layout) in layouts() {
+        with_layout(&layout, || {
+            let mut flash = RamFlash::new();
+            let mut state = BootloaderState::load(&flash);
+            state
+                .set_goal(
+                    BootloaderGoal::JumpToApplication,
+                    BootloaderGoal::JumpToApplication,
+                )
+                .unwrap();
+            state.set_valid(true);
+            state.store(&mut flash).unwrap();
+
+            // A store that loses power must leave either the old or the new goal behind
+            for cut_after in 0..8 {
+                for index in 0..40 {
+                    let (expected, goal) = if index % 2 == 0 {
+                        (BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap)
+                    } else {
+                        (BootloaderGoal::StartSwap, BootloaderGoal::JumpToApplication)
+                    };
+                    if BootloaderState::load(&flash).goal() != expected {
+                        continue;
+                    }
+
+                    flash.cut_power_after(Some(cut_after));
+                    let mut state = BootloaderState::load(&flash);
+                    state.set_goal(expected, goal).unwrap();
+                    state.store(&mut flash).ok();
+                    flash.cut_power_after(None);
+
+                    let state = BootloaderState::load(&flash);
+                    assert!(
+                        state.is_valid(),
+                        "layout {}, power cut after {} operations",
+                        name,
+                        cut_after
+                    );
+                    assert!(
+                        [expected, goal].contains(&state.goal()),
+                        "layout {}, power cut after {} operations",
+                        name,
+                        cut_after
+                    );
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,146 @@
+//! Host tests of the swap with the layouts of [common::layouts]
+
+mod common;
+
+use common::{fill_page, layouts, page_has_pattern, RamFlash};
+use dis_bootloader_core::{layout_check, perform_swap, NullLog};
+use shared::{
+    external_flash::EXTERNAL_FLASH_ADDRESS,
+    flash_addresses::{
+        program_slot_a_page_range, program_slot_b_page_range, with_layout, FlashLayout, PAGE_SIZE,
+    },
+    slots,
+    state::{BootloaderGoal, BootloaderState},
+    Flash,
+};
+
+const SLOT_A_SEED: u32 = 0xAAAA_0000;
+const SLOT_B_SEED: u32 = 0xBBBB_0000;
+
+/// Gives every page of both slots its own pattern
+fn fill_slots(flash: &mut RamFlash) {
+    for page in 0..program_slot_a_page_range().len() as u32 {
+        let slot_a_address = (program_slot_a_page_range().start + page) * PAGE_SIZE;
+        let slot_b_address = (program_slot_b_page_range().start + page) * PAGE_SIZE;
+        fill_page(flash, slot_a_address, SLOT_A_SEED + page);
+        fill_page(flash, slot_b_address, SLOT_B_SEED + page);
+    }
+}
+
+/// Returns true if the contents of every page of the slots have been exchanged
+fn slots_are_swapped(flash: &RamFlash) -> bool {
+    (0..program_slot_a_page_range().len() as u32).all(|page| {
+        let slot_a_address = (program_slot_a_page_range().start + page) * PAGE_SIZE;
+        let slot_b_address = (program_slot_b_page_range().start + page) * PAGE_SIZE;
+        page_has_pattern(flash, slot_a_address, SLOT_B_SEED + page)
+            && page_has_pattern(flash, slot_b_address, SLOT_A_SEED + page)
+    })
+}
+
+/// Fills the slots and sets the goal to a swap, like the application does for an update
+fn prepare_update(flash: &mut RamFlash, test_swap: bool) -> BootloaderState {
+    fill_slots(flash);
+
+    let goal = if test_swap {
+        BootloaderGoal::StartTestSwap
+    } else {
+        BootloaderGoal::StartSwap
+    };
+    let mut state = BootloaderState::load(flash);
+    state
+        .set_goal(BootloaderGoal::JumpToApplication, goal)
+        .unwrap();
+    state.set_valid(true);
+    state.prepare_swap(goal, test_swap, flash).unwrap();
+    state
+}
+
+#[test]
+fn test_swap_exchanges_the_slots() {
+    for (name, layout) in layouts() {
+        with_layout(&layout, || {
+            let mut flash = RamFlash::new();
+            let mut state = prepare_update(&mut flash, true);
+            perform_swap(true, &mut state, &mut flash, &mut NullLog).unwrap();
+
+            assert!(slots_are_swapped(&flash), "layout {}", name);
+
+            // After a test swap, the bootloader must swap back on the next boot
+            let state = BootloaderState::load(&flash);
+            assert!(state.is_valid(), "layout {}", name);
+            assert_eq!(state.goal(), BootloaderGoal::StartSwap, "layout {}", name);
+        });
+    }
+}
+
+#[test]
+fn swap_survives_power_cuts() {
+    for (name, layout) in layouts() {
+        with_layout(&layout, || {
+            let mut flash = RamFlash::new();
+
+            // Every few operations, so the test doesn't take long with the big slots
+            for cut_after in (1..).step_by(53) {
+                let mut state = prepare_update(&mut flash, false);
+                flash.cut_power_after(Some(cut_after));
+                let result = perform_swap(false, &mut state, &mut flash, &mut NullLog);
+                let power_was_cut = flash.power_is_cut();
+                assert!(result.is_ok() || power_was_cut, "layout {}", name);
+
+                // Reboot and finish what the state says is left of the swap
+                flash.cut_power_after(None);
+                let mut state = BootloaderState::load(&flash);
+                if state.goal() == BootloaderGoal::FinishSwap {
+                    perform_swap(false, &mut state, &mut flash, &mut NullLog).unwrap();
+                }
+
+                assert!(
+                    slots_are_swapped(&flash),
+                    "layout {}, power cut after {} operations",
+                    name,
+                    cut_after
+                );
+                assert_eq!(
+                    BootloaderState::load(&flash).goal(),
+                    BootloaderGoal::JumpToApplication,
+                    "layout {}",
+                    name
+                );
+
+                if !power_was_cut {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[test]
+fn only_slot_b_can_be_on_the_external_flash() {
+    let flash = RamFlash::new();
+    let external = EXTERNAL_FLASH_ADDRESS..EXTERNAL_FLASH_ADDRESS + 0x0006_8000;
+    let check = |layout: FlashLayout| {
+        with_layout(&layout, || {
+            layout_check::check(&slots::default_layout(), flash.geometry(), &mut NullLog)
+        })
+    };
+
+    assert!(check(FlashLayout {
+        program_slot_b: external.clone(),
+        modem_staging: external.clone(),
+        ..FlashLayout::NRF9160
+    }));
+    assert!(!check(FlashLayout {
+        program_slot_a: external.clone(),
+        ..FlashLayout::NRF9160
+    }));
+
+    // Beyond the end of the external flash
+    let beyond =
+        external.start + common::EXTERNAL_FLASH_SIZE..external.end + common::EXTERNAL_FLASH_SIZE;
+    assert!(!check(FlashLayout {
+        program_slot_b: beyond.clone(),
+        modem_staging: beyond,
+        ..FlashLayout::NRF9160
+    }));
+}
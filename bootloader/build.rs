@@ -13,6 +13,22 @@ use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[path = "../shared/partitions.rs"]
+mod partitions;
+#[path = "../shared/signing_key.rs"]
+mod signing_key;
+
+/// The timeout of the watchdog when `WATCHDOG_TIMEOUT_MS` isn't set
+const DEFAULT_WATCHDOG_TIMEOUT_MS: u64 = 10_000;
+
+/// The baud rate of the UART when `UART_BAUD_RATE` isn't set
+const DEFAULT_UART_BAUD_RATE: u32 = 115_200;
+/// The baud rates the UARTE can run at, the same as those of `shared::board_config::BAUD_RATES`
+const UART_BAUD_RATES: [u32; 9] = [
+    9600, 19200, 38400, 57600, 115_200, 230_400, 460_800, 921_600, 1_000_000,
+];
 
 fn get_git_short(version: &str) -> String {
     let output = Command::new("git")
@@ -35,16 +51,192 @@ fn main() {
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
+    // `memory.x` includes the flash layout of `partitions.toml`, with the bootloader partition as the flash
+    let layout = partitions::Layout::load();
+    File::create(out.join("partitions.x"))
+        .unwrap()
+        .write_all(layout.linker_script("bootloader_flash").as_bytes())
+        .unwrap();
+
+    // Put `fit.x` there too. It checks that the bootloader fits in its flash region
+    // and must come after the `link.x` of cortex-m-rt, so we pass both in the right order.
+    File::create(out.join("fit.x"))
+        .unwrap()
+        .write_all(include_bytes!("fit.x"))
+        .unwrap();
+    println!("cargo:rustc-link-arg-bins=-Tlink.x");
+    println!("cargo:rustc-link-arg-bins=-Tfit.x");
+
+    // The diagnostics over RTT need the defmt linker script as well
+    if env::var_os("CARGO_FEATURE_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+    }
+
     // By default, Cargo will re-run a build script whenever
     // any file in the project changes. By specifying `memory.x`
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=fit.x");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    // The bootloader embeds a build info block that is read by both the logs and host tools
+    let build_info = generate_build_info();
+    File::create(out.join("build_info.rs"))
+        .unwrap()
+        .write_all(build_info.as_bytes())
+        .unwrap();
+
+    // With secure boot, the public key of the signing key is built into the bootloader
+    println!("cargo:rerun-if-env-changed=SIGNING_PUBLIC_KEY");
+    if env::var_os("CARGO_FEATURE_SECURE_BOOT").is_some() {
+        File::create(out.join("signing_public_key.rs"))
+            .unwrap()
+            .write_all(signing_key::public_key_expression().as_bytes())
+            .unwrap();
+    }
+
+    // The baud rate of the boards, which the UICR board configuration can override at boot
+    println!("cargo:rerun-if-env-changed=UART_BAUD_RATE");
+    let baud_rate = match env::var("UART_BAUD_RATE") {
+        Ok(baud_rate) => baud_rate
+            .parse::<u32>()
+            .ok()
+            .filter(|baud_rate| UART_BAUD_RATES.contains(baud_rate))
+            .unwrap_or_else(|| panic!("UART_BAUD_RATE must be one of {:?}", UART_BAUD_RATES)),
+        Err(_) => DEFAULT_UART_BAUD_RATE,
+    };
+    File::create(out.join("uart_baud_rate.rs"))
+        .unwrap()
+        .write_all(format!("{}u32", baud_rate).as_bytes())
+        .unwrap();
+
+    // The watchdog timeout is the same for the bootloader and the application, because it can't be changed once started
+    println!("cargo:rerun-if-env-changed=WATCHDOG_TIMEOUT_MS");
+    if env::var_os("CARGO_FEATURE_WATCHDOG").is_some() {
+        let timeout_ms = match env::var("WATCHDOG_TIMEOUT_MS") {
+            Ok(timeout_ms) => timeout_ms
+                .parse::<u64>()
+                .ok()
+                .filter(|timeout_ms| *timeout_ms > 0)
+                .expect("WATCHDOG_TIMEOUT_MS must be a number of milliseconds above 0"),
+            Err(_) => DEFAULT_WATCHDOG_TIMEOUT_MS,
+        };
+        File::create(out.join("watchdog_timeout_ms.rs"))
+            .unwrap()
+            .write_all(format!("{}u64", timeout_ms).as_bytes())
+            .unwrap();
+    }
 
-    // We need to print the cargo version and git hash in the bootloader
-    let cargo_package_version = env!("CARGO_PKG_VERSION").trim();
+    // The CryptoCell is driven by Nordic's nrf_cc310_bl library, which comes with nrfxlib
+    println!("cargo:rerun-if-env-changed=NRF_CC310_BL_LIB");
+    if env::var_os("CARGO_FEATURE_CRYPTOCELL").is_some() {
+        let library = PathBuf::from(env::var_os("NRF_CC310_BL_LIB").expect(
+            "The cryptocell feature needs the path of libnrf_cc310_bl*.a in NRF_CC310_BL_LIB",
+        ));
+        let name = library
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix("lib"))
+            .expect("NRF_CC310_BL_LIB must point to a lib*.a file");
+        println!(
+            "cargo:rustc-link-search={}",
+            library.parent().unwrap().display()
+        );
+        println!("cargo:rustc-link-lib=static={}", name);
+    }
+
+    // The KMU and the key derivation of the CryptoCell are driven by Nordic's nrf_cc3xx_platform library
+    println!("cargo:rerun-if-env-changed=NRF_CC310_PLATFORM_LIB");
+    if env::var_os("CARGO_FEATURE_HARDWARE_HUK").is_some() {
+        let library = PathBuf::from(env::var_os("NRF_CC310_PLATFORM_LIB").expect(
+            "The hardware-huk feature needs the path of libnrf_cc310_platform*.a in NRF_CC310_PLATFORM_LIB",
+        ));
+        let name = library
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix("lib"))
+            .expect("NRF_CC310_PLATFORM_LIB must point to a lib*.a file");
+        println!(
+            "cargo:rustc-link-search={}",
+            library.parent().unwrap().display()
+        );
+        println!("cargo:rustc-link-lib=static={}", name);
+    }
+}
+
+/// Generates the `shared::build_info::BuildInfo` expression with the info of the current build
+fn generate_build_info() -> String {
+    let version = |name: &str| env::var(name).unwrap().parse::<u16>().unwrap();
+
+    // Use the `SOURCE_DATE_EPOCH` if it is set so the build can be reproduced
+    let timestamp = match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse::<u64>().unwrap(),
+        Err(_) => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    };
+
+    let mut git_hash = [0u8; 16];
     let git_hash_head = get_git_short("HEAD");
+    let git_hash_length = git_hash_head.len().min(git_hash.len());
+    git_hash[..git_hash_length].copy_from_slice(&git_hash_head.as_bytes()[..git_hash_length]);
+
+    let features = [
+        ("TEST_SWAP", "CARGO_FEATURE_TEST_SWAP"),
+        ("LOGGING", "CARGO_FEATURE_LOGGING"),
+        ("VERIFICATION", "CARGO_FEATURE_VERIFICATION"),
+        ("NON_SECURE", "CARGO_FEATURE_NON_SECURE"),
+        ("STATE_PROTECTION", "CARGO_FEATURE_STATE_PROTECTION"),
+        ("APPROTECT", "CARGO_FEATURE_APPROTECT"),
+        ("PROVISIONING", "CARGO_FEATURE_PROVISIONING"),
+        ("RMA_WIPE", "CARGO_FEATURE_RMA_WIPE"),
+        ("MEASURED_BOOT", "CARGO_FEATURE_MEASURED_BOOT"),
+        ("FI_HARDENING", "CARGO_FEATURE_FI_HARDENING"),
+        ("SELF_UPDATE", "CARGO_FEATURE_SELF_UPDATE"),
+        ("KEY_REVOCATION", "CARGO_FEATURE_KEY_REVOCATION"),
+        ("ENCRYPTED_LOGS", "CARGO_FEATURE_ENCRYPTED_LOGS"),
+        ("EVENT_REPORT", "CARGO_FEATURE_EVENT_REPORT"),
+        ("ERASE_OLD_IMAGE", "CARGO_FEATURE_ERASE_OLD_IMAGE"),
+        ("DIRECT_BOOT", "CARGO_FEATURE_DIRECT_BOOT"),
+        ("BOARD_FEATHER", "CARGO_FEATURE_FEATHER"),
+        ("BOARD_LOGISTICS", "CARGO_FEATURE_LOGISTICS"),
+        ("BOARD_MOBILITY", "CARGO_FEATURE_MOBILITY"),
+        ("BOARD_TURING", "CARGO_FEATURE_TURING"),
+        ("BOARD_ACTINIUS_ICARUS", "CARGO_FEATURE_ACTINIUS_ICARUS"),
+        ("BOARD_NRF52840_DK", "CARGO_FEATURE_NRF52840_DK"),
+        ("DEFMT", "CARGO_FEATURE_DEFMT"),
+        ("FAST_WAKE", "CARGO_FEATURE_FAST_WAKE"),
+        ("FLASH_TRACE", "CARGO_FEATURE_FLASH_TRACE"),
+        ("SECURE_SERVICES", "CARGO_FEATURE_SECURE_SERVICES"),
+        ("DIRECT_XIP", "CARGO_FEATURE_DIRECT_XIP"),
+        ("IMAGE_HEADER", "CARGO_FEATURE_IMAGE_HEADER"),
+        ("IMAGE_DIGEST", "CARGO_FEATURE_IMAGE_DIGEST"),
+        ("SECURE_BOOT", "CARGO_FEATURE_SECURE_BOOT"),
+    ]
+    .iter()
+    .filter(|(_, cargo_feature)| env::var_os(cargo_feature).is_some())
+    .map(|(bit, _)| format!("shared::build_info::features::{} | ", bit))
+    .collect::<String>();
 
-    println!("cargo:rustc-env=CP_GIT={}", git_hash_head);
-    println!("cargo:rustc-env=CP_CARGO={}", cargo_package_version);
+    format!(
+        "shared::build_info::BuildInfo {{
+    magic: shared::build_info::BuildInfo::MAGIC,
+    version_major: {},
+    version_minor: {},
+    version_patch: {},
+    reserved: 0,
+    features: {}0,
+    timestamp: {},
+    git_hash: {:?},
+}}
+",
+        version("CARGO_PKG_VERSION_MAJOR"),
+        version("CARGO_PKG_VERSION_MINOR"),
+        version("CARGO_PKG_VERSION_PATCH"),
+        features,
+        timestamp,
+        git_hash,
+    )
 }
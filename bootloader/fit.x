@@ -0,0 +1,6 @@
+/* This script is linked after `link.x` of cortex-m-rt, so all section symbols are known here */
+
+/* The flash image ends with the initial values of .data */
+_bootloader_image_end = LOADADDR(.data) + SIZEOF(.data);
+
+ASSERT(_bootloader_image_end <= _bootloader_descriptor_start, "The bootloader image doesn't fit in the bootloader flash region. It would overwrite the descriptor block or the scratch region");
@@ -1,36 +1,51 @@
+/* The flash is laid out in partitions.toml in the root of the workspace. The build script generates
+ * partitions.x from it, with the FLASH region of the bootloader and the start and end of every partition. */
+INCLUDE partitions.x
+
 MEMORY
 {
-    FLASH                    : ORIGIN = 0x00000000, LENGTH = 64K
+    RAM   : ORIGIN = 0x20000000, LENGTH = 63K - 768
+    BOOT_INFO: ORIGIN = 0x2000F900, LENGTH = 256
+    MEASUREMENTS: ORIGIN = 0x2000FA00, LENGTH = 256
+    MAILBOX: ORIGIN = 0x2000FB00, LENGTH = 256
+}
 
-    PROGRAM_SLOT_A_FLASH     : ORIGIN = 0x00010000, LENGTH = 448K
-    PROGRAM_SLOT_B_FLASH     : ORIGIN = 0x00080000, LENGTH = 448K
+_bootloader_mailbox_start = ORIGIN(MAILBOX);
+_bootloader_mailbox_end = ORIGIN(MAILBOX) + LENGTH(MAILBOX);
 
-    # Application data       : ORIGIN = 0x000F0000, LENGTH = 32K
+_bootloader_measurements_start = ORIGIN(MEASUREMENTS);
+_bootloader_measurements_end = ORIGIN(MEASUREMENTS) + LENGTH(MEASUREMENTS);
 
-    BOOTLOADER_SCRATCH_FLASH : ORIGIN = 0x000F8000, LENGTH = 24K
-    BOOTLOADER_STATE_FLASH   : ORIGIN = 0x000FE000, LENGTH = 8K
+_bootloader_boot_info_start = ORIGIN(BOOT_INFO);
+_bootloader_boot_info_end = ORIGIN(BOOT_INFO) + LENGTH(BOOT_INFO);
 
-    RAM   : ORIGIN = 0x20000000, LENGTH = 63K
-    PANDUMP: ORIGIN = 0x2000FC00, LENGTH = 1K
-}
+/* The end of the bootloader flash is reserved for the descriptor block */
+_bootloader_descriptor_size = 256;
+_bootloader_descriptor_start = _bootloader_flash_end - _bootloader_descriptor_size;
 
-_panic_dump_start = ORIGIN(PANDUMP);
-_panic_dump_end   = ORIGIN(PANDUMP) + LENGTH(PANDUMP);
-
-_bootloader_flash_start = ORIGIN(FLASH);
-_bootloader_flash_end = _bootloader_flash_start + LENGTH(FLASH);
-_bootloader_scratch_start = ORIGIN(BOOTLOADER_SCRATCH_FLASH);
-_bootloader_scratch_end = _bootloader_scratch_start + LENGTH(BOOTLOADER_SCRATCH_FLASH);
-_bootloader_state_start = ORIGIN(BOOTLOADER_STATE_FLASH);
-_bootloader_state_end = _bootloader_state_start + LENGTH(BOOTLOADER_STATE_FLASH);
-
-_program_slot_a_start = ORIGIN(PROGRAM_SLOT_A_FLASH);
-_program_slot_a_end = _program_slot_a_start + LENGTH(PROGRAM_SLOT_A_FLASH);
-_program_slot_b_start = ORIGIN(PROGRAM_SLOT_B_FLASH);
-_program_slot_b_end = _program_slot_b_start + LENGTH(PROGRAM_SLOT_B_FLASH);
-
-ASSERT(_bootloader_scratch_start % 0x1000 == 0, "Flash area must align with flash pages");
-ASSERT(_bootloader_state_start % 0x1000 == 0, "Flash area must align with flash pages");
-ASSERT((_bootloader_state_end - _bootloader_state_start) == 8192, "Bootloader state area must have a size of 8K");
-ASSERT(_program_slot_a_start % 0x1000 == 0, "Flash area must align with flash pages");
-ASSERT(_program_slot_b_start % 0x1000 == 0, "Flash area must align with flash pages");
+/* The build info is placed at the start of the descriptor block so host tools can always find it */
+SECTIONS
+{
+  .bootloader_descriptor _bootloader_descriptor_start :
+  {
+    KEEP(*(.bootloader_descriptor .bootloader_descriptor.*));
+  } > FLASH
+} INSERT AFTER .uninit;
+
+/* The last 64 bytes of the descriptor block are the non-secure callable veneers of the secure services.
+ * shared::secure_services::VENEERS_ADDRESS is derived from the same layout. */
+_bootloader_veneers_start = _bootloader_flash_end - 64;
+SECTIONS
+{
+  .nsc_veneers _bootloader_veneers_start :
+  {
+    KEEP(*(.nsc_veneers));
+  } > FLASH
+} INSERT AFTER .bootloader_descriptor;
+
+ASSERT(SIZEOF(.bootloader_descriptor) <= 256 - 64, "The build info must not overlap the veneers");
+
+/* There is no room for a separate region, so modem updates are staged in slot B.
+ * An application update and a modem update can therefore not be staged at the same time. */
+_modem_staging_start = _program_slot_b_start;
+_modem_staging_end = _program_slot_b_end;
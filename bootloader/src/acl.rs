@@ -0,0 +1,51 @@
+//! Write protection of the bootloader state with the access control lists (ACL) of the nRF52840
+//!
+//! The nRF52840 has no SPU. Its ACL, the successor of the BPROT of the older nRF52 chips, can take away the write
+//! and erase permission of up to 8 page aligned flash regions. A region can only be configured once, so the
+//! protection holds until the next reset, also against the application.
+
+use shared::{
+    chip::nrf52840::ACL_ADDRESS,
+    flash_addresses::{bootloader_state_log_range, bootloader_state_range},
+};
+
+/// The ADDR register of the first ACL region. Every region has its ADDR, SIZE and PERM registers 16 bytes further.
+const ACL_ADDR: *mut u32 = (ACL_ADDRESS + 0x800) as *mut u32;
+/// The SIZE register of the first ACL region
+const ACL_SIZE: *mut u32 = (ACL_ADDRESS + 0x804) as *mut u32;
+/// The PERM register of the first ACL region
+const ACL_PERM: *mut u32 = (ACL_ADDRESS + 0x808) as *mut u32;
+/// The distance in words between the registers of two regions
+const REGION_STRIDE: usize = 4;
+
+/// The bit of the PERM register that takes away the write and erase permission
+const PERM_WRITE_DISABLE: u32 = 1 << 1;
+
+/// Makes the flash with the bootloader state and the state log read-only until the next reset.
+///
+/// This must be the last thing that's done to the flash. After this, the state can only be changed
+/// by the bootloader at the next boot, for example through the [mailbox](shared::mailbox).
+pub fn protect_state() {
+    let state_ranges = [bootloader_state_range(), bootloader_state_log_range()];
+
+    for (region, range) in state_ranges
+        .iter()
+        .filter(|range| !range.is_empty())
+        .enumerate()
+    {
+        unsafe {
+            ACL_ADDR
+                .add(region * REGION_STRIDE)
+                .write_volatile(range.start);
+            ACL_SIZE
+                .add(region * REGION_STRIDE)
+                .write_volatile(range.end - range.start);
+            ACL_PERM
+                .add(region * REGION_STRIDE)
+                .write_volatile(PERM_WRITE_DISABLE);
+        }
+    }
+
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
@@ -0,0 +1,33 @@
+//! Enforcement of the access port protection (APPROTECT)
+//!
+//! Production devices must not be debuggable. The protection is a UICR setting, so a UICR erase (which the debugger can
+//! do before the protection is active) or a device that was never locked would leave the debug port open.
+//! Because the bootloader is the only code that can't be replaced, it checks the setting at every boot and
+//! enables the protection again when it was found disabled.
+
+use crate::flash::Flash;
+use shared::chip::{UICR_APPROTECT, UICR_SECUREAPPROTECT};
+
+/// The value of the registers that enables the protection
+const PROTECTED: u32 = 0x0000_0000;
+
+/// Makes sure the access port protection is enabled.
+///
+/// Returns true if the protection was disabled and had to be enabled.
+/// In that case, the device must be reset for the protection to take effect.
+pub fn enforce(flash: &mut Flash) -> bool {
+    let mut was_disabled = false;
+
+    // The nRF52840 has no secure world, so only the whole chip is protected there
+    for register in [Some(UICR_APPROTECT), UICR_SECUREAPPROTECT]
+        .into_iter()
+        .flatten()
+    {
+        if flash.read_uicr_word(register) != PROTECTED {
+            flash.write_uicr_word(register, PROTECTED);
+            was_disabled = true;
+        }
+    }
+
+    was_disabled
+}
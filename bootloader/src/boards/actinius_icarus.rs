@@ -0,0 +1,14 @@
+//! The Actinius Icarus
+
+use super::{BoardConfig, DEFAULT_BAUD_RATE};
+
+/// The config of the Actinius Icarus
+pub const BOARD: BoardConfig = BoardConfig {
+    name: "actinius_icarus",
+    uart_rx_pin: 6,
+    uart_tx_pin: 9,
+    uart_baud_rate: DEFAULT_BAUD_RATE,
+    recovery_pin: Some(5),
+    leds: &[],
+    qspi_flash: None,
+};
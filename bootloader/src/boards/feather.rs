@@ -0,0 +1,14 @@
+//! The Circuit Dojo nRF9160 Feather
+
+use super::{BoardConfig, DEFAULT_BAUD_RATE};
+
+/// The config of the nRF9160 Feather
+pub const BOARD: BoardConfig = BoardConfig {
+    name: "feather",
+    uart_rx_pin: 5,
+    uart_tx_pin: 6,
+    uart_baud_rate: DEFAULT_BAUD_RATE,
+    recovery_pin: Some(12),
+    leds: &[3],
+    qspi_flash: None,
+};
@@ -0,0 +1,14 @@
+//! The mobility board
+
+use super::{BoardConfig, DEFAULT_BAUD_RATE};
+
+/// The config of the mobility board
+pub const BOARD: BoardConfig = BoardConfig {
+    name: "mobility",
+    uart_rx_pin: 28,
+    uart_tx_pin: 29,
+    uart_baud_rate: DEFAULT_BAUD_RATE,
+    recovery_pin: None,
+    leds: &[],
+    qspi_flash: None,
+};
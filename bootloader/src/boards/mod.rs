@@ -0,0 +1,110 @@
+//! The definitions of the boards the bootloader can run on
+//!
+//! Every board has its own module with a [BoardConfig] constant.
+//! The board is selected with a cargo feature of the same name.
+//! At boot, the board configuration in the UICR can override the UART and the recovery button of that constant
+//! (see [shared::board_config]), so one binary can run on every board of a chip.
+
+#[cfg(any(feature = "recovery", feature = "shell"))]
+use crate::serial::CYCLES_PER_MS;
+#[cfg(any(feature = "recovery", feature = "shell"))]
+use embassy_nrf::gpio::{AnyPin, Input, Pull};
+use shared::board_config::UicrBoardConfig;
+
+#[cfg(feature = "actinius_icarus")]
+mod actinius_icarus;
+#[cfg(feature = "feather")]
+mod feather;
+#[cfg(feature = "logistics")]
+mod logistics;
+#[cfg(feature = "mobility")]
+mod mobility;
+#[cfg(feature = "nrf52840_dk")]
+mod nrf52840_dk;
+#[cfg(feature = "turing")]
+mod turing;
+
+#[cfg(feature = "actinius_icarus")]
+pub use actinius_icarus::BOARD;
+#[cfg(feature = "feather")]
+pub use feather::BOARD;
+#[cfg(feature = "logistics")]
+pub use logistics::BOARD;
+#[cfg(feature = "mobility")]
+pub use mobility::BOARD;
+#[cfg(feature = "nrf52840_dk")]
+pub use nrf52840_dk::BOARD;
+#[cfg(feature = "turing")]
+pub use turing::BOARD;
+
+#[cfg(not(any(
+    feature = "actinius_icarus",
+    feature = "feather",
+    feature = "logistics",
+    feature = "mobility",
+    feature = "nrf52840_dk",
+    feature = "turing"
+)))]
+compile_error!("No board selected. Enable exactly one of the board features.");
+
+#[cfg(all(feature = "chip-nrf9160", feature = "chip-nrf52840"))]
+compile_error!("The boards are for different chips. Enable exactly one of the board features.");
+
+/// The baud rate of the UART of every board, `UART_BAUD_RATE` at build time or 115200
+pub const DEFAULT_BAUD_RATE: u32 = include!(concat!(env!("OUT_DIR"), "/uart_baud_rate.rs"));
+
+/// The hardware specifics of a board
+#[derive(Debug, Copy, Clone)]
+pub struct BoardConfig {
+    /// The name of the board
+    pub name: &'static str,
+    /// The pin number (port 0) of the UART RX line
+    pub uart_rx_pin: u8,
+    /// The pin number (port 0) of the UART TX line
+    pub uart_tx_pin: u8,
+    /// The baud rate of the UART
+    pub uart_baud_rate: u32,
+    /// The pin number (port 0) of a button that is active low and can be used to enter recovery
+    pub recovery_pin: Option<u8>,
+    /// The pin numbers (port 0) of the active high LEDs that are lit while the bootloader runs
+    pub leds: &'static [u8],
+    /// The pins of the NOR flash on the QSPI bus, which slot B can be put on with the `qspi-slot-b` feature
+    #[allow(dead_code)] // Only used with the qspi-slot-b feature
+    pub qspi_flash: Option<QspiPins>,
+}
+
+/// The pin numbers (port 0) of a flash on the QSPI bus
+#[derive(Debug, Copy, Clone)]
+#[allow(dead_code)] // Only used with the qspi-slot-b feature
+pub struct QspiPins {
+    /// The clock
+    pub sck: u8,
+    /// The chip select
+    pub csn: u8,
+    /// The data lines IO0 to IO3. In single line mode, IO0 is the data to the flash and IO1 the data from it.
+    pub io: [u8; 4],
+}
+
+impl BoardConfig {
+    /// Returns this config with the fields that are programmed in the board configuration of the UICR
+    pub fn with_uicr_config(&self, config: UicrBoardConfig) -> Self {
+        Self {
+            uart_rx_pin: config.uart_rx_pin().unwrap_or(self.uart_rx_pin),
+            uart_tx_pin: config.uart_tx_pin().unwrap_or(self.uart_tx_pin),
+            uart_baud_rate: config.baud_rate().unwrap_or(self.uart_baud_rate),
+            recovery_pin: config.recovery_pin().unwrap_or(self.recovery_pin),
+            ..*self
+        }
+    }
+
+    /// Returns true if the board has a recovery button and it's held
+    #[cfg(any(feature = "recovery", feature = "shell"))]
+    pub fn recovery_button_is_held(&self) -> bool {
+        self.recovery_pin.is_some_and(|pin| {
+            let button = Input::new(unsafe { AnyPin::steal(pin) }, Pull::Up);
+            // Give the pull-up time to charge the line
+            cortex_m::asm::delay(CYCLES_PER_MS);
+            button.is_low()
+        })
+    }
+}
@@ -0,0 +1,22 @@
+//! The Nordic nRF52840 DK
+
+use super::{BoardConfig, QspiPins, DEFAULT_BAUD_RATE};
+
+/// The config of the nRF52840 DK
+pub const BOARD: BoardConfig = BoardConfig {
+    name: "nrf52840_dk",
+    // The UART of the interface MCU, which shows up as a serial port on the USB connection
+    uart_rx_pin: 8,
+    uart_tx_pin: 6,
+    uart_baud_rate: DEFAULT_BAUD_RATE,
+    // Button 1
+    recovery_pin: Some(11),
+    // The LEDs of the DK are active low, so they're left alone
+    leds: &[],
+    // The 8MB MX25R6435F
+    qspi_flash: Some(QspiPins {
+        sck: 19,
+        csn: 17,
+        io: [20, 21, 22, 23],
+    }),
+};
@@ -0,0 +1,14 @@
+//! The turing board
+
+use super::{BoardConfig, DEFAULT_BAUD_RATE};
+
+/// The config of the turing board
+pub const BOARD: BoardConfig = BoardConfig {
+    name: "turing",
+    uart_rx_pin: 30,
+    uart_tx_pin: 19,
+    uart_baud_rate: DEFAULT_BAUD_RATE,
+    recovery_pin: None,
+    leds: &[],
+    qspi_flash: None,
+};
@@ -0,0 +1,88 @@
+//! Verifying ECDSA P-256 signatures with the CC310 inside the CryptoCell
+//!
+//! The PKA of the CC310 is only documented through Nordic's `nrf_cc310_bl` library from nrfxlib, which is the same
+//! library their own immutable bootloader uses. It's linked statically from the path in the `NRF_CC310_BL_LIB`
+//! environment variable (see `build.rs`).
+
+use core::mem::MaybeUninit;
+use shared::chip;
+
+/// The ENABLE register of the CRYPTOCELL peripheral
+const CRYPTOCELL_ENABLE: *mut u32 = (chip::CRYPTOCELL_ADDRESS + 0x500) as *mut u32;
+
+/// The size of the verify context, at least `NRF_CC310_BL_ECDSA_VERIFY_CONTEXT_SIZE_SECP256R1` of the library
+const VERIFY_CONTEXT_SIZE: usize = 160 * 4;
+
+/// `nrf_cc310_bl_ecdsa_verify_context_secp256r1_t`
+#[repr(C)]
+#[allow(dead_code)] // Only the library reads it
+struct VerifyContext {
+    init_value: u32,
+    context_buffer: [u8; VERIFY_CONTEXT_SIZE],
+}
+
+/// `nrf_cc310_bl_ecc_public_key_secp256r1_t`, the big-endian coordinates
+#[repr(C)]
+#[allow(dead_code)] // Only the library reads it
+struct EccPublicKey {
+    x: [u8; 32],
+    y: [u8; 32],
+}
+
+/// `nrf_cc310_bl_ecc_signature_secp256r1_t`, the big-endian r and s
+#[repr(C)]
+#[allow(dead_code)] // Only the library reads it
+struct EccSignature {
+    r: [u8; 32],
+    s: [u8; 32],
+}
+
+/// The return value of the library calls that succeeded
+const CRYS_OK: u32 = 0;
+
+extern "C" {
+    fn nrf_cc310_bl_init() -> u32;
+    fn nrf_cc310_bl_ecdsa_verify_secp256r1(
+        context: *mut VerifyContext,
+        public_key: *const EccPublicKey,
+        signature: *const EccSignature,
+        hash: *const u8,
+        hash_length: u32,
+    ) -> u32;
+}
+
+/// Verifies the signature of the digest in the CC310.
+///
+/// This is the [P256Accelerator](dis_bootloader_core::secure_boot::P256Accelerator) of the bootloader.
+/// Only a valid signature is reported by the CC310 itself. For anything else, this returns `None`, so the software
+/// has the final say. An invalid signature is rare enough that it doesn't matter that it takes longer.
+pub fn verify_p256(public_key: &[u8; 64], digest: &[u8; 32], signature: &[u8; 64]) -> Option<bool> {
+    let public_key = EccPublicKey {
+        x: public_key[..32].try_into().unwrap(),
+        y: public_key[32..].try_into().unwrap(),
+    };
+    let signature = EccSignature {
+        r: signature[..32].try_into().unwrap(),
+        s: signature[32..].try_into().unwrap(),
+    };
+    let mut context = MaybeUninit::<VerifyContext>::uninit();
+
+    unsafe {
+        CRYPTOCELL_ENABLE.write_volatile(1);
+
+        let result = match nrf_cc310_bl_init() {
+            CRYS_OK => nrf_cc310_bl_ecdsa_verify_secp256r1(
+                context.as_mut_ptr(),
+                &public_key,
+                &signature,
+                digest.as_ptr(),
+                digest.len() as u32,
+            ),
+            error => error,
+        };
+
+        CRYPTOCELL_ENABLE.write_volatile(0);
+
+        (result == CRYS_OK).then_some(true)
+    }
+}
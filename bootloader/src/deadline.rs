@@ -0,0 +1,24 @@
+//! The confirmation deadline of test-swapped images
+//!
+//! A test-swapped image that hangs or runs in a degraded state may never reset, so it would never be reverted.
+//! When the UICR config has a deadline, the bootloader starts the watchdog right before it jumps to an
+//! unconfirmed image. The [watchdog](crate::watchdog) can't be stopped by the application. If it isn't fed in time, it resets the
+//! device and the bootloader swaps the old image back. With the `watchdog` feature, the watchdog already runs with
+//! the timeout of that feature, so there is no separate deadline.
+//! The reset reason in the POWER peripheral is retained across that reset, so the bootloader can tell why it happened.
+
+use crate::hal::{Chip, Hal};
+
+/// Starts the watchdog so the device resets after the given number of minutes, unless the application feeds it
+/// through reload request register 0
+#[cfg(not(feature = "watchdog"))]
+pub fn arm(minutes: u32) {
+    Chip::start_watchdog(u64::from(minutes) * 60 * 1000);
+}
+
+/// Returns true if the last reset was done by the watchdog, and clears that reason
+pub fn take_watchdog_reset() -> bool {
+    let is_watchdog_reset = Chip::reset_reason() & shared::reset_reason::DOG != 0;
+    Chip::clear_reset_reason(shared::reset_reason::DOG);
+    is_watchdog_reset
+}
@@ -1,28 +1,89 @@
-//! Implementation of [Flash]
+//! Implementation of [Flash], both blocking and async
+//!
+//! The NVMC of the nRF9160 and the nRF52840 only differ in how a partial erase is started.
+//!
+//! With the `qspi-slot-b` feature, the addresses in the window of [shared::external_flash] go to the
+//! [QspiFlash](crate::qspi::QspiFlash) instead, so slot B can be on the flash on the QSPI bus.
 
-use core::{mem::size_of, ops::Range};
+use crate::power;
+use core::{
+    future::Future,
+    mem::{size_of, size_of_val},
+    ops::Range,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use shared::{
+    chip::{self, PAGE_ERASE_TIME_MS, UICR_RANGE},
+    flash_addresses::PAGE_SIZE,
+    flash_geometry::{self, FlashGeometry},
+    Flash as _, FlashError,
+};
+#[cfg(feature = "qspi-slot-b")]
+use {
+    crate::qspi::QspiFlash,
+    shared::external_flash::{external_address, external_range},
+};
+
+/// The duration of a single partial erase. The CPU stalls at most this long while a page is erased.
+const PARTIAL_ERASE_DURATION_MS: u32 = 10;
+/// The number of partial erases that add up to the full erase time
+const PARTIAL_ERASES: u32 = PAGE_ERASE_TIME_MS.div_ceil(PARTIAL_ERASE_DURATION_MS);
+/// The number of times the ready register is polled before an operation is given up on.
+/// Every poll takes at least a few cycles at 64 MHz, so this is far longer than any operation takes.
+const READY_TIMEOUT_POLLS: u32 = 10_000_000;
+
+/// The RUNSTATUS register of the WDT
+const WDT_RUNSTATUS: *const u32 = (chip::WDT_ADDRESS + 0x400) as *const u32;
+/// The first of the eight reload request registers of the WDT
+const WDT_RR: *mut u32 = (chip::WDT_ADDRESS + 0x600) as *mut u32;
+/// The value that reloads the watchdog when it's written to an enabled reload request register
+const WDT_RELOAD_VALUE: u32 = 0x6E52_4635;
 
 /// The bootloader's implementation of the flash operations
 pub struct Flash<'a> {
     pub registers: &'a embassy_nrf::pac::nvmc::RegisterBlock,
+    /// The geometry of the flash, as the FICR reports it
+    pub geometry: FlashGeometry,
+    /// The flash on the QSPI bus, with slot B
+    #[cfg(feature = "qspi-slot-b")]
+    pub external: QspiFlash,
 }
 
-impl<'a> shared::Flash for Flash<'a> {
+impl<'a> Flash<'a> {
+    /// Creates the driver and reads the geometry of the flash from the FICR
+    pub fn new(registers: &'a embassy_nrf::pac::nvmc::RegisterBlock) -> Self {
+        Self {
+            registers,
+            geometry: read_geometry(),
+            #[cfg(feature = "qspi-slot-b")]
+            external: QspiFlash::new(
+                crate::boards::BOARD
+                    .qspi_flash
+                    .expect("The board has no QSPI flash for slot B"),
+            ),
+        }
+    }
+
+    /// Writes a word in the UICR.
+    ///
+    /// Like normal flash, the UICR can only change bits from 1 to 0. Setting bits again requires an erase of the UICR.
+    /// Most UICR values are only used by the hardware after the next reset.
     #[track_caller]
-    fn erase_page(&mut self, page_address: u32) {
-        assert_valid_page_address(page_address);
+    #[allow(dead_code)] // Only used by some features
+    pub fn write_uicr_word(&mut self, address: u32, value: u32) {
+        assert!(
+            UICR_RANGE.contains(&address) && address % 4 == 0,
+            "The address must be an aligned word in the UICR"
+        );
 
-        // Enable the erase functionality of the flash
-        self.registers.config.modify(|_, w| w.wen().een());
-        // Start the erase process by writing a u32 word containing all 1's to the first word of the page
-        // This is safe because the flash slice is page aligned, so a pointer to the first byte is valid as a pointer to a u32.
+        shared::debug!("Writing {:#010X} to the UICR at {:#010X}", value, address);
+
+        self.registers.config.modify(|_, w| w.wen().wen());
         unsafe {
-            let first_word = page_address as *mut u32;
-            first_word.write_volatile(0xFFFFFFFF);
+            (address as *mut u32).write_volatile(value);
         }
-        // Wait for the erase to be done
         while self.registers.ready.read().ready().is_busy() {}
-
         self.registers.config.modify(|_, w| w.wen().ren());
 
         // Synchronize the changes
@@ -30,49 +91,238 @@ impl<'a> shared::Flash for Flash<'a> {
         cortex_m::asm::isb();
     }
 
+    /// Reads a word in the UICR
     #[track_caller]
-    fn program_page(&mut self, page_address: u32, data: &[u32]) {
-        assert_valid_page_address(page_address);
+    pub fn read_uicr_word(&self, address: u32) -> u32 {
         assert!(
-            data.len() <= 0x0000_1000 / size_of::<u32>(),
-            "Only 4KB can be programmed at a time",
+            UICR_RANGE.contains(&address) && address % 4 == 0,
+            "The address must be an aligned word in the UICR"
         );
 
-        // Now we need to write the buffer to flash
+        unsafe { (address as *const u32).read_volatile() }
+    }
+}
+
+impl<'a> Flash<'a> {
+    /// The geometry of the internal flash, with the size of the flash on the QSPI bus if slot B is on it
+    fn reported_geometry(&self) -> FlashGeometry {
+        #[cfg(feature = "qspi-slot-b")]
+        return FlashGeometry {
+            external_size: self.external.size(),
+            ..self.geometry
+        };
+
+        #[cfg(not(feature = "qspi-slot-b"))]
+        self.geometry
+    }
+
+    /// Checks that the address is at the start of a flash page
+    fn check_page_address(&self, page_address: u32) -> Result<(), FlashError> {
+        if page_address % self.geometry.page_size != 0 {
+            // Page addresses must be aligned to the pages
+            return Err(FlashError::Alignment);
+        }
+        if page_address >= self.geometry.size {
+            // Pages cannot lie outside of flash memory
+            return Err(FlashError::OutOfRange);
+        }
+
+        Ok(())
+    }
+
+    /// Waits until the flash controller is ready for the next operation
+    fn wait_until_ready(&self) -> Result<(), FlashError> {
+        for _ in 0..READY_TIMEOUT_POLLS {
+            if self.registers.ready.read().ready().is_ready() {
+                return Ok(());
+            }
+        }
+
+        Err(FlashError::Timeout)
+    }
+
+    /// Returns a future that completes when the flash controller is ready for the next operation
+    fn ready(&self) -> Ready<'_> {
+        Ready {
+            registers: self.registers,
+            polls_left: READY_TIMEOUT_POLLS,
+        }
+    }
+
+    /// Checks the page address, waits for a healthy supply and enables the partial erase functionality of the flash
+    fn start_erase(&mut self, page_address: u32) -> Result<(), FlashError> {
+        self.check_page_address(page_address)?;
+        power::wait_for_supply();
+        shared::debug!("Erasing the page at {:#010X}", page_address);
+
+        self.registers
+            .erasepagepartialcfg
+            .write(|w| unsafe { w.duration().bits(PARTIAL_ERASE_DURATION_MS as u8) });
+        #[cfg(feature = "chip-nrf9160")]
+        self.registers.config.modify(|_, w| w.wen().peen());
+        #[cfg(feature = "chip-nrf52840")]
+        self.registers.config.modify(|_, w| w.wen().een());
+
+        Ok(())
+    }
+
+    /// Starts a partial erase of the page, which [start_erase](Self::start_erase) has prepared
+    fn start_partial_erase(&self, page_address: u32) {
+        // The nRF9160 starts it with a write of all 1's to the first word of the page.
+        // This is safe because the page address is aligned, so it's valid as a pointer to a u32.
+        #[cfg(feature = "chip-nrf9160")]
+        unsafe {
+            (page_address as *mut u32).write_volatile(0xFFFF_FFFF);
+        }
+
+        // The nRF52840 has a register for it
+        #[cfg(feature = "chip-nrf52840")]
+        self.registers
+            .erasepagepartial
+            .write(|w| unsafe { w.bits(page_address) });
+    }
+
+    /// Sets the flash back to read only mode and checks that the page is erased if the partial erases succeeded
+    fn finish_erase(
+        &mut self,
+        page_address: u32,
+        result: Result<(), FlashError>,
+    ) -> Result<(), FlashError> {
+        self.finish_write();
+
+        result?;
+        if self
+            .read_u32(page_address..page_address + self.geometry.page_size)
+            .iter()
+            .any(|word| *word != 0xFFFF_FFFF)
+        {
+            return Err(FlashError::VerifyFailed);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the program operation, fills `expected` with what the flash must contain afterwards, waits for a healthy
+    /// supply and sets the flash to write mode. Returns the address range of the data.
+    fn start_program(
+        &mut self,
+        page_address: u32,
+        data: &[u32],
+        expected: &mut [u32],
+    ) -> Result<Range<u32>, FlashError> {
+        self.check_page_address(page_address)?;
+        if data.len() > self.geometry.page_size as usize / size_of::<u32>() {
+            // Only a page can be programmed at a time
+            return Err(FlashError::OutOfRange);
+        }
+
+        // Programming can only clear bits, so this is what the flash must contain afterwards
+        let data_range = page_address..page_address + size_of_val(data) as u32;
+        for ((expected, data_word), flash_word) in expected
+            .iter_mut()
+            .zip(data)
+            .zip(self.read_u32(data_range.clone()))
+        {
+            *expected = data_word & flash_word;
+        }
+
+        power::wait_for_supply();
+
         // Set the flash to write mode
         self.registers.config.modify(|_, w| w.wen().wen());
 
-        // Write the buffer words to the flash
-        let word_size = core::mem::size_of::<u32>();
-        let page_words = (page_address..page_address + 0x0000_1000)
-            .step_by(word_size)
-            .map(|address| address as *mut u32);
+        Ok(data_range)
+    }
+
+    /// Sets the flash back to read only mode and checks the programmed data if the writes succeeded
+    fn finish_program(
+        &mut self,
+        data_range: Range<u32>,
+        expected: &[u32],
+        result: Result<(), FlashError>,
+    ) -> Result<(), FlashError> {
+        self.finish_write();
+
+        result?;
+        if self.read_u32(data_range) != expected {
+            return Err(FlashError::VerifyFailed);
+        }
+
+        Ok(())
+    }
+
+    /// Sets the flash to the default read only mode and synchronizes the changes
+    fn finish_write(&mut self) {
+        self.registers.config.modify(|_, w| w.wen().ren());
+
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+    }
+}
+
+impl<'a> shared::Flash for Flash<'a> {
+    /// Erases the page in a number of partial erases, so the CPU isn't stalled for the whole erase time at once.
+    /// Between the partial erases, the interrupts are handled and a running watchdog is fed.
+    fn erase_page(&mut self, page_address: u32) -> Result<(), FlashError> {
+        #[cfg(feature = "qspi-slot-b")]
+        if let Some(page_address) = external_address(page_address) {
+            return self.external.erase_page(page_address);
+        }
+
+        self.start_erase(page_address)?;
+
+        // The partial erases only finish the erase once their durations add up to the full erase time
+        let mut result = Ok(());
+        for _ in 0..PARTIAL_ERASES {
+            self.start_partial_erase(page_address);
+            // Wait for the partial erase to be done
+            result = self.wait_until_ready();
+            if result.is_err() {
+                break;
+            }
+
+            feed_watchdog();
+        }
+
+        self.finish_erase(page_address, result)
+    }
+
+    fn program_page(&mut self, page_address: u32, data: &[u32]) -> Result<(), FlashError> {
+        #[cfg(feature = "qspi-slot-b")]
+        if let Some(page_address) = external_address(page_address) {
+            return self.external.program_page(page_address, data);
+        }
+
+        let mut expected = [0; PAGE_SIZE as usize / size_of::<u32>()];
+        let expected = &mut expected[..data.len().min(PAGE_SIZE as usize / size_of::<u32>())];
+        let data_range = self.start_program(page_address, data, expected)?;
 
         // Every word of the buffer corresponds to a word in flash
         // We only have to write when the words are different
-        for (data_word, flash_word_ptr) in data
-            .iter()
-            .zip(page_words)
-            .filter(|(word, ptr)| **word != unsafe { **ptr })
-        {
+        let mut result = Ok(());
+        for (data_word, flash_word_ptr) in words_to_program(page_address, data) {
             unsafe {
                 flash_word_ptr.write_volatile(*data_word);
             }
             // Wait for the write to be done
-            while self.registers.ready.read().ready().is_busy() {}
+            result = self.wait_until_ready();
+            if result.is_err() {
+                break;
+            }
         }
 
-        // Set the flash to default readonly mode
-        self.registers.config.modify(|_, w| w.wen().ren());
-
-        // Synchronize the changes
-        cortex_m::asm::dsb();
-        cortex_m::asm::isb();
+        self.finish_program(data_range, expected, result)
     }
 
     fn read_u8(&self, address_range: Range<u32>) -> &[u8] {
-        let entire_flash_slice =
-            unsafe { core::slice::from_raw_parts(0x0000_0000 as *const u8, 0x0010_0000) };
+        #[cfg(feature = "qspi-slot-b")]
+        if let Some(address_range) = external_range(address_range.clone()) {
+            return self.external.read_u8(address_range);
+        }
+
+        let entire_flash_slice = unsafe {
+            core::slice::from_raw_parts(0x0000_0000 as *const u8, self.geometry.size as usize)
+        };
 
         entire_flash_slice
             .get(address_range.start as usize..address_range.end as usize)
@@ -83,23 +333,153 @@ impl<'a> shared::Flash for Flash<'a> {
         assert!(address_range.start % 4 == 0);
         assert!(address_range.end % 4 == 0);
 
+        #[cfg(feature = "qspi-slot-b")]
+        if let Some(address_range) = external_range(address_range.clone()) {
+            return self.external.read_u32(address_range);
+        }
+
         let entire_flash_slice = unsafe {
-            core::slice::from_raw_parts(0x0000_0000 as *const u32, 0x0010_0000 / size_of::<u32>())
+            core::slice::from_raw_parts(
+                0x0000_0000 as *const u32,
+                self.geometry.size as usize / size_of::<u32>(),
+            )
         };
 
-        entire_flash_slice.get(address_range.start as usize / 4..address_range.end as usize / 4).unwrap()
+        entire_flash_slice
+            .get(address_range.start as usize / 4..address_range.end as usize / 4)
+            .unwrap()
+    }
+
+    fn geometry(&self) -> FlashGeometry {
+        self.reported_geometry()
     }
 }
 
-/// Asserts that the address is at the start of a flash page
-#[track_caller]
-fn assert_valid_page_address(page_address: u32) {
-    assert!(
-        page_address % 0x0000_1000 == 0,
-        "Page addresses must be aligned to 4KB blocks"
-    );
-    assert!(
-        page_address < 0x0010_0000,
-        "Page cannot lie outside of flash memory"
-    );
+impl<'a> shared::AsyncFlash for Flash<'a> {
+    /// Erases the page in a number of partial erases like [shared::Flash::erase_page], but yields to the executor
+    /// until every partial erase is done.
+    ///
+    /// Code that runs from flash stalls while a partial erase is in progress, so the other tasks get to run between
+    /// the partial erases. They have to feed a running watchdog themselves.
+    /// A page of the flash on the QSPI bus is erased without yielding.
+    async fn erase_page(&mut self, page_address: u32) -> Result<(), FlashError> {
+        #[cfg(feature = "qspi-slot-b")]
+        if let Some(page_address) = external_address(page_address) {
+            return self.external.erase_page(page_address);
+        }
+
+        self.start_erase(page_address)?;
+
+        let mut result = Ok(());
+        for _ in 0..PARTIAL_ERASES {
+            self.start_partial_erase(page_address);
+            result = self.ready().await;
+            if result.is_err() {
+                break;
+            }
+        }
+
+        self.finish_erase(page_address, result)
+    }
+
+    async fn program_page(&mut self, page_address: u32, data: &[u32]) -> Result<(), FlashError> {
+        #[cfg(feature = "qspi-slot-b")]
+        if let Some(page_address) = external_address(page_address) {
+            return self.external.program_page(page_address, data);
+        }
+
+        let mut expected = [0; PAGE_SIZE as usize / size_of::<u32>()];
+        let expected = &mut expected[..data.len().min(PAGE_SIZE as usize / size_of::<u32>())];
+        let data_range = self.start_program(page_address, data, expected)?;
+
+        let mut result = Ok(());
+        for (data_word, flash_word_ptr) in words_to_program(page_address, data) {
+            unsafe {
+                flash_word_ptr.write_volatile(*data_word);
+            }
+            result = self.ready().await;
+            if result.is_err() {
+                break;
+            }
+        }
+
+        self.finish_program(data_range, expected, result)
+    }
+
+    fn read_u8(&self, address_range: Range<u32>) -> &[u8] {
+        shared::Flash::read_u8(self, address_range)
+    }
+
+    fn read_u32(&self, address_range: Range<u32>) -> &[u32] {
+        shared::Flash::read_u32(self, address_range)
+    }
+
+    fn geometry(&self) -> FlashGeometry {
+        self.reported_geometry()
+    }
+}
+
+/// A future that completes when the NVMC is ready for the next operation.
+///
+/// The NVMC has no interrupt for this, so while it's busy the future wakes itself right away and the executor
+/// polls it again after its other tasks. After [READY_TIMEOUT_POLLS] polls it gives up.
+struct Ready<'a> {
+    registers: &'a embassy_nrf::pac::nvmc::RegisterBlock,
+    polls_left: u32,
+}
+
+impl Future for Ready<'_> {
+    type Output = Result<(), FlashError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.registers.ready.read().ready().is_ready() {
+            return Poll::Ready(Ok(()));
+        }
+        if self.polls_left == 0 {
+            return Poll::Ready(Err(FlashError::Timeout));
+        }
+
+        self.polls_left -= 1;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Gives the data words together with the pointers to their words in flash, for the words that are different
+fn words_to_program(page_address: u32, data: &[u32]) -> impl Iterator<Item = (&u32, *mut u32)> {
+    let word_size = core::mem::size_of::<u32>();
+    let page_words = (page_address..page_address + size_of_val(data) as u32)
+        .step_by(word_size)
+        .map(|address| address as *mut u32);
+
+    data.iter()
+        .zip(page_words)
+        .filter(|(word, ptr)| **word != unsafe { **ptr })
+}
+
+/// Feeds the watchdog if it's running, so a long swap doesn't trip the deadline of the previous boot.
+///
+/// The watchdog can't be stopped and keeps running across soft resets, so the application may have left it running.
+/// The registers are repeated from the watchdog module, because this driver is also used by the HIL tests.
+pub fn feed_watchdog() {
+    unsafe {
+        if WDT_RUNSTATUS.read_volatile() & 1 == 0 {
+            return;
+        }
+
+        // Writing to a reload request register that isn't enabled does nothing, so we don't have to check which are
+        for register in 0..8 {
+            WDT_RR.add(register).write_volatile(WDT_RELOAD_VALUE);
+        }
+    }
+}
+
+/// Reads the geometry of the internal flash from the FICR
+pub fn read_geometry() -> FlashGeometry {
+    unsafe {
+        FlashGeometry::from_ficr_words(
+            (flash_geometry::CODE_PAGE_SIZE_ADDRESS as *const u32).read_volatile(),
+            (flash_geometry::CODE_SIZE_ADDRESS as *const u32).read_volatile(),
+        )
+    }
 }
@@ -0,0 +1,124 @@
+//! The chip-specific peripherals of the bootloader, behind the [Hal] trait
+//!
+//! Every chip has its own module with a type that implements [Hal], and the chip feature selects it as [Chip].
+//! The rest of the bootloader only talks to the UART, the flash, the reset reason and the watchdog through [Chip],
+//! so porting the bootloader to another Nordic part means adding its addresses to `shared::chip` and implementing
+//! [Hal] for it. A new board of a supported chip only needs a [BoardConfig](crate::boards::BoardConfig).
+
+use crate::{boards::BoardConfig, flash, flash::Flash, reset_reason, watchdog};
+use core::ops::{Deref, DerefMut};
+use embassy_nrf::{
+    gpio::AnyPin,
+    uarte::{self, Uarte},
+};
+
+#[cfg(feature = "chip-nrf52840")]
+mod nrf52840;
+#[cfg(feature = "chip-nrf9160")]
+mod nrf9160;
+
+/// The chip the bootloader is built for
+#[cfg(feature = "chip-nrf52840")]
+pub type Chip = nrf52840::Nrf52840;
+/// The chip the bootloader is built for
+#[cfg(feature = "chip-nrf9160")]
+pub type Chip = nrf9160::Nrf9160;
+
+/// The peripherals of a chip that the bootloader needs
+///
+/// Only the UART differs between the chips that are supported now. The other functions drive the
+/// registers at the addresses in `shared::chip`, which are the same on every Nordic part so far.
+pub trait Hal {
+    /// The UARTE the bootloader talks over, UARTE0 or with the `uarte1` feature UARTE1
+    type UartPeripheral: uarte::Instance;
+
+    /// The base address of the registers of [UartPeripheral](Hal::UartPeripheral)
+    const UART_ADDRESS: u32;
+
+    /// Sets up the UART on the pins of the board
+    fn uart(
+        peripherals: embassy_nrf::Peripherals,
+        board: &BoardConfig,
+    ) -> Uart<Self::UartPeripheral>;
+
+    /// Creates the driver of the internal flash
+    fn flash() -> Flash<'static> {
+        // Embassy doesn't give us a pac instance of the NVMC, so we need to make a reference ourselves
+        Flash::new(unsafe { &*embassy_nrf::pac::NVMC::PTR })
+    }
+
+    /// Returns the reset reason and clears it in the register, see [reset_reason::take]
+    #[allow(dead_code)] // Only used with the reset-history feature
+    fn take_reset_reason() -> u32 {
+        reset_reason::take()
+    }
+
+    /// Returns the reset reason of this boot
+    fn reset_reason() -> u32 {
+        reset_reason::get()
+    }
+
+    /// Clears the given reset reasons in the register
+    #[allow(dead_code)] // Only used with the test-swap feature
+    fn clear_reset_reason(reasons: u32) {
+        reset_reason::clear(reasons)
+    }
+
+    /// Starts the watchdog so the device resets after the given number of milliseconds, unless it's fed
+    #[allow(dead_code)] // Only used with the test-swap and watchdog features
+    fn start_watchdog(timeout_ms: u64) {
+        watchdog::start(timeout_ms)
+    }
+
+    /// Feeds the watchdog if it runs
+    #[allow(dead_code)] // Only used with the watchdog feature
+    fn feed_watchdog() {
+        flash::feed_watchdog()
+    }
+}
+
+/// The UART of the bootloader. It derefs to the embassy driver.
+pub struct Uart<T: uarte::Instance>(Uarte<'static, T>);
+
+impl<T: uarte::Instance> Deref for Uart<T> {
+    type Target = Uarte<'static, T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T: uarte::Instance> DerefMut for Uart<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Returns the RX and TX pins of the board and the config of the UART, which are the same on every chip
+fn uart_parts(board: &BoardConfig) -> (AnyPin, AnyPin, uarte::Config) {
+    let mut config = uarte::Config::default();
+    config.parity = uarte::Parity::EXCLUDED;
+    config.baudrate = baudrate(board.uart_baud_rate);
+
+    // The pins are defined by the board config, so we take them from there instead of the peripherals
+    let rx_pin = unsafe { AnyPin::steal(board.uart_rx_pin) };
+    let tx_pin = unsafe { AnyPin::steal(board.uart_tx_pin) };
+
+    (rx_pin, tx_pin, config)
+}
+
+/// Returns the setting of the UARTE for the baud rate. The baud rates of the
+/// [UICR board config](shared::board_config::BAUD_RATES) are all supported, anything else runs at 115200 baud.
+fn baudrate(baud_rate: u32) -> uarte::Baudrate {
+    match baud_rate {
+        9600 => uarte::Baudrate::BAUD9600,
+        19200 => uarte::Baudrate::BAUD19200,
+        38400 => uarte::Baudrate::BAUD38400,
+        57600 => uarte::Baudrate::BAUD57600,
+        230_400 => uarte::Baudrate::BAUD230400,
+        460_800 => uarte::Baudrate::BAUD460800,
+        921_600 => uarte::Baudrate::BAUD921600,
+        1_000_000 => uarte::Baudrate::BAUD1M,
+        _ => uarte::Baudrate::BAUD115200,
+    }
+}
@@ -0,0 +1,34 @@
+//! The peripherals of the nRF52840
+
+use super::{uart_parts, Hal, Uart};
+use crate::boards::BoardConfig;
+use embassy_nrf::{interrupt, peripherals, uarte::Uarte};
+use shared::chip;
+
+/// The nRF52840
+pub struct Nrf52840;
+
+impl Hal for Nrf52840 {
+    #[cfg(not(feature = "uarte1"))]
+    type UartPeripheral = peripherals::UARTE0;
+    #[cfg(feature = "uarte1")]
+    type UartPeripheral = peripherals::UARTE1;
+
+    #[cfg(not(feature = "uarte1"))]
+    const UART_ADDRESS: u32 = chip::UARTE0_ADDRESS;
+    #[cfg(feature = "uarte1")]
+    const UART_ADDRESS: u32 = chip::UARTE1_ADDRESS;
+
+    fn uart(
+        peripherals: embassy_nrf::Peripherals,
+        board: &BoardConfig,
+    ) -> Uart<Self::UartPeripheral> {
+        #[cfg(not(feature = "uarte1"))]
+        let (uarte, irq) = (peripherals.UARTE0, interrupt::take!(UARTE0_UART0));
+        #[cfg(feature = "uarte1")]
+        let (uarte, irq) = (peripherals.UARTE1, interrupt::take!(UARTE1));
+
+        let (rx_pin, tx_pin, config) = uart_parts(board);
+        Uart(Uarte::new(uarte, irq, rx_pin, tx_pin, config))
+    }
+}
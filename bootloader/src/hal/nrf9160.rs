@@ -0,0 +1,41 @@
+//! The peripherals of the nRF9160
+
+use super::{uart_parts, Hal, Uart};
+use crate::boards::BoardConfig;
+use embassy_nrf::{interrupt, peripherals, uarte::Uarte};
+use shared::chip;
+
+/// The nRF9160, with the peripherals at their secure addresses
+pub struct Nrf9160;
+
+impl Hal for Nrf9160 {
+    /// The UARTE shares its peripheral ID with the other serial peripherals on the nRF9160
+    #[cfg(not(feature = "uarte1"))]
+    type UartPeripheral = peripherals::UARTETWISPI0;
+    #[cfg(feature = "uarte1")]
+    type UartPeripheral = peripherals::UARTETWISPI1;
+
+    #[cfg(not(feature = "uarte1"))]
+    const UART_ADDRESS: u32 = chip::UARTE0_ADDRESS;
+    #[cfg(feature = "uarte1")]
+    const UART_ADDRESS: u32 = chip::UARTE1_ADDRESS;
+
+    fn uart(
+        peripherals: embassy_nrf::Peripherals,
+        board: &BoardConfig,
+    ) -> Uart<Self::UartPeripheral> {
+        #[cfg(not(feature = "uarte1"))]
+        let (uarte, irq) = (
+            peripherals.UARTETWISPI0,
+            interrupt::take!(UARTE0_SPIM0_SPIS0_TWIM0_TWIS0),
+        );
+        #[cfg(feature = "uarte1")]
+        let (uarte, irq) = (
+            peripherals.UARTETWISPI1,
+            interrupt::take!(UARTE1_SPIM1_SPIS1_TWIM1_TWIS1),
+        );
+
+        let (rx_pin, tx_pin, config) = uart_parts(board);
+        Uart(Uarte::new(uarte, irq, rx_pin, tx_pin, config))
+    }
+}
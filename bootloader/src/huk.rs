@@ -0,0 +1,156 @@
+//! The hardware-unique key in the KMU of the nRF9160
+//!
+//! At provisioning, the device secret is written into two key slots of the KMU, with permissions that only allow
+//! pushing it into the CryptoCell. From then on, not even the bootloader can read it. The keys and tokens are
+//! derived from it by the CryptoCell, with the KDF of [HardwareUniqueKey](dis_bootloader_core::crypto::HardwareUniqueKey).
+//! Nordic's `nrf_cc3xx_platform` library from nrfxlib drives the KMU and the CryptoCell. It's linked statically from
+//! the path in the `NRF_CC310_PLATFORM_LIB` environment variable (see `build.rs`).
+//!
+//! The secret is destroyed by revoking both key slots, which only a full chip erase undoes.
+
+use crate::flash::Flash;
+use core::sync::atomic::{AtomicBool, Ordering};
+use dis_bootloader_core::crypto::KEY_SIZE;
+use shared::chip;
+
+/// The first of the two KMU key slots with the secret. Nordic's libraries use the first slots for their own keys.
+const KEY_SLOT: u32 = 16;
+
+/// The address of the first KEYSLOT.CONFIG in the UICR. Every slot has a DEST and a PERM word.
+const KEYSLOT_CONFIG_ADDRESS: u32 = 0x00FF_8400;
+
+/// The AES key registers of the CryptoCell, where the two halves of the secret are pushed to
+const CRYPTOCELL_AES_KEY_ADDRESSES: [u32; 2] = [
+    chip::CRYPTOCELL_ADDRESS + 0x1400,
+    chip::CRYPTOCELL_ADDRESS + 0x1410,
+];
+
+/// The PERM of the key slots: no reading or writing, only pushing into the CryptoCell, and not revoked
+const KEY_PERMISSIONS: u32 = 0xFFFF_FFFC;
+
+/// The STATE bit of the PERM of a key slot, which is cleared when the slot is revoked
+const PERM_STATE_ACTIVE: u32 = 1 << 16;
+
+/// The ENABLE register of the CRYPTOCELL peripheral
+const CRYPTOCELL_ENABLE: *mut u32 = (chip::CRYPTOCELL_ADDRESS + 0x500) as *mut u32;
+
+/// The return value of the library calls that succeeded
+const NRF_CC3XX_PLATFORM_SUCCESS: i32 = 0;
+
+/// Set once the library is initialized
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    fn nrf_cc3xx_platform_init_no_rng() -> i32;
+    fn nrf_cc3xx_platform_kmu_write_key_slot(
+        slot_id: u32,
+        key_address: u32,
+        key_permissions: u32,
+        key: *const u8,
+    ) -> i32;
+    fn nrf_cc3xx_platform_kmu_shadow_key_derive(
+        slot_id: u32,
+        key_bits: u32,
+        label: *const u8,
+        label_size: usize,
+        context: *const u8,
+        context_size: usize,
+        output: *mut u8,
+        output_size: usize,
+    ) -> i32;
+}
+
+/// Runs the library call with the CryptoCell enabled and returns whether it succeeded
+fn with_cryptocell(call: impl FnOnce() -> i32) -> bool {
+    unsafe {
+        CRYPTOCELL_ENABLE.write_volatile(1);
+
+        let mut result = NRF_CC3XX_PLATFORM_SUCCESS;
+        if !INITIALIZED.load(Ordering::Relaxed) {
+            result = nrf_cc3xx_platform_init_no_rng();
+            INITIALIZED.store(result == NRF_CC3XX_PLATFORM_SUCCESS, Ordering::Relaxed);
+        }
+        if result == NRF_CC3XX_PLATFORM_SUCCESS {
+            result = call();
+        }
+
+        CRYPTOCELL_ENABLE.write_volatile(0);
+
+        result == NRF_CC3XX_PLATFORM_SUCCESS
+    }
+}
+
+/// The addresses of the DEST and the PERM word of the key slot in the UICR
+fn config_addresses(slot: u32) -> (u32, u32) {
+    let dest = KEYSLOT_CONFIG_ADDRESS + slot * 8;
+    (dest, dest + 4)
+}
+
+/// Returns true if both key slots have the secret and aren't revoked
+pub fn is_provisioned() -> bool {
+    (0..2).all(|half| {
+        let (dest, perm) = config_addresses(KEY_SLOT + half);
+        // The KMU config is read like any other UICR word, only the key values themselves are protected
+        let (dest, perm) = unsafe {
+            (
+                (dest as *const u32).read_volatile(),
+                (perm as *const u32).read_volatile(),
+            )
+        };
+        dest == CRYPTOCELL_AES_KEY_ADDRESSES[half as usize] && perm == KEY_PERMISSIONS
+    })
+}
+
+/// Writes the secret into the key slots.
+///
+/// Returns false if that failed, for example because the slots were already written by an earlier, interrupted
+/// provisioning. They can only be written again after a UICR erase.
+pub fn provision(secret: &[u8; KEY_SIZE]) -> bool {
+    (0..2).all(|half| {
+        let key = &secret[half * 16..half * 16 + 16];
+        with_cryptocell(|| unsafe {
+            nrf_cc3xx_platform_kmu_write_key_slot(
+                KEY_SLOT + half as u32,
+                CRYPTOCELL_AES_KEY_ADDRESSES[half],
+                KEY_PERMISSIONS,
+                key.as_ptr(),
+            )
+        })
+    })
+}
+
+/// Derives a key from the secret in the KMU with the CryptoCell.
+///
+/// This is the [KeyDerivation](dis_bootloader_core::crypto::KeyDerivation) of the bootloader. It derives nothing
+/// when the device isn't provisioned or the secret has been destroyed, because the empty or revoked key slots would
+/// give keys that anyone can derive.
+pub fn derive(label: &[u8], context: &[u8]) -> Option<[u8; KEY_SIZE]> {
+    if !is_provisioned() {
+        return None;
+    }
+
+    let mut key = [0; KEY_SIZE];
+    let derived = with_cryptocell(|| unsafe {
+        nrf_cc3xx_platform_kmu_shadow_key_derive(
+            KEY_SLOT,
+            KEY_SIZE as u32 * 8,
+            label.as_ptr(),
+            label.len(),
+            context.as_ptr(),
+            context.len(),
+            key.as_mut_ptr(),
+            key.len(),
+        )
+    });
+
+    derived.then_some(key)
+}
+
+/// Revokes both key slots, so the secret can never be used again
+pub fn destroy(flash: &mut Flash) {
+    for slot in KEY_SLOT..KEY_SLOT + 2 {
+        let (_, perm) = config_addresses(slot);
+        let value = flash.read_uicr_word(perm);
+        flash.write_uicr_word(perm, value & !PERM_STATE_ACTIVE);
+    }
+}
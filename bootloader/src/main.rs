@@ -1,385 +1,783 @@
 #![doc = include_str!("../../README.md")]
 #![no_main]
 #![no_std]
-#![feature(type_alias_impl_trait)]
 #![warn(missing_docs)]
 
-use crate::flash::Flash;
-use core::mem::MaybeUninit;
-use cortex_m::peripheral::SCB;
-use embassy_nrf::{
-    interrupt,
-    peripherals::UARTETWISPI0,
-    uarte::{self, Uarte},
+use crate::{
+    boards::BOARD,
+    flash::Flash,
+    hal::{Chip, Hal},
 };
-use panic_persist::get_panic_message_bytes;
+use arrayvec::ArrayVec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::peripheral::SCB;
+#[cfg(feature = "defmt")]
+use defmt_rtt as _;
+#[cfg(feature = "defmt")]
+use dis_bootloader_core::Level as LogLevel;
+use dis_bootloader_core::{events, uerror, uinfo, uprintln, uwarn, LogSink, VerificationPolicy};
+use embassy_nrf::gpio::{AnyPin, Level, Output, OutputDrive};
+#[cfg(feature = "key-revocation")]
+use shared::revocation::RevokeError;
+#[cfg(feature = "event-report")]
+use shared::state::SwapStatistics;
 use shared::{
-    flash_addresses::{
-        bootloader_flash_page_range, bootloader_flash_range, bootloader_scratch_page_range,
-        bootloader_scratch_range, bootloader_state_page_range, bootloader_state_range,
-        program_slot_a_page_range, program_slot_a_range, program_slot_b_page_range,
-        program_slot_b_range, PAGE_SIZE,
-    },
-    state::{BootloaderGoal, BootloaderState, PageState},
+    board_config::{UicrBoardConfig, BOARD_CONFIG_ADDRESS},
+    board_id,
+    boot_info::BootInfo,
+    build_info::BuildInfo,
+    config::{self, BootloaderConfig, DebuggerPolicy},
+    event_log::SecurityEvent,
+    hardware_revision::{self, HARDWARE_REVISION_ADDRESS},
+    mailbox::{self, Request},
+    panic_log,
+    state::{BootloaderGoal, BootloaderState, GoalChangeError},
 };
 
+#[cfg(all(feature = "chip-nrf52840", feature = "state-protection"))]
+mod acl;
+#[cfg(feature = "approtect")]
+mod approtect;
+mod boards;
+#[cfg(feature = "cryptocell")]
+mod cryptocell;
+#[cfg(feature = "test-swap")]
+mod deadline;
 mod flash;
-
-type Uart = Uarte<'static, UARTETWISPI0>;
-
-/// A counter that keeps track of how many panics there have been. It keeps its value across resets.
-#[link_section = ".uninit"]
-static mut PANIC_COUNTS: MaybeUninit<u32> = MaybeUninit::uninit();
-
-#[embassy_executor::main]
-async fn main(_spawner: embassy_executor::Spawner) {
+mod hal;
+#[cfg(feature = "hardware-huk")]
+mod huk;
+mod panic;
+mod power;
+#[cfg(feature = "provisioning")]
+mod provisioning;
+#[cfg(feature = "qspi-slot-b")]
+mod qspi;
+#[cfg(feature = "recovery")]
+mod recovery;
+mod reset_reason;
+#[cfg(feature = "secure-services")]
+mod secure_services;
+#[cfg(feature = "self-update")]
+mod self_update;
+#[cfg(any(feature = "recovery", feature = "shell"))]
+mod serial;
+#[cfg(feature = "shell")]
+mod shell;
+#[cfg(all(
+    feature = "chip-nrf9160",
+    any(feature = "non-secure", feature = "state-protection")
+))]
+mod spu;
+#[cfg(feature = "event-report")]
+mod stopwatch;
+#[cfg(any(feature = "fi-hardening", feature = "shell"))]
+mod trng;
+mod watchdog;
+
+// The secure services write the state while the application runs, which the state protection doesn't allow
+#[cfg(all(feature = "secure-services", feature = "state-protection"))]
+compile_error!("The secure-services and state-protection features can't be combined.");
+
+// The shell prints everything with the log macros, which the silent build compiles out
+#[cfg(all(feature = "shell", feature = "silent"))]
+compile_error!("The shell needs the log output, it can't be combined with silent.");
+
+// The nRF52840 has no TrustZone, so the application runs in the same state as the bootloader
+#[cfg(all(feature = "chip-nrf52840", feature = "non-secure"))]
+compile_error!("The non-secure feature needs the SPU of the nRF9160.");
+
+// The CryptoCell of the nRF52840 can't derive keys from a secret it can't read, that needs the KMU of the nRF9160
+#[cfg(all(feature = "chip-nrf52840", feature = "hardware-huk"))]
+compile_error!("The hardware-huk feature needs the KMU of the nRF9160.");
+
+// The nRF9160 has no QSPI peripheral
+#[cfg(all(feature = "chip-nrf9160", feature = "qspi-slot-b"))]
+compile_error!("The qspi-slot-b feature needs the QSPI peripheral of the nRF52840.");
+
+// An image on the QSPI flash can't be started in place, and stage 0 can't read a bootloader update from it
+#[cfg(all(
+    feature = "qspi-slot-b",
+    any(
+        feature = "direct-boot",
+        feature = "direct-xip",
+        feature = "self-update"
+    )
+))]
+compile_error!(
+    "Slot B on the QSPI flash can't be combined with direct-boot, direct-xip or self-update."
+);
+
+/// The UART the bootloader talks over
+type Uart = hal::Uart<<Chip as Hal>::UartPeripheral>;
+
+/// The info about this build of the bootloader.
+/// It's placed at the start of the descriptor block, so host tools and the application can read it from flash.
+#[used]
+#[link_section = ".bootloader_descriptor"]
+static BUILD_INFO: BuildInfo = include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
+
+/// Cleared when the UICR config turns off the console, which makes the uart drop all log output
+static CONSOLE_ENABLED: AtomicBool = AtomicBool::new(true);
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
     let device_peripherals = embassy_nrf::init(Default::default());
     let core_peripherals = cortex_m::Peripherals::take().unwrap();
-    // Rust analyzer doesn't like the embassy macro, so as a hack, just immediately go to another function without it
-    run_main(device_peripherals, core_peripherals).await;
-}
-
-/// A print macro that takes the uart and then the print expression like println!.
-#[macro_export]
-macro_rules! uprintln {
-    ($uart:expr, $($arg:tt)*) => {
-        {
-            use core::fmt::Write as _;
-            let mut str = arrayvec::ArrayString::<1024>::new();
-            match writeln!(str, $($arg)*) {
-                Ok(_) => {
-                    $uart.write(str.as_bytes()).await.unwrap();
-                },
-                Err(_) => $uart.write("Error: failed to print string, too long".as_bytes()).await.unwrap(),
-            };
-        }
-    };
+    run_main(device_peripherals, core_peripherals)
 }
 
-async fn run_main(
+fn run_main(
     device_peripherals: embassy_nrf::Peripherals,
     core_peripherals: cortex_m::Peripherals,
-) {
-    // Embassy doesn't give us a pac instance of the NVMC, so we need to make a reference ourselves
-    let mut flash = Flash {
-        registers: unsafe { &*embassy_nrf::pac::NVMC::PTR },
-    };
+) -> ! {
+    let mut flash = Chip::flash();
+
+    // Every boot starts with an empty boot info block, the events of this boot are added to it
+    shared::boot_info::begin();
 
-    // Configure the uart
-    let mut config = uarte::Config::default();
-    config.parity = uarte::Parity::EXCLUDED;
-    config.baudrate = uarte::Baudrate::BAUD115200;
-
-    let irq = interrupt::take!(UARTE0_SPIM0_SPIS0_TWIM0_TWIS0);
-
-    #[cfg(feature = "feather")]
-    let (uart_rx_pin, uart_tx_pin) = (device_peripherals.P0_05, device_peripherals.P0_06);
-    #[cfg(feature = "logistics")]
-    let (uart_rx_pin, uart_tx_pin) = (device_peripherals.P0_28, device_peripherals.P0_29);
-    #[cfg(feature = "mobility")]
-    let (uart_rx_pin, uart_tx_pin) = (device_peripherals.P0_28, device_peripherals.P0_29);
-    #[cfg(feature = "turing")]
-    let (uart_rx_pin, uart_tx_pin) = (device_peripherals.P0_30, device_peripherals.P0_19);
-    #[cfg(feature = "actinius_icarus")]
-    let (uart_rx_pin, uart_tx_pin) = (device_peripherals.P0_06, device_peripherals.P0_09);
-
-    let mut uart: Uart = uarte::Uarte::new(
-        device_peripherals.UARTETWISPI0,
-        irq,
-        uart_rx_pin,
-        uart_tx_pin,
-        config,
+    // The UICR config decides how this unit behaves, so production and development units can run the same binary
+    let config = BootloaderConfig(flash.read_uicr_word(config::CONFIG_ADDRESS));
+    CONSOLE_ENABLED.store(config.logging_enabled(), Ordering::Relaxed);
+    dis_bootloader_core::set_verification_policy(if config.strict_verification() {
+        VerificationPolicy::Strict
+    } else {
+        VerificationPolicy::Lenient
+    });
+    dis_bootloader_core::board_check::set_board_id(board_id::from_uicr_word(
+        flash.read_uicr_word(board_id::BOARD_ID_ADDRESS),
+    ));
+    dis_bootloader_core::revision_check::set_hardware_revision(hardware_revision::from_uicr_word(
+        flash.read_uicr_word(HARDWARE_REVISION_ADDRESS),
+    ));
+    // The UICR can override the pins and baud rate of the board feature, so one binary runs on all our boards
+    let board_config = UicrBoardConfig(flash.read_uicr_word(BOARD_CONFIG_ADDRESS));
+    let board = BOARD.with_uicr_config(board_config);
+    #[cfg(feature = "test-swap")]
+    dis_bootloader_core::health::set_failed_boot_threshold(config.failed_boot_threshold());
+    dis_bootloader_core::rollback::set_boot_attempt_threshold(config.boot_attempt_threshold());
+    #[cfg(feature = "secure-boot")]
+    dis_bootloader_core::secure_boot::set_public_key(include!(concat!(
+        env!("OUT_DIR"),
+        "/signing_public_key.rs"
+    )));
+    #[cfg(feature = "cryptocell")]
+    dis_bootloader_core::secure_boot::set_p256_accelerator(cryptocell::verify_p256);
+    #[cfg(feature = "hardware-huk")]
+    dis_bootloader_core::crypto::set_device_huk(huk::derive);
+    #[cfg(feature = "anti-rollback")]
+    dis_bootloader_core::anti_rollback::set_security_counter(
+        shared::security_counter::from_uicr_words(
+            shared::security_counter::word_addresses().map(|address| flash.read_uicr_word(address)),
+        ),
     );
+    // The flash trace is mirrored into its own partition, so it can be read out of the unit later
+    #[cfg(feature = "flash-trace-mirror")]
+    dis_bootloader_core::trace::set_mirror_region(Some(
+        shared::flash_addresses::bootloader_flash_trace_range(),
+    ));
+
+    // Configure the uart on the pins of the board
+    let mut uart: Uart = Chip::uart(device_peripherals, &board);
 
     // Show a sign of life and print the version
     uprintln!(
         uart,
-        "\n\n--== == == == == == == == == == == == == == ==--\nStarting bootloader version `{}` with git hash `{}`",
-        env!("CP_CARGO"),
-        env!("CP_GIT")
+        "\n\n--== == == == == == == == == == == == == == ==--\nStarting bootloader version `{}.{}.{}` with git hash `{}`",
+        BUILD_INFO.version_major,
+        BUILD_INFO.version_minor,
+        BUILD_INFO.version_patch,
+        BUILD_INFO.git_hash()
+    );
+    uprintln!(
+        uart,
+        "Built at {} (unix time) with features {:#010X}",
+        BUILD_INFO.timestamp,
+        BUILD_INFO.features
+    );
+    uprintln!(
+        uart,
+        "Running on board `{}` with an {}",
+        board.name,
+        shared::chip::NAME
+    );
+    uprintln!(
+        uart,
+        "The flash has {} pages of {} bytes",
+        flash.geometry.page_count(),
+        flash.geometry.page_size
     );
+    if !flash.geometry.fits_layout() {
+        uprintln!(
+            uart,
+            "The memory layout doesn't fit this flash, the images won't be swapped"
+        );
+    }
+    uprintln!(uart, "Using UICR config {:?}", config);
+    if board_config != UicrBoardConfig::DEFAULT {
+        uprintln!(
+            uart,
+            "Using UICR board config {:?}, which gives {:?}",
+            board_config,
+            board
+        );
+    }
+
+    // The random delays around the verification make it hard to time a glitch
+    #[cfg(feature = "fi-hardening")]
+    match trng::random_u32() {
+        Some(entropy) => dis_bootloader_core::hardening::seed(entropy),
+        None => uprintln!(
+            uart,
+            "The TRNG didn't produce anything, the delays are not random"
+        ),
+    }
 
-    // Get how many panics we've gotten
-    let panics = unsafe { PANIC_COUNTS.assume_init_mut() };
-    if *panics > 10 {
-        // Probably random garbage from ram, so we've probably just booted
-        *panics = 0;
+    // Light up the LEDs to show the bootloader is running
+    let leds = board
+        .leds
+        .iter()
+        .map(|pin| {
+            Output::new(
+                unsafe { AnyPin::steal(*pin) },
+                Level::High,
+                OutputDrive::Standard,
+            )
+        })
+        .collect::<ArrayVec<_, 4>>();
+
+    // The reset reasons of the last boots are kept in the state, so the application can spot a watchdog loop
+    #[cfg(feature = "reset-history")]
+    {
+        let reset_reason = Chip::take_reset_reason();
+        uprintln!(uart, "Reset reason: {:#010X}", reset_reason);
+
+        let mut state = BootloaderState::load(&flash);
+        if state.is_valid() {
+            state.push_reset_reason(reset_reason);
+            if let Err(error) = state.store(&mut flash) {
+                uwarn!(uart, "Could not store the reset reason: {:?}", error);
+            }
+        }
     }
 
-    // Check if there was a panic message, if so, send to UART
-    if let Some(msg) = get_panic_message_bytes() {
-        uprintln!(uart, "Booted up from a panic:");
-        uart.write(msg).await.unwrap();
-        *panics += 1;
+    // The panics are counted in the state, so the count survives a power loss
+    let mut panics = BootloaderState::load(&flash).panic_count();
+
+    // Report the panics in the panic log that weren't reported yet. They stay in the log for the application.
+    let mut reported_panics = ArrayVec::<u32, { panic_log::RECORDS }>::new();
+    for record in panic_log::records(&flash).filter(|record| !record.reported) {
+        uprintln!(
+            uart,
+            "Booted up from a panic, with reset reason {:#010X}:",
+            record.reset_reason
+        );
+        // The panic message itself is only printed when logging is enabled.
+        // It's in flash, so it's copied into RAM for the DMA of the uart.
+        #[cfg(all(feature = "logging", not(feature = "silent")))]
+        {
+            let mut message = [0; panic_log::MAX_MESSAGE_LENGTH];
+            let message = &mut message[..record.message.len()];
+            message.copy_from_slice(record.message);
+            uart.write_bytes(message);
+        }
+        panics += 1;
         uprintln!(uart, "");
+        reported_panics.push(record.address);
+    }
+    if !reported_panics.is_empty() {
+        store_panic_count(&mut flash, &mut uart, panics);
+    }
+    for record_address in reported_panics {
+        if let Err(error) = panic_log::mark_reported(&mut flash, record_address) {
+            uwarn!(uart, "Could not mark the panic as reported: {:?}", error);
+        }
     }
 
     uprintln!(uart, "There have been {} panics so far.", panics);
 
     // If there are too many panics, let's just sleep and potentially save the flash memory
-    if *panics > 10 {
-        uprintln!(uart, "There have been too many panics. Bootloader will try to save the flash by going to sleep. The device can be woken up by sending a single byte over serial. The panics counter will then be reset to 0 so you can see all the output again");
+    if panics > 10 {
+        uwarn!(uart, "There have been too many panics. Bootloader will try to save the flash by going to sleep. The device can be woken up by sending a single byte over serial. The panics counter will then be reset to 0 so you can see all the output again");
         let mut buffer = [0; 1];
-        uart.read(&mut buffer).await.unwrap();
-        *panics = 0;
+        uart.blocking_read(&mut buffer).unwrap();
+        store_panic_count(&mut flash, &mut uart, 0);
     }
 
-    // Print the memory regions we're using, just for convenience
-    uprintln!(uart, "\nDefined memory regions:");
-    uprintln!(
-        uart,
-        "\tbootloader flash:   {:08X?} ({:03?})",
-        bootloader_flash_range(),
-        bootloader_flash_page_range()
-    );
-    uprintln!(
-        uart,
-        "\tbootloader scratch: {:08X?} ({:03?})",
-        bootloader_scratch_range(),
-        bootloader_scratch_page_range()
-    );
-    uprintln!(
-        uart,
-        "\tbootloader state:   {:08X?} ({:03?})",
-        bootloader_state_range(),
-        bootloader_state_page_range()
-    );
-    uprintln!(
-        uart,
-        "\tprogram slot a:     {:08X?} ({:03?})",
-        program_slot_a_range(),
-        program_slot_a_page_range()
-    );
-    uprintln!(
-        uart,
-        "\tprogram slot b:     {:08X?} ({:03?})",
-        program_slot_b_range(),
-        program_slot_b_page_range()
-    );
-
-    // Let's check what we need to do by loading the state
-    let mut state = BootloaderState::load(&flash);
+    // The events may say more than the owner of the device should know, so they are encrypted once there is a key
+    #[cfg(feature = "encrypted-logs")]
+    match provisioning::device_id(&flash) {
+        Some(device_id) => events::set_event_log_encryption(device_id),
+        None => uprintln!(
+            uart,
+            "The device is not provisioned, events are logged in plain text"
+        ),
+    }
 
-    let scb = core_peripherals.SCB;
+    // A debugger on a production device means someone is probing it, so the UICR config decides what happens
+    if cortex_m::peripheral::DCB::is_debugger_attached() {
+        handle_debugger(&mut flash, &mut uart, config.debugger_policy());
+    }
 
-    // The state must be valid or we will just jump to the application
-    if !state.is_valid() {
-        uprintln!(uart, "State is invalid, jumping to application");
-        jump_to_application(uart, scb).await;
+    // Production devices must never be left open for the debugger
+    #[cfg(feature = "approtect")]
+    if approtect::enforce(&mut flash) {
+        events::record(
+            &mut flash,
+            &mut uart,
+            SecurityEvent::AccessPortProtectionRestored,
+            0,
+        )
+        .ok();
+        uprintln!(
+            uart,
+            "Access port protection was found disabled and has been enabled again, resetting to apply it"
+        );
+        SCB::sys_reset();
     }
 
-    let goal = state.goal();
-    uprintln!(uart, "Goal: {:?}", goal);
+    // A new device first needs to get its identity
+    #[cfg(feature = "provisioning")]
+    provisioning::provision(&mut flash, &mut uart);
+
+    // The recovery button forces the recovery, or the shell in a bootloader without the recovery
+    #[cfg(any(feature = "recovery", feature = "shell"))]
+    let button_is_held = board.recovery_button_is_held();
+    #[cfg(feature = "recovery")]
+    let button_enters_recovery = button_is_held && config.recovery_enabled();
+    #[cfg(all(feature = "shell", not(feature = "recovery")))]
+    let button_enters_recovery = false;
+
+    // A technician can stop the boot to look around
+    #[cfg(feature = "shell")]
+    if shell::is_requested(&config, button_is_held && !button_enters_recovery) {
+        shell::run(&mut flash, &mut uart, &config);
+    }
 
-    match goal {
-        BootloaderGoal::JumpToApplication => {
-            jump_to_application(uart, scb).await;
+    // The application may have left a request in the mailbox
+    let request = mailbox::take_request();
+
+    // Without a request, the hint of the application tells us there's no need to look at the state
+    #[cfg(feature = "fast-wake")]
+    let nothing_pending = mailbox::take_nothing_pending_hint() && request.is_none();
+    #[cfg(not(feature = "fast-wake"))]
+    let nothing_pending = false;
+
+    match request {
+        Some(Request::Goal { expected, goal }) => {
+            uprintln!(uart, "Got a request for goal {:?} from the mailbox", goal);
+            match BootloaderState::compare_and_set_goal(&mut flash, expected, goal) {
+                Ok(()) => {}
+                Err(GoalChangeError::Mismatch(mismatch)) => {
+                    uprintln!(
+                        uart,
+                        "Dropped the request, the goal is {:?} instead of {:?}",
+                        mismatch.current,
+                        expected
+                    );
+                }
+                Err(GoalChangeError::Flash(error)) => {
+                    uwarn!(uart, "Could not store the requested goal: {:?}", error);
+                }
+            }
         }
-        BootloaderGoal::StartSwap => {
-            state.prepare_swap(false, &mut flash); // TODO: think about reset here
-            perform_swap(false, &mut state, &mut flash, &mut uart).await;
-            jump_to_application(uart, scb).await;
+        #[cfg(feature = "rma-wipe")]
+        Some(Request::Wipe { token }) => {
+            if provisioning::wipe_token_is_valid(&flash, &token) {
+                uprintln!(uart, "Got an authenticated wipe request from the mailbox");
+                // A pending swap or update is finished first, the application can request the wipe again after it
+                match BootloaderState::compare_and_set_goal(
+                    &mut flash,
+                    BootloaderGoal::JumpToApplication,
+                    BootloaderGoal::Wipe,
+                ) {
+                    Ok(()) => {
+                        events::record(&mut flash, &mut uart, SecurityEvent::WipeStarted, 0).ok();
+                    }
+                    Err(GoalChangeError::Mismatch(mismatch)) => {
+                        uprintln!(
+                            uart,
+                            "Postponed the wipe, the goal {:?} is pending",
+                            mismatch.current
+                        );
+                    }
+                    Err(GoalChangeError::Flash(error)) => {
+                        uwarn!(uart, "Could not store the wipe goal: {:?}", error);
+                    }
+                }
+            } else {
+                uwarn!(uart, "Rejected a wipe request with an invalid token");
+                events::record(&mut flash, &mut uart, SecurityEvent::WipeTokenRejected, 0).ok();
+            }
         }
-        BootloaderGoal::FinishSwap => {
-            perform_swap(false, &mut state, &mut flash, &mut uart).await;
-            jump_to_application(uart, scb).await;
+        #[cfg(not(feature = "rma-wipe"))]
+        Some(Request::Wipe { .. }) => {
+            uwarn!(uart, "Rejected a wipe request, wipes are not supported");
         }
-        BootloaderGoal::StartTestSwap => {
-            state.prepare_swap(true, &mut flash);
-            perform_swap(true, &mut state, &mut flash, &mut uart).await;
-            jump_to_application(uart, scb).await;
+        #[cfg(feature = "key-revocation")]
+        Some(Request::RevokeKey { key_id, token }) => {
+            if !provisioning::revocation_token_is_valid(&flash, key_id, &token) {
+                uwarn!(
+                    uart,
+                    "Rejected a revocation request for key {} with an invalid token",
+                    key_id
+                );
+                events::record(
+                    &mut flash,
+                    &mut uart,
+                    SecurityEvent::RevocationTokenRejected,
+                    key_id,
+                )
+                .ok();
+            } else {
+                match shared::revocation::revoke(&mut flash, key_id) {
+                    Ok(()) => {
+                        uprintln!(uart, "Revoked signing key {}", key_id);
+                        events::record(&mut flash, &mut uart, SecurityEvent::KeyRevoked, key_id)
+                            .ok();
+                    }
+                    Err(RevokeError::KeyIdOutOfRange) => {
+                        uprintln!(
+                            uart,
+                            "Key {} can't be revoked, its ID is out of range",
+                            key_id
+                        );
+                    }
+                    Err(RevokeError::Flash(error)) => {
+                        uwarn!(uart, "Could not revoke key {}: {:?}", key_id, error);
+                    }
+                }
+            }
         }
-        BootloaderGoal::FinishTestSwap => {
-            perform_swap(true, &mut state, &mut flash, &mut uart).await;
-            jump_to_application(uart, scb).await;
+        #[cfg(not(feature = "key-revocation"))]
+        Some(Request::RevokeKey { key_id, .. }) => {
+            uwarn!(
+                uart,
+                "Rejected a revocation request for key {}, revocations are not supported",
+                key_id
+            );
         }
+        None => {}
     }
 
-    loop {}
-}
+    // The application of the previous boot tells us it reached its main loop with the handshake
+    #[cfg(feature = "test-swap")]
+    dis_bootloader_core::health::set_handshake_completed(mailbox::take_handshake());
 
-/// Actually performs the swapping procedure.
-///
-/// If the state has been prepared for a swap, all pages will be swapped.
-/// If not, then it will resume a previous swap.
-async fn perform_swap(
-    test_swap: bool,
-    state: &mut BootloaderState,
-    flash: &mut impl shared::Flash,
-    uart: &mut Uart,
-) {
-    // Gather info about our memory layout
-    let total_program_pages = program_slot_a_page_range().len() as u32;
-    let total_scratch_pages = bootloader_scratch_page_range().len() as u32;
-
-    uprintln!(uart, "total_program_pages: {}", total_program_pages);
-    uprintln!(uart, "total_scratch_pages: {}", total_scratch_pages);
-
-    // We're doing a round-robin for scratch page usage, so we need to keep track of the used index
-    let mut scratch_page_index = 0;
-
-    // We need to swap every page
-    for page in 0..total_program_pages {
-        // Get the addresses of the A and B page slot
-        let slot_a_page = program_slot_a_page_range().start + page;
-        let slot_a_address = slot_a_page * PAGE_SIZE;
-        let slot_b_page = program_slot_b_page_range().start + page;
-        let slot_b_address = slot_b_page * PAGE_SIZE;
-
-        // We run a small statemachine that needs to continue until the page is swapped.
-        // If we resume a swap due to a reset, then it is possible that a lot of pages have already been swapped
-        while !state.get_page_state(page).is_swapped() {
+    // Stage 0 has installed a bootloader update, if there was a valid one
+    #[cfg(feature = "self-update")]
+    if !nothing_pending {
+        let state = BootloaderState::load(&flash);
+        if state.is_valid() && state.goal() == BootloaderGoal::UpdateBootloader {
+            self_update::finish(&mut flash, &mut uart);
+        }
+    }
+
+    // The secrets are wiped first. The core wipes the rest and erases the state as the very last step.
+    #[cfg(feature = "rma-wipe")]
+    if !nothing_pending {
+        let state = BootloaderState::load(&flash);
+        if state.is_valid() && state.goal() == BootloaderGoal::Wipe {
+            uprintln!(uart, "Erasing the device secret");
+            provisioning::erase_secret(&mut flash);
+        }
+    }
+
+    // A test-swapped image that didn't make its deadline is swapped back by the core now
+    #[cfg(feature = "test-swap")]
+    if deadline::take_watchdog_reset() {
+        let mut state = BootloaderState::load(&flash);
+        if state.is_valid() && state.goal() == BootloaderGoal::StartSwap {
             uprintln!(
                 uart,
-                "Swapping page {}: {:?}",
-                page,
-                state.get_page_state(page)
+                "The test image wasn't confirmed before its deadline, reverting it"
             );
-            // Depending on the state, we need to swap certain pages
-            match state.get_page_state(page) {
-                PageState::Original => {
-                    // We need to copy the A page to a scratch page
+            events::record(&mut flash, &mut uart, SecurityEvent::RollbackTriggered, 0).ok();
+            // Even when it completed the handshake
+            state.set_failed_test_boots(None);
+            if let Err(error) = state.store(&mut flash) {
+                uwarn!(uart, "Could not store the state: {:?}", error);
+            }
+        }
+    }
 
-                    // Decide which scratch page to use
-                    let scratch_page = bootloader_scratch_page_range().start + scratch_page_index;
-                    let scratch_address = scratch_page * PAGE_SIZE;
+    // A device without a working application can get a new one over the UART
+    #[cfg(feature = "recovery")]
+    if let Some(trigger) = recovery::trigger(&flash, &mut uart, &config, button_enters_recovery) {
+        recovery::run(&mut flash, &mut uart, &config, trigger);
+    }
 
-                    uprintln!(
-                        uart,
-                        "Moving page @{:#010X} to page {:#010X}",
-                        slot_a_address,
-                        scratch_address
-                    );
+    // A hang during a swap would leave the device dead, so the watchdog runs from here on and into the application.
+    // The interactive parts above wait for a person, so they come before it.
+    #[cfg(feature = "watchdog")]
+    {
+        let timeout_ms = include!(concat!(env!("OUT_DIR"), "/watchdog_timeout_ms.rs"));
+        uprintln!(
+            uart,
+            "Starting the watchdog with a timeout of {} ms",
+            timeout_ms
+        );
+        Chip::start_watchdog(timeout_ms);
+        dis_bootloader_core::watchdog::set_feeder(Chip::feed_watchdog);
+    }
 
-                    // Erase the scratch area
-                    flash.erase_page(scratch_address);
-                    // Program the data from slot A into the scratch slot
-                    flash.program_page(scratch_address, unsafe {
-                        core::slice::from_raw_parts(
-                            slot_a_address as *const u32,
-                            PAGE_SIZE as usize / core::mem::size_of::<u32>(),
-                        )
-                    });
-                    // Update the state
-                    state.set_page_state(page, PageState::InScratch { scratch_page });
-                    state.burn_store(flash);
-                }
-                PageState::InScratch { scratch_page } => {
-                    // We need to copy the B page to the A slot
+    // A page that loses power while it's written is torn, so the flash driver waits for a healthy supply from here on
+    #[cfg(feature = "brownout")]
+    {
+        uprintln!(
+            uart,
+            "Enabling the brown-out detection at {} mV",
+            power::THRESHOLD_MV
+        );
+        power::enable_brownout_detection();
+    }
 
-                    uprintln!(
-                        uart,
-                        "Moving page @{:#010X} to page {:#010X}",
-                        slot_b_address,
-                        slot_a_address
-                    );
+    // Run the actual bootloader logic, which gives us the application to jump to
+    let application_address = if nothing_pending {
+        uprintln!(
+            uart,
+            "The application left a nothing pending hint, skipping the state"
+        );
+        dis_bootloader_core::find_application_address(&mut flash, &mut uart)
+    } else {
+        #[cfg(feature = "event-report")]
+        stopwatch::start();
+        let application_address = match dis_bootloader_core::run(&mut flash, &mut uart) {
+            Ok(application_address) => application_address,
+            Err(error) => {
+                // The state still has the goal to finish what was interrupted, so it's resumed after the reset
+                uerror!(uart, "A flash operation failed: {:?}, resetting", error);
+                SCB::sys_reset();
+            }
+        };
+        #[cfg(feature = "event-report")]
+        report_swap_statistics(&mut flash, stopwatch::stop());
+        application_address
+    };
 
-                    // Erase the A page
-                    flash.erase_page(slot_a_address);
-                    // Program the data from slot B into the A slot
-                    flash.program_page(slot_a_address, unsafe {
-                        core::slice::from_raw_parts(
-                            slot_b_address as *const u32,
-                            PAGE_SIZE as usize / core::mem::size_of::<u32>(),
-                        )
-                    });
-                    // Update the state
-                    state.set_page_state(page, PageState::InScratchOverwritten { scratch_page });
-                    state.burn_store(flash);
-                }
-                PageState::InScratchOverwritten { scratch_page } => {
-                    // We need to copy the scratch page to the B slot
+    // Whatever the core decided, only ever start code in a slot that may be booted
+    assert!(
+        dis_bootloader_core::is_valid_bootload_target(&flash, application_address),
+        "Refusing to jump to {:#010X}, it's not a vector table in a bootable slot",
+        application_address
+    );
 
-                    let scratch_address = scratch_page * PAGE_SIZE;
+    // A confirmed image raises the security counter, after which older images are refused
+    #[cfg(feature = "anti-rollback")]
+    if let Some(value) = dis_bootloader_core::anti_rollback::counter_to_store(&flash) {
+        advance_security_counter(&mut flash, &mut uart, value);
+    }
 
-                    uprintln!(
-                        uart,
-                        "Moving page @{:#010X} to page {:#010X}",
-                        scratch_address,
-                        slot_b_address
-                    );
+    // Tell the application exactly what it's running on, so it can prove that to the attestation backend
+    #[cfg(feature = "measured-boot")]
+    dis_bootloader_core::measurement::measure(&flash).store();
+
+    // After a test swap, the goal stays at swapping back until the new image confirms itself.
+    // The watchdog can only be started once, so with the watchdog feature its own timeout is the deadline.
+    #[cfg(all(feature = "test-swap", not(feature = "watchdog")))]
+    if let Some(minutes) = config
+        .confirmation_deadline_minutes()
+        .filter(|_| !nothing_pending)
+    {
+        let state = BootloaderState::load(&flash);
+        if state.is_valid() && state.goal() == BootloaderGoal::StartSwap {
+            uprintln!(
+                uart,
+                "The test image must be confirmed within {} minutes",
+                minutes
+            );
+            deadline::arm(minutes);
+        }
+    }
 
-                    // Erase the B page
-                    flash.erase_page(slot_b_address);
-                    // Program the data from the scratch slot into the B slot
-                    flash.program_page(slot_b_address, unsafe {
-                        core::slice::from_raw_parts(
-                            scratch_address as *const u32,
-                            PAGE_SIZE as usize / core::mem::size_of::<u32>(),
-                        )
-                    });
-                    // Update the state
-                    state.set_page_state(page, PageState::Swapped);
-
-                    state.burn_store(flash);
-                }
-                PageState::Swapped => {
-                    // We're done and shouldn't be able to get here
-                    unreachable!()
-                }
+    // The application may only skip the state at the next boot if nothing is pending now
+    #[cfg(feature = "fast-wake")]
+    mailbox::allow_nothing_pending_hint(
+        nothing_pending || {
+            let state = BootloaderState::load(&flash);
+            !state.is_valid() || state.goal() == BootloaderGoal::JumpToApplication
+        },
+    );
+
+    // Tell the application what we did
+    let report = dis_bootloader_core::report::current(application_address);
+    shared::boot_info::set_boot_info(&BootInfo {
+        bootloader_version: [
+            BUILD_INFO.version_major,
+            BUILD_INFO.version_minor,
+            BUILD_INFO.version_patch,
+        ],
+        git_hash: BUILD_INFO.git_hash,
+        application_address,
+        goal: report.goal,
+        swapped_pages: report.swapped_pages,
+        reset_reason: Chip::reset_reason(),
+        boot_attempts: BootloaderState::load(&flash).boot_attempts(),
+    });
+
+    // One line for the test fixtures, with everything the core did
+    dis_bootloader_core::report::emit(&mut uart, application_address);
+
+    // The LEDs go back to their reset state before we leave
+    drop(leds);
+
+    jump_to_application(uart, core_peripherals.SCB, application_address)
+}
+
+/// Applies the debugger policy of the UICR config when a debugger is attached
+fn handle_debugger(flash: &mut Flash, uart: &mut Uart, policy: DebuggerPolicy) {
+    uprintln!(
+        uart,
+        "A debugger is attached, the debugger policy is {:?}",
+        policy
+    );
+
+    match policy {
+        // Development units are debugged all the time, so this isn't even worth an event
+        DebuggerPolicy::Ignore => {}
+        DebuggerPolicy::ZeroizeSecrets => {
+            events::record(flash, uart, SecurityEvent::DebuggerDetected, 0).ok();
+            #[cfg(feature = "provisioning")]
+            provisioning::erase_secret(flash);
+        }
+        DebuggerPolicy::RefuseBoot => {
+            events::record(flash, uart, SecurityEvent::DebuggerDetected, 1).ok();
+            loop {
+                cortex_m::asm::wfi();
             }
         }
+    }
+}
 
-        // Go to the next scratch page or start over if we were on the last one
-        scratch_page_index = (scratch_page_index + 1) % total_scratch_pages;
+/// Programs the security counter in the UICR up to the given value
+#[cfg(feature = "anti-rollback")]
+fn advance_security_counter(flash: &mut Flash, uart: &mut Uart, value: u32) {
+    uprintln!(uart, "Advancing the security counter to {}", value);
+
+    for (address, word) in
+        shared::security_counter::word_addresses().zip(shared::security_counter::uicr_words(value))
+    {
+        // Words that already have these bits cleared are left alone
+        let current = flash.read_uicr_word(address);
+        if current & word != current {
+            flash.write_uicr_word(address, current & word);
+        }
     }
 
-    // We're done, so we should change the state
-    if test_swap {
-        state.set_goal(BootloaderGoal::StartSwap);
-    } else {
-        state.set_goal(BootloaderGoal::JumpToApplication);
+    dis_bootloader_core::anti_rollback::set_security_counter(value);
+}
+
+/// Stores the statistics of the swap that the core just did, if any, and leaves the statistics of the most recent
+/// swap in the boot info block
+#[cfg(feature = "event-report")]
+fn report_swap_statistics(flash: &mut Flash, duration_ms: u32) {
+    let report = dis_bootloader_core::report::current(0);
+    let mut state = BootloaderState::load(flash);
+
+    if report.swapped_pages > 0 {
+        state.set_last_swap_statistics(SwapStatistics {
+            pages_copied: report.swapped_pages,
+            pages_skipped: report.skipped_pages,
+            erases: report.erases,
+            duration_ms,
+        });
+        state.store(flash).ok();
     }
 
-    // We've changed the goal, so we need to store that
-    state.store(flash);
+    if let Some(statistics) = state.last_swap_statistics() {
+        shared::boot_info::set_swap_statistics(statistics);
+    }
 }
 
-/// Jump to the application if the application vector table can be found
-async fn jump_to_application(mut uart: Uart, scb: SCB) -> ! {
-    // The application may not be stationed at the start of its slot.
-    // We need to search for it first.
-    // We will bootload to the first non-erased & non-padding (0xFFFF_FFFF, 0x0000_0000) word if the word after it could be a pointer to a reset vector inside the program_slot_a_range.
-    // (The first word of the vector table is the initial stack pointer)
-    let mut application_address = None;
-
-    let mut found_init_stack_pointer = false;
-
-    for possible_address in program_slot_a_range().step_by(4) {
-        // We can read this address safely because it will always be in flash
-        let address_value = unsafe { (possible_address as *const u32).read_volatile() };
-
-        match address_value {
-            0xFFFF_FFFF => continue,
-            0x0000_0000 => continue,
-            _ if (0x2000_0000..0x2004_0000).contains(&address_value)
-                && !found_init_stack_pointer =>
-            {
-                application_address = Some(possible_address);
-                found_init_stack_pointer = true;
-            }
-            _ if program_slot_a_range().contains(&address_value) && found_init_stack_pointer => {
-                break;
-            }
-            _ => {
-                application_address = None;
-                break;
-            }
+/// Stores the number of panics in the state. Without a valid state, the panics aren't counted.
+fn store_panic_count(flash: &mut Flash, uart: &mut Uart, panics: u32) {
+    let mut state = BootloaderState::load(flash);
+    if !state.is_valid() {
+        return;
+    }
+
+    state.set_panic_count(panics);
+    if let Err(error) = state.store(flash) {
+        uwarn!(uart, "Could not store the panic count: {:?}", error);
+    }
+}
+
+/// The log of the bootloader, which goes over the UART or with the `defmt` feature over RTT.
+/// The recovery and the provisioning still talk to the host over the UART itself.
+impl LogSink for Uart {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        if !CONSOLE_ENABLED.load(Ordering::Relaxed) {
+            return;
         }
+        #[cfg(not(feature = "defmt"))]
+        self.blocking_write(bytes).unwrap();
+        #[cfg(feature = "defmt")]
+        defmt::info!("{=[u8]:a}", bytes);
     }
 
-    match application_address {
-        Some(application_address) => {
-            uprintln!(uart, "Jumping to {:#08X}", application_address);
+    /// Every line is a single defmt message, instead of the chunks of the default implementation
+    #[cfg(feature = "defmt")]
+    fn write_line(&mut self, args: core::fmt::Arguments) {
+        if CONSOLE_ENABLED.load(Ordering::Relaxed) {
+            defmt::info!("{}", defmt::Display2Format(&args));
+        }
+    }
 
-            // We need to disable all used peripherals
-            drop(uart);
-            unsafe {
-                scb.vtor.write(application_address);
-                cortex_m::asm::bootload(application_address as *const u32)
-            }
+    /// The levels of the messages are those of defmt, instead of a prefix
+    #[cfg(feature = "defmt")]
+    fn log(&mut self, level: LogLevel, args: core::fmt::Arguments) {
+        if !CONSOLE_ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let message = defmt::Display2Format(&args);
+        match level {
+            LogLevel::Error => defmt::error!("{}", message),
+            LogLevel::Warn => defmt::warn!("{}", message),
+            LogLevel::Info => defmt::info!("{}", message),
+            LogLevel::Debug => defmt::debug!("{}", message),
         }
-        None => panic!("Could not find a reset vector in the firmware"),
+    }
+
+    #[cfg(feature = "event-report")]
+    fn security_event(&mut self, event: SecurityEvent, detail: u32) {
+        uprintln!(
+            self,
+            "Security event {:?} with detail {:#010X}",
+            event,
+            detail
+        );
+        // Let the application forward the event to the backend
+        shared::boot_info::push_event(event, detail);
+    }
+}
+
+/// Jump to the application at the given vector table address
+fn jump_to_application(mut uart: Uart, scb: SCB, application_address: u32) -> ! {
+    uinfo!(uart, "Jumping to {:#08X}", application_address);
+
+    // We need to disable all used peripherals
+    drop(uart);
+
+    // From here on, the state can only be changed at the next boot
+    #[cfg(all(feature = "chip-nrf9160", feature = "state-protection"))]
+    spu::protect_state(unsafe { &*nrf9160_pac::SPU_S::PTR });
+    #[cfg(all(feature = "chip-nrf52840", feature = "state-protection"))]
+    acl::protect_state();
+
+    #[cfg(not(feature = "non-secure"))]
+    unsafe {
+        scb.vtor.write(application_address);
+        cortex_m::asm::bootload(application_address as *const u32)
+    }
+
+    #[cfg(feature = "non-secure")]
+    unsafe {
+        // The application gets its own vector table in the non-secure world, the secure one stays ours
+        drop(scb);
+        spu::configure(&*nrf9160_pac::SPU_S::PTR);
+        #[cfg(feature = "secure-services")]
+        spu::enable_secure_services(&*nrf9160_pac::SPU_S::PTR);
+        spu::jump(application_address)
     }
 }
 
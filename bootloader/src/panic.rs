@@ -0,0 +1,57 @@
+//! The panic handler, which writes the message into the panic log in flash and resets the device
+//!
+//! The message is reported at the next boot. See [shared::panic_log].
+
+use crate::hal::{Chip, Hal};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use cortex_m::peripheral::SCB;
+
+/// Set when the handler starts, so a panic while the log is written doesn't try again
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Formats the message into a buffer of the size of a record, cutting off what doesn't fit
+struct MessageBuffer {
+    bytes: [u8; shared::panic_log::MAX_MESSAGE_LENGTH],
+    length: usize,
+}
+
+impl Write for MessageBuffer {
+    fn write_str(&mut self, text: &str) -> core::fmt::Result {
+        let free = &mut self.bytes[self.length..];
+        let length = text.len().min(free.len());
+        free[..length].copy_from_slice(&text.as_bytes()[..length]);
+        self.length += length;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        let mut message = MessageBuffer {
+            bytes: [0; shared::panic_log::MAX_MESSAGE_LENGTH],
+            length: 0,
+        };
+        write!(message, "{}", info).ok();
+
+        // Over RTT, the panic can be seen right away instead of at the next boot
+        #[cfg(feature = "defmt")]
+        defmt::error!("{=[u8]:a}", &message.bytes[..message.length]);
+
+        let mut flash = Chip::flash();
+        // There is nothing left to do if this fails, the reset must happen anyway
+        shared::panic_log::append(
+            &mut flash,
+            Chip::reset_reason(),
+            &message.bytes[..message.length],
+        )
+        .ok();
+    }
+
+    SCB::sys_reset()
+}
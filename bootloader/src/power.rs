@@ -0,0 +1,134 @@
+//! Brown-out awareness with the power-fail comparator
+//!
+//! A page that loses power halfway through an erase or program is torn, and a dying battery tends to do exactly that
+//! in the middle of a swap. With the comparator enabled, [wait_for_supply] is called by the flash driver before every
+//! erase and program. When the supply dropped below [THRESHOLD_MV], it parks the CPU until the supply is back above
+//! it, so the swap continues on a recovered supply or the device browns out between two flash operations, which the
+//! swap can resume from.
+//!
+//! The comparator only warns when the supply falls through the threshold, so while the supply is low it's sampled
+//! again by restarting the comparator, which warns right away when the supply is already below the threshold.
+
+use crate::flash::feed_watchdog;
+use shared::chip;
+
+/// The POFCON register
+const POFCON: *mut u32 = chip::POFCON_ADDRESS as *mut u32;
+/// The EVENTS_POFWARN register of the POWER peripheral
+const EVENTS_POFWARN: *mut u32 = (chip::POWER_ADDRESS + 0x108) as *mut u32;
+
+/// The bit of POFCON that enables the comparator
+const POFCON_ENABLE: u32 = 1 << 0;
+/// The supply voltage under which no flash operation is started
+pub const THRESHOLD_MV: u32 = 2800;
+/// The THRESHOLD field of POFCON for [THRESHOLD_MV], in steps of 100 mV from 1.7 V at 4
+const POFCON_THRESHOLD: u32 = ((THRESHOLD_MV / 100 - 13) & 0xF) << 1;
+
+/// The TASKS_START register of RTC1
+const RTC_TASKS_START: *mut u32 = chip::RTC1_ADDRESS as *mut u32;
+/// The TASKS_STOP register of RTC1
+const RTC_TASKS_STOP: *mut u32 = (chip::RTC1_ADDRESS + 0x4) as *mut u32;
+/// The TASKS_CLEAR register of RTC1
+const RTC_TASKS_CLEAR: *mut u32 = (chip::RTC1_ADDRESS + 0x8) as *mut u32;
+/// The EVENTS_COMPARE[0] register of RTC1
+const RTC_EVENTS_COMPARE: *mut u32 = (chip::RTC1_ADDRESS + 0x140) as *mut u32;
+/// The INTENSET register of RTC1
+const RTC_INTENSET: *mut u32 = (chip::RTC1_ADDRESS + 0x304) as *mut u32;
+/// The INTENCLR register of RTC1
+const RTC_INTENCLR: *mut u32 = (chip::RTC1_ADDRESS + 0x308) as *mut u32;
+/// The PRESCALER register of RTC1
+const RTC_PRESCALER: *mut u32 = (chip::RTC1_ADDRESS + 0x508) as *mut u32;
+/// The CC[0] register of RTC1
+const RTC_CC: *mut u32 = (chip::RTC1_ADDRESS + 0x540) as *mut u32;
+/// The interrupt bit of the COMPARE[0] event of the RTC
+const RTC_COMPARE_INTERRUPT: u32 = 1 << 16;
+/// The prescaler that divides the 32.768 kHz clock down to 1024 Hz
+const RTC_PRESCALER_1024_HZ: u32 = 31;
+
+/// The system control register of the SCB
+const SCB_SCR: *mut u32 = 0xE000_ED10 as *mut u32;
+/// The bit of the SCR that lets a pending interrupt wake the CPU from a WFE, even when it's not enabled in the NVIC
+const SCR_SEVONPEND: u32 = 1 << 4;
+/// The first interrupt clear-pending register of the NVIC
+const NVIC_ICPR0: *mut u32 = 0xE000_E280 as *mut u32;
+
+/// How long the CPU sleeps before the supply is sampled again
+const PARK_INTERVAL_MS: u32 = 500;
+/// How long the comparator gets to settle after it's restarted
+const SETTLE_TIME_MS: u32 = 1;
+
+/// Enables the comparator at [THRESHOLD_MV]. It stays enabled for the application, which can disable it in POFCON.
+#[allow(dead_code)] // Only used with the brownout feature
+pub fn enable_brownout_detection() {
+    unsafe {
+        POFCON.write_volatile(POFCON_THRESHOLD | POFCON_ENABLE);
+        EVENTS_POFWARN.write_volatile(0);
+    }
+}
+
+/// Returns once the supply is above [THRESHOLD_MV], sleeping in between. Returns right away if the comparator is
+/// disabled or the supply never dropped below the threshold since the last check.
+///
+/// Only the RTC and the low frequency clock run while the CPU is parked. A running watchdog is fed every time the
+/// supply is sampled.
+pub fn wait_for_supply() {
+    unsafe {
+        if POFCON.read_volatile() & POFCON_ENABLE == 0 || EVENTS_POFWARN.read_volatile() == 0 {
+            return;
+        }
+    }
+
+    shared::warn!(
+        "The supply dropped below {} mV, waiting before the next flash operation",
+        THRESHOLD_MV
+    );
+
+    loop {
+        feed_watchdog();
+        sleep(PARK_INTERVAL_MS);
+
+        // Restarting the comparator warns right away if the supply is still low
+        unsafe {
+            let pofcon = POFCON.read_volatile();
+            POFCON.write_volatile(pofcon & !POFCON_ENABLE);
+            EVENTS_POFWARN.write_volatile(0);
+            POFCON.write_volatile(pofcon);
+        }
+        sleep(SETTLE_TIME_MS);
+
+        if unsafe { EVENTS_POFWARN.read_volatile() } == 0 {
+            break;
+        }
+    }
+
+    shared::debug!("The supply recovered");
+}
+
+/// Sleeps with a WFE until the COMPARE[0] event of RTC1 after the given time. The RTC is left in its reset state.
+fn sleep(ms: u32) {
+    let ticks = (ms * 1024).div_ceil(1000).max(1);
+
+    unsafe {
+        RTC_PRESCALER.write_volatile(RTC_PRESCALER_1024_HZ);
+        RTC_CC.write_volatile(ticks);
+        RTC_EVENTS_COMPARE.write_volatile(0);
+        // The interrupt isn't enabled in the NVIC, it only becomes pending to wake the CPU
+        RTC_INTENSET.write_volatile(RTC_COMPARE_INTERRUPT);
+        SCB_SCR.write_volatile(SCB_SCR.read_volatile() | SCR_SEVONPEND);
+        RTC_TASKS_CLEAR.write_volatile(1);
+        RTC_TASKS_START.write_volatile(1);
+
+        // A WFE can also return for an older event, so the RTC event is checked every time
+        while RTC_EVENTS_COMPARE.read_volatile() == 0 {
+            cortex_m::asm::wfe();
+        }
+
+        RTC_TASKS_STOP.write_volatile(1);
+        RTC_TASKS_CLEAR.write_volatile(1);
+        RTC_INTENCLR.write_volatile(RTC_COMPARE_INTERRUPT);
+        RTC_EVENTS_COMPARE.write_volatile(0);
+        RTC_PRESCALER.write_volatile(0);
+        SCB_SCR.write_volatile(SCB_SCR.read_volatile() & !SCR_SEVONPEND);
+        NVIC_ICPR0.write_volatile(1 << chip::RTC1_INTERRUPT);
+    }
+}
@@ -0,0 +1,143 @@
+//! First-boot provisioning of the device identity
+//!
+//! When the identity words in the UICR are blank, the bootloader asks for an identity over the UART and waits for it.
+//! The message is the 16 byte device ID, the 32 byte secret and a little-endian CRC-32/MPEG-2 over both
+//! (see [DeviceIdentity::from_message]). A message with a wrong CRC is rejected and the bootloader asks again.
+//!
+//! The identity is written with the marker word last, which marks the provisioning as complete.
+//! Because the UICR can't be written by the application, it can't change the identity.
+//!
+//! With the `hardware-huk` feature, the secret goes into the KMU instead (see [huk](crate::huk)) and the identity in
+//! the UICR gets an all-zero secret, so the secret can't be read from flash. The secret is then what authenticates a
+//! wipe of the device with the `rma-wipe` feature, and the wipe destroys it. With the `key-revocation` feature, it
+//! authenticates the revocation of signing keys, and with the `encrypted-logs` feature the event log is encrypted with
+//! keystreams derived from it. The debugger policy in the UICR config can destroy the device secret as well.
+
+#[cfg(feature = "hardware-huk")]
+use crate::huk;
+use crate::{flash::Flash, Uart};
+use dis_bootloader_core::{events, uprintln};
+use shared::{
+    event_log::SecurityEvent,
+    identity::{DeviceIdentity, IDENTITY_ADDRESS},
+};
+
+/// Reads the words of the identity from the UICR
+fn read_words(flash: &Flash) -> [u32; DeviceIdentity::WORDS] {
+    core::array::from_fn(|index| flash.read_uicr_word(IDENTITY_ADDRESS + index as u32 * 4))
+}
+
+/// Provisions the identity of the device if that hasn't happened yet
+pub fn provision(flash: &mut Flash, uart: &mut Uart) {
+    let words = read_words(flash);
+
+    if let Some(identity) = DeviceIdentity::from_words(&words) {
+        uprintln!(uart, "Device is provisioned with ID {:02X?}", identity.id);
+        return;
+    }
+
+    if !DeviceIdentity::is_blank(&words) {
+        // Only a UICR erase can fix this, which the bootloader can't do without losing the other UICR settings
+        uprintln!(uart, "The device identity is corrupt, the device must be provisioned again after a UICR erase");
+        return;
+    }
+
+    let identity = loop {
+        uprintln!(
+            uart,
+            "Device is not provisioned, waiting for the {} byte identity message",
+            DeviceIdentity::MESSAGE_SIZE + 4
+        );
+
+        let mut message = [0; DeviceIdentity::MESSAGE_SIZE + 4];
+        uart.blocking_read(&mut message).unwrap();
+
+        match DeviceIdentity::from_message(&message) {
+            Some(identity) => break identity,
+            None => uprintln!(uart, "The identity message has a wrong CRC"),
+        }
+    };
+
+    // The secret only lives in the KMU, the UICR keeps the rest of the identity
+    #[cfg(feature = "hardware-huk")]
+    let identity = {
+        if !huk::provision(&identity.secret) {
+            uprintln!(
+                uart,
+                "Provisioning failed, the secret could not be written into the KMU"
+            );
+            return;
+        }
+        DeviceIdentity {
+            secret: [0; 32],
+            ..identity
+        }
+    };
+
+    // Write the marker last, so an interrupted provisioning is seen as corrupt instead of complete
+    let words = identity.to_words();
+    for (index, word) in words
+        .iter()
+        .enumerate()
+        .skip(1)
+        .chain(words.iter().enumerate().take(1))
+    {
+        flash.write_uicr_word(IDENTITY_ADDRESS + index as u32 * 4, *word);
+    }
+
+    if DeviceIdentity::from_words(&read_words(flash)) == Some(identity) {
+        uprintln!(uart, "Provisioned the device with ID {:02X?}", identity.id);
+        events::record(flash, uart, SecurityEvent::Provisioned, 0).ok();
+    } else {
+        uprintln!(
+            uart,
+            "Provisioning failed, the identity could not be read back"
+        );
+    }
+}
+
+/// Returns true if the token is the wipe token of this device
+#[cfg(feature = "rma-wipe")]
+pub fn wipe_token_is_valid(flash: &Flash, token: &[u8; 32]) -> bool {
+    use dis_bootloader_core::crypto::{constant_time_eq, derive_wipe_token, DeviceHuk};
+
+    DeviceIdentity::from_words(&read_words(flash))
+        .and_then(|identity| derive_wipe_token(&DeviceHuk, &identity.id))
+        .is_some_and(|expected| constant_time_eq(&expected, token))
+}
+
+/// Returns true if the token is the revocation token of this device for the key with the given ID
+#[cfg(feature = "key-revocation")]
+pub fn revocation_token_is_valid(flash: &Flash, key_id: u32, token: &[u8; 32]) -> bool {
+    use dis_bootloader_core::crypto::{constant_time_eq, derive_revocation_token, DeviceHuk};
+
+    DeviceIdentity::from_words(&read_words(flash))
+        .and_then(|identity| derive_revocation_token(&DeviceHuk, &identity.id, key_id))
+        .is_some_and(|expected| constant_time_eq(&expected, token))
+}
+
+/// Returns the ID of the device, if it's provisioned and its secret hasn't been destroyed
+#[cfg(any(
+    feature = "encrypted-logs",
+    all(feature = "shell", feature = "hardware-huk")
+))]
+pub fn device_id(flash: &Flash) -> Option<[u8; 16]> {
+    DeviceIdentity::from_words(&read_words(flash))
+        .filter(|_| huk::is_provisioned())
+        .map(|identity| identity.id)
+}
+
+/// Destroys the device secret by clearing all its bits, and with the `hardware-huk` feature by revoking its key slots
+/// in the KMU.
+///
+/// The device ID stays readable. Without the KMU the identity isn't valid anymore, with it the identity stays valid
+/// but nothing can be derived from the revoked secret. The device can only be provisioned again after a UICR erase.
+pub fn erase_secret(flash: &mut Flash) {
+    #[cfg(feature = "hardware-huk")]
+    huk::destroy(flash);
+
+    // The secret starts after the marker and the device ID
+    for index in 5..DeviceIdentity::WORDS as u32 - 1 {
+        flash.write_uicr_word(IDENTITY_ADDRESS + index * 4, 0);
+    }
+}
@@ -0,0 +1,263 @@
+//! The driver of the NOR flash on the QSPI bus of the nRF52840, which slot B is on with the `qspi-slot-b` feature
+//!
+//! [QspiFlash] implements [Flash](shared::Flash) with the addresses of the external flash, starting at 0. The
+//! [Flash](crate::flash::Flash) of the bootloader puts it behind the window of [shared::external_flash], which is
+//! where the QSPI peripheral maps the flash for reading (XIP). So the reads are plain memory reads, like those of the
+//! internal flash.
+//!
+//! The flash is erased in sectors of 4KB, which are the pages of the layout, and programmed with EasyDMA from RAM.
+//! The peripheral sends the write enable before every erase and program, and splits a write into page programs of
+//! 256 bytes. Only the single line read and program commands are used, so any SPI NOR flash works without enabling
+//! its quad mode first. The pins come from the [BoardConfig](crate::boards::BoardConfig).
+
+use crate::{boards::QspiPins, flash::feed_watchdog, power};
+use core::{
+    mem::{size_of, size_of_val},
+    ops::Range,
+};
+use shared::{
+    chip, external_flash::EXTERNAL_FLASH_ADDRESS, flash_addresses::PAGE_SIZE,
+    flash_geometry::FlashGeometry, partitions::EXTERNAL_FLASH_SIZE, Flash as _, FlashError,
+};
+
+// The layout must say how big the external flash is, or slot B can't be on it
+const _: () = assert!(
+    EXTERNAL_FLASH_SIZE > 0,
+    "The qspi-slot-b feature needs an `external_flash_size` in partitions.toml"
+);
+const _: () = assert!(chip::QSPI_XIP_ADDRESS == EXTERNAL_FLASH_ADDRESS);
+
+/// The registers of the QSPI peripheral
+const TASKS_ACTIVATE: *mut u32 = chip::QSPI_ADDRESS as *mut u32;
+const TASKS_WRITESTART: *mut u32 = (chip::QSPI_ADDRESS + 0x008) as *mut u32;
+const TASKS_ERASESTART: *mut u32 = (chip::QSPI_ADDRESS + 0x00C) as *mut u32;
+const EVENTS_READY: *mut u32 = (chip::QSPI_ADDRESS + 0x100) as *mut u32;
+const ENABLE: *mut u32 = (chip::QSPI_ADDRESS + 0x500) as *mut u32;
+const WRITE_DST: *mut u32 = (chip::QSPI_ADDRESS + 0x510) as *mut u32;
+const WRITE_SRC: *mut u32 = (chip::QSPI_ADDRESS + 0x514) as *mut u32;
+const WRITE_CNT: *mut u32 = (chip::QSPI_ADDRESS + 0x518) as *mut u32;
+const ERASE_PTR: *mut u32 = (chip::QSPI_ADDRESS + 0x51C) as *mut u32;
+const ERASE_LEN: *mut u32 = (chip::QSPI_ADDRESS + 0x520) as *mut u32;
+const PSEL_SCK: *mut u32 = (chip::QSPI_ADDRESS + 0x524) as *mut u32;
+const PSEL_CSN: *mut u32 = (chip::QSPI_ADDRESS + 0x528) as *mut u32;
+/// The first of the four PSEL registers of IO0 to IO3
+const PSEL_IO0: *mut u32 = (chip::QSPI_ADDRESS + 0x530) as *mut u32;
+const XIPOFFSET: *mut u32 = (chip::QSPI_ADDRESS + 0x540) as *mut u32;
+const IFCONFIG0: *mut u32 = (chip::QSPI_ADDRESS + 0x544) as *mut u32;
+const IFCONFIG1: *mut u32 = (chip::QSPI_ADDRESS + 0x600) as *mut u32;
+const CINSTRCONF: *mut u32 = (chip::QSPI_ADDRESS + 0x634) as *mut u32;
+const CINSTRDAT0: *const u32 = (chip::QSPI_ADDRESS + 0x638) as *const u32;
+
+/// IFCONFIG0: the FASTREAD and PP commands, 24 bit addresses and page programs of 256 bytes
+const IFCONFIG0_SINGLE_LINE: u32 = 0;
+/// IFCONFIG1: SPI mode 0 and a clock of 32 MHz / (3 + 1) = 8 MHz, which every NOR flash can keep up with in any of
+/// its power modes, with a delay of one clock cycle between the chip select and the clock
+const IFCONFIG1_8_MHZ: u32 = 3 << 28 | 1;
+/// ERASE.LEN of a sector of 4KB
+const ERASE_LEN_4KB: u32 = 0;
+/// CINSTRCONF of the read status register command: the opcode 0x05 with one byte of response, and IO2 and IO3 high
+/// so they don't act as the write protect and the hold of the flash
+const CINSTRCONF_READ_STATUS: u32 = 0x05 | 2 << 8 | 1 << 12 | 1 << 13;
+/// The bit in the status register of the flash that tells an erase or program is still in progress
+const STATUS_WIP: u32 = 1 << 0;
+
+/// The number of times the ready event is polled before an operation is given up on.
+/// Every poll takes at least a few cycles at 64 MHz, so this is far longer than the sector erase time of about
+/// 250 ms of a slow NOR flash.
+const READY_TIMEOUT_POLLS: u32 = 10_000_000;
+
+/// The driver of the flash on the QSPI bus
+pub struct QspiFlash {
+    /// The geometry of the external flash, which is the size in the layout in 4KB sectors
+    geometry: FlashGeometry,
+}
+
+impl QspiFlash {
+    /// Connects the QSPI peripheral to the flash on the given pins and activates it
+    pub fn new(pins: QspiPins) -> Self {
+        unsafe {
+            PSEL_SCK.write_volatile(pins.sck as u32);
+            PSEL_CSN.write_volatile(pins.csn as u32);
+            for (index, pin) in pins.io.iter().enumerate() {
+                PSEL_IO0.add(index).write_volatile(*pin as u32);
+            }
+            XIPOFFSET.write_volatile(0);
+            IFCONFIG0.write_volatile(IFCONFIG0_SINGLE_LINE);
+            IFCONFIG1.write_volatile(IFCONFIG1_8_MHZ);
+
+            ENABLE.write_volatile(1);
+            EVENTS_READY.write_volatile(0);
+            TASKS_ACTIVATE.write_volatile(1);
+        }
+
+        let flash = Self {
+            geometry: FlashGeometry {
+                page_size: PAGE_SIZE,
+                size: EXTERNAL_FLASH_SIZE,
+                external_size: 0,
+            },
+        };
+        if flash.wait_until_ready().is_err() {
+            shared::warn!("The QSPI flash didn't become ready");
+        }
+
+        flash
+    }
+
+    /// Checks that the address is at the start of a sector of the flash
+    fn check_page_address(&self, page_address: u32) -> Result<(), FlashError> {
+        if page_address % self.geometry.page_size != 0 {
+            return Err(FlashError::Alignment);
+        }
+        if page_address >= self.geometry.size {
+            return Err(FlashError::OutOfRange);
+        }
+
+        Ok(())
+    }
+
+    /// Waits until the peripheral has finished the task it was given
+    fn wait_until_ready(&self) -> Result<(), FlashError> {
+        for _ in 0..READY_TIMEOUT_POLLS {
+            if unsafe { EVENTS_READY.read_volatile() } != 0 {
+                return Ok(());
+            }
+        }
+
+        Err(FlashError::Timeout)
+    }
+
+    /// Starts the task and waits until the peripheral is ready and the flash has finished the erase or program
+    fn run_task(&self, task: *mut u32) -> Result<(), FlashError> {
+        unsafe {
+            EVENTS_READY.write_volatile(0);
+            task.write_volatile(1);
+        }
+        self.wait_until_ready()?;
+
+        // The peripheral is ready once the command is sent, the flash can still be busy with it
+        for _ in 0..READY_TIMEOUT_POLLS / 100 {
+            let status = unsafe {
+                EVENTS_READY.write_volatile(0);
+                CINSTRCONF.write_volatile(CINSTRCONF_READ_STATUS);
+                self.wait_until_ready()?;
+                CINSTRDAT0.read_volatile()
+            };
+            if status & STATUS_WIP == 0 {
+                return Ok(());
+            }
+        }
+
+        Err(FlashError::Timeout)
+    }
+
+    /// Synchronizes the reads through the XIP region with the changes
+    fn finish_write(&self) {
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+    }
+}
+
+impl shared::Flash for QspiFlash {
+    fn erase_page(&mut self, page_address: u32) -> Result<(), FlashError> {
+        self.check_page_address(page_address)?;
+        power::wait_for_supply();
+        shared::debug!("Erasing the QSPI sector at {:#010X}", page_address);
+
+        unsafe {
+            ERASE_PTR.write_volatile(page_address);
+            ERASE_LEN.write_volatile(ERASE_LEN_4KB);
+        }
+        let result = self.run_task(TASKS_ERASESTART);
+        self.finish_write();
+        feed_watchdog();
+
+        result?;
+        if self
+            .read_u32(page_address..page_address + self.geometry.page_size)
+            .iter()
+            .any(|word| *word != 0xFFFF_FFFF)
+        {
+            return Err(FlashError::VerifyFailed);
+        }
+
+        Ok(())
+    }
+
+    fn program_page(&mut self, page_address: u32, data: &[u32]) -> Result<(), FlashError> {
+        self.check_page_address(page_address)?;
+        if data.len() > PAGE_SIZE as usize / size_of::<u32>() {
+            // Only a page can be programmed at a time
+            return Err(FlashError::OutOfRange);
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        // EasyDMA can only read from RAM, and the data may be in flash.
+        // Programming can only clear bits, so this is what the flash must contain afterwards.
+        let data_range = page_address..page_address + size_of_val(data) as u32;
+        let mut buffer = [0; PAGE_SIZE as usize / size_of::<u32>()];
+        let buffer = &mut buffer[..data.len()];
+        buffer.copy_from_slice(data);
+        let mut expected = [0; PAGE_SIZE as usize / size_of::<u32>()];
+        let expected = &mut expected[..data.len()];
+        for ((expected, data_word), flash_word) in expected
+            .iter_mut()
+            .zip(data)
+            .zip(self.read_u32(data_range.clone()))
+        {
+            *expected = data_word & flash_word;
+        }
+
+        power::wait_for_supply();
+
+        unsafe {
+            WRITE_DST.write_volatile(page_address);
+            WRITE_SRC.write_volatile(buffer.as_ptr() as u32);
+            WRITE_CNT.write_volatile(size_of_val(buffer) as u32);
+        }
+        let result = self.run_task(TASKS_WRITESTART);
+        self.finish_write();
+
+        result?;
+        if self.read_u32(data_range) != expected {
+            return Err(FlashError::VerifyFailed);
+        }
+
+        Ok(())
+    }
+
+    fn read_u8(&self, address_range: Range<u32>) -> &[u8] {
+        let entire_flash_slice = unsafe {
+            core::slice::from_raw_parts(
+                chip::QSPI_XIP_ADDRESS as *const u8,
+                self.geometry.size as usize,
+            )
+        };
+
+        entire_flash_slice
+            .get(address_range.start as usize..address_range.end as usize)
+            .unwrap()
+    }
+
+    fn read_u32(&self, address_range: Range<u32>) -> &[u32] {
+        assert!(address_range.start % 4 == 0);
+        assert!(address_range.end % 4 == 0);
+
+        let entire_flash_slice = unsafe {
+            core::slice::from_raw_parts(
+                chip::QSPI_XIP_ADDRESS as *const u32,
+                self.geometry.size as usize / size_of::<u32>(),
+            )
+        };
+
+        entire_flash_slice
+            .get(address_range.start as usize / 4..address_range.end as usize / 4)
+            .unwrap()
+    }
+
+    fn geometry(&self) -> FlashGeometry {
+        self.geometry
+    }
+}
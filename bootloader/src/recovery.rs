@@ -0,0 +1,406 @@
+//! The serial recovery: receiving a new image over the UART into slot B
+//!
+//! When the UICR config enables it, the bootloader enters the recovery when the recovery button of the board is
+//! held, when slot A has no valid image or when the host sends [ENTER_RECOVERY] within the boot timeout of the
+//! config. It then receives an image with the framed protocol of [shared::recovery], or with XMODEM-1K (see
+//! [shared::xmodem]) with the `xmodem` feature, writes it into slot B and resets with the `StartSwap` goal, so the
+//! core swaps it in like any other update. That includes the checks of the new image, so the recovery can't be used
+//! to start an image that an update couldn't.
+//!
+//! When the UICR config requires it, the received image must be signed before the `StartSwap` goal is set, so the
+//! UART can't be used to get around the secure boot.
+//!
+//! Slot B is overwritten, so the recovery is only entered by itself when no goal is pending. The button is the way out
+//! for a technician, so it forces the recovery whatever the goal is, and the new image replaces what was pending.
+//! Only a wipe is finished first, so the data of the device is really gone.
+
+use crate::{flash::Flash, serial::wait_for_byte, Uart};
+use cortex_m::peripheral::SCB;
+use dis_bootloader_core::{application::has_valid_image_for, events, uprintln};
+#[cfg(feature = "xmodem")]
+use shared::xmodem::{self, BLOCK_OVERHEAD, CAN, CRC_MODE, EOT, MAX_BLOCK_SIZE};
+use shared::{
+    config::BootloaderConfig,
+    event_log::SecurityEvent,
+    flash_addresses::PAGE_SIZE,
+    recovery::{
+        FrameHeader, FrameKind, ACK, CRC_SIZE, ENTER_RECOVERY, FRAME_START, MAX_PAYLOAD, NAK,
+    },
+    slots::{self, SlotDescriptor},
+    state::{BootloaderGoal, BootloaderState},
+    Flash as _, FlashError,
+};
+
+/// How long the bootloader waits for the start of a transfer before it asks for an XMODEM transfer again
+#[cfg(feature = "xmodem")]
+const PROMPT_INTERVAL_MS: u32 = 3000;
+/// How long the line must be quiet before a damaged XMODEM block is answered
+#[cfg(feature = "xmodem")]
+const PURGE_SILENCE_MS: u32 = 100;
+
+/// The words of a page of flash
+const PAGE_WORDS: usize = PAGE_SIZE as usize / 4;
+
+/// Why the recovery was entered. This is the detail of the [SecurityEvent::RecoveryEntered] event.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Trigger {
+    /// The recovery button was held at boot
+    Button = 0,
+    /// Slot A has no valid image
+    InvalidImage = 1,
+    /// The host sent [ENTER_RECOVERY] during the boot window
+    Requested = 2,
+}
+
+/// Returns why the recovery must be entered, or `None` if the boot goes on as normal.
+/// The button is read by the caller, because it may enter the shell instead.
+pub fn trigger(
+    flash: &Flash,
+    uart: &mut Uart,
+    config: &BootloaderConfig,
+    button_is_held: bool,
+) -> Option<Trigger> {
+    if !config.recovery_enabled() {
+        return None;
+    }
+
+    let goal = BootloaderState::load(flash).current_goal();
+    if button_is_held && goal != Some(BootloaderGoal::Wipe) {
+        return Some(Trigger::Button);
+    }
+
+    if goal != Some(BootloaderGoal::JumpToApplication) {
+        uprintln!(
+            uart,
+            "The goal {:?} is pending, the recovery can't be entered",
+            goal
+        );
+        return None;
+    }
+
+    let [primary, _] = slots::default_layout();
+    if !has_valid_image_for(flash, &primary, &primary) {
+        return Some(Trigger::InvalidImage);
+    }
+
+    let timeout_ms = config.boot_timeout_ms()?;
+    uprintln!(
+        uart,
+        "Send {:#04X} within {} ms to enter the recovery",
+        ENTER_RECOVERY,
+        timeout_ms
+    );
+    wait_for_byte(timeout_ms, |byte| byte == ENTER_RECOVERY).map(|_| Trigger::Requested)
+}
+
+/// Receives a new image into slot B and resets to swap it in. This only returns by resetting.
+pub fn run(flash: &mut Flash, uart: &mut Uart, config: &BootloaderConfig, trigger: Trigger) -> ! {
+    uprintln!(
+        uart,
+        "Entered the recovery ({:?}), waiting for an image",
+        trigger
+    );
+    events::record(flash, uart, SecurityEvent::RecoveryEntered, trigger as u32).ok();
+
+    let size = receive_image(flash, uart);
+    uprintln!(
+        uart,
+        "Received an image of {} bytes, resetting to swap it in",
+        size
+    );
+
+    if config.recovery_requires_signature() && !is_signed(flash, uart) {
+        events::record(flash, uart, SecurityEvent::VerificationFailed, 5).ok();
+        SCB::sys_reset()
+    }
+
+    // The button may have forced the recovery while another goal was pending, which the new image replaces
+    let current = BootloaderState::load(flash)
+        .current_goal()
+        .unwrap_or(BootloaderGoal::JumpToApplication);
+    if let Err(error) =
+        BootloaderState::compare_and_set_goal(flash, current, BootloaderGoal::StartSwap)
+    {
+        uprintln!(uart, "Could not store the swap goal: {:?}", error);
+    }
+
+    SCB::sys_reset()
+}
+
+/// Checks the signature of the image in slot B, like the swap does for an update
+#[cfg(feature = "secure-boot")]
+fn is_signed(flash: &Flash, uart: &mut Uart) -> bool {
+    let [_, secondary] = slots::default_layout();
+    match dis_bootloader_core::secure_boot::verify_signature(flash, &secondary) {
+        Ok(()) => true,
+        Err(error) => {
+            uprintln!(uart, "The signature of the image is refused: {:?}", error);
+            false
+        }
+    }
+}
+
+/// Without the secure boot, there is no signing key to check the image in slot B with
+#[cfg(not(feature = "secure-boot"))]
+fn is_signed(_flash: &Flash, uart: &mut Uart) -> bool {
+    uprintln!(
+        uart,
+        "The image is refused, signed images are required but the bootloader can't check signatures"
+    );
+    false
+}
+
+/// Waits for the host to start a transfer and receives the image into slot B. Returns the size of the image.
+#[cfg(not(feature = "xmodem"))]
+fn receive_image(flash: &mut Flash, uart: &mut Uart) -> u32 {
+    receive_frames(flash, uart, false)
+}
+
+/// Waits for the host to start a transfer and receives the image into slot B. Returns the size of the image.
+///
+/// The protocol follows from the first byte the host sends. Until then, an XMODEM transfer is asked for every few
+/// seconds, which a host tool for the framed protocol skips like the log output.
+#[cfg(feature = "xmodem")]
+fn receive_image(flash: &mut Flash, uart: &mut Uart) -> u32 {
+    loop {
+        answer(uart, CRC_MODE);
+        let start = wait_for_byte(PROMPT_INTERVAL_MS, |byte| {
+            byte == FRAME_START || xmodem::block_size(byte).is_some()
+        });
+
+        match start {
+            Some(FRAME_START) => return receive_frames(flash, uart, true),
+            Some(start) => {
+                if let Some(size) = receive_xmodem(flash, uart, start) {
+                    return size;
+                }
+                uprintln!(uart, "The XMODEM transfer was aborted");
+            }
+            None => {}
+        }
+    }
+}
+
+/// Receives frames until a whole image is written into slot B and returns its size.
+/// The start of the first frame may have been received already.
+fn receive_frames(flash: &mut Flash, uart: &mut Uart, mut at_frame_start: bool) -> u32 {
+    let [_, secondary] = slots::default_layout();
+
+    let mut payload = [0; MAX_PAYLOAD];
+    let mut page = [0xFFFF_FFFF; PAGE_WORDS];
+    let mut image_size = None;
+    let mut next_chunk: u16 = 0;
+
+    loop {
+        let frame = receive_frame(uart, &mut payload, at_frame_start);
+        at_frame_start = false;
+        let header = match frame {
+            Some(header) => header,
+            None => {
+                answer(uart, NAK);
+                continue;
+            }
+        };
+        let payload = &payload[..usize::from(header.length)];
+
+        let accepted = match (header.kind, image_size) {
+            (FrameKind::Begin, _) => {
+                let size = payload
+                    .try_into()
+                    .map(u32::from_le_bytes)
+                    .ok()
+                    .filter(|size| (1..=secondary.image_capacity()).contains(size));
+                if size.is_some() {
+                    image_size = size;
+                    next_chunk = 0;
+                    page.fill(0xFFFF_FFFF);
+                }
+                size.is_some()
+            }
+            // The host didn't get our answer, so it sent the chunk again
+            (FrameKind::Data, Some(_)) if next_chunk.checked_sub(1) == Some(header.sequence) => {
+                true
+            }
+            (FrameKind::Data, Some(size)) if header.sequence == next_chunk => {
+                let offset = u32::from(header.sequence) * MAX_PAYLOAD as u32;
+                let expected_length = size.saturating_sub(offset).min(MAX_PAYLOAD as u32);
+                let is_valid = expected_length != 0 && payload.len() as u32 == expected_length;
+                let is_written =
+                    is_valid && write_chunk(flash, &secondary, &mut page, offset, payload).is_ok();
+                if is_written {
+                    next_chunk += 1;
+                }
+                is_written
+            }
+            (FrameKind::End, Some(size)) if u32::from(next_chunk) * MAX_PAYLOAD as u32 >= size => {
+                if finish_image(flash, &secondary, &mut page, size).is_ok() {
+                    answer(uart, ACK);
+                    return size;
+                }
+                false
+            }
+            _ => false,
+        };
+
+        answer(uart, if accepted { ACK } else { NAK });
+    }
+}
+
+/// Receives the next frame into the payload buffer. Returns `None` if it's not a valid frame.
+fn receive_frame(
+    uart: &mut Uart,
+    payload: &mut [u8; MAX_PAYLOAD],
+    at_frame_start: bool,
+) -> Option<FrameHeader> {
+    // Everything before the start of a frame is skipped
+    let mut byte = [if at_frame_start { FRAME_START } else { 0 }];
+    while byte[0] != FRAME_START {
+        uart.blocking_read(&mut byte).ok()?;
+    }
+
+    let mut header = [0; FrameHeader::SIZE];
+    uart.blocking_read(&mut header).ok()?;
+    let header = FrameHeader::from_bytes(&header)?;
+
+    let payload = &mut payload[..usize::from(header.length)];
+    if !payload.is_empty() {
+        uart.blocking_read(payload).ok()?;
+    }
+    let mut crc = [0; CRC_SIZE];
+    uart.blocking_read(&mut crc).ok()?;
+
+    (u32::from_le_bytes(crc) == header.crc(payload)).then_some(header)
+}
+
+/// Receives an XMODEM transfer into slot B, after the start of the first block. Returns the size of the received
+/// data, or `None` if the transfer was aborted.
+#[cfg(feature = "xmodem")]
+fn receive_xmodem(flash: &mut Flash, uart: &mut Uart, mut start: u8) -> Option<u32> {
+    let [_, secondary] = slots::default_layout();
+
+    let mut block = [0; MAX_BLOCK_SIZE + BLOCK_OVERHEAD];
+    let mut page = [0xFFFF_FFFF; PAGE_WORDS];
+    let mut size = 0;
+    let mut next_number: u8 = 1;
+
+    loop {
+        let block_size = match start {
+            EOT if size > 0 => {
+                if finish_image(flash, &secondary, &mut page, size).is_err() {
+                    return cancel(uart);
+                }
+                answer(uart, ACK);
+                return Some(size);
+            }
+            EOT | CAN => return None,
+            start => xmodem::block_size(start),
+        };
+
+        let block = block_size.and_then(|block_size| {
+            let block = &mut block[..block_size + BLOCK_OVERHEAD];
+            uart.blocking_read(block).ok()?;
+            xmodem::parse_block(block)
+        });
+
+        match block {
+            // The sender didn't get our answer, so it sent the block again
+            Some((number, _)) if size > 0 && number == next_number.wrapping_sub(1) => {
+                answer(uart, ACK)
+            }
+            Some((number, data)) if number == next_number => {
+                if size + data.len() as u32 > secondary.image_capacity()
+                    || write_chunk(flash, &secondary, &mut page, size, data).is_err()
+                {
+                    return cancel(uart);
+                }
+                size += data.len() as u32;
+                next_number = next_number.wrapping_add(1);
+                answer(uart, ACK);
+            }
+            // A block out of sequence can't be recovered from
+            Some(_) => return cancel(uart),
+            None => {
+                // Whatever is left of the damaged block is skipped, so the next read starts at the block that is
+                // sent again
+                while wait_for_byte(PURGE_SILENCE_MS, |_| true).is_some() {}
+                answer(uart, NAK);
+            }
+        }
+
+        let mut byte = [0];
+        uart.blocking_read(&mut byte).ok()?;
+        start = byte[0];
+    }
+}
+
+/// Aborts an XMODEM transfer
+#[cfg(feature = "xmodem")]
+fn cancel(uart: &mut Uart) -> Option<u32> {
+    answer(uart, CAN);
+    answer(uart, CAN);
+    None
+}
+
+/// Copies the chunk at the offset in the image into the page buffer, and writes the page into the slot whenever it's
+/// full
+fn write_chunk(
+    flash: &mut Flash,
+    slot: &SlotDescriptor,
+    page: &mut [u32; PAGE_WORDS],
+    mut offset: u32,
+    mut chunk: &[u8],
+) -> Result<(), FlashError> {
+    while !chunk.is_empty() {
+        let page_offset = offset % PAGE_SIZE;
+        let (part, rest) = chunk.split_at(chunk.len().min((PAGE_SIZE - page_offset) as usize));
+
+        let word_offset = page_offset as usize / 4;
+        for (word, bytes) in page[word_offset..].iter_mut().zip(part.chunks(4)) {
+            let mut word_bytes = [0xFF; 4];
+            word_bytes[..bytes.len()].copy_from_slice(bytes);
+            *word = u32::from_le_bytes(word_bytes);
+        }
+
+        offset += part.len() as u32;
+        chunk = rest;
+        if offset % PAGE_SIZE == 0 {
+            write_page(flash, slot, page, offset - PAGE_SIZE)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the last page of an image of the given size, if it wasn't full
+fn finish_image(
+    flash: &mut Flash,
+    slot: &SlotDescriptor,
+    page: &mut [u32; PAGE_WORDS],
+    image_size: u32,
+) -> Result<(), FlashError> {
+    match image_size % PAGE_SIZE {
+        0 => Ok(()),
+        rest => write_page(flash, slot, page, image_size - rest),
+    }
+}
+
+/// Writes the page buffer to the page at the offset in the slot and clears the buffer
+fn write_page(
+    flash: &mut Flash,
+    slot: &SlotDescriptor,
+    page: &mut [u32; PAGE_WORDS],
+    offset: u32,
+) -> Result<(), FlashError> {
+    let page_address = slot.address() + offset;
+    flash.erase_page(page_address)?;
+    flash.program_page(page_address, page)?;
+    page.fill(0xFFFF_FFFF);
+    Ok(())
+}
+
+/// Sends a byte of the protocol to the host, like the answer to a frame
+fn answer(uart: &mut Uart, answer: u8) {
+    // The buffer must be in RAM for the EasyDMA of the UARTE
+    let buffer = [answer];
+    uart.blocking_write(&buffer).ok();
+}
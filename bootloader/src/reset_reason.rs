@@ -0,0 +1,40 @@
+//! Reading the reset reason of this boot
+//!
+//! The RESETREAS register collects the reasons of all resets until it's cleared. With the `reset-history` feature, the
+//! bootloader clears it at every boot with [take], so every boot only sees its own reason. The taken reason stays
+//! available through [get] for the rest of the boot.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use shared::chip;
+
+/// The RESETREAS register of the POWER peripheral
+const POWER_RESETREAS: *mut u32 = (chip::POWER_ADDRESS + 0x400) as *mut u32;
+
+/// The reasons that were taken from the register at this boot
+static TAKEN: AtomicU32 = AtomicU32::new(0);
+
+/// Returns the reset reason and clears it in the register
+#[allow(dead_code)] // Only used with the reset-history feature
+pub fn take() -> u32 {
+    unsafe {
+        let reason = POWER_RESETREAS.read_volatile();
+        // The bits are cleared by writing 1 to them
+        POWER_RESETREAS.write_volatile(reason);
+        TAKEN.fetch_or(reason, Ordering::Relaxed);
+    }
+
+    get()
+}
+
+/// Returns the reset reason, including what was already [taken](take) at this boot
+pub fn get() -> u32 {
+    TAKEN.load(Ordering::Relaxed) | unsafe { POWER_RESETREAS.read_volatile() }
+}
+
+/// Clears the given reasons in the register, so the next boot doesn't see them again
+#[allow(dead_code)] // Only used with the test-swap feature
+pub fn clear(reasons: u32) {
+    unsafe {
+        POWER_RESETREAS.write_volatile(reasons);
+    }
+}
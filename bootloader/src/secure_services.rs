@@ -0,0 +1,82 @@
+//! The built-in secure services for non-secure applications
+//!
+//! The application calls the veneers in the non-secure callable region at the end of the bootloader flash
+//! (see [shared::secure_services]). A veneer switches to the secure state with an SG instruction and branches to
+//! the service. Services that return go through `secure_services_return`, which clears the registers that the
+//! caller may look at and returns to the non-secure state with BXNS.
+//!
+//! The SPU marks the veneers as non-secure callable with [enable](crate::spu::enable_secure_services).
+//!
+//! The services run on the secure stack that the bootloader left behind when it started the application.
+
+use crate::hal::{Chip, Hal};
+use shared::{
+    secure_services::{encode_goal_result, GoalRequestError},
+    state::{BootloaderGoal, BootloaderState},
+};
+
+// The veneers are the only code in the non-secure callable region, so the application can't enter anywhere else.
+// The SG and BXNS instructions are written as their encodings, because the assembler doesn't have the security
+// extension enabled for this target.
+core::arch::global_asm!(
+    ".section .nsc_veneers, \"ax\"",
+    ".thumb_func",
+    "nsc_request_goal:",
+    "    .inst.w 0xE97FE97F", // sg
+    "    b.w secure_services_request_goal",
+    ".thumb_func",
+    "nsc_reboot:",
+    "    .inst.w 0xE97FE97F", // sg
+    "    b.w {reboot}",
+    "",
+    ".section .text.secure_services, \"ax\"",
+    ".thumb_func",
+    "secure_services_request_goal:",
+    "    push {{r4, lr}}",
+    "    bl {request_goal}",
+    "    pop {{r4, lr}}",
+    "    b secure_services_return",
+    "",
+    ".thumb_func",
+    "secure_services_return:",
+    // Only r0 has the result, the rest must not leak secure values
+    "    movs r1, #0",
+    "    mov r2, r1",
+    "    mov r3, r1",
+    "    mov r12, r1",
+    "    vmov d0, r1, r1",
+    "    vmov d1, r1, r1",
+    "    vmov d2, r1, r1",
+    "    vmov d3, r1, r1",
+    "    vmov d4, r1, r1",
+    "    vmov d5, r1, r1",
+    "    vmov d6, r1, r1",
+    "    vmov d7, r1, r1",
+    "    vmsr fpscr, r1",
+    "    msr APSR_nzcvq, r1",
+    "    .inst.n 0x4774", // bxns lr
+    request_goal = sym request_goal,
+    reboot = sym reboot,
+);
+
+/// Sets the goal if the current goal is the expected one and returns the encoded result
+extern "C" fn request_goal(expected: u32, goal: u32) -> u32 {
+    let result = match (
+        BootloaderGoal::try_from(expected),
+        BootloaderGoal::try_from(goal),
+    ) {
+        (Ok(expected), Ok(goal)) if goal.is_requestable() => {
+            let mut flash = Chip::flash();
+            BootloaderState::compare_and_set_goal(&mut flash, expected, goal)
+                .map_err(GoalRequestError::from)
+        }
+        _ => Err(GoalRequestError::NotRequestable),
+    };
+
+    encode_goal_result(result)
+}
+
+/// Resets the device
+extern "C" fn reboot() -> ! {
+    cortex_m::peripheral::SCB::sys_reset()
+}
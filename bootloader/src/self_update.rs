@@ -0,0 +1,45 @@
+//! Finishing a bootloader update
+//!
+//! The bootloader can't safely overwrite itself, so the staged image is copied over it by stage 0
+//! (see the `stage0` crate), which is never updated. Stage 0 restarts an interrupted copy at the next boot,
+//! so a power loss during the update is harmless. The bootloader only checks the result.
+//! With the `secure-boot` feature, that includes the signature of the staged image.
+
+use crate::{flash::Flash, Uart};
+use dis_bootloader_core::{revision_check, uprintln};
+use shared::{
+    bootloader_update::{self, is_installed},
+    state::{BootloaderGoal, BootloaderState},
+};
+
+/// Finishes the `UpdateBootloader` goal by setting it back to `JumpToApplication`
+pub fn finish(flash: &mut Flash, uart: &mut Uart) {
+    match bootloader_update::validate(flash, revision_check::hardware_revision()) {
+        #[cfg(feature = "secure-boot")]
+        Ok(update)
+            if dis_bootloader_core::secure_boot::verify_bootloader_update(flash, &update)
+                .is_err() =>
+        {
+            uprintln!(
+                uart,
+                "The staged bootloader is not signed with the signing key"
+            );
+        }
+        Ok(update) if is_installed(flash, &update) => {
+            uprintln!(uart, "The bootloader has been updated");
+        }
+        Ok(_) => {
+            uprintln!(uart, "The staged bootloader was not installed by stage 0");
+        }
+        Err(error) => {
+            uprintln!(uart, "The staged bootloader is invalid: {:?}", error);
+        }
+    }
+
+    BootloaderState::compare_and_set_goal(
+        flash,
+        BootloaderGoal::UpdateBootloader,
+        BootloaderGoal::JumpToApplication,
+    )
+    .ok();
+}
@@ -0,0 +1,61 @@
+//! Receiving from the UART with a timeout
+//!
+//! Embassy's UARTE driver has no timeouts, so this uses the registers of the UARTE directly. The receiver is stopped
+//! again before returning, so the driver can use the UARTE afterwards.
+
+use crate::hal::{Chip, Hal};
+use core::sync::atomic::{compiler_fence, Ordering};
+
+/// The TASKS_STARTRX register of the UARTE that embassy has configured
+const UARTE_TASKS_STARTRX: *mut u32 = <Chip as Hal>::UART_ADDRESS as *mut u32;
+/// The TASKS_STOPRX register of the UARTE
+const UARTE_TASKS_STOPRX: *mut u32 = (<Chip as Hal>::UART_ADDRESS + 0x4) as *mut u32;
+/// The EVENTS_ENDRX register of the UARTE
+const UARTE_EVENTS_ENDRX: *mut u32 = (<Chip as Hal>::UART_ADDRESS + 0x110) as *mut u32;
+/// The EVENTS_RXTO register of the UARTE
+const UARTE_EVENTS_RXTO: *mut u32 = (<Chip as Hal>::UART_ADDRESS + 0x144) as *mut u32;
+/// The RXD.PTR register of the UARTE
+const UARTE_RXD_PTR: *mut u32 = (<Chip as Hal>::UART_ADDRESS + 0x534) as *mut u32;
+/// The RXD.MAXCNT register of the UARTE
+const UARTE_RXD_MAXCNT: *mut u32 = (<Chip as Hal>::UART_ADDRESS + 0x538) as *mut u32;
+
+/// The CPU cycles in a millisecond at 64 MHz
+pub const CYCLES_PER_MS: u32 = 64_000;
+
+/// Waits for a byte on the UART that is accepted by the filter and returns it, or `None` if none came before the
+/// timeout. Other bytes are skipped.
+pub fn wait_for_byte(timeout_ms: u32, filter: impl Fn(u8) -> bool) -> Option<u8> {
+    let mut byte = [0u8; 1];
+    let mut accepted = None;
+
+    unsafe {
+        UARTE_RXD_PTR.write_volatile(byte.as_mut_ptr() as u32);
+        UARTE_RXD_MAXCNT.write_volatile(1);
+        UARTE_EVENTS_ENDRX.write_volatile(0);
+        compiler_fence(Ordering::SeqCst);
+        UARTE_TASKS_STARTRX.write_volatile(1);
+
+        for _ in 0..timeout_ms {
+            if UARTE_EVENTS_ENDRX.read_volatile() != 0 {
+                UARTE_EVENTS_ENDRX.write_volatile(0);
+                compiler_fence(Ordering::SeqCst);
+                if filter(byte[0]) {
+                    accepted = Some(byte[0]);
+                    break;
+                }
+                // Any other byte is skipped, so the next one is received into the same buffer
+                UARTE_TASKS_STARTRX.write_volatile(1);
+            }
+            cortex_m::asm::delay(CYCLES_PER_MS);
+        }
+
+        UARTE_EVENTS_RXTO.write_volatile(0);
+        UARTE_TASKS_STOPRX.write_volatile(1);
+        while UARTE_EVENTS_RXTO.read_volatile() == 0 {}
+        UARTE_EVENTS_RXTO.write_volatile(0);
+        UARTE_EVENTS_ENDRX.write_volatile(0);
+        compiler_fence(Ordering::SeqCst);
+    }
+
+    accepted
+}
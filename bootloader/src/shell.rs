@@ -0,0 +1,452 @@
+//! A small command shell on the UART for debugging in the field
+//!
+//! When the host holds [SHELL_KEY] during the first seconds of the boot, or the recovery button of the board is held
+//! at reset in a bootloader without the recovery, the bootloader stops and reads commands from the UART until `boot`.
+//! Type `help` for the commands. The shell can change the goal and erase slot B, so it's only offered when the UICR
+//! config enables the console, which production units turn off.
+//!
+//! A console that is left on in the field must not let anyone who gets to the UART change or read out the device,
+//! so `goal`, `dump` and `erase-b` are locked until `unlock`. That shows a random challenge, which must be answered
+//! with its HMAC-SHA256 with the maintenance key of the device (see
+//! [maintenance_response](dis_bootloader_core::crypto::maintenance_response)). The maintenance key is derived from
+//! the device secret in the KMU, so without the `hardware-huk` feature the commands stay locked. Every wrong response
+//! is recorded as a [SecurityEvent::ShellUnlockRejected] event and doubles the wait before the next attempt, and after
+//! [MAX_UNLOCK_ATTEMPTS] the shell stays locked until the next reset.
+
+use crate::{
+    boards::BOARD,
+    flash::Flash,
+    serial::{wait_for_byte, CYCLES_PER_MS},
+    trng, Uart, BUILD_INFO,
+};
+use arrayvec::ArrayString;
+use core::{
+    fmt::Write,
+    ops::{Deref, DerefMut},
+};
+use dis_bootloader_core::{
+    crypto::{constant_time_eq, maintenance_response, CHALLENGE_SIZE, KEY_SIZE},
+    events, uprintln, LogSink,
+};
+use shared::{
+    config::BootloaderConfig,
+    event_log::SecurityEvent,
+    flash_addresses::PAGE_SIZE,
+    image_header::ImageHeader,
+    slots,
+    state::{BootloaderGoal, BootloaderState},
+    Flash as _,
+};
+
+/// The key that is held to enter the shell
+const SHELL_KEY: u8 = b' ';
+/// How long the bootloader waits for the [SHELL_KEY] at every boot
+const SHELL_WINDOW_MS: u32 = 2000;
+/// How long the line must be quiet before the repeats of the held key are gone
+const REPEAT_SILENCE_MS: u32 = 100;
+
+/// The longest command line
+const LINE_LENGTH: usize = 64;
+/// The most bytes `dump` shows at once
+const MAX_DUMP_LENGTH: u32 = 4096;
+/// The bytes `dump` shows per line
+const DUMP_LINE_LENGTH: usize = 16;
+
+/// The failed unlock attempts after which the shell stays locked until the next reset
+const MAX_UNLOCK_ATTEMPTS: u32 = 5;
+/// The wait after the first failed unlock attempt, which doubles with every next one
+const UNLOCK_BACKOFF_MS: u32 = 1000;
+
+/// The UART as the console of the shell. The shell talks to a person on the UART, so unlike the log of the
+/// bootloader, its output never goes to defmt.
+struct Console<'a>(&'a mut Uart);
+
+impl Deref for Console<'_> {
+    type Target = Uart;
+
+    fn deref(&self) -> &Self::Target {
+        self.0
+    }
+}
+
+impl DerefMut for Console<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0
+    }
+}
+
+impl LogSink for Console<'_> {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.0.blocking_write(bytes).unwrap();
+    }
+}
+
+/// Returns true if the shell is forced by the button or the host asks for it
+pub fn is_requested(config: &BootloaderConfig, button_is_held: bool) -> bool {
+    config.logging_enabled()
+        && (button_is_held || wait_for_byte(SHELL_WINDOW_MS, |byte| byte == SHELL_KEY).is_some())
+}
+
+/// Reads and runs commands until the `boot` command
+pub fn run(flash: &mut Flash, uart: &mut Uart, config: &BootloaderConfig) {
+    let uart = &mut Console(uart);
+
+    // The key is still held, so its repeats must not end up in the first command
+    while wait_for_byte(REPEAT_SILENCE_MS, |_| true).is_some() {}
+
+    // Whatever is changed here, the core must look at the state
+    #[cfg(feature = "fast-wake")]
+    shared::mailbox::take_nothing_pending_hint();
+
+    uprintln!(uart, "Entered the shell, type `help` for the commands");
+
+    let mut unlocked = false;
+    let mut failed_attempts = 0;
+
+    loop {
+        let line = read_line(uart);
+        let mut words = line.split_whitespace();
+
+        match (words.next(), words.next(), words.next(), words.next()) {
+            (None, ..) => {}
+            (Some(command @ ("goal" | "dump" | "erase-b")), ..) if !unlocked => {
+                uprintln!(uart, "`{}` is locked, `unlock` the shell first", command)
+            }
+            (Some("help"), None, ..) => help(uart),
+            (Some("unlock"), None, ..) if unlocked => {
+                uprintln!(uart, "The shell is already unlocked")
+            }
+            (Some("unlock"), None, ..) => unlocked = unlock(flash, uart, &mut failed_attempts),
+            (Some("info"), None, ..) => info(flash, uart, config),
+            (Some("state"), None, ..) => state(flash, uart),
+            (Some("goal"), Some(goal), None, ..) => set_goal(flash, uart, goal),
+            (Some("dump"), Some(address), Some(length), None) => dump(flash, uart, address, length),
+            (Some("erase-b"), None, ..) => erase_slot_b(flash, uart),
+            (Some("boot"), None, ..) => break,
+            _ => uprintln!(
+                uart,
+                "Unknown command `{}`, type `help` for the commands",
+                line.trim()
+            ),
+        }
+    }
+
+    uprintln!(uart, "Leaving the shell");
+}
+
+/// Reads a line of printable characters, with an echo and backspace
+fn read_line(uart: &mut Console) -> ArrayString<LINE_LENGTH> {
+    let prompt = *b"> ";
+    uart.write_bytes(&prompt);
+
+    let mut line = ArrayString::new();
+    loop {
+        let mut byte = [0];
+        if uart.blocking_read(&mut byte).is_err() {
+            continue;
+        }
+
+        match byte[0] {
+            b'\r' | b'\n' => {
+                uprintln!(uart, "");
+                return line;
+            }
+            // Backspace or delete
+            0x08 | 0x7F => {
+                if line.pop().is_some() {
+                    let erase = *b"\x08 \x08";
+                    uart.write_bytes(&erase);
+                }
+            }
+            byte @ 0x20..=0x7E => {
+                if line.try_push(char::from(byte)).is_ok() {
+                    uart.write_bytes(&[byte]);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Parses exactly `N` bytes in hex
+fn parse_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != N * 2 {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+    for (byte, index) in bytes.iter_mut().zip((0..).step_by(2)) {
+        *byte = u8::from_str_radix(text.get(index..index + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Parses a decimal number, or a hexadecimal one with `0x` in front
+fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Lists the commands
+fn help(uart: &mut Console) {
+    uprintln!(
+        uart,
+        "info                  the build, board and UICR config"
+    );
+    uprintln!(
+        uart,
+        "unlock                unlock goal, dump and erase-b with the maintenance key"
+    );
+    uprintln!(uart, "state                 the bootloader state");
+    uprintln!(
+        uart,
+        "goal <n>              set goal n, if the current goal may be changed"
+    );
+    uprintln!(
+        uart,
+        "dump <addr> <len>     show the flash at addr, at most {} bytes",
+        MAX_DUMP_LENGTH
+    );
+    uprintln!(
+        uart,
+        "erase-b               erase slot B, if no goal is pending"
+    );
+    uprintln!(uart, "boot                  leave the shell and boot");
+}
+
+/// Shows what the bootloader logs at the start of the boot, plus the images in the slots
+fn info(flash: &Flash, uart: &mut Console, config: &BootloaderConfig) {
+    uprintln!(
+        uart,
+        "Bootloader version `{}.{}.{}` with git hash `{}`",
+        BUILD_INFO.version_major,
+        BUILD_INFO.version_minor,
+        BUILD_INFO.version_patch,
+        BUILD_INFO.git_hash()
+    );
+    uprintln!(
+        uart,
+        "Built at {} (unix time) with features {:#010X}",
+        BUILD_INFO.timestamp,
+        BUILD_INFO.features
+    );
+    uprintln!(
+        uart,
+        "Board `{}` with board ID {:?}",
+        BOARD.name,
+        dis_bootloader_core::board_check::board_id()
+    );
+    uprintln!(uart, "UICR config {:?}", config);
+
+    let [primary, secondary] = slots::default_layout();
+    for (name, slot) in [("A", primary), ("B", secondary)] {
+        match ImageHeader::load(flash, slot.address()) {
+            Some(header) => uprintln!(
+                uart,
+                "Slot {} has an image with version {:?}",
+                name,
+                header.version
+            ),
+            None => uprintln!(uart, "Slot {} has no image header", name),
+        }
+    }
+}
+
+/// Shows the fields of the state
+fn state(flash: &Flash, uart: &mut Console) {
+    let state = BootloaderState::load(flash);
+    if !state.is_valid() {
+        uprintln!(
+            uart,
+            "The state is not valid, the goal is {:?}",
+            state.current_goal()
+        );
+        return;
+    }
+
+    uprintln!(uart, "Goal: {:?}", state.current_goal());
+    uprintln!(
+        uart,
+        "Modem update status: {:?}",
+        state.modem_update_status()
+    );
+    uprintln!(uart, "Old image status: {:?}", state.old_image_status());
+    uprintln!(uart, "Failed test boots: {:?}", state.failed_test_boots());
+    uprintln!(uart, "Boot attempts: {}", state.boot_attempts());
+    uprintln!(uart, "Rollback reason: {:?}", state.rollback_reason());
+    uprintln!(uart, "Refused board ID: {:?}", state.refused_board_id());
+    uprintln!(uart, "Panics: {}", state.panic_count());
+    uprintln!(uart, "Reset history:");
+    for reason in state.reset_history() {
+        uprintln!(uart, "  {:#06X}", reason);
+    }
+    uprintln!(uart, "Last swap: {:?}", state.last_swap_statistics());
+}
+
+/// Sets the goal, which is only allowed for the goals the application could request too
+fn set_goal(flash: &mut Flash, uart: &mut Console, goal: &str) {
+    let goal = match parse_number(goal).and_then(|goal| BootloaderGoal::try_from(goal).ok()) {
+        Some(goal) => goal,
+        None => {
+            uprintln!(uart, "`{}` is not a goal", goal);
+            return;
+        }
+    };
+    if !goal.is_requestable() {
+        uprintln!(uart, "The goal {:?} is internal and can't be set", goal);
+        return;
+    }
+
+    // An internal goal is in the middle of changing the slots, which must be finished first
+    let current = BootloaderState::load(flash).current_goal();
+    match current {
+        Some(current) if current.is_requestable() => {
+            match BootloaderState::compare_and_set_goal(flash, current, goal) {
+                Ok(()) => uprintln!(uart, "Set the goal to {:?}", goal),
+                Err(error) => uprintln!(uart, "Could not set the goal: {:?}", error),
+            }
+        }
+        _ => uprintln!(uart, "The goal {:?} must be finished first", current),
+    }
+}
+
+/// Shows a range of the internal flash in hex
+fn dump(flash: &Flash, uart: &mut Console, address: &str, length: &str) {
+    let (address, length) = match (parse_number(address), parse_number(length)) {
+        (Some(address), Some(length)) => (address, length.min(MAX_DUMP_LENGTH)),
+        _ => {
+            uprintln!(uart, "The address and length must be numbers");
+            return;
+        }
+    };
+    let end = match address.checked_add(length) {
+        Some(end) if end <= flash.size() => end,
+        _ => {
+            uprintln!(
+                uart,
+                "Only the internal flash up to {:#010X} can be dumped",
+                flash.size()
+            );
+            return;
+        }
+    };
+
+    for (index, line) in flash
+        .read_u8(address..end)
+        .chunks(DUMP_LINE_LENGTH)
+        .enumerate()
+    {
+        uprintln!(
+            uart,
+            "{:#010X}: {:02X?}",
+            address as usize + index * DUMP_LINE_LENGTH,
+            line
+        );
+    }
+}
+
+/// Erases all pages of slot B
+fn erase_slot_b(flash: &mut Flash, uart: &mut Console) {
+    let goal = BootloaderState::load(flash).current_goal();
+    if goal != Some(BootloaderGoal::JumpToApplication) {
+        uprintln!(uart, "The goal {:?} may need slot B, it's not erased", goal);
+        return;
+    }
+
+    let [_, secondary] = slots::default_layout();
+    for page_address in
+        (secondary.address()..secondary.address() + secondary.size()).step_by(PAGE_SIZE as usize)
+    {
+        if let Err(error) = flash.erase_page(page_address) {
+            uprintln!(
+                uart,
+                "Could not erase the page at {:#010X}: {:?}",
+                page_address,
+                error
+            );
+            return;
+        }
+    }
+    uprintln!(uart, "Erased slot B");
+}
+
+/// Derives the maintenance key of the device, if it's provisioned and its secret hasn't been destroyed
+#[cfg(feature = "hardware-huk")]
+fn maintenance_key(flash: &Flash) -> Option<[u8; KEY_SIZE]> {
+    use dis_bootloader_core::crypto::{derive_maintenance_key, DeviceHuk};
+
+    crate::provisioning::device_id(flash)
+        .and_then(|device_id| derive_maintenance_key(&DeviceHuk, &device_id))
+}
+
+/// Without the device secret in the KMU there is no maintenance key, so the shell can't be unlocked
+#[cfg(not(feature = "hardware-huk"))]
+fn maintenance_key(_flash: &Flash) -> Option<[u8; KEY_SIZE]> {
+    None
+}
+
+/// Asks the response to a random challenge and returns true if it's the right one.
+/// A wrong response is recorded and makes the next attempt wait longer.
+fn unlock(flash: &mut Flash, uart: &mut Console, failed_attempts: &mut u32) -> bool {
+    if *failed_attempts >= MAX_UNLOCK_ATTEMPTS {
+        uprintln!(
+            uart,
+            "Too many failed attempts, the shell stays locked until the next reset"
+        );
+        return false;
+    }
+
+    let Some(key) = maintenance_key(flash) else {
+        uprintln!(uart, "There is no maintenance key, the shell stays locked");
+        return false;
+    };
+
+    let mut challenge = [0; CHALLENGE_SIZE];
+    for bytes in challenge.as_chunks_mut::<4>().0 {
+        match trng::random_u32() {
+            Some(random) => *bytes = random.to_le_bytes(),
+            None => {
+                uprintln!(uart, "The TRNG didn't produce a challenge");
+                return false;
+            }
+        }
+    }
+
+    let mut challenge_hex = ArrayString::<{ CHALLENGE_SIZE * 2 }>::new();
+    for byte in challenge {
+        write!(challenge_hex, "{:02X}", byte).unwrap();
+    }
+    uprintln!(uart, "Challenge: {}", challenge_hex);
+    uprintln!(
+        uart,
+        "Enter the HMAC-SHA256 of the challenge with the maintenance key in hex"
+    );
+
+    let expected = maintenance_response(&key, &challenge);
+    let is_valid = parse_hex::<32>(read_line(uart).trim())
+        .is_some_and(|response| constant_time_eq(&response, &expected));
+    if is_valid {
+        uprintln!(uart, "Unlocked the shell");
+        return true;
+    }
+
+    *failed_attempts += 1;
+    events::record(
+        flash,
+        &mut *uart.0,
+        SecurityEvent::ShellUnlockRejected,
+        *failed_attempts,
+    )
+    .ok();
+
+    // Guessing is slow enough already, but this makes trying many responses in one boot slower still
+    let backoff_ms = UNLOCK_BACKOFF_MS << (*failed_attempts - 1);
+    uprintln!(uart, "Wrong response, wait {} ms", backoff_ms);
+    for _ in 0..backoff_ms {
+        cortex_m::asm::delay(CYCLES_PER_MS);
+    }
+
+    false
+}
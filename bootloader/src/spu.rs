@@ -0,0 +1,210 @@
+//! Partitioning of the chip into a secure and a non-secure world with the System Protection Unit (SPU)
+//!
+//! The bootloader flash, scratch area, state and the RAM of the bootloader stay secure.
+//! The program slots, the application data, the rest of the RAM and all peripherals that allow it become non-secure.
+//! After that, the application is started in the non-secure state.
+//!
+//! Independently of that, the flash regions with the bootloader state can be made read-only until the next reset
+//! with [protect_state].
+//!
+//! With the `secure-services` feature, [enable_secure_services] makes the veneers of the secure services
+//! non-secure callable.
+
+// Depending on the features, only a part of this module is used
+#![cfg_attr(
+    not(all(feature = "non-secure", feature = "state-protection")),
+    allow(dead_code, unused_imports)
+)]
+
+use crate::flash::read_geometry;
+use nrf9160_pac::spu_s::RegisterBlock;
+use shared::flash_addresses::{
+    bootloader_flash_range, bootloader_scratch_range, bootloader_state_log_range,
+    bootloader_state_range,
+};
+
+/// The amount of flash regions the SPU divides the flash in
+const FLASH_REGIONS: u32 = 32;
+/// The RAM region that is used by the bootloader and that stays secure.
+/// This matches what Nordic's SPM does, so non-secure applications built for it run unchanged.
+const SECURE_RAM: core::ops::Range<u32> = 0x2000_0000..0x2001_0000;
+/// The size of a RAM region of the SPU
+const RAM_REGION_SIZE: u32 = 0x2000;
+
+/// The permission bits of the FLASHREGION and RAMREGION registers
+const PERM_EXECUTE: u32 = 1 << 0;
+const PERM_WRITE: u32 = 1 << 1;
+const PERM_READ: u32 = 1 << 2;
+const PERM_SECATTR: u32 = 1 << 4;
+const PERM_LOCK: u32 = 1 << 8;
+
+/// The fields of the PERIPHID PERM register
+const PERIPH_SECUREMAPPING_MASK: u32 = 0b11;
+const PERIPH_SECUREMAPPING_USER_SELECTABLE: u32 = 2;
+const PERIPH_SECUREMAPPING_SPLIT: u32 = 3;
+const PERIPH_SECATTR: u32 = 1 << 4;
+const PERIPH_DMASEC: u32 = 1 << 5;
+const PERIPH_PRESENT: u32 = 1 << 31;
+
+/// The SIZE value of the FLASHNSC registers for a non-secure callable region of 64 bytes
+#[cfg(feature = "secure-services")]
+const NSC_SIZE_64_BYTES: u32 = 2;
+
+/// The ID of the SPU itself, which must always stay secure
+const SPU_ID: usize = 3;
+
+/// The non-secure alias of the VTOR register
+const VTOR_NS: *mut u32 = 0xE002_ED08 as *mut u32;
+/// The control register of the SAU
+const SAU_CTRL: *mut u32 = 0xE000_EDD0 as *mut u32;
+/// The first of the NVIC interrupt target non-secure registers
+const NVIC_ITNS: *mut u32 = 0xE000_E380 as *mut u32;
+
+/// Returns the address ranges of the flash regions of the SPU
+fn flash_regions() -> impl Iterator<Item = core::ops::Range<u32>> {
+    let flash_region_size = read_geometry().size / FLASH_REGIONS;
+    (0..FLASH_REGIONS).map(move |index| {
+        let start = index * flash_region_size;
+        start..start + flash_region_size
+    })
+}
+
+/// Returns true if the ranges have at least one address in common
+fn overlaps(a: &core::ops::Range<u32>, b: &core::ops::Range<u32>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Configures the SPU so that everything the application needs is non-secure
+#[cfg(feature = "non-secure")]
+pub fn configure(spu: &RegisterBlock) {
+    // Let the SPU be the only thing that decides what's secure
+    unsafe {
+        SAU_CTRL.write_volatile(0b10); // ALLNS = 1, ENABLE = 0
+    }
+
+    for (region, region_range) in spu.flashregion.iter().zip(flash_regions()) {
+        let is_secure = [
+            bootloader_flash_range(),
+            bootloader_scratch_range(),
+            bootloader_state_log_range(),
+            bootloader_state_range(),
+        ]
+        .iter()
+        .any(|secure_range| overlaps(secure_range, &region_range));
+
+        let permissions = PERM_EXECUTE | PERM_WRITE | PERM_READ;
+        region.perm.write(|w| unsafe {
+            w.bits(if is_secure {
+                permissions | PERM_SECATTR
+            } else {
+                permissions
+            })
+        });
+    }
+
+    for (index, region) in spu.ramregion.iter().enumerate() {
+        let start = SECURE_RAM.start + index as u32 * RAM_REGION_SIZE;
+        let is_secure = SECURE_RAM.contains(&start);
+
+        let permissions = PERM_EXECUTE | PERM_WRITE | PERM_READ;
+        region.perm.write(|w| unsafe {
+            w.bits(if is_secure {
+                permissions | PERM_SECATTR
+            } else {
+                permissions
+            })
+        });
+    }
+
+    for (id, peripheral) in spu.periphid.iter().enumerate() {
+        let perm = peripheral.perm.read().bits();
+
+        let configurable = matches!(
+            perm & PERIPH_SECUREMAPPING_MASK,
+            PERIPH_SECUREMAPPING_USER_SELECTABLE | PERIPH_SECUREMAPPING_SPLIT
+        );
+
+        if perm & PERIPH_PRESENT == 0 || !configurable || id == SPU_ID {
+            continue;
+        }
+
+        peripheral
+            .perm
+            .write(|w| unsafe { w.bits(perm & !(PERIPH_SECATTR | PERIPH_DMASEC)) });
+
+        // The interrupt of the peripheral has the same number as its ID and must go to the non-secure world too
+        unsafe {
+            let itns = NVIC_ITNS.add(id / 32);
+            itns.write_volatile(itns.read_volatile() | 1 << (id % 32));
+        }
+    }
+
+    // All GPIO pins and DPPI channels are non-secure
+    spu.gpioport[0].perm.write(|w| unsafe { w.bits(0) });
+    spu.dppi[0].perm.write(|w| unsafe { w.bits(0) });
+
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// Makes the veneers of the [secure services](crate::secure_services) non-secure callable.
+///
+/// The non-secure callable region is at the end of the flash region that holds the veneers.
+#[cfg(feature = "secure-services")]
+pub fn enable_secure_services(spu: &RegisterBlock) {
+    use shared::secure_services::VENEERS_ADDRESS;
+
+    let flash_region_size = read_geometry().size / FLASH_REGIONS;
+    spu.flashnsc[0]
+        .region
+        .write(|w| unsafe { w.bits(VENEERS_ADDRESS / flash_region_size) });
+    spu.flashnsc[0]
+        .size
+        .write(|w| unsafe { w.bits(NSC_SIZE_64_BYTES) });
+
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// Removes the write permission of the flash regions that contain the bootloader state and its log
+/// and locks them, so the permissions can't be changed anymore until the next reset.
+///
+/// This must be the last thing that's done to the flash regions. After this, the state can only be changed
+/// by the bootloader at the next boot, for example through the [mailbox](shared::mailbox).
+/// Note that the scratch area shares its flash region with the state, so it becomes read-only as well.
+#[cfg(feature = "state-protection")]
+pub fn protect_state(spu: &RegisterBlock) {
+    for (region, region_range) in spu.flashregion.iter().zip(flash_regions()) {
+        let has_state = [bootloader_state_range(), bootloader_state_log_range()]
+            .iter()
+            .any(|state_range| overlaps(state_range, &region_range));
+        if has_state {
+            region
+                .perm
+                .modify(|r, w| unsafe { w.bits((r.bits() & !PERM_WRITE) | PERM_LOCK) });
+        }
+    }
+
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// Starts the non-secure application with the vector table at the given address
+///
+/// # Safety
+///
+/// The address must point to a valid vector table and the SPU must have been configured
+/// with [configure] so that the application can run in the non-secure state.
+#[cfg(feature = "non-secure")]
+pub unsafe fn jump(application_address: u32) -> ! {
+    let initial_stack_pointer = (application_address as *const u32).read_volatile();
+    let reset_vector = (application_address as *const u32).add(1).read_volatile();
+
+    VTOR_NS.write_volatile(application_address);
+    cortex_m::register::msp::write_ns(initial_stack_pointer);
+
+    // A branch to an address with the lowest bit cleared switches to the non-secure state
+    cortex_m::asm::bx_ns(reset_vector & !1);
+
+    unreachable!()
+}
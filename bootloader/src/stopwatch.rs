@@ -0,0 +1,42 @@
+//! Timing the bootloader with the RTC
+//!
+//! RTC0 counts the 32.768 kHz low frequency clock, which embassy has already started at init.
+//! With the prescaler it ticks at 1024 Hz, so its 24 bit counter only wraps after more than 4 hours.
+
+use shared::chip;
+
+/// The TASKS_START register of RTC0
+const RTC_TASKS_START: *mut u32 = chip::RTC0_ADDRESS as *mut u32;
+/// The TASKS_STOP register of RTC0
+const RTC_TASKS_STOP: *mut u32 = (chip::RTC0_ADDRESS + 0x4) as *mut u32;
+/// The TASKS_CLEAR register of RTC0
+const RTC_TASKS_CLEAR: *mut u32 = (chip::RTC0_ADDRESS + 0x8) as *mut u32;
+/// The COUNTER register of RTC0
+const RTC_COUNTER: *const u32 = (chip::RTC0_ADDRESS + 0x504) as *const u32;
+/// The PRESCALER register of RTC0
+const RTC_PRESCALER: *mut u32 = (chip::RTC0_ADDRESS + 0x508) as *mut u32;
+
+/// The prescaler that divides the 32.768 kHz clock down to 1024 Hz
+const PRESCALER: u32 = 31;
+/// The ticks per second of the counter
+const TICKS_PER_SECOND: u32 = 32_768 / (PRESCALER + 1);
+
+/// Starts counting from 0
+pub fn start() {
+    unsafe {
+        RTC_PRESCALER.write_volatile(PRESCALER);
+        RTC_TASKS_CLEAR.write_volatile(1);
+        RTC_TASKS_START.write_volatile(1);
+    }
+}
+
+/// Stops counting and returns the milliseconds since [start]. The RTC is left in its reset state for the application.
+pub fn stop() -> u32 {
+    unsafe {
+        let ticks = RTC_COUNTER.read_volatile();
+        RTC_TASKS_STOP.write_volatile(1);
+        RTC_TASKS_CLEAR.write_volatile(1);
+        RTC_PRESCALER.write_volatile(0);
+        (ticks as u64 * 1000 / TICKS_PER_SECOND as u64) as u32
+    }
+}
@@ -0,0 +1,52 @@
+//! Reading true random numbers from the TRNG of the CryptoCell
+//!
+//! The nRF9160 has no RNG peripheral of its own, so the random numbers come from the CC310 inside the CryptoCell.
+//! The nRF52840 has the same CC310, so it's used there as well.
+
+use shared::chip;
+
+/// The ENABLE register of the CRYPTOCELL peripheral
+const CRYPTOCELL_ENABLE: *mut u32 = (chip::CRYPTOCELL_ADDRESS + 0x500) as *mut u32;
+
+/// The registers of the RNG block of the CC310
+const RNG_ISR: *const u32 = (chip::CRYPTOCELL_ADDRESS + 0x1104) as *const u32;
+const RNG_ICR: *mut u32 = (chip::CRYPTOCELL_ADDRESS + 0x1108) as *mut u32;
+const TRNG_CONFIG: *mut u32 = (chip::CRYPTOCELL_ADDRESS + 0x110C) as *mut u32;
+const EHR_DATA: *const u32 = (chip::CRYPTOCELL_ADDRESS + 0x1114) as *const u32;
+const RND_SOURCE_ENABLE: *mut u32 = (chip::CRYPTOCELL_ADDRESS + 0x112C) as *mut u32;
+const SAMPLE_CNT1: *mut u32 = (chip::CRYPTOCELL_ADDRESS + 0x1130) as *mut u32;
+const RNG_CLK_ENABLE: *mut u32 = (chip::CRYPTOCELL_ADDRESS + 0x11C4) as *mut u32;
+
+/// The bit in [RNG_ISR] that tells the entropy holding register is full
+const EHR_VALID: u32 = 1 << 0;
+/// The amount of clock cycles between the samples of the ring oscillator
+const SAMPLE_COUNT: u32 = 0x30;
+
+/// Reads a random word from the TRNG.
+///
+/// Returns `None` if the TRNG didn't produce anything within a reasonable time.
+pub fn random_u32() -> Option<u32> {
+    unsafe {
+        CRYPTOCELL_ENABLE.write_volatile(1);
+        RNG_CLK_ENABLE.write_volatile(1);
+        SAMPLE_CNT1.write_volatile(SAMPLE_COUNT);
+        TRNG_CONFIG.write_volatile(0);
+        RND_SOURCE_ENABLE.write_volatile(1);
+
+        let is_valid = (0..100_000).any(|_| RNG_ISR.read_volatile() & EHR_VALID != 0);
+
+        // The holding register has 192 bits, we fold them into one word
+        let random = is_valid.then(|| {
+            (0..6).fold(0, |random, index| {
+                random ^ EHR_DATA.add(index).read_volatile()
+            })
+        });
+
+        RND_SOURCE_ENABLE.write_volatile(0);
+        RNG_ICR.write_volatile(0xFFFF_FFFF);
+        RNG_CLK_ENABLE.write_volatile(0);
+        CRYPTOCELL_ENABLE.write_volatile(0);
+
+        random
+    }
+}
@@ -0,0 +1,39 @@
+//! The watchdog timer
+//!
+//! The watchdog runs from the 32.768 kHz low frequency clock, like the RTC. Once started, it can't be stopped or
+//! reconfigured until the next reset, also not by the application. The bootloader feeds it with
+//! [feed_watchdog](crate::flash::feed_watchdog), the application through reload request register 0.
+
+use shared::chip;
+
+/// The TASKS_START register of the WDT
+const WDT_TASKS_START: *mut u32 = chip::WDT_ADDRESS as *mut u32;
+/// The counter reload value register of the WDT
+const WDT_CRV: *mut u32 = (chip::WDT_ADDRESS + 0x504) as *mut u32;
+/// The reload request enable register of the WDT
+const WDT_RREN: *mut u32 = (chip::WDT_ADDRESS + 0x508) as *mut u32;
+/// The CONFIG register of the WDT
+const WDT_CONFIG: *mut u32 = (chip::WDT_ADDRESS + 0x50C) as *mut u32;
+
+/// The ticks per second of the clock of the watchdog
+const WATCHDOG_CLOCK_HZ: u64 = 32_768;
+/// The lowest reload value the watchdog accepts
+const MINIMUM_RELOAD_VALUE: u32 = 0xF;
+/// The CONFIG value that keeps the watchdog running while the CPU sleeps, but pauses it while a debugger halts it
+const CONFIG_RUN_IN_SLEEP: u32 = 1 << 0;
+
+/// Starts the watchdog so the device resets after the given number of milliseconds, unless it's fed through reload
+/// request register 0
+pub fn start(timeout_ms: u64) {
+    let ticks = (timeout_ms.saturating_mul(WATCHDOG_CLOCK_HZ) / 1000)
+        .try_into()
+        .unwrap_or(u32::MAX)
+        .max(MINIMUM_RELOAD_VALUE);
+
+    unsafe {
+        WDT_CONFIG.write_volatile(CONFIG_RUN_IN_SLEEP);
+        WDT_CRV.write_volatile(ticks);
+        WDT_RREN.write_volatile(1);
+        WDT_TASKS_START.write_volatile(1);
+    }
+}
@@ -0,0 +1,43 @@
+MEMORY
+{
+    /* The memory of the emulated MPS2 AN505 (Cortex-M33) board */
+    FLASH : ORIGIN = 0x10000000, LENGTH = 4M
+    RAM   : ORIGIN = 0x38000000, LENGTH = 4M - 768
+    BOOT_INFO : ORIGIN = 0x383FFD00, LENGTH = 256
+    MEASUREMENTS : ORIGIN = 0x383FFE00, LENGTH = 256
+    MAILBOX : ORIGIN = 0x383FFF00, LENGTH = 256
+}
+
+_bootloader_measurements_start = ORIGIN(MEASUREMENTS);
+_bootloader_measurements_end = ORIGIN(MEASUREMENTS) + LENGTH(MEASUREMENTS);
+_bootloader_boot_info_start = ORIGIN(BOOT_INFO);
+_bootloader_boot_info_end = ORIGIN(BOOT_INFO) + LENGTH(BOOT_INFO);
+_bootloader_mailbox_start = ORIGIN(MAILBOX);
+_bootloader_mailbox_end = ORIGIN(MAILBOX) + LENGTH(MAILBOX);
+
+/* The layout of the emulated nRF9160 flash, the same as the one of the bootloader.
+ * These are addresses in the RAM backed flash device, not in the memory of the emulator. */
+_bootloader_flash_start = 0x00008000;
+_bootloader_flash_end = 0x00018000;
+_bootloader_descriptor_start = _bootloader_flash_end - 256;
+_bootloader_flash_trace_start = 0x000F7000;
+_bootloader_flash_trace_end = 0x000F8000;
+_bootloader_scratch_start = 0x000F8000;
+_bootloader_scratch_end = 0x000FA000;
+_bootloader_state_log_start = 0x000FA000;
+_bootloader_state_log_end = 0x000FB000;
+_bootloader_panic_log_start = 0x000FB000;
+_bootloader_panic_log_end = 0x000FC000;
+_bootloader_revocations_start = 0x000FC000;
+_bootloader_revocations_end = 0x000FD000;
+_bootloader_event_log_start = 0x000FD000;
+_bootloader_event_log_end = 0x000FE000;
+_bootloader_state_start = 0x000FE000;
+_bootloader_state_end = 0x00100000;
+
+_program_slot_a_start = 0x00018000;
+_program_slot_a_end = 0x00080000;
+_program_slot_b_start = 0x00080000;
+_program_slot_b_end = 0x000E8000;
+_modem_staging_start = _program_slot_b_start;
+_modem_staging_end = _program_slot_b_end;
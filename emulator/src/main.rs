@@ -0,0 +1,242 @@
+//! Runs the core logic of the bootloader on an emulated Cortex-M33 in QEMU
+//!
+//! The nRF9160 flash is replaced by a [RamFlash] and the log output goes to the host over semihosting.
+//! This makes it possible to step through the swap, including the corner cases around power cuts, without any hardware.
+//!
+//! Run it from this directory with `cargo run --release`. QEMU (`qemu-system-arm`) must be installed.
+
+#![no_main]
+#![no_std]
+
+use crate::ram_flash::RamFlash;
+use cortex_m_rt::entry;
+use cortex_m_semihosting::{debug, hio};
+use dis_bootloader_core::{perform_swap, uprintln, LogSink};
+use panic_semihosting as _;
+use shared::{
+    flash_addresses::{program_slot_a_range, program_slot_b_range, PAGE_SIZE},
+    image_header::{ImageHeader, ImageVersion},
+    state::{BootloaderGoal, BootloaderState},
+    Flash,
+};
+
+mod ram_flash;
+
+/// The size of the emulated flash in bytes
+const FLASH_SIZE: usize = 0x0010_0000;
+
+/// The memory of the emulated flash
+static mut FLASH_MEMORY: [u32; FLASH_SIZE / 4] = [0; FLASH_SIZE / 4];
+
+/// The seed of the image that is in slot A before the update
+const OLD_IMAGE: u32 = 0xAAAA_0000;
+/// The seed of the image that is in slot B before the update
+const NEW_IMAGE: u32 = 0xBBBB_0000;
+
+/// A log sink that writes to the stdout of the host
+struct SemihostingLog {
+    stdout: Option<hio::HostStream>,
+    /// When true, all output is dropped
+    quiet: bool,
+}
+
+impl LogSink for SemihostingLog {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        if let (Some(stdout), false) = (&mut self.stdout, self.quiet) {
+            stdout.write_all(bytes).ok();
+        }
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    let mut flash = RamFlash::new(unsafe { &mut *core::ptr::addr_of_mut!(FLASH_MEMORY) });
+    let mut log = SemihostingLog {
+        stdout: hio::hstdout().ok(),
+        quiet: false,
+    };
+
+    let success = update(&mut flash, &mut log) && power_cuts(&mut flash, &mut log);
+
+    uprintln!(
+        log,
+        "\nEmulation {}",
+        if success { "passed" } else { "failed" }
+    );
+    debug::exit(if success {
+        debug::EXIT_SUCCESS
+    } else {
+        debug::EXIT_FAILURE
+    });
+
+    loop {}
+}
+
+/// Runs a normal update from start to finish
+fn update(flash: &mut RamFlash, log: &mut SemihostingLog) -> bool {
+    uprintln!(log, "Running a normal update");
+
+    prepare_update(flash);
+    let application_address = dis_bootloader_core::run(flash, log).unwrap();
+
+    uprintln!(
+        log,
+        "The bootloader would jump to {:#010X}",
+        application_address
+    );
+
+    application_address == program_slot_a_range().start + ImageHeader::VECTOR_TABLE_OFFSET
+        && slots_are_swapped(flash)
+}
+
+/// Cuts the power at many points during an update and checks that the update finishes after the reboot
+fn power_cuts(flash: &mut RamFlash, log: &mut SemihostingLog) -> bool {
+    uprintln!(log, "\nCutting the power during updates");
+    log.quiet = true;
+
+    for cut_after in (1..).step_by(7) {
+        prepare_update(flash);
+        flash.cut_power_after(Some(cut_after));
+
+        // Do what the bootloader does for `StartSwap` until the power is gone
+        let mut state = BootloaderState::load(flash);
+        state
+            .prepare_swap(BootloaderGoal::StartSwap, false, flash)
+            .unwrap();
+        let result = perform_swap(false, &mut state, flash, log);
+
+        let power_was_cut = flash.power_is_cut();
+
+        // Without power, the pages don't read back like they were written, so the swap stops with an error
+        if result.is_err() && !power_was_cut {
+            log.quiet = false;
+            uprintln!(log, "The swap failed without a power cut: {:?}", result);
+            return false;
+        }
+
+        // Reboot
+        flash.cut_power_after(None);
+        dis_bootloader_core::run(flash, log).unwrap();
+
+        if !slots_are_swapped(flash) {
+            log.quiet = false;
+            uprintln!(
+                log,
+                "Update failed with a power cut after {} operations",
+                cut_after
+            );
+            return false;
+        }
+
+        if !power_was_cut {
+            // The update finished before the power was cut, so we've tried every part of it
+            log.quiet = false;
+            uprintln!(
+                log,
+                "Survived all power cuts up to {} operations",
+                cut_after
+            );
+            return true;
+        }
+    }
+
+    unreachable!()
+}
+
+/// Puts the old image in slot A and the new image in slot B and asks the bootloader to swap them
+fn prepare_update(flash: &mut RamFlash) {
+    flash.erase_all();
+
+    for (slot_start, image) in [
+        (program_slot_a_range().start, OLD_IMAGE),
+        (program_slot_b_range().start, NEW_IMAGE),
+    ] {
+        for page_offset in (0..program_slot_a_range().len() as u32).step_by(PAGE_SIZE as usize) {
+            flash
+                .program_page(slot_start + page_offset, &image_page(image, page_offset))
+                .unwrap();
+        }
+    }
+
+    let mut state = BootloaderState::load(flash);
+    state
+        .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap)
+        .unwrap();
+    state.set_valid(true);
+    state.store(flash).unwrap();
+}
+
+/// Returns true if slot A contains the new image and slot B the old image
+fn slots_are_swapped(flash: &RamFlash) -> bool {
+    (0..program_slot_a_range().len() as u32)
+        .step_by(PAGE_SIZE as usize)
+        .all(|page_offset| {
+            let slot_a_page = program_slot_a_range().start + page_offset;
+            let slot_b_page = program_slot_b_range().start + page_offset;
+
+            flash.read_u32(slot_a_page..slot_a_page + PAGE_SIZE)
+                == image_page(NEW_IMAGE, page_offset)
+                && flash.read_u32(slot_b_page..slot_b_page + PAGE_SIZE)
+                    == image_page(OLD_IMAGE, page_offset)
+        })
+}
+
+/// Creates the contents of a page of a fake image.
+///
+/// The image starts with an [ImageHeader] over the whole slot, followed by a vector table that is valid for slot A,
+/// so the bootloader will accept it.
+fn image_page(image: u32, page_offset: u32) -> [u32; PAGE_SIZE as usize / 4] {
+    let mut page = image_body_page(image, page_offset);
+
+    if page_offset == 0 {
+        page[..ImageHeader::SIZE as usize / 4].copy_from_slice(&image_header(image).to_words());
+    }
+
+    page
+}
+
+/// Creates the contents of a page of a fake image, without the header
+fn image_body_page(image: u32, page_offset: u32) -> [u32; PAGE_SIZE as usize / 4] {
+    let mut page: [u32; PAGE_SIZE as usize / 4] =
+        core::array::from_fn(|index| image ^ (page_offset + index as u32 * 4));
+
+    if page_offset == 0 {
+        // The initial stack pointer and the reset vector
+        let vector_table = ImageHeader::VECTOR_TABLE_OFFSET as usize / 4;
+        page[vector_table] = 0x2000_8000;
+        page[vector_table + 1] = program_slot_a_range().start + 0x201;
+    }
+
+    page
+}
+
+/// Creates the header of a fake image, which covers the rest of the slot
+fn image_header(image: u32) -> ImageHeader {
+    let crc = crc::Crc::<u32>::new(&crc::CRC_32_MPEG_2);
+    let mut digest = crc.digest();
+    for page_offset in (0..program_slot_a_range().len() as u32).step_by(PAGE_SIZE as usize) {
+        let page = image_body_page(image, page_offset);
+        let header_words = if page_offset == 0 {
+            ImageHeader::SIZE as usize / 4
+        } else {
+            0
+        };
+        for word in &page[header_words..] {
+            digest.update(&word.to_le_bytes());
+        }
+    }
+
+    ImageHeader {
+        length: program_slot_a_range().len() as u32 - ImageHeader::SIZE,
+        crc: digest.finalize(),
+        ..ImageHeader::new(
+            ImageVersion {
+                major: 1,
+                minor: 0,
+                patch: 0,
+                build: 0,
+            },
+            &[],
+        )
+    }
+}
@@ -0,0 +1,124 @@
+//! A flash device that lives in RAM
+
+use core::{mem::size_of, ops::Range};
+use shared::{flash_addresses::PAGE_SIZE, flash_geometry::FlashGeometry, FlashError};
+
+/// An implementation of [shared::Flash] that is backed by RAM.
+///
+/// It behaves like NOR flash, so programming can only change bits from 1 to 0.
+/// It can also simulate a power cut, after which all erase and program operations are ignored.
+pub struct RamFlash {
+    memory: &'static mut [u32],
+    operations_left: Option<u32>,
+}
+
+impl RamFlash {
+    /// Creates a new flash device with the given memory. All pages are erased.
+    pub fn new(memory: &'static mut [u32]) -> Self {
+        memory.fill(0xFFFF_FFFF);
+
+        Self {
+            memory,
+            operations_left: None,
+        }
+    }
+
+    /// Cuts the power after the given amount of erase and program operations.
+    /// With `None`, the power is restored and stays on.
+    pub fn cut_power_after(&mut self, operations: Option<u32>) {
+        self.operations_left = operations;
+    }
+
+    /// Returns true if the power has been cut
+    pub fn power_is_cut(&self) -> bool {
+        self.operations_left == Some(0)
+    }
+
+    /// Erases the entire flash
+    pub fn erase_all(&mut self) {
+        self.memory.fill(0xFFFF_FFFF);
+    }
+
+    /// Uses up one operation and returns whether there was still power to perform it
+    fn take_operation(&mut self) -> bool {
+        match &mut self.operations_left {
+            None => true,
+            Some(0) => false,
+            Some(operations_left) => {
+                *operations_left -= 1;
+                true
+            }
+        }
+    }
+
+    fn page_words(&mut self, page_address: u32) -> Result<&mut [u32], FlashError> {
+        if page_address % PAGE_SIZE != 0 {
+            return Err(FlashError::Alignment);
+        }
+        let start = page_address as usize / size_of::<u32>();
+        self.memory
+            .get_mut(start..start + PAGE_SIZE as usize / size_of::<u32>())
+            .ok_or(FlashError::OutOfRange)
+    }
+}
+
+impl shared::Flash for RamFlash {
+    fn erase_page(&mut self, page_address: u32) -> Result<(), FlashError> {
+        let has_power = self.take_operation();
+        let page = self.page_words(page_address)?;
+
+        if has_power {
+            page.fill(0xFFFF_FFFF);
+        }
+
+        Ok(())
+    }
+
+    fn program_page(&mut self, page_address: u32, data: &[u32]) -> Result<(), FlashError> {
+        if data.len() > PAGE_SIZE as usize / size_of::<u32>() {
+            return Err(FlashError::OutOfRange);
+        }
+
+        let has_power = self.take_operation();
+        let page = self.page_words(page_address)?;
+
+        if has_power {
+            // Programming can only clear bits
+            for (flash_word, data_word) in page.iter_mut().zip(data) {
+                *flash_word &= *data_word;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_u8(&self, address_range: Range<u32>) -> &[u8] {
+        let memory_bytes = unsafe {
+            core::slice::from_raw_parts(
+                self.memory.as_ptr() as *const u8,
+                self.memory.len() * size_of::<u32>(),
+            )
+        };
+
+        memory_bytes
+            .get(address_range.start as usize..address_range.end as usize)
+            .unwrap()
+    }
+
+    fn read_u32(&self, address_range: Range<u32>) -> &[u32] {
+        assert!(address_range.start % 4 == 0);
+        assert!(address_range.end % 4 == 0);
+
+        self.memory
+            .get(address_range.start as usize / 4..address_range.end as usize / 4)
+            .unwrap()
+    }
+
+    fn geometry(&self) -> FlashGeometry {
+        FlashGeometry {
+            page_size: PAGE_SIZE,
+            size: (self.memory.len() * size_of::<u32>()) as u32,
+            external_size: 0,
+        }
+    }
+}
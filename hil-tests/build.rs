@@ -0,0 +1,25 @@
+//! The tests run with the memory layout of the bootloader, so this build script
+//! puts the `memory.x` of the bootloader and its flash layout on the linker search path.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+#[path = "../shared/partitions.rs"]
+mod partitions;
+
+fn main() {
+    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::copy("../bootloader/memory.x", out.join("memory.x")).unwrap();
+    // The tests are flashed into the bootloader partition
+    fs::write(
+        out.join("partitions.x"),
+        partitions::Layout::load().linker_script("bootloader_flash"),
+    )
+    .unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=../bootloader/memory.x");
+
+    println!("cargo:rustc-link-arg-tests=-Tlink.x");
+    println!("cargo:rustc-link-arg-tests=-Tdefmt.x");
+}
@@ -0,0 +1,85 @@
+//! Hardware-in-the-loop tests for the bootloader
+//!
+//! The tests run on a real nRF9160 or nRF52840 board against the real NVMC and use the same memory layout as the bootloader.
+//! They are flashed into the bootloader region and erase the scratch, the panic log, the state and both program slots,
+//! so the device has to be reflashed afterwards.
+//!
+//! Run them with a probe attached using `cargo test -p hil-tests`. Another board can be selected with
+//! `--no-default-features --features <board>`, which for the `nrf52840_dk` also needs
+//! `--target thumbv7em-none-eabihf` and `probe-run --chip nRF52840_xxAA` as the runner.
+//!
+//! This library contains the glue the test binaries share.
+
+#![no_std]
+
+use core::{
+    future::Future,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+use dis_bootloader_core::{Level, LogSink};
+use shared::{flash_addresses::PAGE_SIZE, Flash as _};
+
+use defmt_rtt as _;
+use panic_probe as _;
+
+// The flash driver, its supply check and the board definitions are taken directly from the bootloader
+#[allow(dead_code)]
+#[path = "../../bootloader/src/boards/mod.rs"]
+mod boards;
+#[path = "../../bootloader/src/flash.rs"]
+pub mod flash;
+#[path = "../../bootloader/src/power.rs"]
+mod power;
+
+/// Creates the flash driver of the bootloader
+pub fn flash() -> flash::Flash<'static> {
+    flash::Flash::new(unsafe { &*embassy_nrf::pac::NVMC::PTR })
+}
+
+/// A [LogSink] that forwards the log output of the bootloader to defmt, with the levels of defmt
+pub struct DefmtLog;
+
+impl LogSink for DefmtLog {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        defmt::info!("{=[u8]:a}", bytes);
+    }
+
+    fn log(&mut self, level: Level, args: core::fmt::Arguments) {
+        let message = defmt::Display2Format(&args);
+        match level {
+            Level::Error => defmt::error!("{}", message),
+            Level::Warn => defmt::warn!("{}", message),
+            Level::Info => defmt::info!("{}", message),
+            Level::Debug => defmt::debug!("{}", message),
+        }
+    }
+}
+
+/// Creates the contents of a page that is unique for the given seed
+pub fn pattern(seed: u32) -> [u32; PAGE_SIZE as usize / 4] {
+    core::array::from_fn(|index| seed.rotate_left(index as u32 % 32) ^ index as u32)
+}
+
+/// Erases the page and programs the pattern of the seed into it
+pub fn fill_page(flash: &mut flash::Flash, page_address: u32, seed: u32) {
+    flash.erase_page(page_address).unwrap();
+    flash.program_page(page_address, &pattern(seed)).unwrap();
+}
+
+/// Returns true if the page contains the pattern of the seed
+pub fn page_has_pattern(flash: &flash::Flash, page_address: u32, seed: u32) -> bool {
+    flash.read_u32(page_address..page_address + PAGE_SIZE) == pattern(seed)
+}
+
+/// Polls the future until it's done. It's enough for the flash driver, which wakes itself.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
@@ -0,0 +1,550 @@
+#![no_std]
+#![no_main]
+
+use hil_tests::{block_on, fill_page, flash, page_has_pattern, pattern};
+use shared::{
+    board_config::{self, UicrBoardConfig},
+    board_id,
+    boot_info::{self, BootInfo},
+    chip,
+    config::CONFIG_ADDRESS,
+    flash_addresses::{self, bootloader_scratch_range, PAGE_SIZE},
+    flash_geometry::FlashGeometry,
+    hardware_revision::HARDWARE_REVISION_ADDRESS,
+    identity::{DeviceIdentity, IDENTITY_ADDRESS},
+    image_header::{verify_image, ImageError, ImageHeader, ImageVersion},
+    mcuboot::{McubootHeader, Tlv, TlvInfo, TLV_BOARD_ID, TLV_CRITICAL, TLV_SEC_CNT, TLV_SHA256},
+    panic_log, partitions,
+    recovery::{FrameHeader, FrameKind, MAX_PAYLOAD},
+    security_counter,
+    slots::{SlotDescriptor, SlotRole, APPLICATION_IMAGE},
+    state::BootloaderGoal,
+    xmodem, Flash, FlashError,
+};
+
+#[defmt_test::tests]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn erase_page_sets_all_bits() {
+        let mut flash = flash();
+        let page_address = bootloader_scratch_range().start;
+
+        fill_page(&mut flash, page_address, 0x1234_5678);
+        flash.erase_page(page_address).unwrap();
+
+        assert!(flash
+            .read_u32(page_address..page_address + PAGE_SIZE)
+            .iter()
+            .all(|word| *word == 0xFFFF_FFFF));
+    }
+
+    #[test]
+    fn program_page_writes_data() {
+        let mut flash = flash();
+        let page_address = bootloader_scratch_range().start;
+
+        fill_page(&mut flash, page_address, 0xDEAD_BEEF);
+
+        assert!(page_has_pattern(&flash, page_address, 0xDEAD_BEEF));
+    }
+
+    #[test]
+    fn program_page_without_erase_only_clears_bits() {
+        let mut flash = flash();
+        let page_address = bootloader_scratch_range().start;
+
+        flash.erase_page(page_address).unwrap();
+        flash.program_page(page_address, &[0xFFFF_0000]).unwrap();
+        flash.program_page(page_address, &[0x00FF_FF00]).unwrap();
+
+        assert_eq!(
+            flash.read_u32(page_address..page_address + 4),
+            &[0x00FF_0000]
+        );
+    }
+
+    #[test]
+    fn invalid_page_addresses_are_rejected() {
+        let mut flash = flash();
+        let page_address = bootloader_scratch_range().start;
+
+        assert_eq!(
+            flash.erase_page(page_address + 4),
+            Err(FlashError::Alignment)
+        );
+        assert_eq!(
+            flash.program_page(0x1000_0000, &[0]),
+            Err(FlashError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn async_erase_and_program_match_the_blocking_ones() {
+        let mut flash = flash();
+        let page_address = bootloader_scratch_range().start;
+
+        fill_page(&mut flash, page_address, 0x0BAD_F00D);
+        block_on(shared::AsyncFlash::erase_page(&mut flash, page_address)).unwrap();
+        assert!(flash
+            .read_u32(page_address..page_address + PAGE_SIZE)
+            .iter()
+            .all(|word| *word == 0xFFFF_FFFF));
+
+        block_on(shared::AsyncFlash::program_page(
+            &mut flash,
+            page_address,
+            &pattern(0x0BAD_F00D),
+        ))
+        .unwrap();
+        assert!(page_has_pattern(&flash, page_address, 0x0BAD_F00D));
+    }
+
+    #[test]
+    fn read_u8_matches_read_u32() {
+        let mut flash = flash();
+        let page_address = bootloader_scratch_range().start;
+
+        fill_page(&mut flash, page_address, 0xCAFE_F00D);
+
+        let bytes = flash.read_u8(page_address..page_address + PAGE_SIZE);
+        let words = flash.read_u32(page_address..page_address + PAGE_SIZE);
+        assert!(bytes
+            .chunks_exact(4)
+            .zip(words)
+            .all(|(bytes, word)| bytes == word.to_le_bytes()));
+    }
+
+    #[test]
+    fn read_rejects_ranges_outside_the_flash() {
+        let mut flash = flash();
+        let page_address = bootloader_scratch_range().start;
+
+        fill_page(&mut flash, page_address, 0xCAFE_F00D);
+
+        let mut buffer = [0; 8];
+        flash.read(page_address, &mut buffer).unwrap();
+        assert_eq!(buffer, [0x0D, 0xF0, 0xFE, 0xCA, 0x0D, 0xF0, 0xFE, 0xCA]);
+
+        // The end of the flash, the RAM and an address range that wraps around
+        let end = flash.size();
+        assert_eq!(
+            flash.read(end - 4, &mut buffer),
+            Err(FlashError::OutOfRange)
+        );
+        assert_eq!(
+            flash.read(0x2000_0000, &mut buffer),
+            Err(FlashError::OutOfRange)
+        );
+        assert_eq!(
+            flash.read(u32::MAX - 3, &mut buffer),
+            Err(FlashError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn geometry_is_read_from_the_ficr() {
+        let flash = flash();
+
+        assert_eq!(flash.geometry, FlashGeometry::NRF9160);
+        assert!(flash.geometry.fits_layout());
+        assert_eq!(flash.geometry.page_count(), 256);
+
+        // An erased FICR falls back to the nRF9160
+        assert_eq!(
+            FlashGeometry::from_ficr_words(0xFFFF_FFFF, 0xFFFF_FFFF),
+            FlashGeometry::NRF9160
+        );
+        assert!(!FlashGeometry::from_ficr_words(0x2000, 128).fits_layout());
+    }
+
+    #[test]
+    fn partition_constants_match_the_linker_script() {
+        assert_eq!(partitions::PAGE_SIZE, PAGE_SIZE);
+        assert_eq!(
+            partitions::BOOTLOADER_FLASH,
+            flash_addresses::bootloader_flash_range()
+        );
+        assert_eq!(
+            partitions::BOOTLOADER_SCRATCH,
+            flash_addresses::bootloader_scratch_range()
+        );
+        assert_eq!(
+            partitions::BOOTLOADER_STATE_LOG,
+            flash_addresses::bootloader_state_log_range()
+        );
+        assert_eq!(
+            partitions::BOOTLOADER_STATE,
+            flash_addresses::bootloader_state_range()
+        );
+        assert_eq!(
+            partitions::PROGRAM_SLOT_A,
+            flash_addresses::program_slot_a_range()
+        );
+        assert_eq!(
+            partitions::PROGRAM_SLOT_B,
+            flash_addresses::program_slot_b_range()
+        );
+    }
+
+    #[test]
+    fn image_header_roundtrip() {
+        let mut flash = flash();
+        let page_address = bootloader_scratch_range().start;
+
+        let header = ImageHeader {
+            board_id: board_id::MOBILITY,
+            ..ImageHeader::new(
+                ImageVersion {
+                    major: 1,
+                    minor: 2,
+                    patch: 3,
+                    build: 4,
+                },
+                &[0x12, 0x34],
+            )
+        };
+        flash.erase_page(page_address).unwrap();
+        flash
+            .program_page(page_address, &header.to_words())
+            .unwrap();
+        assert_eq!(ImageHeader::load(&flash, page_address), Some(header));
+
+        // A newer build of the same version is newer, an older minor version with a higher build isn't
+        assert!(
+            ImageVersion {
+                build: 5,
+                ..header.version
+            } > header.version
+        );
+        assert!(
+            ImageVersion {
+                minor: 1,
+                build: 100,
+                ..header.version
+            } < header.version
+        );
+
+        flash.erase_page(page_address).unwrap();
+        assert_eq!(ImageHeader::load(&flash, page_address), None);
+    }
+
+    #[test]
+    fn verify_image_detects_a_corrupt_image() {
+        let mut flash = flash();
+        let slot = SlotDescriptor {
+            role: SlotRole::Scratch,
+            range: bootloader_scratch_range(),
+            image_id: APPLICATION_IMAGE,
+            executable: false,
+            vector_table_offset: None,
+            excluded_pages: &[],
+        };
+        let image_range = slot.address() + ImageHeader::SIZE..slot.address() + PAGE_SIZE;
+
+        // The image is the rest of the first page, the header is programmed in front of it afterwards
+        let mut page = pattern(0x1A6E);
+        page[..ImageHeader::SIZE as usize / 4].fill(0xFFFF_FFFF);
+        flash.erase_page(slot.address()).unwrap();
+        flash.program_page(slot.address(), &page).unwrap();
+        let header = ImageHeader::new(
+            ImageVersion {
+                major: 1,
+                minor: 0,
+                patch: 0,
+                build: 0,
+            },
+            flash.read_u8(image_range.clone()),
+        );
+        flash
+            .program_page(slot.address(), &header.to_words())
+            .unwrap();
+        assert_eq!(verify_image(&flash, &slot), Ok(header));
+
+        // Clearing a single bit of the image breaks the CRC
+        let last_word = image_range.end - 4;
+        let word = flash.read_u32(last_word..image_range.end)[0];
+        flash.program_page(last_word, &[word & (word - 1)]).unwrap();
+        assert_eq!(verify_image(&flash, &slot), Err(ImageError::CrcMismatch));
+
+        flash.erase_page(slot.address()).unwrap();
+        assert_eq!(verify_image(&flash, &slot), Err(ImageError::NoHeader));
+    }
+
+    #[test]
+    fn mcuboot_header_gives_the_image_layout() {
+        let mut flash = flash();
+        let page_address = bootloader_scratch_range().start;
+
+        // A 0x200 byte header, 0x100 bytes of image and the unprotected TLVs right after it, with only a digest
+        let mut page = [0xFFFF_FFFF; PAGE_SIZE as usize / 4];
+        page[..8].copy_from_slice(&[McubootHeader::MAGIC, 0, 0x200, 0x100, 0, 0x0003_0201, 4, 0]);
+        page[0x300 / 4] = u32::from(TlvInfo::MAGIC) | 0x28 << 16;
+        page[0x304 / 4] = u32::from(TLV_SHA256) | 32 << 16;
+        flash.erase_page(page_address).unwrap();
+        flash.program_page(page_address, &page).unwrap();
+
+        let header = McubootHeader::load(&flash, page_address).unwrap();
+        assert_eq!(header.vector_table_offset(), 0x200);
+        assert_eq!(
+            header.version,
+            ImageVersion {
+                major: 1,
+                minor: 2,
+                patch: 3,
+                build: 4,
+            }
+        );
+        assert_eq!(
+            header.image_range(page_address),
+            page_address + 0x200..page_address + 0x300
+        );
+        assert_eq!(
+            header.tlv_range(&flash, page_address, page_address + PAGE_SIZE),
+            Some(page_address + 0x300..page_address + 0x328)
+        );
+        assert_eq!(
+            header.find_tlv(&flash, page_address, page_address + PAGE_SIZE, TLV_SHA256),
+            Some(page_address + 0x308..page_address + 0x328)
+        );
+        assert_eq!(
+            header.hashed_range(page_address),
+            page_address..page_address + 0x300
+        );
+
+        // The TLVs can't run past the end of the slot
+        assert_eq!(
+            header.tlv_range(&flash, page_address, page_address + 0x320),
+            None
+        );
+    }
+    #[test]
+    fn security_counter_is_read_from_the_protected_tlvs() {
+        let mut flash = flash();
+        let page_address = bootloader_scratch_range().start;
+
+        // The protected TLVs with the security counter come before the unprotected TLVs, which are empty
+        let mut page = [0xFFFF_FFFF; PAGE_SIZE as usize / 4];
+        page[..8].copy_from_slice(&[McubootHeader::MAGIC, 0, 0x200 | 12 << 16, 0x100, 0, 0, 0, 0]);
+        page[0x300 / 4] = u32::from(TlvInfo::PROTECTED_MAGIC) | 12 << 16;
+        page[0x304 / 4] = u32::from(TLV_SEC_CNT) | 4 << 16;
+        page[0x308 / 4] = 7;
+        page[0x30C / 4] = u32::from(TlvInfo::MAGIC) | 4 << 16;
+        flash.erase_page(page_address).unwrap();
+        flash.program_page(page_address, &page).unwrap();
+
+        let header = McubootHeader::load(&flash, page_address).unwrap();
+        assert_eq!(
+            header.security_counter(&flash, page_address, page_address + PAGE_SIZE),
+            Some(7)
+        );
+
+        // The UICR words count up by clearing bits and never go back
+        let words = security_counter::uicr_words(37);
+        assert_eq!(words[..3], [0, 0xFFFF_FFE0, 0xFFFF_FFFF]);
+        assert_eq!(security_counter::from_uicr_words(words), 37);
+        assert_eq!(
+            security_counter::from_uicr_words(security_counter::uicr_words(u32::MAX)),
+            security_counter::CAPACITY
+        );
+    }
+    #[test]
+    fn tlvs_are_iterated_and_unknown_critical_ones_are_found() {
+        let mut flash = flash();
+        let page_address = bootloader_scratch_range().start;
+
+        // A protected board ID, and an unprotected unknown TLV that is made critical below
+        let mut page = [0xFFFF_FFFF; PAGE_SIZE as usize / 4];
+        page[..8].copy_from_slice(&[McubootHeader::MAGIC, 0, 0x200 | 12 << 16, 0x100, 0, 0, 0, 0]);
+        page[0x300 / 4] = u32::from(TlvInfo::PROTECTED_MAGIC) | 12 << 16;
+        page[0x304 / 4] = u32::from(TLV_BOARD_ID) | 4 << 16;
+        page[0x308 / 4] = 0x42;
+        page[0x30C / 4] = u32::from(TlvInfo::MAGIC) | 12 << 16;
+        page[0x310 / 4] = 0xA5 | 4 << 16;
+        flash.erase_page(page_address).unwrap();
+        flash.program_page(page_address, &page).unwrap();
+
+        let header = McubootHeader::load(&flash, page_address).unwrap();
+        let slot_end = page_address + PAGE_SIZE;
+        let mut tlvs = header.tlvs(&flash, page_address, slot_end).unwrap();
+        assert_eq!(
+            tlvs.next(),
+            Some(Ok(Tlv {
+                tlv_type: TLV_BOARD_ID,
+                value: page_address + 0x308..page_address + 0x30C,
+                protected: true,
+            }))
+        );
+        assert_eq!(
+            tlvs.next(),
+            Some(Ok(Tlv {
+                tlv_type: 0xA5,
+                value: page_address + 0x314..page_address + 0x318,
+                protected: false,
+            }))
+        );
+        assert_eq!(tlvs.next(), None);
+        assert_eq!(
+            header.unknown_critical_tlv(&flash, page_address, slot_end),
+            None
+        );
+
+        // Programming can only clear bits, so the unknown TLV is rewritten on a fresh page
+        page[0x310 / 4] = u32::from(0xA5 | TLV_CRITICAL) | 4 << 16;
+        flash.erase_page(page_address).unwrap();
+        flash.program_page(page_address, &page).unwrap();
+        assert_eq!(
+            header.unknown_critical_tlv(&flash, page_address, slot_end),
+            Some(0xA5 | TLV_CRITICAL)
+        );
+    }
+    #[test]
+    fn recovery_frame_headers_roundtrip() {
+        let header = FrameHeader {
+            kind: FrameKind::Data,
+            sequence: 3,
+            length: 4,
+        };
+        let bytes = header.to_bytes();
+        assert_eq!(bytes, [2, 3, 0, 4, 0]);
+        assert_eq!(FrameHeader::from_bytes(&bytes), Some(header));
+
+        // The CRC covers the header and the payload, so a host can check it against any CRC-32/MPEG-2
+        assert_eq!(header.crc(&[1, 2, 3, 4]), 0x5466_9CEB);
+
+        // Unknown kinds and payloads that don't fit in the buffer are refused
+        assert_eq!(FrameHeader::from_bytes(&[0, 3, 0, 4, 0]), None);
+        let too_long = (MAX_PAYLOAD as u16 + 1).to_le_bytes();
+        assert_eq!(
+            FrameHeader::from_bytes(&[1, 0, 0, too_long[0], too_long[1]]),
+            None
+        );
+    }
+
+    #[test]
+    fn xmodem_blocks_are_checked() {
+        // Block 1 with 128 bytes of data, as it follows the start byte
+        let mut block = [0x1A; 128 + xmodem::BLOCK_OVERHEAD];
+        block[..2].copy_from_slice(&[1, 0xFE]);
+        block[2..11].copy_from_slice(b"123456789");
+        let crc = xmodem::crc(&block[2..130]);
+        block[130..].copy_from_slice(&crc.to_be_bytes());
+
+        assert_eq!(xmodem::crc(b"123456789"), 0x31C3);
+        assert_eq!(xmodem::block_size(xmodem::SOH), Some(128));
+        assert_eq!(xmodem::block_size(xmodem::STX), Some(1024));
+        assert_eq!(xmodem::block_size(xmodem::EOT), None);
+        assert_eq!(xmodem::parse_block(&block), Some((1, &block[2..130])));
+
+        // A wrong complement of the block number or damaged data is refused
+        block[1] = 0xFD;
+        assert_eq!(xmodem::parse_block(&block), None);
+        block[1] = 0xFE;
+        block[20] ^= 1;
+        assert_eq!(xmodem::parse_block(&block), None);
+    }
+    #[test]
+    fn panic_log_starts_over_when_full() {
+        let mut flash = flash();
+        panic_log::clear(&mut flash).unwrap();
+
+        for index in 0..panic_log::RECORDS as u32 {
+            panic_log::append(&mut flash, index, b"panicked at src/main.rs:1:1").unwrap();
+        }
+        assert_eq!(panic_log::records(&flash).count(), panic_log::RECORDS);
+
+        // A full log is erased for the next panic, and a long message is cut off
+        panic_log::append(&mut flash, 0x0000_0004, &[b'x'; 300]).unwrap();
+        let record = panic_log::records(&flash).next().unwrap();
+        assert_eq!(panic_log::records(&flash).count(), 1);
+        assert_eq!(record.reset_reason, 0x0000_0004);
+        assert_eq!(record.message, &[b'x'; panic_log::MAX_MESSAGE_LENGTH]);
+        assert!(!record.reported);
+
+        panic_log::mark_reported(&mut flash, record.address).unwrap();
+        assert!(panic_log::records(&flash).next().unwrap().reported);
+    }
+    #[test]
+    fn boot_info_roundtrip() {
+        let info = BootInfo {
+            bootloader_version: [1, 2, 3],
+            git_hash: *b"abcdef12\0\0\0\0\0\0\0\0",
+            application_address: 0x0001_0200,
+            goal: Some(BootloaderGoal::FinishSwap),
+            swapped_pages: 112,
+            reset_reason: 0x0000_0004,
+            boot_attempts: 2,
+        };
+
+        boot_info::begin();
+        assert_eq!(boot_info::boot_info(), None);
+        boot_info::set_boot_info(&info);
+        assert_eq!(boot_info::boot_info(), Some(info));
+
+        // Without a goal, the word is all ones, which must not read back as JumpToApplication
+        boot_info::set_boot_info(&BootInfo { goal: None, ..info });
+        assert_eq!(boot_info::boot_info().unwrap().goal, None);
+    }
+
+    #[test]
+    fn uicr_words_fit_the_customer_area_of_the_chip() {
+        let customer_area = chip::CUSTOMER_UICR_ADDRESS
+            ..chip::CUSTOMER_UICR_ADDRESS + chip::CUSTOMER_UICR_WORDS as u32 * 4;
+        assert!(chip::UICR_RANGE.contains(&customer_area.start));
+        assert!(customer_area.end <= chip::UICR_RANGE.end);
+
+        // The words follow each other from the start of the customer area up to the board config
+        assert_eq!(IDENTITY_ADDRESS, customer_area.start);
+        assert_eq!(
+            HARDWARE_REVISION_ADDRESS,
+            IDENTITY_ADDRESS + DeviceIdentity::WORDS as u32 * 4
+        );
+        assert_eq!(CONFIG_ADDRESS, HARDWARE_REVISION_ADDRESS + 4);
+        assert_eq!(
+            security_counter::SECURITY_COUNTER_ADDRESS,
+            CONFIG_ADDRESS + 4
+        );
+        assert_eq!(
+            board_config::BOARD_CONFIG_ADDRESS,
+            board_id::BOARD_ID_ADDRESS + 4
+        );
+        assert!(board_config::BOARD_CONFIG_ADDRESS + 4 <= customer_area.end);
+
+        // The FICR of the chip has the geometry the layout is made for
+        assert_eq!(flash().geometry, FlashGeometry::NRF9160);
+    }
+
+    #[test]
+    fn uicr_board_config_falls_back_to_the_board_feature() {
+        // An erased word keeps everything of the board feature
+        let erased = UicrBoardConfig::DEFAULT;
+        assert_eq!(erased.uart_rx_pin(), None);
+        assert_eq!(erased.uart_tx_pin(), None);
+        assert_eq!(erased.recovery_pin(), None);
+        assert_eq!(erased.baud_rate(), None);
+
+        let config = UicrBoardConfig::new(30, 19, None, 1_000_000).unwrap();
+        assert_eq!(config.uart_rx_pin(), Some(30));
+        assert_eq!(config.uart_tx_pin(), Some(19));
+        assert_eq!(config.recovery_pin(), Some(None));
+        assert_eq!(config.baud_rate(), Some(1_000_000));
+        assert_eq!(
+            UicrBoardConfig::new(30, 19, Some(6), 115_200)
+                .unwrap()
+                .recovery_pin(),
+            Some(Some(6))
+        );
+
+        // Pins the chip doesn't have and baud rates the UART can't do are refused, and ignored in the word
+        assert_eq!(
+            UicrBoardConfig::new(chip::GPIO_PINS, 19, None, 115_200),
+            None
+        );
+        assert_eq!(UicrBoardConfig::new(30, 19, None, 115_201), None);
+        let broken = UicrBoardConfig(0xFCFE_1300 | u32::from(chip::GPIO_PINS));
+        assert_eq!(broken.uart_rx_pin(), None);
+        assert_eq!(broken.uart_tx_pin(), Some(19));
+        assert_eq!(broken.baud_rate(), None);
+    }
+}
@@ -0,0 +1,408 @@
+#![no_std]
+#![no_main]
+
+use hil_tests::flash;
+use shared::{
+    app_api,
+    flash_addresses::{
+        bootloader_state_log_range, bootloader_state_range, program_slot_a_page_range,
+    },
+    mailbox, reset_reason,
+    state::{
+        BootloaderGoal, BootloaderState, GoalChangeError, GoalMismatch, PageState, RollbackReason,
+    },
+    state_checksum, Flash,
+};
+
+#[defmt_test::tests]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_and_load_roundtrip() {
+        let mut flash = flash();
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(
+                BootloaderGoal::JumpToApplication,
+                BootloaderGoal::StartTestSwap,
+            )
+            .unwrap();
+        state.set_valid(true);
+        state.store(&mut flash).unwrap();
+
+        let state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(state.goal(), BootloaderGoal::StartTestSwap);
+    }
+
+    #[test]
+    fn prepare_swap_resets_the_page_states() {
+        let mut flash = flash();
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap)
+            .unwrap();
+        state.set_valid(true);
+        state
+            .prepare_swap(BootloaderGoal::StartSwap, false, &mut flash)
+            .unwrap();
+
+        let state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(state.goal(), BootloaderGoal::FinishSwap);
+        for page in 0..program_slot_a_page_range().len() as u32 {
+            assert_eq!(state.get_page_state(page), PageState::Original);
+        }
+    }
+
+    #[test]
+    fn burn_store_keeps_the_state_valid() {
+        let mut flash = flash();
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap)
+            .unwrap();
+        state.set_valid(true);
+        state
+            .prepare_swap(BootloaderGoal::StartSwap, false, &mut flash)
+            .unwrap();
+        state.set_page_state(0, PageState::InScratch { scratch_page: 0xF8 });
+        state.burn_store(&mut flash).unwrap();
+        state.set_page_state(0, PageState::InScratchOverwritten { scratch_page: 0xF8 });
+        state.burn_store(&mut flash).unwrap();
+
+        let state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(
+            state.get_page_state(0),
+            PageState::InScratchOverwritten { scratch_page: 0xF8 }
+        );
+        assert_eq!(state.get_page_state(1), PageState::Original);
+    }
+
+    #[test]
+    fn load_finds_the_newest_record() {
+        let mut flash = flash();
+
+        // More stores than there are records on a page, so the pages are rotated as well
+        let goals = [
+            BootloaderGoal::StartSwap,
+            BootloaderGoal::JumpToApplication,
+            BootloaderGoal::StartTestSwap,
+        ];
+        let mut previous_goal = BootloaderGoal::JumpToApplication;
+        for goal in goals.iter().cycle().take(20) {
+            let mut state = BootloaderState::load(&flash);
+            state.set_goal(previous_goal, *goal).unwrap();
+            previous_goal = *goal;
+            state.set_valid(true);
+            state.store(&mut flash).unwrap();
+
+            let state = BootloaderState::load(&flash);
+            assert!(state.is_valid());
+            assert_eq!(state.goal(), *goal);
+        }
+    }
+
+    #[test]
+    fn state_log_starts_over_when_full() {
+        let mut flash = flash();
+
+        // Enough stores to fill every page of the log twice, with records of 128 bytes
+        let stores = bootloader_state_log_range().len() / 128 * 2 + 8;
+        let mut previous_goal = BootloaderState::load(&flash)
+            .current_goal()
+            .unwrap_or(BootloaderGoal::JumpToApplication);
+        for goal in [BootloaderGoal::StartSwap, BootloaderGoal::JumpToApplication]
+            .iter()
+            .cycle()
+            .take(stores)
+        {
+            let mut state = BootloaderState::load(&flash);
+            state.set_goal(previous_goal, *goal).unwrap();
+            previous_goal = *goal;
+            state.set_valid(true);
+            state.store(&mut flash).unwrap();
+
+            let state = BootloaderState::load(&flash);
+            assert!(state.is_valid());
+            assert_eq!(state.goal(), *goal);
+        }
+
+        BootloaderState::erase(&mut flash).unwrap();
+        assert!(!BootloaderState::load(&flash).is_valid());
+    }
+
+    #[test]
+    fn load_falls_back_to_the_second_page() {
+        let mut flash = flash();
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap)
+            .unwrap();
+        state.set_valid(true);
+        state.store(&mut flash).unwrap();
+
+        // Simulate a reset right after the first page was erased
+        flash.erase_page(bootloader_state_range().start).unwrap();
+
+        let state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(state.goal(), BootloaderGoal::StartSwap);
+    }
+
+    #[test]
+    fn set_goal_forgets_the_failed_test_boots() {
+        let mut flash = flash();
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap)
+            .unwrap();
+        state.set_failed_test_boots(Some(2));
+        state.set_valid(true);
+        state.store(&mut flash).unwrap();
+
+        let mut state = BootloaderState::load(&flash);
+        assert_eq!(state.failed_test_boots(), Some(2));
+
+        // The application confirms the test image
+        state
+            .set_goal(BootloaderGoal::StartSwap, BootloaderGoal::JumpToApplication)
+            .unwrap();
+        assert!(state.is_valid());
+        assert_eq!(state.failed_test_boots(), None);
+    }
+
+    #[test]
+    fn set_goal_keeps_a_goal_that_changed() {
+        let mut flash = flash();
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap)
+            .unwrap();
+        state.set_valid(true);
+        state.store(&mut flash).unwrap();
+
+        // Another writer still expects that nothing is pending
+        let mismatch = BootloaderState::compare_and_set_goal(
+            &mut flash,
+            BootloaderGoal::JumpToApplication,
+            BootloaderGoal::StartModemUpdate,
+        );
+        assert_eq!(
+            mismatch,
+            Err(GoalChangeError::Mismatch(GoalMismatch {
+                current: Some(BootloaderGoal::StartSwap)
+            }))
+        );
+
+        let state = BootloaderState::load(&flash);
+        assert_eq!(state.goal(), BootloaderGoal::StartSwap);
+    }
+
+    #[test]
+    fn clear_boot_attempts_resets_the_count() {
+        let mut flash = flash();
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(
+                BootloaderGoal::JumpToApplication,
+                BootloaderGoal::JumpToApplication,
+            )
+            .unwrap();
+        state.set_boot_attempts(3);
+        state.set_valid(true);
+        state.store(&mut flash).unwrap();
+        assert_eq!(BootloaderState::load(&flash).boot_attempts(), 3);
+
+        // The application is running properly
+        BootloaderState::clear_boot_attempts(&mut flash).unwrap();
+
+        let state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(state.boot_attempts(), 0);
+        assert_eq!(state.goal(), BootloaderGoal::JumpToApplication);
+    }
+
+    #[test]
+    fn rollback_reason_survives_the_boot_attempts() {
+        let mut flash = flash();
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::Revert)
+            .unwrap();
+        state.set_rollback_reason(RollbackReason::Requested);
+        state.set_boot_attempts(2);
+        state.set_valid(true);
+        state.store(&mut flash).unwrap();
+
+        BootloaderState::clear_boot_attempts(&mut flash).unwrap();
+
+        let state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(state.boot_attempts(), 0);
+        assert_eq!(state.rollback_reason(), RollbackReason::Requested);
+    }
+    #[test]
+    fn refused_board_id_is_stored() {
+        let mut flash = flash();
+
+        let mut state = BootloaderState::load(&flash);
+        assert_eq!(state.refused_board_id(), None);
+        state.set_refused_board_id(Some(4));
+        state.set_valid(true);
+        state.store(&mut flash).unwrap();
+
+        let state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(state.refused_board_id(), Some(4));
+    }
+    #[test]
+    fn panic_count_is_kept_as_cleared_bits() {
+        let mut flash = flash();
+
+        let mut state = BootloaderState::load(&flash);
+        state.set_panic_count(0);
+        state.set_valid(true);
+        state.store(&mut flash).unwrap();
+        assert_eq!(BootloaderState::load(&flash).panic_count(), 0);
+
+        let mut state = BootloaderState::load(&flash);
+        state.set_panic_count(3);
+        state.store(&mut flash).unwrap();
+
+        let mut state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(state.panic_count(), 3);
+
+        // The count stops when all bits are cleared
+        state.set_panic_count(40);
+        assert_eq!(state.panic_count(), 32);
+    }
+
+    #[test]
+    fn reset_history_keeps_the_last_boots() {
+        let mut flash = flash();
+
+        let mut state = BootloaderState::load(&flash);
+        state.set_valid(true);
+        for boot in 0..=BootloaderState::RESET_HISTORY_LENGTH as u32 {
+            state.push_reset_reason(if boot < 2 {
+                reset_reason::SREQ
+            } else {
+                reset_reason::DOG
+            });
+        }
+        state.store(&mut flash).unwrap();
+
+        let state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(
+            state.reset_history().count(),
+            BootloaderState::RESET_HISTORY_LENGTH
+        );
+        // The oldest boot was dropped, so only one soft reset is left at the end
+        assert_eq!(
+            reset_reason::watchdog_streak(state.reset_history()),
+            BootloaderState::RESET_HISTORY_LENGTH - 1
+        );
+        assert_eq!(state.reset_history().last(), Some(reset_reason::SREQ));
+    }
+
+    #[test]
+    fn confirm_image_keeps_the_test_image() {
+        let mut flash = flash();
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(
+                BootloaderGoal::JumpToApplication,
+                BootloaderGoal::JumpToApplication,
+            )
+            .unwrap();
+        state.set_valid(true);
+        state.store(&mut flash).unwrap();
+        app_api::request_test_update(&mut flash).unwrap();
+        assert_eq!(
+            app_api::current_goal(&flash),
+            Some(BootloaderGoal::StartTestSwap)
+        );
+
+        // What the bootloader leaves behind after the test swap
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(BootloaderGoal::StartTestSwap, BootloaderGoal::StartSwap)
+            .unwrap();
+        state.set_failed_test_boots(Some(0));
+        state.set_boot_attempts(1);
+        state.store(&mut flash).unwrap();
+        assert!(app_api::last_swap_status(&flash).awaiting_confirmation);
+
+        app_api::confirm_image(&mut flash).unwrap();
+
+        let state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(state.goal(), BootloaderGoal::JumpToApplication);
+        assert_eq!(state.boot_attempts(), 0);
+        assert!(!app_api::last_swap_status(&flash).awaiting_confirmation);
+    }
+
+    #[test]
+    fn app_api_forbids_the_nothing_pending_hint() {
+        let mut flash = flash();
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(
+                BootloaderGoal::JumpToApplication,
+                BootloaderGoal::JumpToApplication,
+            )
+            .unwrap();
+        state.set_valid(true);
+        state.store(&mut flash).unwrap();
+
+        mailbox::allow_nothing_pending_hint(true);
+        app_api::request_update(&mut flash).unwrap();
+        assert!(!mailbox::leave_nothing_pending_hint());
+        assert!(!mailbox::take_nothing_pending_hint());
+    }
+
+    #[test]
+    fn format_version_is_stored() {
+        let mut flash = flash();
+
+        BootloaderState::erase(&mut flash).unwrap();
+
+        // An erased state is migrated like a state of an older bootloader
+        let mut state = BootloaderState::load(&flash);
+        assert!(!state.is_valid());
+        assert_eq!(state.format_version(), BootloaderState::FORMAT_VERSION);
+
+        state
+            .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap)
+            .unwrap();
+        state.set_valid(true);
+        state.store(&mut flash).unwrap();
+
+        let state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(state.format_version(), BootloaderState::FORMAT_VERSION);
+        assert_eq!(state.goal(), BootloaderGoal::StartSwap);
+    }
+
+    #[test]
+    fn checksum_of_erased_flash_is_not_erased() {
+        // Otherwise an erased record would pass as a valid one with any scheme
+        let erased = [0xFFFF_FFFF; 31];
+        assert_ne!(state_checksum::checksum(&erased), 0xFFFF_FFFF);
+    }
+}
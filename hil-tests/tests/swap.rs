@@ -0,0 +1,382 @@
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use dis_bootloader_core::{
+    is_valid_bootload_target, layout_check, overwrite::finish_overwrite, perform_swap, report,
+    uinfo, uwarn, watchdog, LogSink,
+};
+use hil_tests::{fill_page, flash, page_has_pattern, DefmtLog};
+use shared::{
+    flash_addresses::{
+        bootloader_scratch_page_range, program_slot_a_page_range, program_slot_b_page_range,
+        PAGE_SIZE,
+    },
+    image_header::{ImageHeader, ImageVersion},
+    slots::{self, SlotRole},
+    state::{BootloaderGoal, BootloaderState, PageState},
+    user_data, Flash,
+};
+
+const SLOT_A_SEED: u32 = 0xAAAA_0000;
+const SLOT_B_SEED: u32 = 0xBBBB_0000;
+
+/// How often the core fed the watchdog
+static FEEDS: AtomicU32 = AtomicU32::new(0);
+
+/// A [LogSink] that keeps the first bytes of the log output
+struct CapturedLog {
+    bytes: [u8; 64],
+    length: usize,
+}
+
+impl LogSink for CapturedLog {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        let length = bytes.len().min(self.bytes.len() - self.length);
+        self.bytes[self.length..][..length].copy_from_slice(&bytes[..length]);
+        self.length += length;
+    }
+}
+
+/// Gives every page of both slots its own pattern
+fn fill_slots(flash: &mut hil_tests::flash::Flash) {
+    for page in 0..program_slot_a_page_range().len() as u32 {
+        let slot_a_address = (program_slot_a_page_range().start + page) * PAGE_SIZE;
+        let slot_b_address = (program_slot_b_page_range().start + page) * PAGE_SIZE;
+        fill_page(flash, slot_a_address, SLOT_A_SEED + page);
+        fill_page(flash, slot_b_address, SLOT_B_SEED + page);
+    }
+}
+
+/// Checks that the contents of every page of the slots have been exchanged
+fn assert_slots_swapped(flash: &hil_tests::flash::Flash) {
+    for page in 0..program_slot_a_page_range().len() as u32 {
+        let slot_a_address = (program_slot_a_page_range().start + page) * PAGE_SIZE;
+        let slot_b_address = (program_slot_b_page_range().start + page) * PAGE_SIZE;
+        assert!(page_has_pattern(flash, slot_a_address, SLOT_B_SEED + page));
+        assert!(page_has_pattern(flash, slot_b_address, SLOT_A_SEED + page));
+    }
+}
+
+#[defmt_test::tests]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_swap_exchanges_the_slots() {
+        let mut flash = flash();
+        fill_slots(&mut flash);
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(
+                BootloaderGoal::JumpToApplication,
+                BootloaderGoal::StartTestSwap,
+            )
+            .unwrap();
+        state.set_valid(true);
+        state
+            .prepare_swap(BootloaderGoal::StartTestSwap, true, &mut flash)
+            .unwrap();
+        perform_swap(true, &mut state, &mut flash, &mut DefmtLog).unwrap();
+
+        assert_slots_swapped(&flash);
+
+        // After a test swap, the bootloader must swap back on the next boot
+        let state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(state.goal(), BootloaderGoal::StartSwap);
+    }
+
+    #[test]
+    fn swap_keeps_the_user_data() {
+        let mut flash = flash();
+        fill_slots(&mut flash);
+        for (index, page_address) in user_data::page_addresses().enumerate() {
+            fill_page(&mut flash, page_address, 0x0DA7_A000 + index as u32);
+        }
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap)
+            .unwrap();
+        state.set_valid(true);
+        state
+            .prepare_swap(BootloaderGoal::StartSwap, true, &mut flash)
+            .unwrap();
+        perform_swap(false, &mut state, &mut flash, &mut DefmtLog).unwrap();
+
+        assert_slots_swapped(&flash);
+        for (index, page_address) in user_data::page_addresses().enumerate() {
+            assert!(page_has_pattern(
+                &flash,
+                page_address,
+                0x0DA7_A000 + index as u32
+            ));
+        }
+        assert_eq!(user_data::address(0), Some(user_data::range().start));
+        assert_eq!(user_data::address(user_data::range().len() as u32), None);
+    }
+
+    #[test]
+    fn swap_only_moves_the_pages_of_the_images() {
+        let mut flash = flash();
+        fill_slots(&mut flash);
+        let slot_a_address = program_slot_a_page_range().start * PAGE_SIZE;
+        let slot_b_address = program_slot_b_page_range().start * PAGE_SIZE;
+
+        // The image in slot A takes two pages, the one in slot B three, so three pages are swapped
+        let header = |length| ImageHeader {
+            length,
+            ..ImageHeader::new(
+                ImageVersion {
+                    major: 1,
+                    minor: 0,
+                    patch: 0,
+                    build: 0,
+                },
+                &[],
+            )
+        };
+        let (header_a, header_b) = (header(PAGE_SIZE), header(2 * PAGE_SIZE));
+        for (address, header) in [(slot_a_address, header_a), (slot_b_address, header_b)] {
+            flash.erase_page(address).unwrap();
+            flash.program_page(address, &header.to_words()).unwrap();
+        }
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap)
+            .unwrap();
+        state.set_valid(true);
+        state
+            .prepare_swap(BootloaderGoal::StartSwap, true, &mut flash)
+            .unwrap();
+        perform_swap(false, &mut state, &mut flash, &mut DefmtLog).unwrap();
+
+        assert_eq!(ImageHeader::load(&flash, slot_a_address), Some(header_b));
+        assert_eq!(ImageHeader::load(&flash, slot_b_address), Some(header_a));
+        for page in 1..program_slot_a_page_range().len() as u32 {
+            let slot_a_page = slot_a_address + page * PAGE_SIZE;
+            let slot_b_page = slot_b_address + page * PAGE_SIZE;
+            if page < 3 {
+                assert!(page_has_pattern(&flash, slot_a_page, SLOT_B_SEED + page));
+                assert!(page_has_pattern(&flash, slot_b_page, SLOT_A_SEED + page));
+            } else {
+                for address in [slot_a_page, slot_b_page] {
+                    let page = flash.read_u32(address..address + PAGE_SIZE);
+                    assert!(page.iter().all(|word| *word == 0xFFFF_FFFF));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn layout_check_rejects_mismatched_slots() {
+        let geometry = flash().geometry;
+        let mut layout = slots::default_layout();
+        assert!(layout_check::check(&layout, geometry, &mut DefmtLog));
+
+        // A secondary slot that is a page short is fine, one that isn't whole pages isn't
+        layout[1].range.end -= PAGE_SIZE;
+        assert!(layout_check::check(&layout, geometry, &mut DefmtLog));
+        layout[1].range.end -= 4;
+        assert!(!layout_check::check(&layout, geometry, &mut DefmtLog));
+
+        // A secondary slot that overlaps the primary slot
+        let mut layout = slots::default_layout();
+        layout[1].range.start -= PAGE_SIZE;
+        layout[1].range.end -= PAGE_SIZE;
+        assert_eq!(layout[1].role, SlotRole::Secondary);
+        assert!(!layout_check::check(&layout, geometry, &mut DefmtLog));
+    }
+
+    #[test]
+    fn bootload_target_is_an_aligned_vector_table_in_slot_a() {
+        let mut flash = flash();
+        let slot_a_address = program_slot_a_page_range().start * PAGE_SIZE;
+        let slot_b_address = program_slot_b_page_range().start * PAGE_SIZE;
+
+        // A stack at the very end of RAM, and the vector table repeated at an unaligned address
+        flash.erase_page(slot_a_address).unwrap();
+        let reset_vector = slot_a_address + 0x201;
+        flash
+            .program_page(
+                slot_a_address,
+                &[0x2004_0000, reset_vector, 0x2004_0000, reset_vector],
+            )
+            .unwrap();
+        assert!(is_valid_bootload_target(&flash, slot_a_address));
+        assert!(!is_valid_bootload_target(&flash, slot_a_address + 8));
+        assert!(!is_valid_bootload_target(&flash, slot_b_address));
+
+        // A stack pointer outside of RAM
+        flash.erase_page(slot_a_address).unwrap();
+        flash
+            .program_page(slot_a_address, &[0x1000_0000, reset_vector])
+            .unwrap();
+        assert!(!is_valid_bootload_target(&flash, slot_a_address));
+    }
+
+    #[test]
+    fn swap_resumes_after_a_reset() {
+        let mut flash = flash();
+        fill_slots(&mut flash);
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap)
+            .unwrap();
+        state.set_valid(true);
+        state
+            .prepare_swap(BootloaderGoal::StartSwap, false, &mut flash)
+            .unwrap();
+
+        // Do the first step of the first page by hand, like a swap that got reset right after it
+        let scratch_page = bootloader_scratch_page_range().start;
+        fill_page(&mut flash, scratch_page * PAGE_SIZE, SLOT_A_SEED);
+        state.set_page_state(0, PageState::InScratch { scratch_page });
+        state.burn_store(&mut flash).unwrap();
+        assert!(page_has_pattern(
+            &flash,
+            scratch_page * PAGE_SIZE,
+            SLOT_A_SEED
+        ));
+
+        // Reboot and continue
+        let mut state = BootloaderState::load(&flash);
+        assert_eq!(state.goal(), BootloaderGoal::FinishSwap);
+        perform_swap(false, &mut state, &mut flash, &mut DefmtLog).unwrap();
+
+        assert_slots_swapped(&flash);
+
+        let state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(state.goal(), BootloaderGoal::JumpToApplication);
+    }
+
+    #[test]
+    fn overwrite_copies_slot_b_into_slot_a() {
+        let mut flash = flash();
+        fill_slots(&mut flash);
+
+        // The patterns aren't valid images, so the overwrite is started like the bootloader does after a reset
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(
+                BootloaderGoal::JumpToApplication,
+                BootloaderGoal::FinishOverwrite,
+            )
+            .unwrap();
+        for page in 0..BootloaderState::MAX_SWAP_PAGES as u32 {
+            state.set_page_state(page, PageState::Original);
+        }
+        state.set_valid(true);
+        state.store(&mut flash).unwrap();
+
+        finish_overwrite(
+            &slots::default_layout(),
+            &mut state,
+            &mut flash,
+            &mut DefmtLog,
+        )
+        .unwrap();
+
+        // Slot B is only read
+        for page in 0..program_slot_a_page_range().len() as u32 {
+            let slot_a_address = (program_slot_a_page_range().start + page) * PAGE_SIZE;
+            let slot_b_address = (program_slot_b_page_range().start + page) * PAGE_SIZE;
+            assert!(page_has_pattern(&flash, slot_a_address, SLOT_B_SEED + page));
+            assert!(page_has_pattern(&flash, slot_b_address, SLOT_B_SEED + page));
+        }
+
+        let state = BootloaderState::load(&flash);
+        assert!(state.is_valid());
+        assert_eq!(state.goal(), BootloaderGoal::JumpToApplication);
+    }
+
+    #[test]
+    fn identical_pages_are_not_rewritten() {
+        let mut flash = flash();
+        fill_slots(&mut flash);
+
+        // The first pages of slot B didn't change in the new image
+        const IDENTICAL_PAGES: u32 = 4;
+        for page in 0..IDENTICAL_PAGES {
+            let slot_b_address = (program_slot_b_page_range().start + page) * PAGE_SIZE;
+            fill_page(&mut flash, slot_b_address, SLOT_A_SEED + page);
+        }
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap)
+            .unwrap();
+        state.set_valid(true);
+        state
+            .prepare_swap(BootloaderGoal::StartSwap, false, &mut flash)
+            .unwrap();
+
+        let erases_before = report::current(0).erases;
+        perform_swap(false, &mut state, &mut flash, &mut DefmtLog).unwrap();
+
+        // Every page that was swapped took three erases
+        let swapped_pages = program_slot_a_page_range().len() as u32 - IDENTICAL_PAGES;
+        assert_eq!(report::current(0).erases - erases_before, swapped_pages * 3);
+
+        for page in 0..program_slot_a_page_range().len() as u32 {
+            let slot_a_address = (program_slot_a_page_range().start + page) * PAGE_SIZE;
+            let slot_b_address = (program_slot_b_page_range().start + page) * PAGE_SIZE;
+            let (slot_a_seed, slot_b_seed) = if page < IDENTICAL_PAGES {
+                (SLOT_A_SEED + page, SLOT_A_SEED + page)
+            } else {
+                (SLOT_B_SEED + page, SLOT_A_SEED + page)
+            };
+            assert!(page_has_pattern(&flash, slot_a_address, slot_a_seed));
+            assert!(page_has_pattern(&flash, slot_b_address, slot_b_seed));
+        }
+    }
+
+    #[test]
+    fn swap_feeds_the_watchdog() {
+        let mut flash = flash();
+        fill_slots(&mut flash);
+        watchdog::set_feeder(|| {
+            FEEDS.fetch_add(1, Ordering::Relaxed);
+        });
+
+        let mut state = BootloaderState::load(&flash);
+        state
+            .set_goal(BootloaderGoal::JumpToApplication, BootloaderGoal::StartSwap)
+            .unwrap();
+        state.set_valid(true);
+        state
+            .prepare_swap(BootloaderGoal::StartSwap, false, &mut flash)
+            .unwrap();
+
+        let feeds_before = FEEDS.load(Ordering::Relaxed);
+        perform_swap(false, &mut state, &mut flash, &mut DefmtLog).unwrap();
+
+        // The watchdog is fed for every page, so its timeout only has to cover a single page
+        assert_slots_swapped(&flash);
+        assert_eq!(
+            FEEDS.load(Ordering::Relaxed) - feeds_before,
+            program_slot_a_page_range().len() as u32
+        );
+    }
+
+    #[test]
+    fn log_levels_are_a_prefix_of_the_line() {
+        let mut log = CapturedLog {
+            bytes: [0; 64],
+            length: 0,
+        };
+
+        uwarn!(log, "Page {} is bad", 3);
+        uinfo!(log, "Swapping image {}", 0);
+
+        assert_eq!(
+            &log.bytes[..log.length],
+            b"Warning: Page 3 is bad\nSwapping image 0\n"
+        );
+    }
+}
@@ -0,0 +1,13 @@
+//! Generates the constants of the flash layout in `partitions.toml` for the `partitions` module
+
+use std::{env, fs, path::PathBuf};
+
+#[path = "partitions.rs"]
+mod partitions;
+
+fn main() {
+    let layout = partitions::Layout::load();
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out.join("partitions.rs"), layout.rust_module()).unwrap();
+}
@@ -0,0 +1,340 @@
+//! Reads the flash layout in `partitions.toml` and generates the linker script and the Rust constants from it
+//!
+//! This is not a module of the library. The build scripts of the shared crate, the bootloader, stage 0 and the
+//! HIL tests include it with a `#[path]` attribute, so they all work from the same layout.
+//!
+//! The file is a small subset of TOML: the top level has `page_size` and `flash_size`, and every partition is a
+//! table with an `origin` and a `length` in bytes. Numbers can be decimal or hexadecimal and can have underscores.
+//! The partitions are checked for page alignment, overlap and whether they fit in the flash, and a broken layout
+//! fails the build.
+//!
+//! With an `external_flash_size` at the top level, slot B can be put on the external flash, in the window at
+//! [EXTERNAL_FLASH_ADDRESS] (see `shared::external_flash`).
+
+#![allow(dead_code)] // Not every build script uses every function
+
+use std::{env, fmt::Write, fs, ops::Range, path::PathBuf};
+
+/// The partitions every layout must have. Their names are the prefixes of the symbols in the linker scripts.
+const REQUIRED_PARTITIONS: [&str; 12] = [
+    "stage0_flash",
+    "bootloader_flash",
+    "program_slot_a",
+    "program_slot_b",
+    "user_data",
+    "bootloader_flash_trace",
+    "bootloader_scratch",
+    "bootloader_state_log",
+    "bootloader_panic_log",
+    "bootloader_revocations",
+    "bootloader_event_log",
+    "bootloader_state",
+];
+
+/// The address of the window with the external flash, the same as `shared::external_flash::EXTERNAL_FLASH_ADDRESS`
+const EXTERNAL_FLASH_ADDRESS: u32 = 0x1200_0000;
+
+/// The partitions that can be on the external flash. The rest is run or used in place by the bootloader.
+const EXTERNAL_PARTITIONS: [&str; 1] = ["program_slot_b"];
+
+/// A partition of the flash
+pub struct Partition {
+    /// The name of the table in the layout file
+    pub name: String,
+    /// The address range of the partition
+    pub range: Range<u32>,
+}
+
+/// The flash layout of a `partitions.toml`
+pub struct Layout {
+    /// The size of a flash page in bytes
+    pub page_size: u32,
+    /// The size of the flash in bytes
+    pub flash_size: u32,
+    /// The size of the external flash in bytes, or 0 if there is none
+    pub external_flash_size: u32,
+    /// The partitions in the order of the file
+    pub partitions: Vec<Partition>,
+}
+
+impl Layout {
+    /// Loads the layout in the `PARTITIONS_TOML` environment variable, or the `partitions.toml` in the root of the
+    /// workspace, and tells cargo to run the build script again when it changes.
+    /// Panics with a message about the first problem if the layout is broken.
+    pub fn load() -> Self {
+        println!("cargo:rerun-if-env-changed=PARTITIONS_TOML");
+        let path = match env::var_os("PARTITIONS_TOML") {
+            Some(path) => PathBuf::from(path),
+            None => {
+                PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("../partitions.toml")
+            }
+        };
+        println!("cargo:rerun-if-changed={}", path.display());
+
+        let text = fs::read_to_string(&path)
+            .unwrap_or_else(|error| panic!("Could not read {}: {}", path.display(), error));
+        let layout =
+            Self::parse(&text).unwrap_or_else(|error| panic!("{}: {}", path.display(), error));
+        if let Err(error) = layout.validate() {
+            panic!("{}: {}", path.display(), error);
+        }
+
+        layout
+    }
+
+    /// Parses the text of a layout file
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut page_size = None;
+        let mut flash_size = None;
+        let mut external_flash_size = None;
+        let mut tables: Vec<(String, Option<u32>, Option<u32>)> = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line
+                .strip_prefix('[')
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                let name = name.trim();
+                let is_identifier = name.starts_with(|c: char| c.is_ascii_lowercase())
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+                if !is_identifier {
+                    return Err(format!(
+                        "line {}: the partition name `{}` must be lowercase letters, digits and underscores",
+                        line_number, name
+                    ));
+                }
+                if tables.iter().any(|(existing, _, _)| existing == name) {
+                    return Err(format!(
+                        "line {}: the partition `{}` is defined twice",
+                        line_number, name
+                    ));
+                }
+                tables.push((name.to_string(), None, None));
+                continue;
+            }
+
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                format!(
+                    "line {}: expected a `[partition]` or a `key = value`",
+                    line_number
+                )
+            })?;
+            let (key, value) = (key.trim(), parse_number(value.trim()));
+            let value =
+                value.ok_or_else(|| format!("line {}: `{}` must be a number", line_number, key))?;
+
+            let field = match (tables.last_mut(), key) {
+                (None, "page_size") => &mut page_size,
+                (None, "flash_size") => &mut flash_size,
+                (None, "external_flash_size") => &mut external_flash_size,
+                (Some((_, origin, _)), "origin") => origin,
+                (Some((_, _, length)), "length") => length,
+                _ => return Err(format!("line {}: unknown key `{}`", line_number, key)),
+            };
+            if field.replace(value).is_some() {
+                return Err(format!("line {}: `{}` is set twice", line_number, key));
+            }
+        }
+
+        let partitions = tables
+            .into_iter()
+            .map(|(name, origin, length)| match (origin, length) {
+                (Some(origin), Some(length)) => origin
+                    .checked_add(length)
+                    .map(|end| Partition {
+                        range: origin..end,
+                        name: name.clone(),
+                    })
+                    .ok_or_else(|| format!("the partition `{}` lies beyond 4GB", name)),
+                _ => Err(format!(
+                    "the partition `{}` needs an origin and a length",
+                    name
+                )),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            page_size: page_size.ok_or("the `page_size` is missing")?,
+            flash_size: flash_size.ok_or("the `flash_size` is missing")?,
+            external_flash_size: external_flash_size.unwrap_or(0),
+            partitions,
+        })
+    }
+
+    /// Checks that the required partitions are there, are page aligned, lie in the flash or the external flash and
+    /// don't overlap
+    fn validate(&self) -> Result<(), String> {
+        if !self.page_size.is_power_of_two() {
+            return Err(format!(
+                "the page size {:#X} is not a power of two",
+                self.page_size
+            ));
+        }
+
+        for name in REQUIRED_PARTITIONS {
+            if !self
+                .partitions
+                .iter()
+                .any(|partition| partition.name == name)
+            {
+                return Err(format!("the partition `{}` is missing", name));
+            }
+        }
+
+        for partition in &self.partitions {
+            let Range { start, end } = partition.range;
+            if start % self.page_size != 0 || end % self.page_size != 0 || start == end {
+                return Err(format!(
+                    "the partition `{}` ({:#010X}..{:#010X}) must be whole pages of {:#X} bytes",
+                    partition.name, start, end, self.page_size
+                ));
+            }
+            if start >= EXTERNAL_FLASH_ADDRESS {
+                if !EXTERNAL_PARTITIONS.contains(&partition.name.as_str()) {
+                    return Err(format!(
+                        "the partition `{}` can't be on the external flash, only {:?} can",
+                        partition.name, EXTERNAL_PARTITIONS
+                    ));
+                }
+                if end - EXTERNAL_FLASH_ADDRESS > self.external_flash_size {
+                    return Err(format!(
+                        "the partition `{}` ends at {:#010X}, beyond the external flash of {:#X} bytes",
+                        partition.name, end, self.external_flash_size
+                    ));
+                }
+            } else if end > self.flash_size {
+                return Err(format!(
+                    "the partition `{}` ends at {:#010X}, beyond the flash of {:#X} bytes",
+                    partition.name, end, self.flash_size
+                ));
+            }
+        }
+
+        for (index, a) in self.partitions.iter().enumerate() {
+            for b in &self.partitions[index + 1..] {
+                if a.range.start < b.range.end && b.range.start < a.range.end {
+                    return Err(format!(
+                        "the partitions `{}` and `{}` overlap",
+                        a.name, b.name
+                    ));
+                }
+            }
+        }
+
+        // The state is kept in two pages, see shared::state
+        let state = self.range("bootloader_state");
+        if state.end - state.start != 2 * self.page_size {
+            return Err("the partition `bootloader_state` must be two pages".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Gets the address range of the partition with the name, which must exist
+    pub fn range(&self, name: &str) -> Range<u32> {
+        self.partitions
+            .iter()
+            .find(|partition| partition.name == name)
+            .map(|partition| partition.range.clone())
+            .unwrap()
+    }
+
+    /// Generates the linker script with the `FLASH` memory region of the binary, which is the given partition,
+    /// and the start and end symbols of all partitions
+    pub fn linker_script(&self, flash_partition: &str) -> String {
+        let flash = self.range(flash_partition);
+        let mut script = String::new();
+        writeln!(
+            script,
+            "/* Generated from partitions.toml by the build script, don't edit */"
+        )
+        .unwrap();
+        writeln!(script, "MEMORY\n{{").unwrap();
+        writeln!(
+            script,
+            "    FLASH : ORIGIN = {:#010X}, LENGTH = {:#X}",
+            flash.start,
+            flash.end - flash.start
+        )
+        .unwrap();
+        writeln!(script, "}}\n").unwrap();
+
+        for partition in &self.partitions {
+            writeln!(
+                script,
+                "_{}_start = {:#010X};",
+                partition.name, partition.range.start
+            )
+            .unwrap();
+            writeln!(
+                script,
+                "_{}_end = {:#010X};",
+                partition.name, partition.range.end
+            )
+            .unwrap();
+        }
+
+        script
+    }
+
+    /// Generates the Rust constants of the page size, the flash sizes and the address ranges of the partitions
+    pub fn rust_module(&self) -> String {
+        let mut module = String::new();
+        writeln!(module, "/// The size of a flash page in bytes").unwrap();
+        writeln!(module, "pub const PAGE_SIZE: u32 = {:#X};", self.page_size).unwrap();
+        writeln!(module, "/// The size of the flash in bytes").unwrap();
+        writeln!(
+            module,
+            "pub const FLASH_SIZE: u32 = {:#X};",
+            self.flash_size
+        )
+        .unwrap();
+        writeln!(
+            module,
+            "/// The size of the external flash in bytes, or 0 if there is none"
+        )
+        .unwrap();
+        writeln!(
+            module,
+            "pub const EXTERNAL_FLASH_SIZE: u32 = {:#X};",
+            self.external_flash_size
+        )
+        .unwrap();
+
+        for partition in &self.partitions {
+            writeln!(
+                module,
+                "/// The address range of the `{}` partition",
+                partition.name
+            )
+            .unwrap();
+            writeln!(
+                module,
+                "pub const {}: core::ops::Range<u32> = {:#010X}..{:#010X};",
+                partition.name.to_uppercase(),
+                partition.range.start,
+                partition.range.end
+            )
+            .unwrap();
+        }
+
+        module
+    }
+}
+
+/// Parses a decimal or `0x` hexadecimal number with optional underscores
+fn parse_number(text: &str) -> Option<u32> {
+    let text = text.replace('_', "");
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
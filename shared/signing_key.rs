@@ -0,0 +1,33 @@
+//! Turns the public key of the signing key into Rust code, at build time
+//!
+//! This is not a module of the library. The build scripts of the bootloader and stage 0 include it with a `#[path]`
+//! attribute, so both check the signatures of their images with a key from the same `SIGNING_PUBLIC_KEY` environment
+//! variable.
+
+use std::env;
+
+/// Generates the `PublicKey` expression of the key in the `SIGNING_PUBLIC_KEY` environment variable.
+/// An Ed25519 key has 64 hex digits, a P-256 key has 128 for the x and y coordinates.
+pub fn public_key_expression() -> String {
+    let key = env::var("SIGNING_PUBLIC_KEY")
+        .expect("The public key must be in the SIGNING_PUBLIC_KEY environment variable");
+    let kind = match key.len() {
+        64 => "Ed25519",
+        128 if env::var_os("CARGO_FEATURE_ECDSA_P256").is_some() => "P256",
+        128 => panic!("A P-256 SIGNING_PUBLIC_KEY needs the ecdsa-p256 feature"),
+        _ => panic!("SIGNING_PUBLIC_KEY must have 64 hex digits for Ed25519 or 128 for P-256"),
+    };
+    assert!(
+        key.chars().all(|c| c.is_ascii_hexdigit()),
+        "SIGNING_PUBLIC_KEY must be hex digits"
+    );
+
+    let bytes = (0..key.len())
+        .step_by(2)
+        .map(|index| format!("0x{}, ", &key[index..index + 2]))
+        .collect::<String>();
+    format!(
+        "dis_bootloader_core::secure_boot::PublicKey::{}([{}])",
+        kind, bytes
+    )
+}
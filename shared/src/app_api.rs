@@ -0,0 +1,96 @@
+//! The state operations an application needs, over its own [Flash] implementation
+//!
+//! An application that writes the state directly doesn't need to know its layout. It installs an update with
+//! [request_update] or [request_test_update] and resets the device. A test-swapped image that is running properly
+//! calls [confirm_image], or it's swapped back at the next reset. [current_goal] and [last_swap_status] tell what the
+//! bootloader is going to do and what it did.
+//!
+//! Like [BootloaderState::compare_and_set_goal], the goal is only changed when it's the one the application
+//! expects, so a request doesn't overwrite a goal that was set in the meantime.
+//!
+//! These functions write the state, so they can't be used when the state is protected. The application then uses
+//! the [mailbox](crate::mailbox) or the [secure services](crate::secure_services) instead.
+//!
+//! Every function that changes the state forbids the [nothing pending hint](mailbox::leave_nothing_pending_hint),
+//! because the next boot then has to load the state.
+
+use crate::{
+    mailbox,
+    state::{BootloaderGoal, BootloaderState, GoalChangeError, RollbackReason, SwapStatistics},
+    Flash, FlashError,
+};
+
+/// What the most recent swap did
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SwapStatus {
+    /// The statistics of the swap, or `None` if none have been stored
+    pub statistics: Option<SwapStatistics>,
+    /// Why the image was swapped back, or [RollbackReason::None] if the swap installed an update
+    pub rollback_reason: RollbackReason,
+    /// True when the swap was a test swap and the image still has to be [confirmed](confirm_image)
+    pub awaiting_confirmation: bool,
+}
+
+/// Requests that the image in slot B is swapped into slot A at the next reset.
+/// Fails with a mismatch if the bootloader isn't idle.
+pub fn request_update(flash: &mut (impl Flash + ?Sized)) -> Result<(), GoalChangeError> {
+    mailbox::allow_nothing_pending_hint(false);
+    BootloaderState::compare_and_set_goal(
+        flash,
+        BootloaderGoal::JumpToApplication,
+        BootloaderGoal::StartSwap,
+    )
+}
+
+/// Requests that the image in slot B is test-swapped into slot A at the next reset. The new image must then
+/// [confirm](confirm_image) itself, or it's swapped back at the reset after.
+/// Fails with a mismatch if the bootloader isn't idle.
+pub fn request_test_update(flash: &mut (impl Flash + ?Sized)) -> Result<(), GoalChangeError> {
+    mailbox::allow_nothing_pending_hint(false);
+    BootloaderState::compare_and_set_goal(
+        flash,
+        BootloaderGoal::JumpToApplication,
+        BootloaderGoal::StartTestSwap,
+    )
+}
+
+/// Confirms the running image, so a test swap isn't swapped back and the boot attempts are cleared.
+///
+/// Does nothing when there is nothing to confirm, so the application can call it at every boot. The state is only
+/// stored when it changes.
+pub fn confirm_image(flash: &mut (impl Flash + ?Sized)) -> Result<(), FlashError> {
+    let mut state = BootloaderState::load(flash);
+    if !state.is_valid() {
+        return Ok(());
+    }
+
+    let awaiting_confirmation = state.failed_test_boots().is_some();
+    if !awaiting_confirmation && state.boot_attempts() == 0 {
+        return Ok(());
+    }
+
+    if awaiting_confirmation {
+        // Another goal may have been set after the test swap, which is kept
+        state
+            .set_goal(BootloaderGoal::StartSwap, BootloaderGoal::JumpToApplication)
+            .ok();
+    }
+    state.set_boot_attempts(0);
+    mailbox::allow_nothing_pending_hint(false);
+    state.store(flash)
+}
+
+/// Gets the goal the bootloader acts on at the next reset, or `None` if the stored value is unknown
+pub fn current_goal(flash: &(impl Flash + ?Sized)) -> Option<BootloaderGoal> {
+    BootloaderState::load(flash).current_goal()
+}
+
+/// Gets what the most recent swap did
+pub fn last_swap_status(flash: &(impl Flash + ?Sized)) -> SwapStatus {
+    let state = BootloaderState::load(flash);
+    SwapStatus {
+        statistics: state.last_swap_statistics(),
+        rollback_reason: state.rollback_reason(),
+        awaiting_confirmation: state.is_valid() && state.failed_test_boots().is_some(),
+    }
+}
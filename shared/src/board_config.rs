@@ -0,0 +1,115 @@
+//! The board configuration in the UICR
+//!
+//! Our boards only differ in the pins of the UART and the recovery button, so one bootloader binary can run on all
+//! of them when those are programmed into a customer OTP word of the UICR at production, right after the
+//! [board ID](crate::board_id):
+//!
+//! | Bits  | Field                                                                      |
+//! |-------|----------------------------------------------------------------------------|
+//! | 0-7   | UART RX pin, 0xFF = the pin of the board feature                           |
+//! | 8-15  | UART TX pin, 0xFF = the pin of the board feature                           |
+//! | 16-23 | recovery button pin, 0xFE = no button, 0xFF = the pin of the board feature |
+//! | 24-27 | baud rate, an index into [BAUD_RATES], 0xF = 115200 baud                   |
+//! | 28-31 | reserved, keep them erased                                                 |
+//!
+//! The pin numbers are those of port 0, except that 32 and up are the pins of port 1 on the nRF52840.
+//! An erased word keeps the configuration of the board feature the bootloader was built with, and so does a field
+//! with a pin or baud rate the chip doesn't have.
+//!
+//! To provision a board, build the word with [UicrBoardConfig::new] and program it at [BOARD_CONFIG_ADDRESS], for
+//! example with `nrfjprog --memwr 0x00FF818C --val 0xF4FE131E` for the UART of the turing board (RX 30, TX 19)
+//! without a button at 115200 baud. Like all UICR words, it can only be changed again after a full chip erase.
+
+use crate::{board_id::BOARD_ID_ADDRESS, chip};
+
+/// The address of the UICR word with the board configuration
+pub const BOARD_CONFIG_ADDRESS: u32 = BOARD_ID_ADDRESS + 4;
+
+/// The baud rates the UART can run at, in the order of their index in the UICR word
+pub const BAUD_RATES: [u32; 9] = [
+    9600, 19200, 38400, 57600, 115_200, 230_400, 460_800, 921_600, 1_000_000,
+];
+
+/// The baud rate of an erased field
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// The board configuration in the UICR. Every getter returns `None` when the field keeps the configuration of the
+/// board feature.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct UicrBoardConfig(pub u32);
+
+impl UicrBoardConfig {
+    const UART_RX_PIN_SHIFT: u32 = 0;
+    const UART_TX_PIN_SHIFT: u32 = 8;
+    const RECOVERY_PIN_SHIFT: u32 = 16;
+    const BAUD_RATE_SHIFT: u32 = 24;
+    const NO_PIN: u32 = 0xFE;
+    const ERASED_PIN: u32 = 0xFF;
+    const ERASED_BAUD_RATE: u32 = 0xF;
+
+    /// The configuration of an erased UICR word
+    pub const DEFAULT: Self = Self(0xFFFF_FFFF);
+
+    /// Builds the word for the given pins and baud rate. Returns `None` if the chip doesn't have one of the pins, or if
+    /// the baud rate isn't in [BAUD_RATES].
+    pub fn new(
+        uart_rx_pin: u8,
+        uart_tx_pin: u8,
+        recovery_pin: Option<u8>,
+        baud_rate: u32,
+    ) -> Option<Self> {
+        let pins = [Some(uart_rx_pin), Some(uart_tx_pin), recovery_pin];
+        if pins.into_iter().flatten().any(|pin| pin >= chip::GPIO_PINS) {
+            return None;
+        }
+        let baud_rate_index = BAUD_RATES.iter().position(|rate| *rate == baud_rate)? as u32;
+
+        Some(Self(
+            0xF000_0000
+                | baud_rate_index << Self::BAUD_RATE_SHIFT
+                | recovery_pin.map_or(Self::NO_PIN, u32::from) << Self::RECOVERY_PIN_SHIFT
+                | u32::from(uart_tx_pin) << Self::UART_TX_PIN_SHIFT
+                | u32::from(uart_rx_pin) << Self::UART_RX_PIN_SHIFT,
+        ))
+    }
+
+    /// The pin of the UART RX line
+    pub fn uart_rx_pin(&self) -> Option<u8> {
+        self.pin(Self::UART_RX_PIN_SHIFT)
+    }
+
+    /// The pin of the UART TX line
+    pub fn uart_tx_pin(&self) -> Option<u8> {
+        self.pin(Self::UART_TX_PIN_SHIFT)
+    }
+
+    /// The pin of the active low recovery button. `Some(None)` means the board has no button.
+    pub fn recovery_pin(&self) -> Option<Option<u8>> {
+        match (self.0 >> Self::RECOVERY_PIN_SHIFT) & 0xFF {
+            Self::NO_PIN => Some(None),
+            _ => self.pin(Self::RECOVERY_PIN_SHIFT).map(Some),
+        }
+    }
+
+    /// The baud rate of the UART
+    pub fn baud_rate(&self) -> Option<u32> {
+        match (self.0 >> Self::BAUD_RATE_SHIFT) & 0xF {
+            Self::ERASED_BAUD_RATE => None,
+            index => BAUD_RATES.get(index as usize).copied(),
+        }
+    }
+
+    /// The pin in the byte at the shift, if it's programmed and the chip has it
+    fn pin(&self, shift: u32) -> Option<u8> {
+        match (self.0 >> shift) & 0xFF {
+            Self::ERASED_PIN => None,
+            pin => (pin < u32::from(chip::GPIO_PINS)).then_some(pin as u8),
+        }
+    }
+}
+
+impl Default for UicrBoardConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
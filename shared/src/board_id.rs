@@ -0,0 +1,34 @@
+//! The ID of the board an image is built for
+//!
+//! The boards are built in the same factory from the same firmware train, so an image for one board can easily end up
+//! on another one. The board ID is programmed into a customer OTP word of the UICR at production, right after the
+//! [security counter](crate::security_counter), and the bootloader refuses to swap in a new image that was built for
+//! another board. An image says which board it's for in the board ID of its
+//! [ImageHeader](crate::image_header::ImageHeader) or in the [TLV_BOARD_ID](crate::mcuboot::TLV_BOARD_ID) TLV of
+//! its MCUboot trailer. An image without a board ID, or with [ANY_BOARD], runs on every board.
+
+use crate::security_counter::{SECURITY_COUNTER_ADDRESS, SECURITY_COUNTER_WORDS};
+
+/// The address of the UICR word with the board ID
+pub const BOARD_ID_ADDRESS: u32 = SECURITY_COUNTER_ADDRESS + SECURITY_COUNTER_WORDS as u32 * 4;
+
+/// The board ID of an image that runs on every board
+pub const ANY_BOARD: u16 = 0;
+/// The board ID of the Circuit Dojo nRF9160 Feather
+pub const FEATHER: u16 = 1;
+/// The board ID of the logistics board
+pub const LOGISTICS: u16 = 2;
+/// The board ID of the mobility board
+pub const MOBILITY: u16 = 3;
+/// The board ID of the turing board
+pub const TURING: u16 = 4;
+/// The board ID of the Actinius Icarus
+pub const ACTINIUS_ICARUS: u16 = 5;
+/// The board ID of the nRF52840 DK
+pub const NRF52840_DK: u16 = 6;
+
+/// Turns the value of the UICR word into a board ID. An erased word means the board ID was never programmed, so
+/// images for every board are accepted.
+pub fn from_uicr_word(word: u32) -> Option<u16> {
+    (word != 0xFFFF_FFFF).then_some(word as u16)
+}
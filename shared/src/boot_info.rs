@@ -0,0 +1,224 @@
+//! The boot info block that the bootloader hands over to the application
+//!
+//! The bootloader leaves what it did at the current boot in RAM (see [bootloader_boot_info_range]), so the
+//! application can tell which bootloader started it and why, and can forward the security events to the backend.
+//! The block is started at every boot with [begin]. Every event is added with [push_event] and the statistics of
+//! the most recent swap are added with [set_swap_statistics]. Right before the jump, the bootloader adds the
+//! [BootInfo] with [set_boot_info], which the application reads with [boot_info]. The layout is little-endian words:
+//!
+//! | Word | Field                                                              |
+//! |------|--------------------------------------------------------------------|
+//! | 0    | magic                                                              |
+//! | 1    | layout version in the lower 16 bits                                |
+//! | 2    | the number of events of this boot, including the ones that were dropped |
+//! | 3..  | up to [MAX_EVENTS] events of two words, like in the [event_log](crate::event_log) |
+//! | 35..39 | the [SwapStatistics] of the most recent swap, all ones if there are none |
+//! | 39..50 | the [BootInfo] of this boot, all ones until the bootloader set it |
+
+use crate::{
+    event_log::{EventRecord, SecurityEvent},
+    flash_addresses::bootloader_boot_info_range,
+    state::{BootloaderGoal, SwapStatistics},
+};
+
+/// The word that marks a valid boot info block
+pub const MAGIC: u32 = 0xB0071F0B;
+
+/// The current version of the layout
+pub const VERSION: u16 = 3;
+
+/// The maximum number of events in the block. Later events are counted, but dropped.
+pub const MAX_EVENTS: usize = 16;
+
+/// The layout of the boot info block in RAM
+#[repr(C)]
+struct BootInfoBlock {
+    magic: u32,
+    version: u32,
+    event_count: u32,
+    events: [[u32; 2]; MAX_EVENTS],
+    swap_statistics: [u32; 4],
+    boot_info: [u32; BootInfo::WORDS],
+}
+
+/// What the bootloader did at the current boot
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BootInfo {
+    /// The major, minor and patch version of the bootloader
+    pub bootloader_version: [u16; 3],
+    /// The short git hash of the bootloader in ascii, padded with zeroes, like in its
+    /// [BuildInfo](crate::build_info::BuildInfo)
+    pub git_hash: [u8; 16],
+    /// The address of the vector table that was started, which is in the active slot
+    pub application_address: u32,
+    /// The goal that the bootloader performed, or `None` if the state was invalid or wasn't loaded.
+    /// With a swap goal, the swap was finished, because the bootloader resets when a swap fails.
+    pub goal: Option<BootloaderGoal>,
+    /// The number of pages that were swapped at this boot
+    pub swapped_pages: u32,
+    /// The RESETREAS register of the POWER peripheral at this boot (see [reset_reason](crate::reset_reason))
+    pub reset_reason: u32,
+    /// The number of times the bootloader started the application since the application last
+    /// [cleared](crate::state::BootloaderState::clear_boot_attempts) them
+    pub boot_attempts: u32,
+}
+
+impl BootInfo {
+    /// The number of words of the boot info in the block
+    const WORDS: usize = 11;
+
+    fn to_words(self) -> [u32; Self::WORDS] {
+        let [major, minor, patch] = self.bootloader_version;
+        let mut words = [0; Self::WORDS];
+        words[0] = u32::from(major) | u32::from(minor) << 16;
+        words[1] = u32::from(patch);
+        for (word, bytes) in words[2..6].iter_mut().zip(self.git_hash.as_chunks::<4>().0) {
+            *word = u32::from_le_bytes(*bytes);
+        }
+        words[6] = self.application_address;
+        words[7] = self.goal.map_or(0xFFFF_FFFF, u32::from);
+        words[8] = self.swapped_pages;
+        words[9] = self.reset_reason;
+        words[10] = self.boot_attempts;
+        words
+    }
+
+    fn from_words(words: [u32; Self::WORDS]) -> Option<Self> {
+        if words[6] == 0xFFFF_FFFF {
+            return None;
+        }
+
+        let mut git_hash = [0; 16];
+        for (bytes, word) in git_hash.as_chunks_mut::<4>().0.iter_mut().zip(&words[2..6]) {
+            *bytes = word.to_le_bytes();
+        }
+        Some(Self {
+            bootloader_version: [words[0] as u16, (words[0] >> 16) as u16, words[1] as u16],
+            git_hash,
+            application_address: words[6],
+            goal: match words[7] {
+                0xFFFF_FFFF => None,
+                goal => goal.try_into().ok(),
+            },
+            swapped_pages: words[8],
+            reset_reason: words[9],
+            boot_attempts: words[10],
+        })
+    }
+}
+
+fn block() -> *mut BootInfoBlock {
+    bootloader_boot_info_range().start as *mut BootInfoBlock
+}
+
+/// Starts a new boot info block without any events. Called by the bootloader at the start of every boot.
+pub fn begin() {
+    unsafe {
+        let block = block();
+        core::ptr::addr_of_mut!((*block).version).write_volatile(VERSION as u32);
+        core::ptr::addr_of_mut!((*block).event_count).write_volatile(0);
+        core::ptr::addr_of_mut!((*block).swap_statistics).write_volatile([0xFFFF_FFFF; 4]);
+        core::ptr::addr_of_mut!((*block).boot_info).write_volatile([0xFFFF_FFFF; BootInfo::WORDS]);
+        core::ptr::addr_of_mut!((*block).magic).write_volatile(MAGIC);
+    }
+}
+
+/// Adds an event to the boot info block, if it has been started and there is room left
+pub fn push_event(event: SecurityEvent, detail: u32) {
+    unsafe {
+        let block = block();
+        if core::ptr::addr_of!((*block).magic).read_volatile() != MAGIC {
+            return;
+        }
+
+        let event_count = core::ptr::addr_of!((*block).event_count).read_volatile();
+        if let Some(record) = (*block).events.get_mut(event_count as usize) {
+            let event: u16 = event.into();
+            core::ptr::write_volatile(record, [detail, event as u32]);
+        }
+        core::ptr::addr_of_mut!((*block).event_count).write_volatile(event_count.saturating_add(1));
+    }
+}
+
+/// Adds the statistics of the most recent swap to the boot info block, if it has been started
+pub fn set_swap_statistics(statistics: SwapStatistics) {
+    unsafe {
+        let block = block();
+        if core::ptr::addr_of!((*block).magic).read_volatile() != MAGIC {
+            return;
+        }
+
+        core::ptr::addr_of_mut!((*block).swap_statistics).write_volatile([
+            statistics.pages_copied,
+            statistics.pages_skipped,
+            statistics.erases,
+            statistics.duration_ms,
+        ]);
+    }
+}
+
+/// Adds what the bootloader did at this boot to the boot info block, if it has been started.
+/// Called by the bootloader right before it jumps to the application.
+pub fn set_boot_info(info: &BootInfo) {
+    unsafe {
+        let block = block();
+        if core::ptr::addr_of!((*block).magic).read_volatile() != MAGIC {
+            return;
+        }
+
+        core::ptr::addr_of_mut!((*block).boot_info).write_volatile(info.to_words());
+    }
+}
+
+/// Gives what the bootloader did at the last boot.
+///
+/// Returns `None` if the bootloader didn't leave a boot info block or is too old to add the boot info.
+pub fn boot_info() -> Option<BootInfo> {
+    event_count()?;
+
+    BootInfo::from_words(unsafe { core::ptr::addr_of!((*block()).boot_info).read_volatile() })
+}
+
+/// Gives the statistics of the most recent swap, which may have been done at an earlier boot.
+///
+/// Returns `None` if there was no swap yet or the bootloader didn't leave a boot info block.
+pub fn swap_statistics() -> Option<SwapStatistics> {
+    event_count()?;
+
+    let [pages_copied, pages_skipped, erases, duration_ms] =
+        unsafe { core::ptr::addr_of!((*block()).swap_statistics).read_volatile() };
+    (pages_copied != 0xFFFF_FFFF).then_some(SwapStatistics {
+        pages_copied,
+        pages_skipped,
+        erases,
+        duration_ms,
+    })
+}
+
+/// Gives the number of events of the last boot, including the ones that didn't fit in the block.
+///
+/// Returns `None` if the bootloader didn't leave a boot info block.
+pub fn event_count() -> Option<u32> {
+    unsafe {
+        let block = block();
+        let valid = core::ptr::addr_of!((*block).magic).read_volatile() == MAGIC
+            && core::ptr::addr_of!((*block).version).read_volatile() == VERSION as u32;
+        valid.then(|| core::ptr::addr_of!((*block).event_count).read_volatile())
+    }
+}
+
+/// Iterates over the events of the last boot that are in the block, from old to new.
+///
+/// There are none if the bootloader didn't leave a boot info block.
+pub fn events() -> impl Iterator<Item = EventRecord> {
+    let stored_events = event_count().unwrap_or(0).min(MAX_EVENTS as u32) as usize;
+
+    (0..stored_events).filter_map(|index| {
+        let [detail, event] =
+            unsafe { core::ptr::addr_of!((*block()).events[index]).read_volatile() };
+        Some(EventRecord {
+            event: SecurityEvent::try_from(event as u16).ok()?,
+            detail,
+        })
+    })
+}
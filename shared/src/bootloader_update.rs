@@ -0,0 +1,129 @@
+//! Staging of bootloader updates
+//!
+//! A new bootloader is staged in slot B behind a [StagedImageHeader](crate::staged_image::StagedImageHeader)
+//! with [MAGIC]. The staged image is the new stage 1 as an MCUboot image, signed with `imgtool` like an application:
+//! an [McubootHeader], the bootloader itself, which must be exactly as large as the bootloader flash and must contain
+//! a valid [BuildInfo] in its descriptor block, and a TLV trailer with the digest and the signature.
+//! It must also be compatible with the hardware revision of the board.
+//! The application then sets the [UpdateBootloader](crate::state::BootloaderGoal::UpdateBootloader) goal.
+//!
+//! [validate] only checks the structure of the staged image. The signature is checked with the
+//! [signed image](StagedBootloader::signed_image) by `dis_bootloader_core::secure_boot::verify_bootloader_update`.
+
+use crate::{
+    build_info::BuildInfo,
+    flash_addresses::{bootloader_descriptor_range, bootloader_flash_range, program_slot_b_range},
+    mcuboot::McubootHeader,
+    slots::{SlotDescriptor, SlotRole},
+    staged_image::{self, StagedImage, StagedImageError, StagedImageHeader},
+    Flash,
+};
+use core::ops::Range;
+
+/// The magic word of the header of a staged bootloader image
+pub const MAGIC: u32 = 0xB007_0DA7;
+
+/// The image ID of the [signed image](StagedBootloader::signed_image), which is never in a layout of the application
+pub const BOOTLOADER_IMAGE: u8 = u8::MAX;
+
+/// Why a staged bootloader is not valid
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BootloaderUpdateError {
+    /// The staged image itself is not valid
+    Image(StagedImageError),
+    /// The staged image is not an MCUboot image with a TLV trailer, so it can't be signed
+    NoMcubootImage,
+    /// The bootloader in the image doesn't have the size of the bootloader flash
+    WrongSize,
+    /// The image has no valid build info in its descriptor block
+    NoBuildInfo,
+    /// The image doesn't run on the hardware revision of the board
+    IncompatibleHardware,
+}
+
+/// A bootloader update that is staged in slot B
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StagedBootloader {
+    /// The staged image behind the staged image header, which is the signed MCUboot image
+    pub image: StagedImage,
+    /// The MCUboot header at the start of the staged image
+    pub header: McubootHeader,
+    /// The build info of the new bootloader
+    pub build_info: BuildInfo,
+}
+
+impl StagedBootloader {
+    /// The address range of the new bootloader in slot B, which is copied over stage 1
+    pub fn bootloader_range(&self) -> Range<u32> {
+        self.header.image_range(self.image.address)
+    }
+
+    /// The signed image as a slot, for the signature check.
+    ///
+    /// It's only used to find the header and the TLV trailer, so unlike the slots of a layout, it isn't aligned to
+    /// pages.
+    pub fn signed_image(&self) -> SlotDescriptor {
+        SlotDescriptor {
+            role: SlotRole::Secondary,
+            range: self.image.range(),
+            image_id: BOOTLOADER_IMAGE,
+            executable: false,
+            vector_table_offset: None,
+            excluded_pages: &[],
+        }
+    }
+}
+
+/// Validates the structure of the bootloader image that is staged in slot B. This doesn't check its signature.
+pub fn validate(
+    flash: &(impl Flash + ?Sized),
+    hardware_revision: Option<u32>,
+) -> Result<StagedBootloader, BootloaderUpdateError> {
+    let image = staged_image::validate(flash, program_slot_b_range(), MAGIC)
+        .map_err(BootloaderUpdateError::Image)?;
+
+    if !image.is_compatible_with(hardware_revision) {
+        return Err(BootloaderUpdateError::IncompatibleHardware);
+    }
+
+    // The TLV trailer must be part of the staged image, so it's covered by its CRC too.
+    // The bootloader is copied in words, so it must start at a word.
+    let header = McubootHeader::load(flash, image.address)
+        .filter(|header| {
+            header.header_size % 4 == 0
+                && header
+                    .tlv_range(flash, image.address, image.range().end)
+                    .is_some()
+        })
+        .ok_or(BootloaderUpdateError::NoMcubootImage)?;
+
+    if header.image_size as usize != bootloader_flash_range().len() {
+        return Err(BootloaderUpdateError::WrongSize);
+    }
+
+    let build_info = build_info(flash).ok_or(BootloaderUpdateError::NoBuildInfo)?;
+
+    Ok(StagedBootloader {
+        image,
+        header,
+        build_info,
+    })
+}
+
+/// Reads the build info from the descriptor block of the bootloader image that is staged in slot B.
+///
+/// The image itself isn't validated.
+pub fn build_info(flash: &(impl Flash + ?Sized)) -> Option<BuildInfo> {
+    let image_address = program_slot_b_range().start + StagedImageHeader::SIZE;
+    let header = McubootHeader::load(flash, image_address)?;
+    let descriptor_offset = bootloader_descriptor_range().start - bootloader_flash_range().start;
+    let descriptor_start = header.image_range(image_address).start + descriptor_offset;
+    BuildInfo::from_bytes(
+        flash.read_u8(descriptor_start..descriptor_start + BuildInfo::SIZE as u32),
+    )
+}
+
+/// Returns true if the bootloader flash contains exactly the staged bootloader
+pub fn is_installed(flash: &(impl Flash + ?Sized), update: &StagedBootloader) -> bool {
+    flash.read_u8(bootloader_flash_range()) == flash.read_u8(update.bootloader_range())
+}
@@ -0,0 +1,163 @@
+//! The build information block of the bootloader
+//!
+//! The bootloader places a [BuildInfo] at the start of its descriptor block
+//! (see [bootloader_descriptor_range](crate::flash_addresses::bootloader_descriptor_range)).
+//! It has a fixed little-endian layout so the bootloader logs, the application and host tools all read the same data.
+//!
+//! | Offset | Size | Field           |
+//! |--------|------|-----------------|
+//! | 0      | 4    | magic           |
+//! | 4      | 2    | version major   |
+//! | 6      | 2    | version minor   |
+//! | 8      | 2    | version patch   |
+//! | 10     | 2    | reserved        |
+//! | 12     | 4    | feature bitmap  |
+//! | 16     | 8    | build timestamp |
+//! | 24     | 16   | git hash        |
+
+use crate::{flash_addresses::bootloader_descriptor_range, Flash};
+use core::mem::size_of;
+
+/// The bits of the [BuildInfo::features] bitmap
+pub mod features {
+    /// The bootloader supports test swaps
+    pub const TEST_SWAP: u32 = 1 << 0;
+    /// The bootloader has log output
+    pub const LOGGING: u32 = 1 << 1;
+    /// The bootloader verifies the application before jumping to it
+    pub const VERIFICATION: u32 = 1 << 2;
+    /// The bootloader starts the application in the non-secure state
+    pub const NON_SECURE: u32 = 1 << 3;
+    /// The bootloader makes its state read-only before starting the application
+    pub const STATE_PROTECTION: u32 = 1 << 4;
+    /// The bootloader enables the access port protection at every boot
+    pub const APPROTECT: u32 = 1 << 5;
+    /// The bootloader provisions the device identity at the first boot
+    pub const PROVISIONING: u32 = 1 << 6;
+    /// The bootloader wipes the device on an authenticated request
+    pub const RMA_WIPE: u32 = 1 << 7;
+    /// The bootloader leaves the boot measurements in RAM for the application
+    pub const MEASURED_BOOT: u32 = 1 << 8;
+    /// The verification decision is hardened against fault injection
+    pub const FI_HARDENING: u32 = 1 << 9;
+    /// The bootloader can be replaced with a new one from slot B
+    pub const SELF_UPDATE: u32 = 1 << 10;
+    /// Signing keys can be revoked with an authenticated request
+    pub const KEY_REVOCATION: u32 = 1 << 11;
+    /// The event log is encrypted with a key derived from the device secret
+    pub const ENCRYPTED_LOGS: u32 = 1 << 12;
+    /// The security events of a boot are left in the boot info block
+    pub const EVENT_REPORT: u32 = 1 << 13;
+    /// The old image is erased after an update is confirmed
+    pub const ERASE_OLD_IMAGE: u32 = 1 << 14;
+    /// The image in slot B can be started in place
+    pub const DIRECT_BOOT: u32 = 1 << 15;
+
+    /// The bootloader is built for the nRF9160 Feather
+    pub const BOARD_FEATHER: u32 = 1 << 16;
+    /// The bootloader is built for the logistics board
+    pub const BOARD_LOGISTICS: u32 = 1 << 17;
+    /// The bootloader is built for the mobility board
+    pub const BOARD_MOBILITY: u32 = 1 << 18;
+    /// The bootloader is built for the turing board
+    pub const BOARD_TURING: u32 = 1 << 19;
+    /// The bootloader is built for the Actinius Icarus
+    pub const BOARD_ACTINIUS_ICARUS: u32 = 1 << 20;
+    /// The bootloader is built for the nRF52840 DK
+    pub const BOARD_NRF52840_DK: u32 = 1 << 21;
+
+    // The bits up to 23 are kept for more boards
+
+    /// The log output and the diagnostics of the flash driver and the state go to defmt over RTT
+    pub const DEFMT: u32 = 1 << 24;
+    /// The state isn't loaded at boots after the application left a nothing pending hint
+    pub const FAST_WAKE: u32 = 1 << 25;
+    /// The erases and programs of a swap are traced to the log output
+    pub const FLASH_TRACE: u32 = 1 << 26;
+    /// The non-secure application can use the secure services of the bootloader
+    pub const SECURE_SERVICES: u32 = 1 << 27;
+    /// The newest image in slot A or slot B can be started in place
+    pub const DIRECT_XIP: u32 = 1 << 28;
+    /// A new image must have an image header before it replaces the running one
+    pub const IMAGE_HEADER: u32 = 1 << 29;
+    /// A new image must have a matching SHA-256 digest before it replaces the running one
+    pub const IMAGE_DIGEST: u32 = 1 << 30;
+    /// Only images signed with the signing key are swapped in and started
+    pub const SECURE_BOOT: u32 = 1 << 31;
+}
+
+/// Information about how the bootloader was built
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BuildInfo {
+    /// Always [Self::MAGIC] for a valid block
+    pub magic: u32,
+    /// The major part of the semver version
+    pub version_major: u16,
+    /// The minor part of the semver version
+    pub version_minor: u16,
+    /// The patch part of the semver version
+    pub version_patch: u16,
+    /// Always 0
+    pub reserved: u16,
+    /// The features the bootloader was built with. See the [features] module for the bits.
+    pub features: u32,
+    /// The time of the build in seconds since the unix epoch
+    pub timestamp: u64,
+    /// The short git hash in ascii, padded with zeroes
+    pub git_hash: [u8; 16],
+}
+
+impl BuildInfo {
+    /// The word that marks the start of a valid build info block
+    pub const MAGIC: u32 = 0xB0071AF0;
+
+    /// The size of the block in bytes
+    pub const SIZE: usize = size_of::<Self>();
+
+    /// Parses the build info from its little-endian byte representation.
+    ///
+    /// Returns `None` if the bytes are too short or the magic word doesn't match.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..Self::SIZE)?;
+
+        let u16_at =
+            |offset: usize| u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        let info = Self {
+            magic: u32_at(0),
+            version_major: u16_at(4),
+            version_minor: u16_at(6),
+            version_patch: u16_at(8),
+            reserved: u16_at(10),
+            features: u32_at(12),
+            timestamp: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            git_hash: bytes[24..40].try_into().unwrap(),
+        };
+
+        (info.magic == Self::MAGIC).then_some(info)
+    }
+
+    /// Reads the build info of the bootloader from its descriptor block
+    pub fn load(flash: &(impl Flash + ?Sized)) -> Option<Self> {
+        let start = bootloader_descriptor_range().start;
+        Self::from_bytes(flash.read_u8(start..start + Self::SIZE as u32))
+    }
+
+    /// The git hash as a string
+    pub fn git_hash(&self) -> &str {
+        let length = self
+            .git_hash
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(self.git_hash.len());
+        core::str::from_utf8(&self.git_hash[..length]).unwrap_or("<invalid>")
+    }
+
+    /// Returns true if all the given feature bits are set
+    pub fn has_features(&self, features: u32) -> bool {
+        self.features & features == features
+    }
+}
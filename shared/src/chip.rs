@@ -0,0 +1,150 @@
+//! The addresses that differ between the chips the bootloader runs on
+//!
+//! The nRF9160 is the default. With the `chip-nrf52840` feature, the addresses are those of the nRF52840.
+//! The peripherals of the nRF9160 are at their secure addresses, because the bootloader runs in the secure state.
+//!
+//! The UICR words of the bootloader start at [CUSTOMER_UICR_ADDRESS] on both chips, in the same order
+//! (see [identity](crate::identity)). The nRF52840 only has 32 customer words, so its
+//! [security counter](crate::security_counter) is two words shorter.
+
+#[cfg(feature = "chip-nrf52840")]
+pub use nrf52840::*;
+#[cfg(not(feature = "chip-nrf52840"))]
+pub use nrf9160::*;
+
+/// The addresses of the nRF9160
+pub mod nrf9160 {
+    use core::ops::Range;
+
+    /// The name of the chip
+    pub const NAME: &str = "nRF9160";
+
+    /// The address range of the user information configuration registers
+    pub const UICR_RANGE: Range<u32> = 0x00FF_8000..0x00FF_9000;
+
+    /// The first UICR word the customer can use, the start of the customer OTP area
+    pub const CUSTOMER_UICR_ADDRESS: u32 = 0x00FF_8108;
+    /// The number of customer words in the UICR
+    pub const CUSTOMER_UICR_WORDS: usize = 190;
+
+    /// The number of GPIO pins, which are all on port 0
+    pub const GPIO_PINS: u8 = 32;
+
+    /// The UICR register that protects the whole chip from the debugger
+    pub const UICR_APPROTECT: u32 = 0x00FF_8000;
+
+    /// The UICR register that protects the secure world from the debugger
+    pub const UICR_SECUREAPPROTECT: Option<u32> = Some(0x00FF_802C);
+
+    /// The address of the FICR word with the size of a code page in bytes (INFO.CODEPAGESIZE)
+    pub const FICR_CODE_PAGE_SIZE_ADDRESS: u32 = 0x00FF_0220;
+
+    /// The address of the FICR word with the number of code pages (INFO.CODESIZE)
+    pub const FICR_CODE_SIZE_ADDRESS: u32 = 0x00FF_0224;
+
+    /// The total erase time a page needs, rounded up to whole milliseconds (t_ERASEPAGE in the product specification)
+    pub const PAGE_ERASE_TIME_MS: u32 = 88;
+
+    /// The base address of the NVMC
+    pub const NVMC_ADDRESS: u32 = 0x5003_9000;
+
+    /// The base address of the POWER peripheral
+    pub const POWER_ADDRESS: u32 = 0x5000_5000;
+
+    /// The address of the POFCON register, which is in the REGULATORS peripheral
+    pub const POFCON_ADDRESS: u32 = 0x5000_4510;
+
+    /// The base address of RTC0
+    pub const RTC0_ADDRESS: u32 = 0x5001_4000;
+
+    /// The base address of RTC1
+    pub const RTC1_ADDRESS: u32 = 0x5001_5000;
+
+    /// The interrupt number of RTC1
+    pub const RTC1_INTERRUPT: u32 = 21;
+
+    /// The base address of the WDT
+    pub const WDT_ADDRESS: u32 = 0x5001_8000;
+
+    /// The base address of UARTE0
+    pub const UARTE0_ADDRESS: u32 = 0x5000_8000;
+
+    /// The base address of UARTE1
+    pub const UARTE1_ADDRESS: u32 = 0x5000_9000;
+
+    /// The base address of the CRYPTOCELL peripheral. The registers of the CC310 follow it at an offset of 0x1000.
+    pub const CRYPTOCELL_ADDRESS: u32 = 0x5084_0000;
+}
+
+/// The addresses of the nRF52840
+pub mod nrf52840 {
+    use core::ops::Range;
+
+    /// The name of the chip
+    pub const NAME: &str = "nRF52840";
+
+    /// The address range of the user information configuration registers
+    pub const UICR_RANGE: Range<u32> = 0x1000_1000..0x1000_2000;
+
+    /// The first UICR word the customer can use, the first of the 32 CUSTOMER registers
+    pub const CUSTOMER_UICR_ADDRESS: u32 = 0x1000_1080;
+    /// The number of customer words in the UICR
+    pub const CUSTOMER_UICR_WORDS: usize = 32;
+
+    /// The number of GPIO pins, where 32 and up are those of port 1
+    pub const GPIO_PINS: u8 = 48;
+
+    /// The UICR register that protects the whole chip from the debugger
+    pub const UICR_APPROTECT: u32 = 0x1000_1208;
+
+    /// The nRF52840 has no secure world, so there is no separate protection for it
+    pub const UICR_SECUREAPPROTECT: Option<u32> = None;
+
+    /// The address of the FICR word with the size of a code page in bytes (CODEPAGESIZE)
+    pub const FICR_CODE_PAGE_SIZE_ADDRESS: u32 = 0x1000_0010;
+
+    /// The address of the FICR word with the number of code pages (CODESIZE)
+    pub const FICR_CODE_SIZE_ADDRESS: u32 = 0x1000_0014;
+
+    /// The total erase time a page needs, rounded up to whole milliseconds (t_ERASEPAGE in the product specification)
+    pub const PAGE_ERASE_TIME_MS: u32 = 86;
+
+    /// The base address of the NVMC
+    pub const NVMC_ADDRESS: u32 = 0x4001_E000;
+
+    /// The base address of the ACL, which shares its peripheral ID with the NVMC
+    pub const ACL_ADDRESS: u32 = 0x4001_E000;
+
+    /// The base address of the POWER peripheral
+    pub const POWER_ADDRESS: u32 = 0x4000_0000;
+
+    /// The address of the POFCON register, which is in the POWER peripheral
+    pub const POFCON_ADDRESS: u32 = 0x4000_0510;
+
+    /// The base address of RTC0
+    pub const RTC0_ADDRESS: u32 = 0x4000_B000;
+
+    /// The base address of RTC1
+    pub const RTC1_ADDRESS: u32 = 0x4001_1000;
+
+    /// The interrupt number of RTC1
+    pub const RTC1_INTERRUPT: u32 = 17;
+
+    /// The base address of the WDT
+    pub const WDT_ADDRESS: u32 = 0x4001_0000;
+
+    /// The base address of UARTE0
+    pub const UARTE0_ADDRESS: u32 = 0x4000_2000;
+
+    /// The base address of UARTE1
+    pub const UARTE1_ADDRESS: u32 = 0x4002_8000;
+
+    /// The base address of the CRYPTOCELL peripheral. The registers of the CC310 follow it at an offset of 0x1000.
+    pub const CRYPTOCELL_ADDRESS: u32 = 0x5002_A000;
+
+    /// The base address of the QSPI peripheral
+    pub const QSPI_ADDRESS: u32 = 0x4002_9000;
+
+    /// The address of the XIP region, where the QSPI peripheral maps the external flash for reading
+    pub const QSPI_XIP_ADDRESS: u32 = 0x1200_0000;
+}
@@ -0,0 +1,133 @@
+//! The runtime configuration of the bootloader in the UICR
+//!
+//! One bootloader binary can be used for both development and production units. The behavior that differs
+//! between them is configured in a customer OTP word of the UICR, right after the
+//! [hardware revision](crate::hardware_revision):
+//!
+//! | Bits  | Field                                                                         |
+//! |-------|-------------------------------------------------------------------------------|
+//! | 0     | console logging, 1 = enabled                                                  |
+//! | 1     | recovery mode, 1 = enabled                                                    |
+//! | 2     | verification policy, 1 = strict (refuse to boot), 0 = lenient (boot anyway)  |
+//! | 3     | recovery signatures, 1 = not required, 0 = recovery images must be signed     |
+//! | 4-5   | debugger policy, see [DebuggerPolicy]                                         |
+//! | 8-15  | boot timeout in steps of 100 ms, 0xFF = no timeout                            |
+//! | 16-23 | confirmation deadline of a test swap in minutes, 0xFF = no deadline           |
+//! | 24-27 | failed boots before a test image is reverted, 0xF = no handshake              |
+//! | 28-31 | boot attempts before the image is reverted, 0xF = no limit                    |
+//!
+//! An erased word gives the development defaults: everything enabled, strict verification, no timeout, no deadline,
+//! no handshake, no boot attempt limit, unsigned recovery images and an ignored debugger. A production unit typically
+//! clears the logging and recovery bits, or the recovery signature bit if it keeps the recovery, and picks a debugger
+//! policy.
+
+use crate::hardware_revision::HARDWARE_REVISION_ADDRESS;
+
+/// The address of the UICR word with the configuration
+pub const CONFIG_ADDRESS: u32 = HARDWARE_REVISION_ADDRESS + 4;
+
+/// What the bootloader does when it finds a debugger attached at boot.
+///
+/// The policies are ordered so clearing more bits of the UICR word only makes it stricter.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DebuggerPolicy {
+    /// Boot normally (`0b11`, erased)
+    Ignore,
+    /// Destroy the device secret and then boot normally (`0b10`)
+    ZeroizeSecrets,
+    /// Don't boot at all (`0b01` or `0b00`)
+    RefuseBoot,
+}
+
+/// The configuration of the bootloader
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BootloaderConfig(pub u32);
+
+impl BootloaderConfig {
+    const LOGGING: u32 = 1 << 0;
+    const RECOVERY: u32 = 1 << 1;
+    const STRICT_VERIFICATION: u32 = 1 << 2;
+    const UNSIGNED_RECOVERY: u32 = 1 << 3;
+    const DEBUGGER_POLICY_SHIFT: u32 = 4;
+    const BOOT_TIMEOUT_SHIFT: u32 = 8;
+    const CONFIRMATION_DEADLINE_SHIFT: u32 = 16;
+    const FAILED_BOOT_THRESHOLD_SHIFT: u32 = 24;
+    const BOOT_ATTEMPT_THRESHOLD_SHIFT: u32 = 28;
+
+    /// The configuration of an erased UICR word
+    pub const DEFAULT: Self = Self(0xFFFF_FFFF);
+
+    /// Returns true if the bootloader may log to the console
+    pub fn logging_enabled(&self) -> bool {
+        self.0 & Self::LOGGING != 0
+    }
+
+    /// Returns true if the bootloader may enter the recovery mode
+    pub fn recovery_enabled(&self) -> bool {
+        self.0 & Self::RECOVERY != 0
+    }
+
+    /// Returns true if the bootloader must refuse to boot an application that fails the verification
+    pub fn strict_verification(&self) -> bool {
+        self.0 & Self::STRICT_VERIFICATION != 0
+    }
+
+    /// Returns true if an image received by the recovery must be signed before the bootloader swaps it in
+    pub fn recovery_requires_signature(&self) -> bool {
+        self.0 & Self::UNSIGNED_RECOVERY == 0
+    }
+
+    /// What to do when a debugger is attached at boot
+    pub fn debugger_policy(&self) -> DebuggerPolicy {
+        match (self.0 >> Self::DEBUGGER_POLICY_SHIFT) & 0b11 {
+            0b11 => DebuggerPolicy::Ignore,
+            0b10 => DebuggerPolicy::ZeroizeSecrets,
+            _ => DebuggerPolicy::RefuseBoot,
+        }
+    }
+
+    /// The time the bootloader waits for the user before it boots, in milliseconds.
+    /// Returns `None` if there is no timeout.
+    pub fn boot_timeout_ms(&self) -> Option<u32> {
+        match (self.0 >> Self::BOOT_TIMEOUT_SHIFT) & 0xFF {
+            0xFF => None,
+            steps => Some(steps * 100),
+        }
+    }
+
+    /// The time a test-swapped image gets to confirm itself before the bootloader reverts it, in minutes.
+    /// Returns `None` if there is no deadline.
+    pub fn confirmation_deadline_minutes(&self) -> Option<u32> {
+        match (self.0 >> Self::CONFIRMATION_DEADLINE_SHIFT) & 0xFF {
+            0xFF => None,
+            minutes => Some(minutes),
+        }
+    }
+
+    /// The number of boots without the [handshake](crate::mailbox::complete_handshake) after which a
+    /// test-swapped image is reverted.
+    /// Returns `None` if the handshake isn't used, then the image is reverted at the first boot it isn't confirmed.
+    pub fn failed_boot_threshold(&self) -> Option<u32> {
+        match (self.0 >> Self::FAILED_BOOT_THRESHOLD_SHIFT) & 0xF {
+            0xF => None,
+            boots => Some(boots),
+        }
+    }
+
+    /// The number of boots in which the application doesn't
+    /// [clear the boot attempts](crate::state::BootloaderState::clear_boot_attempts) after which the bootloader
+    /// swaps back to the previous image.
+    /// Returns `None` if there is no limit, then the boot attempts aren't counted.
+    pub fn boot_attempt_threshold(&self) -> Option<u32> {
+        match (self.0 >> Self::BOOT_ATTEMPT_THRESHOLD_SHIFT) & 0xF {
+            0xF => None,
+            boots => Some(boots),
+        }
+    }
+}
+
+impl Default for BootloaderConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
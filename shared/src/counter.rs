@@ -0,0 +1,125 @@
+//! A monotonic counter in flash that counts by clearing bits
+//!
+//! Every increment clears one more bit in the words that are reserved for the counter, so it never needs an erase
+//! until the counter is reset on purpose. Clearing a bit is a single word program, so after a power loss the counter
+//! has either the old or the new value and can simply be used again.
+//!
+//! The bits are cleared from the lowest bit of the first word upwards, and the value is the amount of cleared bits.
+
+use crate::{flash_addresses::PAGE_SIZE, Flash, FlashError};
+use core::{mem::size_of, ops::Range};
+
+/// A monotonic counter in a range of erased flash words
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MonotonicCounter {
+    address_range: Range<u32>,
+}
+
+/// Why the counter could not be incremented
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CounterError {
+    /// The counter has reached its highest value
+    Full,
+    /// The flash could not be programmed
+    Flash(FlashError),
+}
+
+impl From<FlashError> for CounterError {
+    fn from(error: FlashError) -> Self {
+        Self::Flash(error)
+    }
+}
+
+impl MonotonicCounter {
+    /// Creates a counter that uses the words in the given address range.
+    ///
+    /// The range must be word aligned and must be erased before the counter is used for the first time.
+    pub const fn new(address_range: Range<u32>) -> Self {
+        assert!(address_range.start & 3 == 0 && address_range.end & 3 == 0);
+        Self { address_range }
+    }
+
+    /// The highest value the counter can have
+    pub fn capacity(&self) -> u32 {
+        self.address_range.len() as u32 / size_of::<u32>() as u32 * u32::BITS
+    }
+
+    /// Reads the current value of the counter
+    pub fn value(&self, flash: &(impl Flash + ?Sized)) -> u32 {
+        flash
+            .read_u32(self.address_range.clone())
+            .iter()
+            .map(|word| word.count_zeros())
+            .sum()
+    }
+
+    /// Increments the counter by one and returns the new value
+    pub fn increment(&self, flash: &mut (impl Flash + ?Sized)) -> Result<u32, CounterError> {
+        let words = flash.read_u32(self.address_range.clone());
+        let (index, word) = words
+            .iter()
+            .enumerate()
+            .find(|(_, word)| **word != 0)
+            .ok_or(CounterError::Full)?;
+
+        // Clear the lowest bit that is still set
+        let word_address = self.address_range.start + index as u32 * size_of::<u32>() as u32;
+        let new_word = word & (word - 1);
+        program_word(flash, word_address, new_word)?;
+
+        Ok(self.value(flash))
+    }
+
+    /// Increments the counter until it has at least the given value.
+    ///
+    /// This is the operation for anti-rollback: the counter follows the highest value that was ever seen.
+    /// Full words are cleared at once, so this takes at most one program operation per word.
+    pub fn advance_to(
+        &self,
+        flash: &mut (impl Flash + ?Sized),
+        value: u32,
+    ) -> Result<(), CounterError> {
+        if value > self.capacity() {
+            return Err(CounterError::Full);
+        }
+
+        let mut word_address = self.address_range.start;
+        let mut bits_before = 0;
+
+        while bits_before < value {
+            let bits_in_word = (value - bits_before).min(u32::BITS);
+            let cleared_bits = if bits_in_word == u32::BITS {
+                0
+            } else {
+                u32::MAX << bits_in_word
+            };
+
+            let word = flash.read_u32(word_address..word_address + 4)[0];
+            if word & cleared_bits != word {
+                program_word(flash, word_address, word & cleared_bits)?;
+            }
+
+            word_address += size_of::<u32>() as u32;
+            bits_before += u32::BITS;
+        }
+
+        Ok(())
+    }
+}
+
+/// Programs a single word in flash
+pub(crate) fn program_word(
+    flash: &mut (impl Flash + ?Sized),
+    address: u32,
+    value: u32,
+) -> Result<(), FlashError> {
+    let page_address = address - address % PAGE_SIZE;
+    let word_index = (address - page_address) as usize / size_of::<u32>();
+
+    // Only the words that differ from what is in flash are written, so we pass the current contents up to our word
+    let mut buffer = [0; PAGE_SIZE as usize / size_of::<u32>()];
+    buffer[..word_index].copy_from_slice(flash.read_u32(page_address..address));
+    buffer[word_index] = value;
+
+    flash.program_page(page_address, &buffer[..=word_index])
+}
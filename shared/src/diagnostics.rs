@@ -0,0 +1,73 @@
+//! Diagnostics from inside the shared code and the flash driver
+//!
+//! The [uprintln](../../dis_bootloader_core/macro.uprintln.html) output of the core needs a log sink that is passed
+//! around, which code like the [state](crate::state) and the flash driver doesn't have. Instead, they use the
+//! [debug](crate::debug) and [warn](crate::warn) macros in here, which forward to a logging facade:
+//!
+//! - with the `log` feature, to the `log` crate, for example for host tools using the `std-compat` feature
+//! - with the `defmt` feature, to `defmt`, for example for the bootloader over RTT and the HIL tests
+//! - without either, the messages are compiled out. The arguments are still type checked.
+//!
+//! When both features are enabled, `log` is used. The macros take the same arguments as `format_args!`.
+
+/// Emits a debug message through the selected logging facade
+#[cfg(feature = "log")]
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::__log::debug!($($arg)*)
+    };
+}
+
+/// Emits a warning through the selected logging facade
+#[cfg(feature = "log")]
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::__log::warn!($($arg)*)
+    };
+}
+
+/// Emits a debug message through the selected logging facade
+#[cfg(all(feature = "defmt", not(feature = "log")))]
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::__defmt::debug!("{}", $crate::__defmt::Display2Format(&format_args!($($arg)*)))
+    };
+}
+
+/// Emits a warning through the selected logging facade
+#[cfg(all(feature = "defmt", not(feature = "log")))]
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::__defmt::warn!("{}", $crate::__defmt::Display2Format(&format_args!($($arg)*)))
+    };
+}
+
+/// Emits a debug message through the selected logging facade.
+///
+/// No facade is selected, so this compiles to nothing.
+#[cfg(not(any(feature = "log", feature = "defmt")))]
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+/// Emits a warning through the selected logging facade.
+///
+/// No facade is selected, so this compiles to nothing.
+#[cfg(not(any(feature = "log", feature = "defmt")))]
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
@@ -0,0 +1,288 @@
+//! An append-only log of security relevant events in flash
+//!
+//! The bootloader records events like state CRC failures and rejected wipe requests, so the application or
+//! host tooling can read them out for audits. Every record is two words that are programmed once into erased
+//! flash and never changed, so the log never needs an erase. When it's full, new events are dropped.
+//!
+//! | Word | Field                                                 |
+//! |------|-------------------------------------------------------|
+//! | 0    | the detail, which depends on the event                |
+//! | 1    | the event, see [SecurityEvent], in the lower 16 bits   |
+//!
+//! The detail is programmed first. A record that lost its event word to a power loss is skipped.
+//!
+//! Records can also be encrypted with [append_encrypted]. The detail and the event are then XORed with a keystream
+//! that is unique to the position of the record (see [RecordKeystream]) and the upper half of the event word is
+//! a different marker. Only [decrypted_records] returns encrypted records, [records] skips them.
+//!
+//! The application can add its own breadcrumbs, like "entered DFU" or "confirmed the image", with
+//! [append_application]. They have an application defined code instead of a [SecurityEvent] and yet another marker,
+//! and are read with [application_records]. The application may use at most [APPLICATION_QUOTA] records, so the
+//! bootloader always has room left for its events. Like the rest of the log, they survive updates and wipes.
+
+use crate::{
+    counter::program_word, flash_addresses::bootloader_event_log_range, Flash, FlashError,
+};
+use core::mem::size_of;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+/// The upper half of the event word of a record
+const RECORD_MARKER: u32 = 0xE7E7_0000;
+/// The upper half of the event word of an encrypted record
+const ENCRYPTED_RECORD_MARKER: u32 = 0xE7E6_0000;
+/// The upper half of the event word of a record of the application
+const APPLICATION_RECORD_MARKER: u32 = 0xE7E5_0000;
+/// The size of a record in bytes
+const RECORD_SIZE: u32 = 2 * size_of::<u32>() as u32;
+
+/// A security relevant event
+#[repr(u16)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, IntoPrimitive, TryFromPrimitive)]
+pub enum SecurityEvent {
+    /// The state was not erased, but its CRC didn't match
+    StateCrcFailure = 1,
+    /// The signature of an image didn't match
+    SignatureCheckFailed = 2,
+    /// An image was rolled back
+    RollbackTriggered = 3,
+    /// The bootloader entered the recovery mode. The detail is the trigger: 0 for the button, 1 for an invalid image
+    /// in slot A and 2 for a request of the host.
+    RecoveryEntered = 4,
+    /// An authenticated wipe was started
+    WipeStarted = 5,
+    /// A wipe request with an invalid token was rejected
+    WipeTokenRejected = 6,
+    /// The access port protection was found disabled and enabled again
+    AccessPortProtectionRestored = 7,
+    /// The device identity was provisioned
+    Provisioned = 8,
+    /// A signing key was revoked. The detail is the key ID.
+    KeyRevoked = 9,
+    /// A revocation request with an invalid token was rejected. The detail is the key ID.
+    RevocationTokenRejected = 10,
+    /// An image didn't pass the verification. The detail is 0 for slot A, 1 for the direct boot slot, 2 for the
+    /// slots of a direct-XIP boot, 3 for a new image with a missing or corrupt image header, 4 for a new image
+    /// with a digest that doesn't match, 5 for a new image that isn't signed, 6 for a new image with a lower
+    /// security counter, 7 for a new image with an unknown critical TLV, 8 for a new image that is built for
+    /// another board and 9 for an encrypted image that would be swapped in.
+    VerificationFailed = 11,
+    /// A debugger was attached at boot. The detail is 1 if the boot was refused and 0 if it went on.
+    DebuggerDetected = 12,
+    /// The primary slot had no valid image, so the golden image was started instead
+    GoldenImageStarted = 13,
+    /// A wrong response to a challenge of the shell was rejected. The detail is the number of failed attempts in this
+    /// boot.
+    ShellUnlockRejected = 14,
+}
+
+/// A record in the event log
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct EventRecord {
+    /// The event
+    pub event: SecurityEvent,
+    /// The detail of the event. Its meaning depends on the event and is 0 when there is nothing to add.
+    pub detail: u32,
+}
+
+/// Why an event was not recorded
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AppendError {
+    /// The log is full
+    Full,
+    /// The record could not be programmed
+    Flash(FlashError),
+}
+
+impl From<FlashError> for AppendError {
+    fn from(error: FlashError) -> Self {
+        Self::Flash(error)
+    }
+}
+
+/// The maximum number of records the application can add to the log
+pub const APPLICATION_QUOTA: usize = 128;
+
+/// A record that the application added to the log
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ApplicationRecord {
+    /// The code of the breadcrumb, which is defined by the application
+    pub code: u16,
+    /// The detail of the breadcrumb, which is defined by the application
+    pub detail: u32,
+}
+
+/// Why a record of the application was not added
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ApplicationAppendError {
+    /// The log is full
+    Full,
+    /// The application already has [APPLICATION_QUOTA] records in the log
+    QuotaExceeded,
+    /// The record could not be programmed
+    Flash(FlashError),
+}
+
+impl From<FlashError> for ApplicationAppendError {
+    fn from(error: FlashError) -> Self {
+        Self::Flash(error)
+    }
+}
+
+/// Gives the keystream that encrypts a record
+pub trait RecordKeystream {
+    /// Returns the keystream of the record with the given index in the log.
+    /// The first 4 bytes are XORed with the detail, the last 2 with the event.
+    ///
+    /// Every index must give another keystream, because the records are encrypted with a simple XOR.
+    fn keystream(&self, record_index: u32) -> [u8; 6];
+}
+
+/// Finds the address of the first free record
+fn free_record_address(flash: &(impl Flash + ?Sized)) -> Result<u32, AppendError> {
+    // A record can only be placed where both words are still erased
+    bootloader_event_log_range()
+        .step_by(RECORD_SIZE as usize)
+        .rev()
+        .take_while(|address| flash.read_u32(*address..*address + RECORD_SIZE) == [0xFFFF_FFFF; 2])
+        .last()
+        .ok_or(AppendError::Full)
+}
+
+/// Gives the index of the record at the given address
+fn record_index(record_address: u32) -> u32 {
+    (record_address - bootloader_event_log_range().start) / RECORD_SIZE
+}
+
+/// XORs the detail and the event with the keystream of the record
+fn apply_keystream(keystream: [u8; 6], detail: u32, event: u16) -> (u32, u16) {
+    let [d0, d1, d2, d3, e0, e1] = keystream;
+    (
+        detail ^ u32::from_le_bytes([d0, d1, d2, d3]),
+        event ^ u16::from_le_bytes([e0, e1]),
+    )
+}
+
+/// Appends an event to the log
+pub fn append(
+    flash: &mut (impl Flash + ?Sized),
+    event: SecurityEvent,
+    detail: u32,
+) -> Result<(), AppendError> {
+    let record_address = free_record_address(flash)?;
+
+    let event: u16 = event.into();
+    program_word(flash, record_address, detail)?;
+    program_word(flash, record_address + 4, RECORD_MARKER | event as u32)?;
+
+    Ok(())
+}
+
+/// Appends an event to the log as an encrypted record
+pub fn append_encrypted(
+    flash: &mut (impl Flash + ?Sized),
+    keystream: &dyn RecordKeystream,
+    event: SecurityEvent,
+    detail: u32,
+) -> Result<(), AppendError> {
+    let record_address = free_record_address(flash)?;
+
+    let (detail, event) = apply_keystream(
+        keystream.keystream(record_index(record_address)),
+        detail,
+        event.into(),
+    );
+    program_word(flash, record_address, detail)?;
+    program_word(
+        flash,
+        record_address + 4,
+        ENCRYPTED_RECORD_MARKER | event as u32,
+    )?;
+
+    Ok(())
+}
+
+/// Appends a record of the application to the log, if its quota allows it
+pub fn append_application(
+    flash: &mut (impl Flash + ?Sized),
+    code: u16,
+    detail: u32,
+) -> Result<(), ApplicationAppendError> {
+    if application_records(flash).count() >= APPLICATION_QUOTA {
+        return Err(ApplicationAppendError::QuotaExceeded);
+    }
+
+    let record_address = free_record_address(flash).map_err(|_| ApplicationAppendError::Full)?;
+
+    program_word(flash, record_address, detail)?;
+    program_word(
+        flash,
+        record_address + 4,
+        APPLICATION_RECORD_MARKER | code as u32,
+    )?;
+
+    Ok(())
+}
+
+/// Iterates over all the records of the application in the log, from old to new
+pub fn application_records(
+    flash: &(impl Flash + ?Sized),
+) -> impl Iterator<Item = ApplicationRecord> + '_ {
+    flash
+        .read_u32(bootloader_event_log_range())
+        .as_chunks::<2>()
+        .0
+        .iter()
+        .filter(|record| record[1] & 0xFFFF_0000 == APPLICATION_RECORD_MARKER)
+        .map(|record| ApplicationRecord {
+            code: record[1] as u16,
+            detail: record[0],
+        })
+}
+
+/// Iterates over all the records in the log, from old to new
+pub fn records(flash: &(impl Flash + ?Sized)) -> impl Iterator<Item = EventRecord> + '_ {
+    flash
+        .read_u32(bootloader_event_log_range())
+        .as_chunks::<2>()
+        .0
+        .iter()
+        .filter_map(|record| {
+            if record[1] & 0xFFFF_0000 != RECORD_MARKER {
+                return None;
+            }
+
+            Some(EventRecord {
+                event: SecurityEvent::try_from(record[1] as u16).ok()?,
+                detail: record[0],
+            })
+        })
+}
+
+/// Iterates over all the records in the log, from old to new, and decrypts the encrypted ones.
+///
+/// Encrypted records that don't decrypt to a known event were encrypted with another key and are skipped.
+pub fn decrypted_records<'a>(
+    flash: &'a (impl Flash + ?Sized),
+    keystream: &'a dyn RecordKeystream,
+) -> impl Iterator<Item = EventRecord> + 'a {
+    flash
+        .read_u32(bootloader_event_log_range())
+        .as_chunks::<2>()
+        .0
+        .iter()
+        .zip(0..)
+        .filter_map(|(record, index)| {
+            let (detail, event) = match record[1] & 0xFFFF_0000 {
+                RECORD_MARKER => (record[0], record[1] as u16),
+                ENCRYPTED_RECORD_MARKER => {
+                    apply_keystream(keystream.keystream(index), record[0], record[1] as u16)
+                }
+                _ => return None,
+            };
+
+            Some(EventRecord {
+                event: SecurityEvent::try_from(event).ok()?,
+                detail,
+            })
+        })
+}
@@ -0,0 +1,36 @@
+//! Slot B on an external flash
+//!
+//! When an image doesn't fit twice into the internal flash, slot B can be put on a NOR flash outside of the chip.
+//! The layout then gives the `program_slot_b` partition an origin in the window at [EXTERNAL_FLASH_ADDRESS], and
+//! `external_flash_size` in `partitions.toml` tells how big the external flash is. Everything else, including
+//! slot A, stays in the internal flash.
+//!
+//! The driver of the external flash implements [Flash](crate::Flash) with its own addresses, starting at 0. The
+//! [Flash](crate::Flash) of the binary puts it behind the window with [external_address] and [external_range], so
+//! the swap and everything else in the core work on both flashes without knowing which is which. Its
+//! [geometry](crate::Flash::geometry) has the size of the window in
+//! [external_size](crate::flash_geometry::FlashGeometry::external_size).
+//!
+//! The external flash isn't written through the memory map: its driver erases and programs it with commands over
+//! its bus. It must be readable through the memory map though, because the reads of [Flash](crate::Flash) return
+//! slices. The window is where the nRF52840 maps its QSPI flash for reading (XIP).
+
+use core::ops::Range;
+
+/// The address where the window with the external flash starts. This is the XIP region of the nRF52840.
+///
+/// The build scripts have the same value (see `partitions.rs`).
+pub const EXTERNAL_FLASH_ADDRESS: u32 = 0x1200_0000;
+
+/// Translates an address in the window to the address in the external flash.
+/// Returns `None` for an address of the internal flash.
+pub fn external_address(address: u32) -> Option<u32> {
+    address.checked_sub(EXTERNAL_FLASH_ADDRESS)
+}
+
+/// Translates an address range in the window to the range in the external flash.
+/// Returns `None` for a range that starts in the internal flash.
+pub fn external_range(address_range: Range<u32>) -> Option<Range<u32>> {
+    let start = external_address(address_range.start)?;
+    Some(start..address_range.end.saturating_sub(EXTERNAL_FLASH_ADDRESS))
+}
@@ -0,0 +1,75 @@
+//! The page size and the size of the internal flash, as the FICR reports them, and the size of an external flash
+//!
+//! The drivers read the geometry from the FICR at startup instead of assuming the 4KB pages and 1MB of the nRF9160,
+//! so their range checks match the part they run on. The memory layout is still linked for [PAGE_SIZE] pages,
+//! so the swap refuses to run on a part where the layout doesn't [fit](FlashGeometry::fits_layout).
+
+use crate::{
+    chip,
+    external_flash::external_range,
+    flash_addresses::{bootloader_state_range, PAGE_SIZE},
+};
+use core::ops::Range;
+
+/// The address of the FICR word with the size of a code page in bytes
+pub const CODE_PAGE_SIZE_ADDRESS: u32 = chip::FICR_CODE_PAGE_SIZE_ADDRESS;
+/// The address of the FICR word with the number of code pages
+pub const CODE_SIZE_ADDRESS: u32 = chip::FICR_CODE_SIZE_ADDRESS;
+
+/// The page size and the size of a flash
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FlashGeometry {
+    /// The size of a page in bytes, which is the unit of an erase
+    pub page_size: u32,
+    /// The size of the flash in bytes. The flash starts at address 0.
+    pub size: u32,
+    /// The size of the [external flash](crate::external_flash) in bytes, or 0 if there is none
+    pub external_size: u32,
+}
+
+impl FlashGeometry {
+    /// The geometry of the nRF9160, which the memory layout is made for. The nRF52840 has the same flash.
+    pub const NRF9160: Self = Self {
+        page_size: PAGE_SIZE,
+        size: 0x0010_0000,
+        external_size: 0,
+    };
+
+    /// Turns the values of the FICR words into a geometry.
+    /// An erased or zero word can't be right, so the geometry of the nRF9160 is used instead.
+    pub fn from_ficr_words(code_page_size: u32, code_size: u32) -> Self {
+        match code_page_size.checked_mul(code_size) {
+            Some(size) if code_page_size != 0 && code_size != 0 && code_size != 0xFFFF_FFFF => {
+                Self {
+                    page_size: code_page_size,
+                    size,
+                    external_size: 0,
+                }
+            }
+            _ => Self::NRF9160,
+        }
+    }
+
+    /// The number of pages in the flash
+    pub fn page_count(&self) -> u32 {
+        self.size / self.page_size
+    }
+
+    /// Returns true if the address range lies in the internal flash or in the window of the external flash
+    pub fn contains(&self, address_range: &Range<u32>) -> bool {
+        if address_range.start > address_range.end {
+            return false;
+        }
+
+        match external_range(address_range.clone()) {
+            Some(range) => range.end <= self.external_size,
+            None => address_range.end <= self.size,
+        }
+    }
+
+    /// Returns true if the memory layout in [flash_addresses](crate::flash_addresses) can be used on this flash:
+    /// the pages have the size of [PAGE_SIZE] and the state, which is the last region, lies in the flash.
+    pub fn fits_layout(&self) -> bool {
+        self.page_size == PAGE_SIZE && bootloader_state_range().end <= self.size
+    }
+}
@@ -0,0 +1,31 @@
+//! The hardware revision of the board
+//!
+//! Electrically different spins of the same board share one firmware train, but not every image runs on
+//! every spin. The revision is programmed into a customer OTP word of the UICR at production, right after the
+//! [device identity](crate::identity).
+//!
+//! Images say which revisions they run on with a bitmask where bit `n` means the image runs on revision `n`, and 0
+//! means it runs on all of them (see [is_compatible]). Staged images have the mask in their
+//! [header](crate::staged_image::StagedImageHeader), application images in the
+//! [TLV_HARDWARE_REVISIONS](crate::mcuboot::TLV_HARDWARE_REVISIONS) TLV of their MCUboot trailer.
+
+use crate::identity::{DeviceIdentity, IDENTITY_ADDRESS};
+
+/// The address of the UICR word with the hardware revision
+pub const HARDWARE_REVISION_ADDRESS: u32 = IDENTITY_ADDRESS + DeviceIdentity::WORDS as u32 * 4;
+
+/// Turns the value of the UICR word into a revision. An erased word means the revision was never programmed.
+pub fn from_uicr_word(word: u32) -> Option<u32> {
+    (word != 0xFFFF_FFFF).then_some(word)
+}
+
+/// Returns true if an image with the given mask of compatible revisions runs on the given hardware revision.
+///
+/// When the revision is unknown, only images that run on all revisions are compatible.
+pub fn is_compatible(compatible_revisions: u32, hardware_revision: Option<u32>) -> bool {
+    match hardware_revision {
+        _ if compatible_revisions == 0 => true,
+        Some(revision) if revision < u32::BITS => compatible_revisions & (1 << revision) != 0,
+        _ => false,
+    }
+}
@@ -0,0 +1,107 @@
+//! The identity of the device that is provisioned into the UICR by the bootloader
+//!
+//! At the first boot of a device, the bootloader receives a device ID and a per-device secret over the
+//! provisioning channel and writes them into the customer OTP words of the UICR. This module defines the layout of
+//! those words, so the bootloader and anything else that's allowed to read them agree on it.
+//!
+//! | Word | Field                                    |
+//! |------|------------------------------------------|
+//! | 0    | marker, [DeviceIdentity::PROVISIONED]    |
+//! | 1-4  | device ID                                |
+//! | 5-12 | device secret                            |
+//! | 13   | CRC over the device ID and secret        |
+//!
+//! The marker is written last, so a device that lost power during provisioning is not seen as provisioned.
+
+use crate::chip;
+use core::mem::size_of;
+
+/// The address of the first word of the identity in the UICR, the first customer word of the [chip](crate::chip)
+pub const IDENTITY_ADDRESS: u32 = chip::CUSTOMER_UICR_ADDRESS;
+
+/// The identity of a device
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DeviceIdentity {
+    /// The unique ID of the device
+    pub id: [u8; 16],
+    /// The secret that only this device knows
+    pub secret: [u8; 32],
+}
+
+impl DeviceIdentity {
+    /// The marker word that shows that the provisioning is complete
+    pub const PROVISIONED: u32 = 0xB001_D1D0;
+
+    /// The amount of words the identity takes up in the UICR
+    pub const WORDS: usize = 14;
+
+    /// The size of the identity in the provisioning message in bytes, without the CRC
+    pub const MESSAGE_SIZE: usize = 16 + 32;
+
+    /// Creates an identity from the bytes of a provisioning message.
+    ///
+    /// The message is the device ID, the secret and the CRC of both (CRC-32/MPEG-2, little-endian).
+    /// Returns `None` if the message is too short or the CRC doesn't match.
+    pub fn from_message(message: &[u8]) -> Option<Self> {
+        let message = message.get(..Self::MESSAGE_SIZE + size_of::<u32>())?;
+        let (data, crc) = message.split_at(Self::MESSAGE_SIZE);
+
+        let identity = Self {
+            id: data[..16].try_into().unwrap(),
+            secret: data[16..].try_into().unwrap(),
+        };
+
+        (identity.crc() == u32::from_le_bytes(crc.try_into().unwrap())).then_some(identity)
+    }
+
+    /// Reads the identity from its words in the UICR.
+    ///
+    /// Returns `None` if the device isn't provisioned or the CRC doesn't match.
+    pub fn from_words(words: &[u32; Self::WORDS]) -> Option<Self> {
+        if words[0] != Self::PROVISIONED {
+            return None;
+        }
+
+        let mut bytes = [0; Self::MESSAGE_SIZE];
+        let (chunks, _) = bytes.as_chunks_mut::<4>();
+        for (chunk, word) in chunks.iter_mut().zip(&words[1..Self::WORDS - 1]) {
+            *chunk = word.to_le_bytes();
+        }
+
+        let identity = Self {
+            id: bytes[..16].try_into().unwrap(),
+            secret: bytes[16..].try_into().unwrap(),
+        };
+
+        (identity.crc() == words[Self::WORDS - 1]).then_some(identity)
+    }
+
+    /// Creates the words that are stored in the UICR
+    pub fn to_words(&self) -> [u32; Self::WORDS] {
+        let mut words = [0; Self::WORDS];
+        words[0] = Self::PROVISIONED;
+
+        let (id_chunks, _) = self.id.as_chunks::<4>();
+        let (secret_chunks, _) = self.secret.as_chunks::<4>();
+        let chunks = id_chunks.iter().chain(secret_chunks);
+        for (word, chunk) in words[1..Self::WORDS - 1].iter_mut().zip(chunks) {
+            *word = u32::from_le_bytes(*chunk);
+        }
+
+        words[Self::WORDS - 1] = self.crc();
+        words
+    }
+
+    /// Returns true if the words in the UICR are all erased, so the device has never been provisioned
+    pub fn is_blank(words: &[u32; Self::WORDS]) -> bool {
+        words.iter().all(|word| *word == 0xFFFF_FFFF)
+    }
+
+    fn crc(&self) -> u32 {
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_MPEG_2);
+        let mut digest = crc.digest();
+        digest.update(&self.id);
+        digest.update(&self.secret);
+        digest.finalize()
+    }
+}
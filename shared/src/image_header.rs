@@ -0,0 +1,150 @@
+//! The header in front of an application image
+//!
+//! An application image can start with this little-endian header, at the start of its slot:
+//!
+//! | Offset | Size | Field                                      |
+//! |--------|------|--------------------------------------------|
+//! | 0      | 4    | magic                                      |
+//! | 4      | 2    | version major                              |
+//! | 6      | 2    | version minor                              |
+//! | 8      | 2    | version patch                              |
+//! | 10     | 2    | board ID, see [board_id](crate::board_id)  |
+//! | 12     | 4    | build number                               |
+//! | 16     | 4    | length of the image after the header       |
+//! | 20     | 4    | CRC-32/MPEG-2 of the image after the header |
+//!
+//! The length and the CRC cover everything from the end of the header, so including the padding in front of the
+//! vector table. The vector table follows at the
+//! [vector table offset](crate::slots::SlotDescriptor::vector_table_offset) of the slot, or at
+//! [ImageHeader::VECTOR_TABLE_OFFSET] if the slot has none.
+//!
+//! The bootloader checks the header of a new image with [verify_image] before a swap touches the image that is
+//! running, and the application can do the same before it requests the swap. With the
+//! [DirectXip](crate::state::BootloaderGoal::DirectXip) goal, the version decides which slot is started.
+
+use crate::{
+    slots::{SlotDescriptor, VECTOR_TABLE_ALIGNMENT},
+    Flash,
+};
+
+/// The version of an image. Versions compare by their fields in order, so the build number only counts when the
+/// rest is equal.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ImageVersion {
+    /// The major part of the semver version
+    pub major: u16,
+    /// The minor part of the semver version
+    pub minor: u16,
+    /// The patch part of the semver version
+    pub patch: u16,
+    /// The build number, for images with the same semver version
+    pub build: u32,
+}
+
+/// The header at the start of a slot with an application image
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ImageHeader {
+    /// The version of the image
+    pub version: ImageVersion,
+    /// The board the image is built for, or [ANY_BOARD](crate::board_id::ANY_BOARD)
+    pub board_id: u16,
+    /// The length of the image after the header in bytes
+    pub length: u32,
+    /// The CRC-32/MPEG-2 of the image after the header
+    pub crc: u32,
+}
+
+impl ImageHeader {
+    /// The word that marks the start of a valid header
+    pub const MAGIC: u32 = 0x1A6E_4EAD;
+
+    /// The size of the header in bytes. The image follows right after it.
+    pub const SIZE: u32 = 24;
+
+    /// The offset of the vector table from the start of the slot, if the slot has no offset of its own.
+    /// The header is padded up to the alignment of the vector table.
+    pub const VECTOR_TABLE_OFFSET: u32 = VECTOR_TABLE_ALIGNMENT;
+
+    /// Creates the header for the given image, which is everything that follows the header in the slot.
+    /// The image runs on every board, unless the [board ID](Self::board_id) is changed afterwards.
+    pub fn new(version: ImageVersion, image: &[u8]) -> Self {
+        Self {
+            version,
+            board_id: crate::board_id::ANY_BOARD,
+            length: image.len() as u32,
+            crc: crc::Crc::<u32>::new(&crc::CRC_32_MPEG_2).checksum(image),
+        }
+    }
+
+    /// Creates the words of the header, to be written at the start of the slot
+    pub fn to_words(&self) -> [u32; 6] {
+        [
+            Self::MAGIC,
+            u32::from(self.version.major) | u32::from(self.version.minor) << 16,
+            u32::from(self.version.patch) | u32::from(self.board_id) << 16,
+            self.version.build,
+            self.length,
+            self.crc,
+        ]
+    }
+
+    /// Parses the header from its little-endian byte representation.
+    ///
+    /// Returns `None` if the bytes are too short or the magic word doesn't match.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..Self::SIZE as usize)?;
+
+        let u16_at =
+            |offset: usize| u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        (u32_at(0) == Self::MAGIC).then(|| Self {
+            version: ImageVersion {
+                major: u16_at(4),
+                minor: u16_at(6),
+                patch: u16_at(8),
+                build: u32_at(12),
+            },
+            board_id: u16_at(10),
+            length: u32_at(16),
+            crc: u32_at(20),
+        })
+    }
+
+    /// Reads the header at the start of the slot with the given address
+    pub fn load(flash: &(impl Flash + ?Sized), slot_address: u32) -> Option<Self> {
+        Self::from_bytes(flash.read_u8(slot_address..slot_address + Self::SIZE))
+    }
+}
+
+/// Why an image doesn't pass [verify_image]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ImageError {
+    /// There is no header with the [magic word](ImageHeader::MAGIC) at the start of the slot
+    NoHeader,
+    /// The image doesn't fit in the slot, up to its first excluded page
+    TooLong,
+    /// The CRC of the image doesn't match the header
+    CrcMismatch,
+}
+
+/// Checks the image in the slot against the length and CRC in its header and returns the header
+pub fn verify_image(
+    flash: &(impl Flash + ?Sized),
+    slot: &SlotDescriptor,
+) -> Result<ImageHeader, ImageError> {
+    let header = ImageHeader::load(flash, slot.address()).ok_or(ImageError::NoHeader)?;
+
+    let address = slot.address() + ImageHeader::SIZE;
+    if header.length > slot.image_capacity().saturating_sub(ImageHeader::SIZE) {
+        return Err(ImageError::TooLong);
+    }
+
+    let crc = crc::Crc::<u32>::new(&crc::CRC_32_MPEG_2);
+    if crc.checksum(flash.read_u8(address..address + header.length)) != header.crc {
+        return Err(ImageError::CrcMismatch);
+    }
+
+    Ok(header)
+}
@@ -2,7 +2,11 @@
 #![no_std]
 #![warn(missing_docs)]
 
-use core::ops::Range;
+#[cfg(feature = "std-compat")]
+extern crate std;
+
+use core::{future::Future, ops::Range};
+use flash_geometry::FlashGeometry;
 
 #[cfg(not(feature = "std-compat"))]
 mod linker_flash_addresses;
@@ -20,24 +24,167 @@ pub mod flash_addresses {
     pub use crate::std_compat_flash_addresses::*;
 }
 
+pub mod app_api;
+pub mod board_config;
+pub mod board_id;
+pub mod boot_info;
+pub mod bootloader_update;
+pub mod build_info;
+pub mod chip;
+pub mod config;
+pub mod counter;
+pub mod diagnostics;
+pub mod event_log;
+pub mod external_flash;
+pub mod flash_geometry;
+pub mod hardware_revision;
+pub mod identity;
+pub mod image_header;
+pub mod mailbox;
+pub mod mcuboot;
+pub mod measurements;
+pub mod modem_update;
+pub mod panic_log;
+pub mod partitions;
+pub mod recovery;
+pub mod reset_reason;
+pub mod revocation;
+pub mod secure_services;
+pub mod security_counter;
+pub mod slots;
+pub mod staged_image;
 pub mod state;
+pub mod state_checksum;
+pub mod user_data;
+pub mod xmodem;
+
+#[cfg(feature = "defmt")]
+#[doc(hidden)]
+pub use defmt as __defmt;
+#[cfg(feature = "log")]
+#[doc(hidden)]
+pub use log as __log;
+
+/// The reason a flash operation failed
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FlashError {
+    /// The address or the data is not aligned the way the operation needs it
+    Alignment,
+    /// The address or the data lies (partly) outside of the flash
+    OutOfRange,
+    /// The flash controller didn't become ready in time
+    Timeout,
+    /// The flash doesn't contain what was programmed or erased
+    VerifyFailed,
+}
 
 /// A trait defining the common flash operations
+///
+/// The erase and program operations report their failures as a [FlashError], so the bootloader can log them and
+/// fall back to a safe goal instead of faulting in the middle of a swap.
+///
+/// With the `flash-trace` feature, [Self::erase_page] and [Self::program_page] track their caller, so the
+/// implementations can find it with [core::panic::Location::caller].
 pub trait Flash {
     /// Erase the given page
-    fn erase_page(&mut self, page_address: u32);
+    #[cfg_attr(feature = "flash-trace", track_caller)]
+    fn erase_page(&mut self, page_address: u32) -> Result<(), FlashError>;
 
     /// Program the page with the given data.
     /// Only the data words that are different from what is currently stored in flash may be written to.
-    fn program_page(&mut self, page_address: u32, data: &[u32]);
+    #[cfg_attr(feature = "flash-trace", track_caller)]
+    fn program_page(&mut self, page_address: u32, data: &[u32]) -> Result<(), FlashError>;
 
     /// Read the flash in the given address range
     ///
-    /// If the address range is invalid, then the function may panic
+    /// The range must lie in the flash, which is [Self::size] bytes from address 0, or in the window of the
+    /// [external flash](external_flash), or the function panics.
+    /// Use [Self::read] for an address that isn't known to be valid.
     fn read_u8(&self, address_range: Range<u32>) -> &[u8];
 
     /// Read the flash in the given address range
     ///
-    /// If the index range is invalid, then the function may panic
+    /// The range must be word aligned and lie in the flash, or the function panics.
     fn read_u32(&self, address_range: Range<u32>) -> &[u32];
+
+    /// The page size and the size of the flash
+    fn geometry(&self) -> FlashGeometry;
+
+    /// The size of the flash in bytes. The flash starts at address 0.
+    fn size(&self) -> u32 {
+        self.geometry().size
+    }
+
+    /// Copies the flash at the given address into the buffer.
+    ///
+    /// Unlike the other reads, this checks the range against the [geometry](Self::geometry) of the flash and returns
+    /// [FlashError::OutOfRange] instead of panicking. An address that comes from the flash contents, like a field
+    /// of an image header, then can't make the caller read RAM or a peripheral.
+    fn read(&self, address: u32, buffer: &mut [u8]) -> Result<(), FlashError> {
+        let address_range = checked_range(address, buffer.len(), self.geometry())?;
+        buffer.copy_from_slice(self.read_u8(address_range));
+        Ok(())
+    }
+}
+
+/// The non-blocking variant of [Flash]
+///
+/// A page erase takes about 85 ms on the nRF9160. The erase and program operations of this trait yield to the
+/// executor while the flash is busy instead of stalling the CPU, so other tasks, like feeding a watchdog, keep
+/// running. The reads are the same as in [Flash], because the flash is memory mapped.
+pub trait AsyncFlash {
+    /// Erase the given page
+    fn erase_page(&mut self, page_address: u32) -> impl Future<Output = Result<(), FlashError>>;
+
+    /// Program the page with the given data.
+    /// Only the data words that are different from what is currently stored in flash may be written to.
+    fn program_page(
+        &mut self,
+        page_address: u32,
+        data: &[u32],
+    ) -> impl Future<Output = Result<(), FlashError>>;
+
+    /// Read the flash in the given address range
+    ///
+    /// The range must lie in the flash, which is [Self::size] bytes from address 0, or the function panics.
+    /// Use [Self::read] for an address that isn't known to be valid.
+    fn read_u8(&self, address_range: Range<u32>) -> &[u8];
+
+    /// Read the flash in the given address range
+    ///
+    /// The range must be word aligned and lie in the flash, or the function panics.
+    fn read_u32(&self, address_range: Range<u32>) -> &[u32];
+
+    /// The page size and the size of the flash
+    fn geometry(&self) -> FlashGeometry;
+
+    /// The size of the flash in bytes. The flash starts at address 0.
+    fn size(&self) -> u32 {
+        self.geometry().size
+    }
+
+    /// Copies the flash at the given address into the buffer.
+    ///
+    /// Unlike the other reads, this checks the range against the [size](Self::size) of the flash and returns
+    /// [FlashError::OutOfRange] instead of panicking. An address that comes from the flash contents, like a field
+    /// of an image header, then can't make the caller read RAM or a peripheral.
+    fn read(&self, address: u32, buffer: &mut [u8]) -> Result<(), FlashError> {
+        let address_range = checked_range(address, buffer.len(), self.geometry())?;
+        buffer.copy_from_slice(self.read_u8(address_range));
+        Ok(())
+    }
+}
+
+/// Returns the address range of the given length at the address if it lies in a flash with the given geometry
+fn checked_range(
+    address: u32,
+    length: usize,
+    geometry: FlashGeometry,
+) -> Result<Range<u32>, FlashError> {
+    u32::try_from(length)
+        .ok()
+        .and_then(|length| address.checked_add(length))
+        .map(|end| address..end)
+        .filter(|range| geometry.contains(range))
+        .ok_or(FlashError::OutOfRange)
 }
@@ -1,5 +1,5 @@
 //! Helper functions for finding the flash addresses of the memory regions more easily
-//! 
+//!
 //! This version gets the addresses from the linker script
 
 use core::ops::Range;
@@ -7,19 +7,40 @@ use core::ops::Range;
 extern "C" {
     static mut _bootloader_flash_start: u32;
     static mut _bootloader_flash_end: u32;
+    static mut _bootloader_descriptor_start: u32;
     static mut _bootloader_scratch_start: u32;
     static mut _bootloader_scratch_end: u32;
+    static mut _bootloader_state_log_start: u32;
+    static mut _bootloader_state_log_end: u32;
+    static mut _bootloader_panic_log_start: u32;
+    static mut _bootloader_panic_log_end: u32;
+    static mut _bootloader_revocations_start: u32;
+    static mut _bootloader_revocations_end: u32;
+    static mut _bootloader_event_log_start: u32;
+    static mut _bootloader_event_log_end: u32;
     static mut _bootloader_state_start: u32;
     static mut _bootloader_state_end: u32;
+    static mut _bootloader_mailbox_start: u32;
+    static mut _bootloader_mailbox_end: u32;
+    static mut _bootloader_measurements_start: u32;
+    static mut _bootloader_measurements_end: u32;
+    static mut _bootloader_boot_info_start: u32;
+    static mut _bootloader_boot_info_end: u32;
+    static mut _bootloader_flash_trace_start: u32;
+    static mut _bootloader_flash_trace_end: u32;
 
     static mut _program_slot_a_start: u32;
     static mut _program_slot_a_end: u32;
     static mut _program_slot_b_start: u32;
     static mut _program_slot_b_end: u32;
+    static mut _modem_staging_start: u32;
+    static mut _modem_staging_end: u32;
+    static mut _user_data_start: u32;
+    static mut _user_data_end: u32;
 }
 
 /// The size of a page in bytes
-pub const PAGE_SIZE: u32 = 0x0000_1000;
+pub const PAGE_SIZE: u32 = crate::partitions::PAGE_SIZE;
 
 /// The address range of the bootloader's flash
 pub fn bootloader_flash_range() -> Range<u32> {
@@ -36,6 +57,16 @@ pub fn bootloader_flash_page_range() -> Range<u32> {
     address_range.start / PAGE_SIZE..address_range.end / PAGE_SIZE
 }
 
+/// The address range of the descriptor block at the end of the bootloader's flash.
+/// It starts with the [BuildInfo](crate::build_info::BuildInfo) of the bootloader.
+pub fn bootloader_descriptor_range() -> Range<u32> {
+    unsafe {
+        let start = &_bootloader_descriptor_start as *const u32 as u32;
+        let end = &_bootloader_flash_end as *const u32 as u32;
+        start..end
+    }
+}
+
 /// The address range of the bootloader's scratch area flash
 pub fn bootloader_scratch_range() -> Range<u32> {
     unsafe {
@@ -51,6 +82,46 @@ pub fn bootloader_scratch_page_range() -> Range<u32> {
     address_range.start / PAGE_SIZE..address_range.end / PAGE_SIZE
 }
 
+/// The address range of the bootloader's state log flash.
+/// See [BootloaderState](crate::state::BootloaderState).
+pub fn bootloader_state_log_range() -> Range<u32> {
+    unsafe {
+        let start = &_bootloader_state_log_start as *const u32 as u32;
+        let end = &_bootloader_state_log_end as *const u32 as u32;
+        start..end
+    }
+}
+
+/// The address range of the bootloader's panic log flash.
+/// See the [panic_log](crate::panic_log) module.
+pub fn bootloader_panic_log_range() -> Range<u32> {
+    unsafe {
+        let start = &_bootloader_panic_log_start as *const u32 as u32;
+        let end = &_bootloader_panic_log_end as *const u32 as u32;
+        start..end
+    }
+}
+
+/// The address range of the key revocation list flash.
+/// See the [revocation](crate::revocation) module.
+pub fn bootloader_revocations_range() -> Range<u32> {
+    unsafe {
+        let start = &_bootloader_revocations_start as *const u32 as u32;
+        let end = &_bootloader_revocations_end as *const u32 as u32;
+        start..end
+    }
+}
+
+/// The address range of the bootloader's event log flash.
+/// See the [event_log](crate::event_log) module.
+pub fn bootloader_event_log_range() -> Range<u32> {
+    unsafe {
+        let start = &_bootloader_event_log_start as *const u32 as u32;
+        let end = &_bootloader_event_log_end as *const u32 as u32;
+        start..end
+    }
+}
+
 /// The address range of the bootloader's state flash
 pub fn bootloader_state_range() -> Range<u32> {
     unsafe {
@@ -66,6 +137,36 @@ pub fn bootloader_state_page_range() -> Range<u32> {
     address_range.start / PAGE_SIZE..address_range.end / PAGE_SIZE
 }
 
+/// The address range in RAM of the mailbox the application can use to request a goal.
+/// See the [mailbox](crate::mailbox) module.
+pub fn bootloader_mailbox_range() -> Range<u32> {
+    unsafe {
+        let start = &_bootloader_mailbox_start as *const u32 as u32;
+        let end = &_bootloader_mailbox_end as *const u32 as u32;
+        start..end
+    }
+}
+
+/// The address range in RAM where the bootloader leaves the boot measurements for the application.
+/// See the [measurements](crate::measurements) module.
+pub fn bootloader_measurements_range() -> Range<u32> {
+    unsafe {
+        let start = &_bootloader_measurements_start as *const u32 as u32;
+        let end = &_bootloader_measurements_end as *const u32 as u32;
+        start..end
+    }
+}
+
+/// The address range in RAM where the bootloader leaves the boot info block for the application.
+/// See the [boot_info](crate::boot_info) module.
+pub fn bootloader_boot_info_range() -> Range<u32> {
+    unsafe {
+        let start = &_bootloader_boot_info_start as *const u32 as u32;
+        let end = &_bootloader_boot_info_end as *const u32 as u32;
+        start..end
+    }
+}
+
 /// The address range of slot A of the firmware
 pub fn program_slot_a_range() -> Range<u32> {
     unsafe {
@@ -95,3 +196,39 @@ pub fn program_slot_b_page_range() -> Range<u32> {
     let address_range = program_slot_b_range();
     address_range.start / PAGE_SIZE..address_range.end / PAGE_SIZE
 }
+
+/// The address range where modem firmware updates are staged.
+/// See the [modem_update](crate::modem_update) module.
+pub fn modem_staging_range() -> Range<u32> {
+    unsafe {
+        let start = &_modem_staging_start as *const u32 as u32;
+        let end = &_modem_staging_end as *const u32 as u32;
+        start..end
+    }
+}
+
+/// The address range of the user data, which the bootloader never erases or programs.
+/// See the [user_data](crate::user_data) module.
+pub fn user_data_range() -> Range<u32> {
+    unsafe {
+        let start = &_user_data_start as *const u32 as u32;
+        let end = &_user_data_end as *const u32 as u32;
+        start..end
+    }
+}
+
+/// The page range of the user data
+pub fn user_data_page_range() -> Range<u32> {
+    let address_range = user_data_range();
+    address_range.start / PAGE_SIZE..address_range.end / PAGE_SIZE
+}
+
+/// The address range of the flash the bootloader mirrors its flash trace into.
+/// See the `trace` module of the bootloader core.
+pub fn bootloader_flash_trace_range() -> Range<u32> {
+    unsafe {
+        let start = &_bootloader_flash_trace_start as *const u32 as u32;
+        let end = &_bootloader_flash_trace_end as *const u32 as u32;
+        start..end
+    }
+}
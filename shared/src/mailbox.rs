@@ -0,0 +1,221 @@
+//! A mailbox in RAM that the application can use to pass a request to the bootloader
+//!
+//! When the bootloader state is write protected (or secure), the application can't change the goal itself.
+//! Instead, it calls [request_goal] and resets the device. At the next boot, the bootloader takes the request
+//! with [take_request] and writes the goal into the state before the state is protected again. Like
+//! [BootloaderState::set_goal](crate::state::BootloaderState::set_goal), the request has the goal the application
+//! expects to be set, and it's dropped when the goal has changed in the meantime.
+//! The same way, [request_wipe] asks the bootloader to wipe the device and [request_revocation] asks it to
+//! revoke a signing key.
+//!
+//! The mailbox also has the handshake word. An application calls [complete_handshake] once it has reached its main
+//! loop. The bootloader takes it at the next boot with [take_handshake], so it knows whether a test-swapped image got
+//! that far (see [failed_boot_threshold](crate::config::BootloaderConfig::failed_boot_threshold)).
+//!
+//! An application that resets or goes to sleep with nothing pending can leave a hint with
+//! [leave_nothing_pending_hint], so the next boot doesn't have to load the state from flash. The bootloader only
+//! allows the hint when it started the application with nothing pending (see [allow_nothing_pending_hint]), and
+//! takes it at the next boot with [take_nothing_pending_hint].
+//!
+//! The mailbox lives in RAM that is not initialized at startup, so it survives a reset, but not a power cycle.
+//! The application must not use the mailbox region ([bootloader_mailbox_range]) for anything else.
+
+use crate::{flash_addresses::bootloader_mailbox_range, state::BootloaderGoal};
+
+/// The layout of the mailbox in RAM
+#[repr(C)]
+struct Mailbox {
+    /// Must be [MAGIC] for the request to be valid
+    magic: u32,
+    /// The requested goal, or [REVOKE_KEY]
+    goal: u32,
+    /// Must be the inverse of the goal for the request to be valid
+    check: u32,
+    /// The token that authenticates a wipe or revocation request
+    token: [u8; 32],
+    /// The ID of the key to revoke
+    key_id: u32,
+    /// Is [HANDSHAKE] when the application has completed the handshake
+    handshake: u32,
+    /// Is [NOTHING_PENDING] when the bootloader allows the application to leave the hint
+    nothing_pending_allowed: u32,
+    /// Is [NOTHING_PENDING] when the application has left the hint
+    nothing_pending: u32,
+    /// Must be the inverse of the hint for the hint to be valid
+    nothing_pending_check: u32,
+    /// The goal that the application expects to be set when the requested goal is written
+    expected_goal: u32,
+}
+
+/// The word that marks a valid request
+const MAGIC: u32 = 0xB0C5_60A1;
+/// The value of the goal field of a revocation request. It's not a [BootloaderGoal].
+const REVOKE_KEY: u32 = 0x5245_564B;
+/// The value of the handshake word when the application has completed the handshake
+const HANDSHAKE: u32 = 0x600D_B007;
+/// The value of the nothing pending hint
+const NOTHING_PENDING: u32 = 0x1D1E_B007;
+
+/// A request that the application left in the mailbox
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Request {
+    /// The goal should be set to the given goal, if the current goal is the expected one
+    Goal {
+        /// The goal that the application saw
+        expected: BootloaderGoal,
+        /// The new goal
+        goal: BootloaderGoal,
+    },
+    /// The device should be wiped if the token is valid for this device
+    Wipe {
+        /// The token that authenticates the request
+        token: [u8; 32],
+    },
+    /// The signing key with the given ID should be revoked if the token is valid for this device and key
+    RevokeKey {
+        /// The ID of the key, see the [revocation](crate::revocation) module
+        key_id: u32,
+        /// The token that authenticates the request
+        token: [u8; 32],
+    },
+}
+
+fn mailbox() -> *mut Mailbox {
+    bootloader_mailbox_range().start as *mut Mailbox
+}
+
+fn write_request(goal: u32, expected_goal: u32, token: [u8; 32], key_id: u32) {
+    unsafe {
+        let mailbox = mailbox();
+        core::ptr::addr_of_mut!((*mailbox).goal).write_volatile(goal);
+        core::ptr::addr_of_mut!((*mailbox).expected_goal).write_volatile(expected_goal);
+        core::ptr::addr_of_mut!((*mailbox).check).write_volatile(!goal);
+        core::ptr::addr_of_mut!((*mailbox).token).write_volatile(token);
+        core::ptr::addr_of_mut!((*mailbox).key_id).write_volatile(key_id);
+        core::ptr::addr_of_mut!((*mailbox).magic).write_volatile(MAGIC);
+    }
+}
+
+/// Requests the bootloader to set the given goal at the next boot, if the goal is still the expected one.
+///
+/// Only the [requestable](BootloaderGoal::is_requestable) goals are accepted by the bootloader.
+/// The device must be reset for the request to be handled.
+pub fn request_goal(expected: BootloaderGoal, goal: BootloaderGoal) {
+    write_request(goal.into(), expected.into(), [0; 32], 0);
+}
+
+/// Requests the bootloader to wipe the device at the next boot.
+///
+/// The token must be the wipe token of this device, which only the party that provisioned the device can create.
+/// The wipe is only started when the goal is [BootloaderGoal::JumpToApplication], so it never interrupts a pending
+/// swap or update. The device must be reset for the request to be handled.
+pub fn request_wipe(token: [u8; 32]) {
+    write_request(BootloaderGoal::Wipe.into(), 0, token, 0);
+}
+
+/// Requests the bootloader to revoke the signing key with the given ID at the next boot.
+///
+/// The token must be the revocation token of this device and key, which only the party that provisioned the device
+/// can create. The device must be reset for the request to be handled.
+pub fn request_revocation(key_id: u32, token: [u8; 32]) {
+    write_request(REVOKE_KEY, 0, token, key_id);
+}
+
+/// Takes the request out of the mailbox, if there is a valid one.
+///
+/// The mailbox is always cleared, so a request is only handled once.
+pub fn take_request() -> Option<Request> {
+    let (magic, goal, check, token, key_id, expected_goal) = unsafe {
+        let mailbox = mailbox();
+        let request = (
+            core::ptr::addr_of!((*mailbox).magic).read_volatile(),
+            core::ptr::addr_of!((*mailbox).goal).read_volatile(),
+            core::ptr::addr_of!((*mailbox).check).read_volatile(),
+            core::ptr::addr_of!((*mailbox).token).read_volatile(),
+            core::ptr::addr_of!((*mailbox).key_id).read_volatile(),
+            core::ptr::addr_of!((*mailbox).expected_goal).read_volatile(),
+        );
+        core::ptr::addr_of_mut!((*mailbox).magic).write_volatile(0);
+        request
+    };
+
+    if magic != MAGIC || goal != !check {
+        return None;
+    }
+
+    if goal == REVOKE_KEY {
+        return Some(Request::RevokeKey { key_id, token });
+    }
+
+    let expected = BootloaderGoal::try_from(expected_goal).ok()?;
+    match BootloaderGoal::try_from(goal) {
+        Ok(BootloaderGoal::Wipe) => Some(Request::Wipe { token }),
+        Ok(goal) if goal.is_requestable() => Some(Request::Goal { expected, goal }),
+        _ => None,
+    }
+}
+
+/// Tells the bootloader that the application has started successfully.
+///
+/// The application should call this shortly after boot, once it has reached its main loop.
+/// A test-swapped image that doesn't do this counts a failed boot at the next boot.
+pub fn complete_handshake() {
+    unsafe { core::ptr::addr_of_mut!((*mailbox()).handshake).write_volatile(HANDSHAKE) }
+}
+
+/// Returns true if the application of the previous boot has completed the handshake.
+///
+/// The handshake word is always cleared, so the next application has to complete it again.
+pub fn take_handshake() -> bool {
+    unsafe {
+        let handshake = core::ptr::addr_of_mut!((*mailbox()).handshake);
+        let completed = handshake.read_volatile() == HANDSHAKE;
+        handshake.write_volatile(0);
+        completed
+    }
+}
+
+/// Allows or forbids the application to leave the nothing pending hint for the next boot.
+///
+/// The bootloader allows it right before the jump if there is no goal pending and nothing to clean up.
+pub fn allow_nothing_pending_hint(allowed: bool) {
+    let allowed = if allowed { NOTHING_PENDING } else { 0 };
+    unsafe { core::ptr::addr_of_mut!((*mailbox()).nothing_pending_allowed).write_volatile(allowed) }
+}
+
+/// Tells the bootloader that it can skip loading the state at the next boot, because nothing is pending.
+///
+/// Call this right before a reset or going to sleep, and only if the application didn't change the state since it
+/// was started. Returns false if the bootloader didn't allow the hint, because it started the application with
+/// something pending. The bootloader then loads the state as usual.
+pub fn leave_nothing_pending_hint() -> bool {
+    unsafe {
+        let mailbox = mailbox();
+        if core::ptr::addr_of!((*mailbox).nothing_pending_allowed).read_volatile()
+            != NOTHING_PENDING
+        {
+            return false;
+        }
+
+        core::ptr::addr_of_mut!((*mailbox).nothing_pending_check).write_volatile(!NOTHING_PENDING);
+        core::ptr::addr_of_mut!((*mailbox).nothing_pending).write_volatile(NOTHING_PENDING);
+        true
+    }
+}
+
+/// Returns true if the application of the previous boot left a valid nothing pending hint.
+///
+/// The hint is always cleared and forbidden again, so it's only used once.
+pub fn take_nothing_pending_hint() -> bool {
+    unsafe {
+        let mailbox = mailbox();
+        let valid = core::ptr::addr_of!((*mailbox).nothing_pending_allowed).read_volatile()
+            == NOTHING_PENDING
+            && core::ptr::addr_of!((*mailbox).nothing_pending).read_volatile() == NOTHING_PENDING
+            && core::ptr::addr_of!((*mailbox).nothing_pending_check).read_volatile()
+                == !NOTHING_PENDING;
+        core::ptr::addr_of_mut!((*mailbox).nothing_pending_allowed).write_volatile(0);
+        core::ptr::addr_of_mut!((*mailbox).nothing_pending).write_volatile(0);
+        valid
+    }
+}
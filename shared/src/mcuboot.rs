@@ -0,0 +1,415 @@
+//! The header that MCUboot's `imgtool` puts in front of an image
+//!
+//! Images that are signed for MCUboot, like the SPM of the nRF Connect SDK, start with this little-endian header:
+//!
+//! | Offset | Size | Field                                  |
+//! |--------|------|----------------------------------------|
+//! | 0      | 4    | magic                                  |
+//! | 4      | 4    | load address                           |
+//! | 8      | 2    | header size, the offset of the image   |
+//! | 10     | 2    | size of the protected TLVs             |
+//! | 12     | 4    | image size, without header and TLVs    |
+//! | 16     | 4    | flags                                  |
+//! | 20     | 1    | version major                          |
+//! | 21     | 1    | version minor                          |
+//! | 22     | 2    | version revision                       |
+//! | 24     | 4    | version build number                   |
+//! | 28     | 4    | padding                                |
+//!
+//! The header is padded up to the header size, after which the image starts with its vector table. The TLV trailer,
+//! with the hash and signature of the image, follows right after the image.
+//! First the protected TLVs, if there are any, and then the unprotected TLVs, each behind a [TlvInfo].
+//! Every TLV starts with a 16-bit type and a 16-bit length, followed by the value. The SHA-256 digest in the
+//! [TLV_SHA256] TLV covers the [hashed range](McubootHeader::hashed_range) of the image.
+//!
+//! Next to the TLVs of MCUboot, images can carry metadata in our own TLVs in the vendor range, like
+//! [TLV_BUILD_TIMESTAMP], [TLV_BOARD_ID], [TLV_HARDWARE_REVISIONS] and [TLV_DEVICE_ENCRYPTION].
+//! [McubootHeader::tlvs] iterates over all of them. Unknown TLVs are skipped, unless their type has the
+//! [TLV_CRITICAL] bit: then the image can't be used safely by a bootloader that doesn't know them
+//! (see [McubootHeader::unknown_critical_tlv]).
+
+use crate::{image_header::ImageVersion, Flash};
+use core::ops::Range;
+
+/// The bit in the type of a TLV that marks it as critical: an image with a critical TLV that the bootloader doesn't
+/// know is refused. MCUboot itself never sets it, vendor TLVs set it when ignoring them would be unsafe.
+pub const TLV_CRITICAL: u16 = 0x8000;
+/// The type of the TLV with the SHA-256 digest of the image
+pub const TLV_SHA256: u16 = 0x10;
+/// The type of the TLV with the DER encoded ECDSA signature of the SHA-256 digest of the image
+pub const TLV_ECDSA_SIG: u16 = 0x22;
+/// The type of the TLV with the Ed25519 signature of the SHA-256 digest of the image
+pub const TLV_ED25519: u16 = 0x24;
+/// The type of the protected TLV with the security counter of the image, as a little-endian word
+pub const TLV_SEC_CNT: u16 = 0x50;
+/// The type of the TLV with the minimal version of another image that this image needs, see the MCUboot documentation
+pub const TLV_DEPENDENCY: u16 = 0x40;
+/// The type of our vendor TLV with the build time of the image, as a little-endian UNIX timestamp in seconds
+pub const TLV_BUILD_TIMESTAMP: u16 = 0xA0;
+/// The type of our vendor TLV with the ID of the board the image is built for (see [board_id](crate::board_id)), as a
+/// little-endian 16-bit value
+pub const TLV_BOARD_ID: u16 = 0xA1;
+/// The type of our vendor TLV with the bitmask of the hardware revisions the image runs on
+/// (see [hardware_revision](crate::hardware_revision)), as a little-endian word
+pub const TLV_HARDWARE_REVISIONS: u16 = 0xA3;
+
+/// The type of our vendor TLV that marks an image as encrypted for one device, with the 16 byte nonce of the image.
+/// Images always set the [TLV_CRITICAL] bit on it, so a bootloader that can't decrypt them refuses them. It isn't in
+/// [KNOWN_TLVS], because only a bootloader with the encryption knows it.
+pub const TLV_DEVICE_ENCRYPTION: u16 = 0xA2;
+
+/// The TLV types that the bootloader knows, without the [TLV_CRITICAL] bit
+pub const KNOWN_TLVS: &[u16] = &[
+    TLV_SHA256,
+    TLV_ECDSA_SIG,
+    TLV_ED25519,
+    TLV_DEPENDENCY,
+    TLV_SEC_CNT,
+    TLV_BUILD_TIMESTAMP,
+    TLV_BOARD_ID,
+    TLV_HARDWARE_REVISIONS,
+];
+
+/// The header at the start of a slot with an image that was signed for MCUboot
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct McubootHeader {
+    /// The address the image must be loaded to, if the [flags](Self::flags) say so
+    pub load_address: u32,
+    /// The size of the header, including its padding. The image starts at this offset.
+    pub header_size: u16,
+    /// The size of the protected TLVs, including their [TlvInfo]. 0 if there are none.
+    pub protected_tlv_size: u16,
+    /// The size of the image, without the header and the TLVs
+    pub image_size: u32,
+    /// The flags of the image, see the MCUboot documentation
+    pub flags: u32,
+    /// The version of the image
+    pub version: ImageVersion,
+}
+
+impl McubootHeader {
+    /// The word that marks the start of a valid header
+    pub const MAGIC: u32 = 0x96F3_B83D;
+
+    /// The size of the header fields in bytes. The header size in the header itself includes the padding.
+    pub const SIZE: u32 = 32;
+
+    /// Parses the header from its little-endian byte representation.
+    ///
+    /// Returns `None` if the bytes are too short, the magic word doesn't match or the header size is smaller than the
+    /// header fields.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..Self::SIZE as usize)?;
+
+        let u16_at =
+            |offset: usize| u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        let header = Self {
+            load_address: u32_at(4),
+            header_size: u16_at(8),
+            protected_tlv_size: u16_at(10),
+            image_size: u32_at(12),
+            flags: u32_at(16),
+            version: ImageVersion {
+                major: u16::from(bytes[20]),
+                minor: u16::from(bytes[21]),
+                patch: u16_at(22),
+                build: u32_at(24),
+            },
+        };
+
+        (u32_at(0) == Self::MAGIC && u32::from(header.header_size) >= Self::SIZE).then_some(header)
+    }
+
+    /// Reads the header at the start of the slot with the given address
+    pub fn load(flash: &(impl Flash + ?Sized), slot_address: u32) -> Option<Self> {
+        Self::from_bytes(flash.read_u8(slot_address..slot_address + Self::SIZE))
+    }
+
+    /// The offset of the vector table from the start of the slot, which is where the image starts
+    pub fn vector_table_offset(&self) -> u32 {
+        u32::from(self.header_size)
+    }
+
+    /// The address range of the image in the slot with the given address, without the header and the TLVs
+    pub fn image_range(&self, slot_address: u32) -> Range<u32> {
+        let start = slot_address + self.vector_table_offset();
+        start..start.saturating_add(self.image_size)
+    }
+
+    /// The address of the TLV trailer in the slot with the given address, right after the image
+    pub fn tlv_address(&self, slot_address: u32) -> u32 {
+        self.image_range(slot_address).end
+    }
+
+    /// Reads the TLV infos after the image and returns the address range of the whole TLV trailer, from the
+    /// protected TLVs up to the end of the unprotected TLVs.
+    ///
+    /// Returns `None` if the infos don't match the header or the trailer doesn't end before `slot_end`.
+    pub fn tlv_range(
+        &self,
+        flash: &(impl Flash + ?Sized),
+        slot_address: u32,
+        slot_end: u32,
+    ) -> Option<Range<u32>> {
+        let start = self.tlv_address(slot_address);
+        let mut end = start;
+
+        if self.protected_tlv_size != 0 {
+            let info = TlvInfo::load(flash, end, slot_end)?;
+            if info.magic != TlvInfo::PROTECTED_MAGIC || info.total_size != self.protected_tlv_size
+            {
+                return None;
+            }
+            end += u32::from(info.total_size);
+        }
+
+        let info = TlvInfo::load(flash, end, slot_end)?;
+        if info.magic != TlvInfo::MAGIC {
+            return None;
+        }
+        end += u32::from(info.total_size);
+
+        (end <= slot_end).then_some(start..end)
+    }
+
+    /// The address range that the digest of the image covers: the header, the image and the protected TLVs
+    pub fn hashed_range(&self, slot_address: u32) -> Range<u32> {
+        slot_address..self.tlv_address(slot_address) + u32::from(self.protected_tlv_size)
+    }
+
+    /// Iterates over the TLVs in the TLV trailer, the protected ones first.
+    ///
+    /// Returns `None` if the trailer isn't valid (see [Self::tlv_range]).
+    pub fn tlvs<'a, F: Flash + ?Sized>(
+        &self,
+        flash: &'a F,
+        slot_address: u32,
+        slot_end: u32,
+    ) -> Option<Tlvs<'a, F>> {
+        let trailer = self.tlv_range(flash, slot_address, slot_end)?;
+
+        // Both areas start with their info
+        Some(Tlvs {
+            flash,
+            address: trailer.start + TlvInfo::SIZE,
+            area_end: trailer.start + u32::from(self.protected_tlv_size),
+            end: trailer.end,
+        })
+    }
+
+    /// Finds the first TLV of the given type in the TLV trailer and returns the address range of its value.
+    /// The [TLV_CRITICAL] bit of the type is ignored.
+    ///
+    /// Returns `None` if there is no such TLV or the trailer isn't valid (see [Self::tlvs]).
+    pub fn find_tlv(
+        &self,
+        flash: &(impl Flash + ?Sized),
+        slot_address: u32,
+        slot_end: u32,
+        tlv_type: u16,
+    ) -> Option<Range<u32>> {
+        for tlv in self.tlvs(flash, slot_address, slot_end)? {
+            let tlv = tlv.ok()?;
+            if tlv.kind() == tlv_type & !TLV_CRITICAL {
+                return Some(tlv.value);
+            }
+        }
+
+        None
+    }
+
+    /// Returns the type of the first critical TLV that isn't in [KNOWN_TLVS], which this bootloader can't honor.
+    ///
+    /// A TLV that runs past the end of its area counts as an unknown critical TLV of type `0xFFFF`.
+    /// Returns `None` if all critical TLVs are known or the trailer isn't valid, which the digest check catches.
+    pub fn unknown_critical_tlv(
+        &self,
+        flash: &(impl Flash + ?Sized),
+        slot_address: u32,
+        slot_end: u32,
+    ) -> Option<u16> {
+        self.unknown_critical_tlv_except(flash, slot_address, slot_end, &[])
+    }
+
+    /// Returns the type of the first critical TLV that is neither in [KNOWN_TLVS] nor in `also_known`, like
+    /// [Self::unknown_critical_tlv]. This is for the TLVs that only some builds of the bootloader know.
+    pub fn unknown_critical_tlv_except(
+        &self,
+        flash: &(impl Flash + ?Sized),
+        slot_address: u32,
+        slot_end: u32,
+        also_known: &[u16],
+    ) -> Option<u16> {
+        self.tlvs(flash, slot_address, slot_end)?
+            .find_map(|tlv| match tlv {
+                Ok(tlv)
+                    if tlv.is_critical()
+                        && !KNOWN_TLVS.contains(&tlv.kind())
+                        && !also_known.contains(&tlv.kind()) =>
+                {
+                    Some(tlv.tlv_type)
+                }
+                Ok(_) => None,
+                Err(MalformedTlv) => Some(u16::MAX),
+            })
+    }
+
+    /// Reads the security counter of the image from its [TLV_SEC_CNT] TLV.
+    ///
+    /// Only a protected TLV counts, since only those are covered by the digest. Returns `None` if there is none.
+    pub fn security_counter(
+        &self,
+        flash: &(impl Flash + ?Sized),
+        slot_address: u32,
+        slot_end: u32,
+    ) -> Option<u32> {
+        let value = self
+            .tlvs(flash, slot_address, slot_end)?
+            .filter_map(Result::ok)
+            .find(|tlv| tlv.protected && tlv.kind() == TLV_SEC_CNT)?
+            .value;
+
+        let mut bytes = [0; 4];
+        (value.len() == bytes.len()).then_some(())?;
+        flash.read(value.start, &mut bytes).ok()?;
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    /// Reads the board ID of the image from its [TLV_BOARD_ID] TLV, or returns `None` if it has none
+    pub fn board_id(
+        &self,
+        flash: &(impl Flash + ?Sized),
+        slot_address: u32,
+        slot_end: u32,
+    ) -> Option<u16> {
+        let value = self.find_tlv(flash, slot_address, slot_end, TLV_BOARD_ID)?;
+        let mut bytes = [0; 2];
+        (value.len() == bytes.len()).then_some(())?;
+        flash.read(value.start, &mut bytes).ok()?;
+        Some(u16::from_le_bytes(bytes))
+    }
+
+    /// Reads the bitmask of the hardware revisions the image runs on from its [TLV_HARDWARE_REVISIONS] TLV, or
+    /// returns `None` if it has none
+    pub fn compatible_revisions(
+        &self,
+        flash: &(impl Flash + ?Sized),
+        slot_address: u32,
+        slot_end: u32,
+    ) -> Option<u32> {
+        let value = self.find_tlv(flash, slot_address, slot_end, TLV_HARDWARE_REVISIONS)?;
+        let mut bytes = [0; 4];
+        (value.len() == bytes.len()).then_some(())?;
+        flash.read(value.start, &mut bytes).ok()?;
+        Some(u32::from_le_bytes(bytes))
+    }
+}
+
+/// A TLV in the TLV trailer
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Tlv {
+    /// The type of the TLV, including the [TLV_CRITICAL] bit
+    pub tlv_type: u16,
+    /// The address range of the value
+    pub value: Range<u32>,
+    /// True if the TLV is covered by the digest of the image
+    pub protected: bool,
+}
+
+impl Tlv {
+    /// The type of the TLV without the [TLV_CRITICAL] bit
+    pub fn kind(&self) -> u16 {
+        self.tlv_type & !TLV_CRITICAL
+    }
+
+    /// Returns true if the image must be refused when the bootloader doesn't know the TLV
+    pub fn is_critical(&self) -> bool {
+        self.tlv_type & TLV_CRITICAL != 0
+    }
+}
+
+/// A TLV runs past the end of the protected or the unprotected TLVs
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MalformedTlv;
+
+/// The iterator over the TLVs in the TLV trailer of an image, see [McubootHeader::tlvs].
+///
+/// After a [MalformedTlv], the iteration stops.
+pub struct Tlvs<'a, F: Flash + ?Sized> {
+    flash: &'a F,
+    /// The address of the next TLV
+    address: u32,
+    /// The end of the protected or the unprotected TLVs, whichever are being iterated
+    area_end: u32,
+    /// The end of the trailer
+    end: u32,
+}
+
+impl<'a, F: Flash + ?Sized> Iterator for Tlvs<'a, F> {
+    type Item = Result<Tlv, MalformedTlv>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.address + 4 > self.area_end {
+            if self.area_end == self.end {
+                return None;
+            }
+
+            // The protected TLVs are done, the unprotected ones start behind their own info
+            self.address = self.area_end + TlvInfo::SIZE;
+            self.area_end = self.end;
+            return self.next();
+        }
+
+        let bytes = self.flash.read_u8(self.address..self.address + 4);
+        let value_start = self.address + 4;
+        let value_end = value_start + u32::from(u16::from_le_bytes([bytes[2], bytes[3]]));
+        if value_end > self.area_end {
+            self.address = self.end;
+            self.area_end = self.end;
+            return Some(Err(MalformedTlv));
+        }
+
+        let tlv = Tlv {
+            tlv_type: u16::from_le_bytes([bytes[0], bytes[1]]),
+            value: value_start..value_end,
+            protected: self.area_end != self.end,
+        };
+        self.address = value_end;
+        Some(Ok(tlv))
+    }
+}
+
+/// The info in front of the protected and the unprotected TLVs
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TlvInfo {
+    /// [TlvInfo::MAGIC] or [TlvInfo::PROTECTED_MAGIC]
+    pub magic: u16,
+    /// The size of the TLVs in bytes, including this info
+    pub total_size: u16,
+}
+
+impl TlvInfo {
+    /// The magic of the unprotected TLVs
+    pub const MAGIC: u16 = 0x6907;
+    /// The magic of the protected TLVs, which are covered by the hash of the image
+    pub const PROTECTED_MAGIC: u16 = 0x6908;
+    /// The size of the info in bytes
+    pub const SIZE: u32 = 4;
+
+    /// Reads the info at the given address, if it lies before `end`
+    fn load(flash: &(impl Flash + ?Sized), address: u32, end: u32) -> Option<Self> {
+        if address.checked_add(Self::SIZE)? > end {
+            return None;
+        }
+
+        let mut bytes = [0; Self::SIZE as usize];
+        flash.read(address, &mut bytes).ok()?;
+        Some(Self {
+            magic: u16::from_le_bytes([bytes[0], bytes[1]]),
+            total_size: u16::from_le_bytes([bytes[2], bytes[3]]),
+        })
+    }
+}
@@ -0,0 +1,104 @@
+//! The boot measurements that the bootloader hands over to the application for attestation
+//!
+//! Before starting the application, the bootloader hashes its own flash and both program slots with SHA-256
+//! and leaves the result in RAM (see [bootloader_measurements_range]). The application can pass it on to the
+//! attestation backend as it is, so the layout is fixed and little-endian:
+//!
+//! | Offset | Size | Field            |
+//! |--------|------|------------------|
+//! | 0      | 4    | magic            |
+//! | 4      | 2    | layout version   |
+//! | 6      | 2    | reserved         |
+//! | 8      | 32   | bootloader hash  |
+//! | 40     | 32   | slot A hash      |
+//! | 72     | 32   | slot B hash      |
+
+use crate::flash_addresses::bootloader_measurements_range;
+use core::mem::size_of;
+
+/// The SHA-256 hashes of everything that was in flash when the application was started
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BootMeasurements {
+    /// Always [Self::MAGIC] for valid measurements
+    pub magic: u32,
+    /// The version of this layout, always [Self::VERSION]
+    pub version: u16,
+    /// Always 0
+    pub reserved: u16,
+    /// The hash of the entire bootloader flash, including the build info
+    pub bootloader_hash: [u8; 32],
+    /// The hash of the entire slot A, which holds the application that is started
+    pub slot_a_hash: [u8; 32],
+    /// The hash of the entire slot B
+    pub slot_b_hash: [u8; 32],
+}
+
+impl BootMeasurements {
+    /// The word that marks valid measurements
+    pub const MAGIC: u32 = 0xB0075EA5;
+
+    /// The current version of the layout
+    pub const VERSION: u16 = 1;
+
+    /// The size of the measurements in bytes
+    pub const SIZE: usize = size_of::<Self>();
+
+    /// Creates the measurements from the hashes
+    pub fn new(bootloader_hash: [u8; 32], slot_a_hash: [u8; 32], slot_b_hash: [u8; 32]) -> Self {
+        Self {
+            magic: Self::MAGIC,
+            version: Self::VERSION,
+            reserved: 0,
+            bootloader_hash,
+            slot_a_hash,
+            slot_b_hash,
+        }
+    }
+
+    /// Creates the little-endian byte representation that the attestation backend expects
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.version.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.reserved.to_le_bytes());
+        bytes[8..40].copy_from_slice(&self.bootloader_hash);
+        bytes[40..72].copy_from_slice(&self.slot_a_hash);
+        bytes[72..104].copy_from_slice(&self.slot_b_hash);
+        bytes
+    }
+
+    /// Parses the measurements from their little-endian byte representation.
+    ///
+    /// Returns `None` if the bytes are too short or the magic word or version doesn't match.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..Self::SIZE)?;
+
+        let measurements = Self {
+            magic: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            version: u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+            reserved: u16::from_le_bytes(bytes[6..8].try_into().unwrap()),
+            bootloader_hash: bytes[8..40].try_into().unwrap(),
+            slot_a_hash: bytes[40..72].try_into().unwrap(),
+            slot_b_hash: bytes[72..104].try_into().unwrap(),
+        };
+
+        (measurements.magic == Self::MAGIC && measurements.version == Self::VERSION)
+            .then_some(measurements)
+    }
+
+    /// Leaves the measurements in RAM for the application
+    pub fn store(&self) {
+        let bytes = self.to_bytes();
+        let destination = bootloader_measurements_range().start as *mut [u8; Self::SIZE];
+        unsafe { destination.write_volatile(bytes) };
+    }
+
+    /// Reads the measurements the bootloader left in RAM.
+    ///
+    /// Returns `None` if the bootloader didn't measure this boot.
+    pub fn load() -> Option<Self> {
+        let source = bootloader_measurements_range().start as *const [u8; Self::SIZE];
+        Self::from_bytes(&unsafe { source.read_volatile() })
+    }
+}
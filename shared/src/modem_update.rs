@@ -0,0 +1,56 @@
+//! Staging of modem firmware updates
+//!
+//! The modem firmware is updated by the application through the modem library, but the bootloader keeps track
+//! of the update so it survives resets:
+//!
+//! 1. The application writes a [StagedImageHeader](crate::staged_image::StagedImageHeader) with [MAGIC] and the
+//!    delta image into the staging region ([modem_staging_range]) and sets the
+//!    [StartModemUpdate](crate::state::BootloaderGoal::StartModemUpdate) goal.
+//! 2. At the next boot, the bootloader validates the staged image and sets the [ModemUpdateStatus] in the state
+//!    to [Validated](ModemUpdateStatus::Validated) or [Invalid](ModemUpdateStatus::Invalid).
+//! 3. While the status is [Validated](ModemUpdateStatus::Validated), the application gets the location of the image
+//!    from [descriptor] and performs the modem DFU. This is also where it continues after a reset during the DFU.
+//! 4. When the DFU is done, the application sets the [FinishModemUpdate](crate::state::BootloaderGoal::FinishModemUpdate) goal
+//!    and the bootloader sets the status to [Done](ModemUpdateStatus::Done).
+
+use crate::{
+    flash_addresses::modem_staging_range,
+    staged_image::{self, StagedImage, StagedImageError},
+    state::BootloaderState,
+    Flash,
+};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+/// The magic word of the header of a staged modem image
+pub const MAGIC: u32 = 0x30DE_3DF7;
+
+/// The progress of a modem update
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, IntoPrimitive, TryFromPrimitive)]
+pub enum ModemUpdateStatus {
+    /// No modem update has been staged
+    #[num_enum(alternatives = [0xFFFF_FFFF])]
+    None = 0,
+    /// The staged image is valid and the application should perform the DFU
+    Validated = 1,
+    /// The staged image is not valid
+    Invalid = 2,
+    /// The application has finished the DFU
+    Done = 3,
+}
+
+/// Validates the modem image in the staging region
+pub fn validate(flash: &(impl Flash + ?Sized)) -> Result<StagedImage, StagedImageError> {
+    staged_image::validate(flash, modem_staging_range(), MAGIC)
+}
+
+/// Returns where the staged image is if the bootloader has validated it and the DFU is not done yet
+pub fn descriptor(flash: &(impl Flash + ?Sized)) -> Option<StagedImage> {
+    let state = BootloaderState::load(flash);
+
+    if !state.is_valid() || state.modem_update_status() != ModemUpdateStatus::Validated {
+        return None;
+    }
+
+    validate(flash).ok()
+}
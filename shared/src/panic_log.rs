@@ -0,0 +1,139 @@
+//! A log of the panics of the bootloader in flash
+//!
+//! The panic handler of the bootloader writes the message of a panic into this log before it resets the device, so
+//! unlike a message in RAM it survives a power loss. The log is a single page of [RECORDS] records of
+//! [RECORD_SIZE] bytes:
+//!
+//! | Word  | Field                                                                          |
+//! |-------|--------------------------------------------------------------------------------|
+//! | 0     | [RECORD_MARKER] in the upper half, the length of the message in the lower half |
+//! | 1     | the reset reason, the RESETREAS register of the POWER peripheral at the panic  |
+//! | 2     | `0xFFFFFFFF` until the bootloader reported the panic at the next boot, then 0  |
+//! | 3..64 | the message, padded with `0xFF`                                                |
+//!
+//! Everything but the marker word is programmed first, so a record that lost its marker word to a power loss is
+//! skipped. When there is no erased record left, the page is erased and the log starts over with the new panic, so
+//! the most recent panic is always kept.
+//!
+//! The application can read the log with [records] and erase it with [clear].
+
+use crate::{
+    counter::program_word,
+    flash_addresses::{bootloader_panic_log_range, PAGE_SIZE},
+    Flash, FlashError,
+};
+use core::mem::size_of;
+
+/// The upper half of the first word of a record
+const RECORD_MARKER: u32 = 0xBA1C_0000;
+/// The size of a record in bytes
+pub const RECORD_SIZE: u32 = 256;
+/// The number of records in the log
+pub const RECORDS: usize = (PAGE_SIZE / RECORD_SIZE) as usize;
+/// The size of the words before the message in a record
+const HEADER_SIZE: u32 = 3 * size_of::<u32>() as u32;
+/// The longest message a record can hold. Longer messages are cut off.
+pub const MAX_MESSAGE_LENGTH: usize = (RECORD_SIZE - HEADER_SIZE) as usize;
+
+/// A record in the panic log
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PanicRecord<'a> {
+    /// The address of the record in flash
+    pub address: u32,
+    /// The RESETREAS register of the POWER peripheral when the panic happened
+    pub reset_reason: u32,
+    /// True once the bootloader reported the panic
+    pub reported: bool,
+    /// The message of the panic, cut off after [MAX_MESSAGE_LENGTH] bytes
+    pub message: &'a [u8],
+}
+
+/// Writes a panic into the next free record, erasing the log first if it's full
+pub fn append(
+    flash: &mut (impl Flash + ?Sized),
+    reset_reason: u32,
+    message: &[u8],
+) -> Result<(), FlashError> {
+    let record_address = match free_record_address(flash) {
+        Some(record_address) => record_address,
+        None => {
+            clear(flash)?;
+            bootloader_panic_log_range().start
+        }
+    };
+    let message = &message[..message.len().min(MAX_MESSAGE_LENGTH)];
+
+    // The words of the page up to the record stay what they are, only the record is programmed
+    let page_address = bootloader_panic_log_range().start;
+    let record_index = (record_address - page_address) as usize / size_of::<u32>();
+    let mut buffer = [0xFFFF_FFFF; PAGE_SIZE as usize / size_of::<u32>()];
+    buffer[..record_index].copy_from_slice(flash.read_u32(page_address..record_address));
+
+    let record_end = record_index + RECORD_SIZE as usize / size_of::<u32>();
+    let record = &mut buffer[record_index..record_end];
+    record[1] = reset_reason;
+    for (word, bytes) in record[HEADER_SIZE as usize / size_of::<u32>()..]
+        .iter_mut()
+        .zip(message.chunks(size_of::<u32>()))
+    {
+        let mut word_bytes = [0xFF; size_of::<u32>()];
+        word_bytes[..bytes.len()].copy_from_slice(bytes);
+        *word = u32::from_le_bytes(word_bytes);
+    }
+
+    flash.program_page(page_address, &buffer[..record_end])?;
+    program_word(flash, record_address, RECORD_MARKER | message.len() as u32)
+}
+
+/// Iterates over all the records in the log, from old to new
+pub fn records(flash: &(impl Flash + ?Sized)) -> impl Iterator<Item = PanicRecord<'_>> + '_ {
+    bootloader_panic_log_range()
+        .step_by(RECORD_SIZE as usize)
+        .filter_map(|address| {
+            let words = flash.read_u32(address..address + HEADER_SIZE);
+            if words[0] & 0xFFFF_0000 != RECORD_MARKER {
+                return None;
+            }
+
+            let length = (words[0] as u16 as usize).min(MAX_MESSAGE_LENGTH) as u32;
+            Some(PanicRecord {
+                address,
+                reset_reason: words[1],
+                reported: words[2] != 0xFFFF_FFFF,
+                message: flash.read_u8(address + HEADER_SIZE..address + HEADER_SIZE + length),
+            })
+        })
+}
+
+/// Marks the record at the given address as reported, so the bootloader doesn't report it again
+pub fn mark_reported(
+    flash: &mut (impl Flash + ?Sized),
+    record_address: u32,
+) -> Result<(), FlashError> {
+    program_word(flash, record_address + 2 * size_of::<u32>() as u32, 0)
+}
+
+/// Erases the log
+pub fn clear(flash: &mut (impl Flash + ?Sized)) -> Result<(), FlashError> {
+    let range = bootloader_panic_log_range();
+    for page_address in range.step_by(PAGE_SIZE as usize) {
+        flash.erase_page(page_address)?;
+    }
+
+    Ok(())
+}
+
+/// Finds the address of the first record that is still completely erased
+fn free_record_address(flash: &(impl Flash + ?Sized)) -> Option<u32> {
+    // A record that was torn by a power loss may have some words programmed, so it can't be used anymore
+    bootloader_panic_log_range()
+        .step_by(RECORD_SIZE as usize)
+        .rev()
+        .take_while(|address| {
+            flash
+                .read_u32(*address..*address + RECORD_SIZE)
+                .iter()
+                .all(|word| *word == 0xFFFF_FFFF)
+        })
+        .last()
+}
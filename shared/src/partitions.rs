@@ -0,0 +1,7 @@
+//! The flash layout in `partitions.toml`, as constants
+//!
+//! The build script generates this module from the same file as the linker scripts, so the constants always match
+//! the ranges in [flash_addresses](crate::flash_addresses). They can be used in a const context, and by an
+//! application that doesn't link the symbols of the bootloader's linker script.
+
+include!(concat!(env!("OUT_DIR"), "/partitions.rs"));
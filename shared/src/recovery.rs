@@ -0,0 +1,98 @@
+//! The framed protocol of the serial recovery
+//!
+//! A device without a working application can get a new image over the UART of the bootloader. The host sends
+//! [ENTER_RECOVERY] during the boot window to start the recovery, unless the bootloader enters it by itself.
+//! The image is then sent in frames:
+//!
+//! | Size    | Field                                                             |
+//! |---------|-------------------------------------------------------------------|
+//! | 1       | [FRAME_START]                                                     |
+//! | 1       | the kind of the frame, see [FrameKind]                            |
+//! | 2       | the sequence number                                               |
+//! | 2       | the length of the payload, at most [MAX_PAYLOAD]                  |
+//! | length  | the payload                                                       |
+//! | 4       | CRC-32/MPEG-2 over everything from the kind up to the payload     |
+//!
+//! All numbers are little-endian. A transfer is a [FrameKind::Begin] with the size of the image as its payload,
+//! the [FrameKind::Data] frames with the image in chunks of [MAX_PAYLOAD] bytes, numbered from 0, and a
+//! [FrameKind::End]. The bootloader answers every frame with [ACK] or [NAK]. After a [NAK], or when no answer comes,
+//! the host sends the frame again. A frame that was already received is acknowledged again without being written,
+//! so a lost [ACK] doesn't break the transfer. The host skips any other bytes, which are the log output.
+
+use core::mem::size_of;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+/// The byte the host sends during the boot window to enter the recovery
+pub const ENTER_RECOVERY: u8 = 0xA5;
+/// The byte that starts a frame
+pub const FRAME_START: u8 = 0x7E;
+/// The answer to a frame that was received and handled
+pub const ACK: u8 = 0x06;
+/// The answer to a frame that was damaged or didn't fit in the transfer
+pub const NAK: u8 = 0x15;
+
+/// The maximum size of the payload of a frame. A page of flash is exactly four payloads.
+pub const MAX_PAYLOAD: usize = 1024;
+/// The size of the CRC after the payload
+pub const CRC_SIZE: usize = size_of::<u32>();
+
+/// The kind of a frame
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, IntoPrimitive, TryFromPrimitive)]
+pub enum FrameKind {
+    /// Starts a transfer. The payload is the size of the image as a little-endian word.
+    Begin = 1,
+    /// A chunk of the image. The sequence number is the index of the chunk.
+    Data = 2,
+    /// Ends the transfer, after which the image is swapped in
+    End = 3,
+}
+
+/// The part of a frame between the [FRAME_START] and the payload
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FrameHeader {
+    /// The kind of the frame
+    pub kind: FrameKind,
+    /// The sequence number of the frame
+    pub sequence: u16,
+    /// The length of the payload in bytes
+    pub length: u16,
+}
+
+impl FrameHeader {
+    /// The size of the header in bytes
+    pub const SIZE: usize = 5;
+
+    /// Parses the header. Returns `None` if the kind is unknown or the payload is too long.
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Option<Self> {
+        let header = Self {
+            kind: FrameKind::try_from(bytes[0]).ok()?,
+            sequence: u16::from_le_bytes([bytes[1], bytes[2]]),
+            length: u16::from_le_bytes([bytes[3], bytes[4]]),
+        };
+
+        (usize::from(header.length) <= MAX_PAYLOAD).then_some(header)
+    }
+
+    /// Creates the bytes of the header
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let sequence = self.sequence.to_le_bytes();
+        let length = self.length.to_le_bytes();
+        [
+            self.kind.into(),
+            sequence[0],
+            sequence[1],
+            length[0],
+            length[1],
+        ]
+    }
+
+    /// Calculates the CRC of the frame with this header and the given payload
+    pub fn crc(&self, payload: &[u8]) -> u32 {
+        let crc = crc::Crc::<u32>::new(&crc::CRC_32_MPEG_2);
+        let mut digest = crc.digest();
+        digest.update(&self.to_bytes());
+        digest.update(payload);
+        digest.finalize()
+    }
+}
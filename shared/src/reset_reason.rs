@@ -0,0 +1,29 @@
+//! The reset reasons in the RESETREAS register of the POWER peripheral
+//!
+//! With the `reset-history` feature, the bootloader reads the register at every boot, clears it and adds the
+//! reason to the [reset history](crate::state::BootloaderState::reset_history) in the state. The application then
+//! reads the reason of its own boot from there instead of from the register. A reason can have several bits set.
+
+/// The reset pin was used
+pub const RESETPIN: u32 = 1 << 0;
+/// The watchdog reset the device
+pub const DOG: u32 = 1 << 1;
+/// The device woke up from System OFF mode
+pub const OFF: u32 = 1 << 2;
+/// The device woke up from the debug interface mode
+pub const DIF: u32 = 1 << 3;
+/// A soft reset, like the one the bootloader and most panic handlers do
+pub const SREQ: u32 = 1 << 4;
+/// The CPU locked up
+pub const LOCKUP: u32 = 1 << 5;
+/// A reset through the CTRL-AP of the debugger
+pub const CTRLAP: u32 = 1 << 6;
+
+/// Returns the number of boots in a row, from the newest, that followed a watchdog reset.
+/// Feed it the [reset history](crate::state::BootloaderState::reset_history) to find a watchdog loop.
+pub fn watchdog_streak(history: impl IntoIterator<Item = u32>) -> usize {
+    history
+        .into_iter()
+        .take_while(|reason| reason & DOG != 0)
+        .count()
+}
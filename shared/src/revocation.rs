@@ -0,0 +1,63 @@
+//! The list of revoked signing keys in flash
+//!
+//! Every signing key has a key ID. When a key leaks, it's revoked on the device by burning its bit in the
+//! revocation page, after which the bootloader must refuse every image signed with it. The page is never erased,
+//! not even by a wipe, so a revocation is permanent.
+//!
+//! Key ID `n` is bit `n % 32` of word `n / 32` of the page. A set bit (erased flash) means the key is still valid,
+//! a cleared bit means it's revoked. A 4K page holds the bits of 32768 key IDs.
+//!
+//! Only the bootloader writes the page. The application asks for a revocation with
+//! [request_revocation](crate::mailbox::request_revocation), authenticated with a token of the device.
+
+use crate::{
+    counter::program_word, flash_addresses::bootloader_revocations_range, Flash, FlashError,
+};
+
+/// The key ID doesn't fit in the revocation page
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct KeyIdOutOfRange;
+
+/// Why a key could not be revoked
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RevokeError {
+    /// The key ID doesn't fit in the revocation page
+    KeyIdOutOfRange,
+    /// The revocation bit could not be programmed
+    Flash(FlashError),
+}
+
+/// Gives the address of the word with the bit of the key and the mask of that bit
+fn locate(key_id: u32) -> Result<(u32, u32), KeyIdOutOfRange> {
+    let range = bootloader_revocations_range();
+    let address = key_id
+        .checked_div(u32::BITS)
+        .and_then(|word| word.checked_mul(4))
+        .and_then(|offset| range.start.checked_add(offset))
+        .filter(|address| *address < range.end)
+        .ok_or(KeyIdOutOfRange)?;
+
+    Ok((address, 1 << (key_id % u32::BITS)))
+}
+
+/// Returns true if the key with the given ID is revoked.
+///
+/// Key IDs that don't fit in the page can never be revoked, so they are treated as revoked.
+pub fn is_revoked(flash: &(impl Flash + ?Sized), key_id: u32) -> bool {
+    match locate(key_id) {
+        Ok((address, mask)) => flash.read_u32(address..address + 4)[0] & mask == 0,
+        Err(KeyIdOutOfRange) => true,
+    }
+}
+
+/// Revokes the key with the given ID. This can't be undone.
+pub fn revoke(flash: &mut (impl Flash + ?Sized), key_id: u32) -> Result<(), RevokeError> {
+    let (address, mask) = locate(key_id).map_err(|_| RevokeError::KeyIdOutOfRange)?;
+
+    let word = flash.read_u32(address..address + 4)[0];
+    if word & mask != 0 {
+        program_word(flash, address, word & !mask).map_err(RevokeError::Flash)?;
+    }
+
+    Ok(())
+}
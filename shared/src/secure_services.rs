@@ -0,0 +1,128 @@
+//! The secure services that the bootloader offers to a non-secure application
+//!
+//! With the `secure-services` feature, the bootloader is the minimal secure firmware of the chip, so a non-secure
+//! application doesn't need Nordic's SPM. The bootloader partitions the chip like with the `non-secure` feature and
+//! leaves a small table of non-secure callable veneers at [VENEERS_ADDRESS], at the end of its flash.
+//! The application calls them through the functions of this module:
+//!
+//! - [request_goal] changes the goal right away, with the same compare-and-set semantics as
+//!   [BootloaderState::set_goal](crate::state::BootloaderState::set_goal). The secure state can't be written by the
+//!   non-secure application itself.
+//! - [reboot] resets the device, so the bootloader handles the goal.
+//!
+//! Every veneer is [VENEER_SIZE] bytes, in the order of [Service]. The functions must only be called from a
+//! non-secure application that was started by a bootloader with the feature.
+
+use crate::{
+    state::{BootloaderGoal, GoalChangeError, GoalMismatch},
+    FlashError,
+};
+
+/// The address of the first veneer, in the last 64 bytes of the bootloader flash
+pub const VENEERS_ADDRESS: u32 = crate::partitions::BOOTLOADER_FLASH.end - 64;
+/// The size of a veneer in bytes
+pub const VENEER_SIZE: u32 = 8;
+
+/// The result code of a goal request that succeeded
+const OK: u32 = 0;
+/// The result code of a goal request for a goal that isn't requestable
+const NOT_REQUESTABLE: u32 = 1;
+/// The result code of a goal request with the wrong expected goal is this plus the current goal
+const MISMATCH: u32 = 0x100;
+/// The result code of a goal request with the wrong expected goal when the current goal is unknown
+const MISMATCH_UNKNOWN_GOAL: u32 = 0x200;
+/// The result code of a goal request that failed to store the state is this plus the index of the [FlashError]
+const FLASH_ERROR: u32 = 0x300;
+
+/// The flash errors in the order of their result codes
+const FLASH_ERRORS: [FlashError; 4] = [
+    FlashError::Alignment,
+    FlashError::OutOfRange,
+    FlashError::Timeout,
+    FlashError::VerifyFailed,
+];
+
+/// The services in the order of their veneers
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Service {
+    /// Sets the goal, see [request_goal]
+    RequestGoal = 0,
+    /// Resets the device, see [reboot]
+    Reboot = 1,
+}
+
+impl Service {
+    /// The address of the veneer of the service, with the thumb bit set so it can be called
+    pub fn address(self) -> u32 {
+        (VENEERS_ADDRESS + self as u32 * VENEER_SIZE) | 1
+    }
+}
+
+/// The reason a goal request was refused
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GoalRequestError {
+    /// The goal is not [requestable](BootloaderGoal::is_requestable) by the application
+    NotRequestable,
+    /// The current goal is not the expected one
+    Mismatch(GoalMismatch),
+    /// The state could not be stored
+    Flash(FlashError),
+}
+
+impl From<GoalChangeError> for GoalRequestError {
+    fn from(error: GoalChangeError) -> Self {
+        match error {
+            GoalChangeError::Mismatch(mismatch) => Self::Mismatch(mismatch),
+            GoalChangeError::Flash(error) => Self::Flash(error),
+        }
+    }
+}
+
+/// Encodes the result of a goal request into the word that the secure service returns
+pub fn encode_goal_result(result: Result<(), GoalRequestError>) -> u32 {
+    match result {
+        Ok(()) => OK,
+        Err(GoalRequestError::NotRequestable) => NOT_REQUESTABLE,
+        Err(GoalRequestError::Mismatch(GoalMismatch {
+            current: Some(current),
+        })) => MISMATCH + u32::from(current),
+        Err(GoalRequestError::Mismatch(GoalMismatch { current: None })) => MISMATCH_UNKNOWN_GOAL,
+        Err(GoalRequestError::Flash(error)) => {
+            FLASH_ERROR + FLASH_ERRORS.iter().position(|e| *e == error).unwrap_or(0) as u32
+        }
+    }
+}
+
+/// Decodes the word that the secure service returns for a goal request
+pub fn decode_goal_result(value: u32) -> Result<(), GoalRequestError> {
+    match value {
+        OK => Ok(()),
+        MISMATCH..=0x1FF => Err(GoalRequestError::Mismatch(GoalMismatch {
+            current: BootloaderGoal::try_from(value - MISMATCH).ok(),
+        })),
+        MISMATCH_UNKNOWN_GOAL => Err(GoalRequestError::Mismatch(GoalMismatch { current: None })),
+        FLASH_ERROR..=0x3FF => match FLASH_ERRORS.get((value - FLASH_ERROR) as usize) {
+            Some(error) => Err(GoalRequestError::Flash(*error)),
+            None => Err(GoalRequestError::NotRequestable),
+        },
+        _ => Err(GoalRequestError::NotRequestable),
+    }
+}
+
+/// Sets the goal in the state if the current goal is the expected one, through the secure service of the bootloader
+pub fn request_goal(
+    expected: BootloaderGoal,
+    goal: BootloaderGoal,
+) -> Result<(), GoalRequestError> {
+    let service: extern "C" fn(u32, u32) -> u32 =
+        unsafe { core::mem::transmute(Service::RequestGoal.address() as usize) };
+    decode_goal_result(service(expected.into(), goal.into()))
+}
+
+/// Resets the device through the secure service of the bootloader
+pub fn reboot() -> ! {
+    let service: extern "C" fn() -> ! =
+        unsafe { core::mem::transmute(Service::Reboot.address() as usize) };
+    service()
+}
@@ -0,0 +1,61 @@
+//! The security counter for anti-rollback in the UICR
+//!
+//! An image can carry a security counter in the protected [TLV_SEC_CNT](crate::mcuboot::TLV_SEC_CNT) TLV of its
+//! MCUboot trailer. The bootloader keeps the highest counter of a confirmed image in customer OTP words of the UICR,
+//! right after the [configuration](crate::config), and refuses to swap in an image with a lower counter.
+//!
+//! Like a [MonotonicCounter](crate::counter::MonotonicCounter), the counter counts by clearing bits: its value is
+//! the amount of cleared bits, from the lowest bit of the first word upwards. The UICR can't be erased without
+//! erasing the whole chip, so the counter can only ever go up.
+
+use crate::config::CONFIG_ADDRESS;
+use core::mem::size_of;
+
+/// The address of the first UICR word of the security counter
+pub const SECURITY_COUNTER_ADDRESS: u32 = CONFIG_ADDRESS + size_of::<u32>() as u32;
+/// The amount of UICR words the security counter takes up. The nRF52840 has two words less to spare,
+/// see [chip](crate::chip).
+pub const SECURITY_COUNTER_WORDS: usize = if cfg!(feature = "chip-nrf52840") {
+    14
+} else {
+    16
+};
+/// The highest value the security counter can have
+pub const CAPACITY: u32 = SECURITY_COUNTER_WORDS as u32 * u32::BITS;
+
+/// The addresses of the UICR words of the security counter
+pub fn word_addresses() -> impl Iterator<Item = u32> {
+    (0..SECURITY_COUNTER_WORDS as u32)
+        .map(|index| SECURITY_COUNTER_ADDRESS + index * size_of::<u32>() as u32)
+}
+
+/// Turns the UICR words into the value of the counter
+pub fn from_uicr_words(words: impl IntoIterator<Item = u32>) -> u32 {
+    words.into_iter().map(|word| word.count_zeros()).sum()
+}
+
+/// Creates the UICR words for the given value. Values above the [CAPACITY] clear all bits.
+///
+/// Programming them over the current words never sets a bit, so the counter keeps the highest of both values.
+pub fn uicr_words(value: u32) -> [u32; SECURITY_COUNTER_WORDS] {
+    let mut words = [u32::MAX; SECURITY_COUNTER_WORDS];
+    for (index, word) in words.iter_mut().enumerate() {
+        let bits_before = index as u32 * u32::BITS;
+        *word = match value.saturating_sub(bits_before) {
+            bits if bits >= u32::BITS => 0,
+            bits => u32::MAX << bits,
+        };
+    }
+    words
+}
+
+/// Reads the current value of the security counter from the UICR.
+///
+/// This is what the application uses to find out which images the bootloader still accepts.
+#[cfg(not(feature = "std-compat"))]
+pub fn read() -> u32 {
+    // Safety: the UICR is always readable and these words are only ever programmed by the bootloader
+    from_uicr_words(
+        word_addresses().map(|address| unsafe { (address as *const u32).read_volatile() }),
+    )
+}
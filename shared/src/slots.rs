@@ -0,0 +1,117 @@
+//! The descriptors of the image slots in flash
+//!
+//! Instead of a fixed pair of slots, the layout of the images is an array of [SlotDescriptor]s. Every slot has a
+//! [SlotRole] and belongs to an image, so the core can find the slots it needs for a goal by their role.
+//! The standard layout is [default_layout], with slot A as the primary and slot B as the secondary slot of the
+//! application. Other layouts, for example with a golden image or a separate test slot, can be passed to
+//! `dis_bootloader_core::run_with_slots` without changing the crate.
+
+use crate::flash_addresses::{program_slot_a_range, program_slot_b_range, PAGE_SIZE};
+use core::ops::Range;
+
+/// The image ID of the application
+pub const APPLICATION_IMAGE: u8 = 0;
+
+/// The alignment of a vector table. The VTOR needs the table to be aligned to its size rounded up to a power of two,
+/// which is 512 bytes for the 81 vectors of the nRF9160.
+pub const VECTOR_TABLE_ALIGNMENT: u32 = 512;
+
+/// What a slot is used for
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SlotRole {
+    /// The image is started from here. This is slot A in the standard layout.
+    Primary,
+    /// New images are staged here and swapped with the primary slot. This is slot B in the standard layout.
+    Secondary,
+    /// A known good image that is never overwritten by an update
+    Golden,
+    /// A slot to try out an image in place, without swapping it into the primary slot
+    Test,
+}
+
+/// The descriptor of a slot
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SlotDescriptor {
+    /// What the slot is used for
+    pub role: SlotRole,
+    /// The address range of the slot. It must be aligned to pages.
+    pub range: Range<u32>,
+    /// The image this slot belongs to, like [APPLICATION_IMAGE]. Multi-image layouts use other IDs,
+    /// for example for the firmware of a co-processor.
+    pub image_id: u8,
+    /// True if the images in this slot are linked to run from it, so they can be started in place
+    pub executable: bool,
+    /// The offset of the vector table from the start of the slot, for images that have a header in front of it.
+    /// It must be a multiple of [VECTOR_TABLE_ALIGNMENT]. With `None`, the vector table is right after the header of an
+    /// image that starts with one (see the [image_header](crate::image_header) and [mcuboot](crate::mcuboot) modules),
+    /// and otherwise at the start of the slot.
+    pub vector_table_offset: Option<u32>,
+    /// The pages of the slot that are left alone by swaps, as page indices from the start of the slot.
+    /// They can hold data that belongs to the slot instead of the image, like calibration data of the application.
+    pub excluded_pages: &'static [u32],
+}
+
+impl SlotDescriptor {
+    /// The address of the slot
+    pub fn address(&self) -> u32 {
+        self.range.start
+    }
+
+    /// The size of the slot in bytes
+    pub fn size(&self) -> u32 {
+        self.range.end - self.range.start
+    }
+
+    /// The address of the vector table if the slot has a [vector table offset](Self::vector_table_offset)
+    pub fn vector_table_address(&self) -> Option<u32> {
+        self.vector_table_offset
+            .map(|offset| self.range.start + offset)
+    }
+
+    /// Returns true if the page with the given index from the start of the slot is excluded from swaps
+    pub fn is_page_excluded(&self, page: u32) -> bool {
+        self.excluded_pages.contains(&page)
+    }
+
+    /// The number of bytes at the start of the slot that an image can use, which is up to the first excluded page.
+    /// Images that are bigger would have their end cut off by the swap.
+    pub fn image_capacity(&self) -> u32 {
+        let pages = self.size() / PAGE_SIZE;
+        let first_excluded_page = (0..pages).find(|page| self.is_page_excluded(*page));
+        first_excluded_page.map_or(self.size(), |page| page * PAGE_SIZE)
+    }
+
+    /// The global page range of the slot
+    pub fn page_range(&self) -> Range<u32> {
+        self.range.start / PAGE_SIZE..self.range.end / PAGE_SIZE
+    }
+}
+
+/// The standard layout from the linker script: slot A is the primary and slot B the secondary slot of the application
+pub fn default_layout() -> [SlotDescriptor; 2] {
+    [
+        SlotDescriptor {
+            role: SlotRole::Primary,
+            range: program_slot_a_range(),
+            image_id: APPLICATION_IMAGE,
+            executable: true,
+            vector_table_offset: None,
+            excluded_pages: &[],
+        },
+        SlotDescriptor {
+            role: SlotRole::Secondary,
+            range: program_slot_b_range(),
+            image_id: APPLICATION_IMAGE,
+            executable: true,
+            vector_table_offset: None,
+            excluded_pages: &[],
+        },
+    ]
+}
+
+/// Finds the slot with the given role of the given image
+pub fn find(slots: &[SlotDescriptor], role: SlotRole, image_id: u8) -> Option<&SlotDescriptor> {
+    slots
+        .iter()
+        .find(|slot| slot.role == role && slot.image_id == image_id)
+}
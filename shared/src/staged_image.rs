@@ -0,0 +1,201 @@
+//! Images that are staged in flash for the bootloader to validate
+//!
+//! Modem firmware updates and bootloader updates are staged behind the same little-endian header.
+//! The magic word tells what kind of image it is.
+//!
+//! | Offset | Size | Field                                      |
+//! |--------|------|--------------------------------------------|
+//! | 0      | 4    | magic                                      |
+//! | 4      | 4    | length of the image in bytes               |
+//! | 8      | 4    | CRC-32/MPEG-2 of the image                 |
+//! | 12     | 4    | compatible hardware revisions              |
+//!
+//! The compatible hardware revisions are a bitmask where bit `n` means the image runs on revision `n`
+//! (see [hardware_revision](crate::hardware_revision)). When it's 0, the image runs on all revisions.
+//!
+//! The application can show what is in slot B, like "bootloader 2.3.1 staged", with [slot_b_metadata], without
+//! parsing the header itself.
+
+use crate::{
+    bootloader_update, build_info::BuildInfo, flash_addresses::program_slot_b_range,
+    hardware_revision, modem_update, Flash,
+};
+use core::ops::Range;
+
+/// The header in front of a staged image
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StagedImageHeader {
+    /// The kind of image
+    pub magic: u32,
+    /// The length of the image in bytes
+    pub length: u32,
+    /// The CRC-32/MPEG-2 of the image
+    pub crc: u32,
+    /// The bitmask of the hardware revisions the image runs on, or 0 for all revisions
+    pub compatible_revisions: u32,
+}
+
+impl StagedImageHeader {
+    /// The size of the header in bytes. The image follows right after it.
+    pub const SIZE: u32 = 16;
+
+    /// Creates the header for the given image, which runs on all hardware revisions
+    pub fn new(magic: u32, image: &[u8]) -> Self {
+        Self {
+            magic,
+            length: image.len() as u32,
+            crc: crc::Crc::<u32>::new(&crc::CRC_32_MPEG_2).checksum(image),
+            compatible_revisions: 0,
+        }
+    }
+
+    /// Creates the words of the header, to be written at the start of the staging region
+    pub fn to_words(&self) -> [u32; 4] {
+        [self.magic, self.length, self.crc, self.compatible_revisions]
+    }
+}
+
+/// Where a staged image can be found
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StagedImage {
+    /// The flash address of the image, right after the header
+    pub address: u32,
+    /// The length of the image in bytes
+    pub length: u32,
+    /// The bitmask of the hardware revisions the image runs on, or 0 for all revisions
+    pub compatible_revisions: u32,
+}
+
+impl StagedImage {
+    /// The address range of the image
+    pub fn range(&self) -> Range<u32> {
+        self.address..self.address + self.length
+    }
+
+    /// Returns true if the image runs on the given hardware revision.
+    ///
+    /// When the revision is unknown, only images that run on all revisions are compatible.
+    pub fn is_compatible_with(&self, hardware_revision: Option<u32>) -> bool {
+        hardware_revision::is_compatible(self.compatible_revisions, hardware_revision)
+    }
+}
+
+/// Why a staged image is not valid
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StagedImageError {
+    /// There is no header with the expected magic word at the start of the staging region
+    NoHeader,
+    /// The image doesn't fit in the staging region
+    TooLong,
+    /// The CRC of the image doesn't match the header
+    CrcMismatch,
+}
+
+/// Validates the image with the given magic word at the start of the staging region
+pub fn validate(
+    flash: &(impl Flash + ?Sized),
+    staging: Range<u32>,
+    magic: u32,
+) -> Result<StagedImage, StagedImageError> {
+    let header = flash.read_u32(staging.start..staging.start + StagedImageHeader::SIZE);
+
+    if header[0] != magic {
+        return Err(StagedImageError::NoHeader);
+    }
+
+    let header = StagedImageHeader {
+        magic,
+        length: header[1],
+        crc: header[2],
+        compatible_revisions: header[3],
+    };
+
+    let address = staging.start + StagedImageHeader::SIZE;
+    if header.length > staging.end - address {
+        return Err(StagedImageError::TooLong);
+    }
+
+    let crc = crc::Crc::<u32>::new(&crc::CRC_32_MPEG_2);
+    if crc.checksum(flash.read_u8(address..address + header.length)) != header.crc {
+        return Err(StagedImageError::CrcMismatch);
+    }
+
+    Ok(StagedImage {
+        address,
+        length: header.length,
+        compatible_revisions: header.compatible_revisions,
+    })
+}
+
+/// What kind of image is in slot B
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StagedImageKind {
+    /// An application image, which has no header
+    Application,
+    /// A modem firmware update, see [modem_update]
+    Modem,
+    /// A bootloader update, see [bootloader_update]
+    Bootloader,
+}
+
+/// The metadata of the image in slot B
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StagedImageMetadata {
+    /// What kind of image it is
+    pub kind: StagedImageKind,
+    /// The length of the image in bytes. An application image has no header, so this is up to its last
+    /// programmed word.
+    pub length: u32,
+    /// The CRC-32/MPEG-2 of the image. For an application image, it's calculated over its length.
+    pub crc: u32,
+    /// The bitmask of the hardware revisions the image runs on, or 0 for all revisions or an application image
+    pub compatible_revisions: u32,
+    /// True if the image matches the CRC in its header. An application image is always valid.
+    pub valid: bool,
+    /// The build info of a valid bootloader update, with its version
+    pub build_info: Option<BuildInfo>,
+}
+
+/// Reads the metadata of the image in slot B, or returns `None` if slot B is erased.
+///
+/// This only reads the flash, it doesn't say whether the bootloader will accept the image. Note that after a swap,
+/// slot B has the previous application image.
+pub fn slot_b_metadata(flash: &(impl Flash + ?Sized)) -> Option<StagedImageMetadata> {
+    let slot_b = program_slot_b_range();
+    let header = flash.read_u32(slot_b.start..slot_b.start + StagedImageHeader::SIZE);
+
+    let kind = match header[0] {
+        modem_update::MAGIC => StagedImageKind::Modem,
+        bootloader_update::MAGIC => StagedImageKind::Bootloader,
+        _ => {
+            // Without a header, the image is everything up to the last programmed word
+            let words = flash.read_u32(slot_b.clone());
+            let length = words.iter().rposition(|word| *word != 0xFFFF_FFFF)? as u32 * 4 + 4;
+            let crc = crc::Crc::<u32>::new(&crc::CRC_32_MPEG_2);
+
+            return Some(StagedImageMetadata {
+                kind: StagedImageKind::Application,
+                length,
+                crc: crc.checksum(flash.read_u8(slot_b.start..slot_b.start + length)),
+                compatible_revisions: 0,
+                valid: true,
+                build_info: None,
+            });
+        }
+    };
+
+    let valid = validate(flash, slot_b, header[0]).is_ok();
+    let build_info = match kind {
+        StagedImageKind::Bootloader if valid => bootloader_update::build_info(flash),
+        _ => None,
+    };
+
+    Some(StagedImageMetadata {
+        kind,
+        length: header[1],
+        crc: header[2],
+        compatible_revisions: header[3],
+        valid,
+        build_info,
+    })
+}
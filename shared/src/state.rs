@@ -1,8 +1,11 @@
 //! Implementation of the bootloader state
 
 use crate::{
-    flash_addresses::{bootloader_state_range, program_slot_a_page_range, PAGE_SIZE},
-    Flash,
+    flash_addresses::{bootloader_state_log_range, bootloader_state_range, PAGE_SIZE},
+    modem_update::ModemUpdateStatus,
+    slots::APPLICATION_IMAGE,
+    state_checksum::checksum,
+    Flash, FlashError,
 };
 use core::{mem::size_of, ops::Range};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
@@ -11,9 +14,24 @@ use num_enum::{IntoPrimitive, TryFromPrimitive};
 ///
 /// It is both the API the application uses to set the bootloader goal and the store for the swapping process.
 ///
-/// Semantically this is stored on one flash page, but if it were only stored on one, then
-/// there is a possibility that the page would be corrupted in the erase-program cycle.
-/// By using two pages, this is prevented.
+/// The state is stored as a log of records, so most stores don't need an erase. The first 256 words of a page
+/// have room for 8 records of 32 words: a crc, the [Self::VALID_WORD] marker, a sequence number and the first
+/// [Self::HEADER_WORDS] words of the buffer. Every [Self::store] appends a record, and [Self::load] uses the record
+/// with the highest sequence number. The rest of the page has the page states, which are burned in.
+/// Only when all records are in use, or when the page states have to go back to erased, the pages are erased.
+///
+/// Both pages have the same contents. If a page gets corrupted in an interrupted erase-program cycle,
+/// the other still has the state.
+///
+/// Most records don't go on the state pages, but in the state log ([bootloader_state_log_range]). It has as many
+/// pages as the memory layout gives it, which are filled with 32 records each, one after the other. When the log is
+/// full, it starts over on its first page, which is erased then. The state pages only get a record when the page
+/// states are reset for a new swap, or when the log page that would be erased has the newest record, which only
+/// happens with a log of a single page. Every record has a sequence number across the log and the state pages.
+///
+/// The buffer has a [format version](Self::FORMAT_VERSION), so a later change of the layout can migrate the states
+/// of older bootloaders when they're loaded. States from before the version word are migrated too. A state with a
+/// newer version than this crate knows is loaded as an invalid state, so the bootloader just starts the application.
 pub struct BootloaderState {
     buffer: [u32; 4096 / size_of::<u32>()],
 }
@@ -26,6 +44,67 @@ impl BootloaderState {
     const CRC_INDEX: usize = 0;
     /// The index of where the goal is stored
     const GOAL_INDEX: usize = 1;
+    /// The index of where the status of the modem update is stored
+    const MODEM_UPDATE_STATUS_INDEX: usize = 2;
+    /// The index of where the status of the old image in slot B is stored
+    const OLD_IMAGE_STATUS_INDEX: usize = 3;
+    /// The index of where the CRC of slot B with the old image is stored
+    const OLD_IMAGE_CRC_INDEX: usize = 4;
+    /// The index of where the bitmask of the images that take part in a swap is stored
+    const SWAP_IMAGES_INDEX: usize = 5;
+    /// The index of where the bitmask of the images that still need to be swapped is stored.
+    /// A bit is cleared when the image is swapped.
+    const PENDING_IMAGE_SWAPS_INDEX: usize = 6;
+    /// The range of words where the [SwapStatistics] of the most recent swap are stored
+    const LAST_SWAP_STATISTICS_RANGE: Range<usize> = 7..11;
+    /// The index of where the number of failed boots of a test-swapped image is stored.
+    /// It's all ones when there is no test image waiting for its confirmation.
+    const FAILED_TEST_BOOTS_INDEX: usize = 11;
+    /// The index of the word with the number of boots since the application last cleared it in the lower half and
+    /// the [RollbackReason] in the upper half. Both halves are all ones when they were never set.
+    const BOOT_STATUS_INDEX: usize = 12;
+    /// The index of where the board ID of the most recent new image that was refused for another board is stored.
+    /// It's all ones when the most recent new image was accepted.
+    const REFUSED_BOARD_ID_INDEX: usize = 13;
+    /// The index of where the number of panics of the bootloader is stored, as the number of cleared bits.
+    /// Records of older bootloaders have all ones there, which is no panics.
+    const PANIC_COUNT_INDEX: usize = 14;
+    /// The range of words where the reset reasons of the last boots are stored, two per word, newest first.
+    /// A half that is all ones has no boot yet.
+    const RESET_HISTORY_RANGE: Range<usize> = 15..23;
+    /// The index of where the [format version](Self::FORMAT_VERSION) of the buffer is stored.
+    /// States of older bootloaders have all ones there.
+    const FORMAT_VERSION_INDEX: usize = 23;
+    /// The index of where the number of pages of the image swap in progress is stored.
+    /// It's all ones when the swap of the image hasn't started yet.
+    const SWAP_PAGE_COUNT_INDEX: usize = 24;
+
+    /// The number of words at the start of the buffer that are stored in a record, including the crc
+    const HEADER_WORDS: usize = 25;
+    /// The number of words of a record in flash
+    const RECORD_WORDS: usize = 32;
+    /// The number of words of a record of older bootloaders, which only stored the first 13 words of the buffer
+    const LEGACY_RECORD_WORDS: usize = 16;
+    /// The number of records that fit on a page in front of the page states
+    const RECORDS_PER_PAGE: usize = Self::CACHED_PAGES_RANGE.start / Self::RECORD_WORDS;
+    /// The number of records that fit on a page of the state log
+    const LOG_RECORDS_PER_PAGE: usize = PAGE_SIZE as usize / size_of::<u32>() / Self::RECORD_WORDS;
+    /// The index in a record of the crc over the rest of the record
+    const RECORD_CRC_INDEX: usize = 0;
+    /// The index in a record of the [Self::VALID_WORD] that marks it
+    const RECORD_MARKER_INDEX: usize = 1;
+    /// The index in a record of its sequence number, which is one higher for every new record
+    const RECORD_SEQUENCE_INDEX: usize = 2;
+    /// The index in a record where the first [Self::HEADER_WORDS] words of the buffer start
+    const RECORD_HEADER_START: usize = 3;
+
+    /// The version of the layout of the buffer. It must go up with every change of the layout that needs a
+    /// migration in [Self::migrate].
+    pub const FORMAT_VERSION: u32 = 1;
+    /// The maximum number of pages in a slot that can be swapped
+    pub const MAX_SWAP_PAGES: usize = 256;
+    /// The number of boots the [reset history](Self::reset_history) goes back
+    pub const RESET_HISTORY_LENGTH: usize = 16;
 
     /// The range of words that stores the page status for the copy from the A image to scratch
     const CACHED_PAGES_RANGE: Range<usize> = 256..512;
@@ -57,13 +136,9 @@ impl BootloaderState {
     /// The crc is not included because we can't calculate that.
     /// The page state ranges are not included because those are burn_stored and we don't want to have to update the CRC
     /// everytime because that would defeat the purpose of doing the burn stores.
+    /// The checksum scheme is picked at build time, see the [state_checksum](crate::state_checksum) module.
     fn calculate_self_crc(&self) -> u32 {
-        let crc = crc::Crc::<u32>::new(&crc::CRC_32_MPEG_2);
-        let mut digest = crc.digest();
-        for word in &self.buffer[Self::CRC_INDEX + 1..Self::CACHED_PAGES_RANGE.start] {
-            digest.update(&word.to_ne_bytes());
-        }
-        digest.finalize()
+        checksum(&self.buffer[Self::CRC_INDEX + 1..Self::CACHED_PAGES_RANGE.start])
     }
 
     /// Get the stored goal value from the buffer.
@@ -72,12 +147,57 @@ impl BootloaderState {
         self.buffer[Self::GOAL_INDEX].try_into().unwrap()
     }
 
-    /// Sets the stored goal value into the buffer.
-    pub fn set_goal(&mut self, goal: BootloaderGoal) {
+    /// Gets the goal that [Self::set_goal] compares against.
+    ///
+    /// An invalid state has no goal, so the bootloader just starts the application and the goal is
+    /// [BootloaderGoal::JumpToApplication]. Returns `None` if a valid state has an unknown goal value.
+    pub fn current_goal(&self) -> Option<BootloaderGoal> {
+        if !self.is_valid() {
+            return Some(BootloaderGoal::JumpToApplication);
+        }
+        self.buffer[Self::GOAL_INDEX].try_into().ok()
+    }
+
+    /// Sets the stored goal value into the buffer, but only if the [current goal](Self::current_goal) is the
+    /// expected one.
+    ///
+    /// When another writer has changed the goal since this state was loaded, its goal is kept and the mismatch
+    /// is returned, so the caller can decide again.
+    /// This also forgets the [failed test boots](Self::failed_test_boots), because they belong to the goal that
+    /// the test swap has set.
+    pub fn set_goal(
+        &mut self,
+        expected: BootloaderGoal,
+        goal: BootloaderGoal,
+    ) -> Result<(), GoalMismatch> {
+        let current = self.current_goal();
+        if current != Some(expected) {
+            return Err(GoalMismatch { current });
+        }
+
+        self.write_goal(goal);
+        Ok(())
+    }
+
+    /// Loads the state, sets the goal if the current goal is the expected one and stores the state again
+    pub fn compare_and_set_goal(
+        flash: &mut (impl Flash + ?Sized),
+        expected: BootloaderGoal,
+        goal: BootloaderGoal,
+    ) -> Result<(), GoalChangeError> {
+        let mut state = Self::load(flash);
+        state.set_goal(expected, goal)?;
+        state.set_valid(true);
+        state.store(flash)?;
+        Ok(())
+    }
+
+    fn write_goal(&mut self, goal: BootloaderGoal) {
         // When we change the goal, we also need to update the CRC
         let is_valid = self.is_valid();
 
         self.buffer[Self::GOAL_INDEX] = goal.into();
+        self.buffer[Self::FAILED_TEST_BOOTS_INDEX] = 0xFFFF_FFFF;
 
         if is_valid {
             // The state was valid before, so let's update it so it is valid again
@@ -85,8 +205,303 @@ impl BootloaderState {
         }
     }
 
-    /// Gets the state of the page with the given index. The index is global,
-    /// so the page that starts at address 0x000A_3000 has index 0xA3.
+    /// Gets the progress of the modem update.
+    /// Returns [ModemUpdateStatus::None] if the stored value is unknown.
+    pub fn modem_update_status(&self) -> ModemUpdateStatus {
+        self.buffer[Self::MODEM_UPDATE_STATUS_INDEX]
+            .try_into()
+            .unwrap_or(ModemUpdateStatus::None)
+    }
+
+    /// Sets the progress of the modem update
+    pub fn set_modem_update_status(&mut self, status: ModemUpdateStatus) {
+        let is_valid = self.is_valid();
+
+        self.buffer[Self::MODEM_UPDATE_STATUS_INDEX] = status.into();
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+    }
+
+    /// Gets what should happen with the old image that a swap left in slot B.
+    /// Returns [OldImageStatus::None] if the stored value is unknown.
+    pub fn old_image_status(&self) -> OldImageStatus {
+        self.buffer[Self::OLD_IMAGE_STATUS_INDEX]
+            .try_into()
+            .unwrap_or(OldImageStatus::None)
+    }
+
+    /// Sets what should happen with the old image in slot B
+    pub fn set_old_image_status(&mut self, status: OldImageStatus) {
+        let is_valid = self.is_valid();
+
+        self.buffer[Self::OLD_IMAGE_STATUS_INDEX] = status.into();
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+    }
+
+    /// Gets the CRC-32/MPEG-2 of slot B right after the swap that left the old image there
+    pub fn old_image_crc(&self) -> u32 {
+        self.buffer[Self::OLD_IMAGE_CRC_INDEX]
+    }
+
+    /// Sets the CRC-32/MPEG-2 of slot B with the old image
+    pub fn set_old_image_crc(&mut self, crc: u32) {
+        let is_valid = self.is_valid();
+
+        self.buffer[Self::OLD_IMAGE_CRC_INDEX] = crc;
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+    }
+
+    /// Gets the bitmask of the images that take part in a swap, with bit n for the image with ID n.
+    /// Returns only the application image if no images have been set.
+    pub fn swap_images(&self) -> u32 {
+        match self.buffer[Self::SWAP_IMAGES_INDEX] {
+            0 | 0xFFFF_FFFF => 1 << APPLICATION_IMAGE,
+            images => images,
+        }
+    }
+
+    /// Sets the bitmask of the images that take part in the next swap, with bit n for the image with ID n.
+    /// The images are swapped one after the other in the same run of the bootloader.
+    pub fn set_swap_images(&mut self, images: u32) {
+        let is_valid = self.is_valid();
+
+        self.buffer[Self::SWAP_IMAGES_INDEX] = images;
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+    }
+
+    /// Returns true if the image with the given ID takes part in the swap and hasn't been swapped yet
+    pub fn is_image_swap_pending(&self, image_id: u8) -> bool {
+        let image_bit = 1u32.checked_shl(image_id as u32).unwrap_or(0);
+        self.swap_images() & self.buffer[Self::PENDING_IMAGE_SWAPS_INDEX] & image_bit != 0
+    }
+
+    /// Marks the image with the given ID as swapped and resets the page states for the swap of the next image.
+    /// The state must be stored after this.
+    pub fn finish_image_swap(&mut self, image_id: u8) {
+        let is_valid = self.is_valid();
+
+        self.buffer[Self::PENDING_IMAGE_SWAPS_INDEX] &=
+            !1u32.checked_shl(image_id as u32).unwrap_or(0);
+        self.buffer[Self::SWAP_PAGE_COUNT_INDEX] = 0xFFFF_FFFF;
+        for page in 0..Self::MAX_SWAP_PAGES as u32 {
+            self.set_page_state(page, PageState::Original);
+        }
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+    }
+
+    /// Gets the number of pages of the slots that the swap of the current image covers,
+    /// or `None` if the swap of the image hasn't started yet
+    pub fn swap_page_count(&self) -> Option<u32> {
+        match self.buffer[Self::SWAP_PAGE_COUNT_INDEX] {
+            0xFFFF_FFFF => None,
+            pages => Some(pages),
+        }
+    }
+
+    /// Sets the number of pages of the slots that the swap of the current image covers.
+    /// It's kept until the image is swapped, so a resumed swap covers the same pages.
+    pub fn set_swap_page_count(&mut self, pages: Option<u32>) {
+        let is_valid = self.is_valid();
+
+        self.buffer[Self::SWAP_PAGE_COUNT_INDEX] = pages.unwrap_or(0xFFFF_FFFF);
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+    }
+
+    /// Gets the statistics of the most recent swap, or `None` if none have been stored
+    pub fn last_swap_statistics(&self) -> Option<SwapStatistics> {
+        let [pages_copied, pages_skipped, erases, duration_ms]: [u32; 4] = self.buffer
+            [Self::LAST_SWAP_STATISTICS_RANGE]
+            .try_into()
+            .unwrap();
+
+        (pages_copied != 0xFFFF_FFFF).then_some(SwapStatistics {
+            pages_copied,
+            pages_skipped,
+            erases,
+            duration_ms,
+        })
+    }
+
+    /// Sets the statistics of the most recent swap
+    pub fn set_last_swap_statistics(&mut self, statistics: SwapStatistics) {
+        let is_valid = self.is_valid();
+
+        self.buffer[Self::LAST_SWAP_STATISTICS_RANGE].copy_from_slice(&[
+            statistics.pages_copied,
+            statistics.pages_skipped,
+            statistics.erases,
+            statistics.duration_ms,
+        ]);
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+    }
+
+    /// Gets the number of boots of the test-swapped image in which the application didn't do the
+    /// [handshake](crate::mailbox::complete_handshake).
+    /// Returns `None` if there is no test image waiting for its confirmation.
+    pub fn failed_test_boots(&self) -> Option<u32> {
+        match self.buffer[Self::FAILED_TEST_BOOTS_INDEX] {
+            0xFFFF_FFFF => None,
+            failed_boots => Some(failed_boots),
+        }
+    }
+
+    /// Sets the number of failed boots of the test-swapped image, or `None` if there is no test image waiting for
+    /// its confirmation
+    pub fn set_failed_test_boots(&mut self, failed_boots: Option<u32>) {
+        let is_valid = self.is_valid();
+
+        self.buffer[Self::FAILED_TEST_BOOTS_INDEX] = failed_boots.unwrap_or(0xFFFF_FFFF);
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+    }
+
+    /// Gets the board ID of the most recent new image, if it was refused because it was built for another board
+    /// (see [board_id](crate::board_id))
+    pub fn refused_board_id(&self) -> Option<u16> {
+        match self.buffer[Self::REFUSED_BOARD_ID_INDEX] {
+            0xFFFF_FFFF => None,
+            board_id => Some(board_id as u16),
+        }
+    }
+
+    /// Sets the board ID of a new image that was refused because it was built for another board, or `None` when a
+    /// new image was accepted
+    pub fn set_refused_board_id(&mut self, board_id: Option<u16>) {
+        let is_valid = self.is_valid();
+
+        self.buffer[Self::REFUSED_BOARD_ID_INDEX] = board_id.map_or(0xFFFF_FFFF, u32::from);
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+    }
+
+    /// Gets the number of panics of the bootloader since the count was last reset
+    pub fn panic_count(&self) -> u32 {
+        self.buffer[Self::PANIC_COUNT_INDEX].count_zeros()
+    }
+
+    /// Sets the number of panics of the bootloader. The count is kept as cleared bits, so it stops at 32 and a
+    /// higher count only ever clears more bits of the word.
+    pub fn set_panic_count(&mut self, panics: u32) {
+        let is_valid = self.is_valid();
+
+        self.buffer[Self::PANIC_COUNT_INDEX] = u32::MAX.checked_shl(panics).unwrap_or(0);
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+    }
+
+    /// Gets the reset reasons of the last boots that were recorded, newest first, as the RESETREAS register of
+    /// the POWER peripheral (see [reset_reason](crate::reset_reason))
+    pub fn reset_history(&self) -> impl Iterator<Item = u32> + '_ {
+        self.buffer[Self::RESET_HISTORY_RANGE]
+            .iter()
+            .flat_map(|word| [*word as u16, (*word >> 16) as u16])
+            .take_while(|reason| *reason != 0xFFFF)
+            .map(u32::from)
+    }
+
+    /// Adds the reset reason of a boot to the front of the reset history, dropping the oldest one.
+    /// Only the lower 15 bits of the reason are kept.
+    pub fn push_reset_reason(&mut self, reason: u32) {
+        let is_valid = self.is_valid();
+
+        let mut carry = reason & 0x7FFF;
+        for word in &mut self.buffer[Self::RESET_HISTORY_RANGE] {
+            let shifted = *word << 16 | carry;
+            carry = *word >> 16;
+            *word = shifted;
+        }
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+    }
+
+    /// Gets the number of times the bootloader started the application since the application last
+    /// [cleared](Self::clear_boot_attempts) it
+    pub fn boot_attempts(&self) -> u32 {
+        match self.buffer[Self::BOOT_STATUS_INDEX] & 0xFFFF {
+            0xFFFF => 0,
+            boot_attempts => boot_attempts,
+        }
+    }
+
+    /// Sets the number of times the bootloader started the application since the application last cleared it.
+    /// The number is stored in 16 bits, so it stops at `0xFFFE`.
+    pub fn set_boot_attempts(&mut self, boot_attempts: u32) {
+        let is_valid = self.is_valid();
+
+        self.buffer[Self::BOOT_STATUS_INDEX] =
+            self.buffer[Self::BOOT_STATUS_INDEX] & 0xFFFF_0000 | boot_attempts.min(0xFFFE);
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+    }
+
+    /// Gets why the image was last swapped back to the previous one.
+    /// Returns [RollbackReason::None] if the stored value is unknown.
+    pub fn rollback_reason(&self) -> RollbackReason {
+        (self.buffer[Self::BOOT_STATUS_INDEX] >> 16)
+            .try_into()
+            .unwrap_or(RollbackReason::None)
+    }
+
+    /// Sets why the image is swapped back to the previous one, or [RollbackReason::None] for a swap that installs
+    /// an update
+    pub fn set_rollback_reason(&mut self, reason: RollbackReason) {
+        let is_valid = self.is_valid();
+
+        self.buffer[Self::BOOT_STATUS_INDEX] =
+            self.buffer[Self::BOOT_STATUS_INDEX] & 0xFFFF | u32::from(reason) << 16;
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+    }
+
+    /// Loads the state, clears the boot attempts and stores the state again.
+    ///
+    /// The application calls this once it's running properly. When the bootloader has a boot attempt threshold,
+    /// it reverts the image after that many boots in which the application didn't.
+    /// An invalid state isn't stored, because the bootloader doesn't count boots without a valid state.
+    pub fn clear_boot_attempts(flash: &mut (impl Flash + ?Sized)) -> Result<(), FlashError> {
+        let mut state = Self::load(flash);
+        if !state.is_valid() || state.boot_attempts() == 0 {
+            return Ok(());
+        }
+
+        state.set_boot_attempts(0);
+        state.store(flash)
+    }
+
+    /// Gets the state of the page with the given index. The index is relative to the start of the swapped slots,
+    /// so page 0 is the first page of both slots.
     pub fn get_page_state(&self, page: u32) -> PageState {
         let cached_value = self.buffer[Self::CACHED_PAGES_RANGE][page as usize];
         let copied_value = self.buffer[Self::COPIED_PAGES_RANGE][page as usize];
@@ -119,68 +534,393 @@ impl BootloaderState {
         self.buffer[Self::FINISHED_PAGE_RANGE][page as usize] = finished_value;
     }
 
-    /// Sets the state so that a swap can be started.
+    /// Sets the state so that a swap can be started from the expected goal.
     /// Also performs a fresh erase so that all expected burn-in flashing can happen as expected.
-    pub fn prepare_swap(&mut self, test_swap: bool, flash: &mut impl Flash) {
+    ///
+    /// If the goal isn't the expected one, nothing is changed.
+    pub fn prepare_swap(
+        &mut self,
+        expected: BootloaderGoal,
+        test_swap: bool,
+        flash: &mut (impl Flash + ?Sized),
+    ) -> Result<(), GoalChangeError> {
         // We're starting a swap, so our new goal is finishing it
-        self.set_goal(if test_swap {
-            BootloaderGoal::FinishTestSwap
-        } else {
-            BootloaderGoal::FinishSwap
-        });
+        self.set_goal(
+            expected,
+            if test_swap {
+                BootloaderGoal::FinishTestSwap
+            } else {
+                BootloaderGoal::FinishSwap
+            },
+        )?;
 
-        for page in 0..program_slot_a_page_range().len() as u32 {
+        for page in 0..Self::MAX_SWAP_PAGES as u32 {
             self.set_page_state(page, PageState::Original);
         }
 
-        self.store(flash);
+        // All images of the swap still need to be swapped
+        let is_valid = self.is_valid();
+        self.buffer[Self::PENDING_IMAGE_SWAPS_INDEX] = 0xFFFF_FFFF;
+        self.buffer[Self::SWAP_PAGE_COUNT_INDEX] = 0xFFFF_FFFF;
+        if is_valid {
+            self.set_valid(is_valid);
+        }
+
+        self.store(flash)?;
+        Ok(())
     }
 
-    /// Loads the bootloader state from flash
-    pub fn load(flash: &impl Flash) -> Self {
+    /// Loads the bootloader state from flash.
+    ///
+    /// The newest valid record of the log and both pages is used. If there is none, the state may still be in the
+    /// layout of older bootloaders, with shorter records or with the whole buffer on a page, so those are tried as
+    /// well.
+    pub fn load(flash: &(impl Flash + ?Sized)) -> Self {
         // Get where the state is stored
-        let (state_flash_slice_0, state_flash_slice_1) = unsafe { Self::get_state_flash_slices(flash) };
+        let pages = Self::get_state_flash_pages(flash);
 
-        // Create our buffer and do a sanity check
-        let mut buffer = [0xFFFFFFFF; 1024];
+        let mut s = Self {
+            buffer: [0xFFFF_FFFF; 1024],
+        };
 
-        // Read the flash into our ram buffer
-        buffer.copy_from_slice(state_flash_slice_0);
+        if let Some(slot) = Self::newest_record_in_log(flash) {
+            let record = &Self::get_state_log_flash(flash)[slot * Self::RECORD_WORDS..]
+                [..Self::RECORD_WORDS];
+            s.buffer[..Self::HEADER_WORDS]
+                .copy_from_slice(&record[Self::RECORD_HEADER_START..][..Self::HEADER_WORDS]);
 
-        let mut s = Self { buffer };
+            // The page states are only burned in, so the page that got further has the most cleared bits.
+            // A page that was erased when a new swap reset the page states has a record of its own.
+            for (index, word) in s
+                .buffer
+                .iter_mut()
+                .enumerate()
+                .skip(Self::CACHED_PAGES_RANGE.start)
+            {
+                *word = pages[0][index] & pages[1][index];
+            }
 
-        // If the first page is not valid (which is possible when the [Self::store] function gets reset inbetween or during its erase_page and program_page calls),
-        // Then we want to return the second page.
+            s.migrate();
+            return s;
+        }
+
+        for record_words in [Self::RECORD_WORDS, Self::LEGACY_RECORD_WORDS] {
+            if let Some((page, slot, _)) = Self::find_newest_record(flash, record_words) {
+                let record = &pages[page][slot * record_words..][..record_words];
+                let header_words = Self::HEADER_WORDS.min(record_words - Self::RECORD_HEADER_START);
+                s.buffer[..header_words]
+                    .copy_from_slice(&record[Self::RECORD_HEADER_START..][..header_words]);
+                s.buffer[Self::CACHED_PAGES_RANGE.start..]
+                    .copy_from_slice(&pages[page][Self::CACHED_PAGES_RANGE.start..]);
+                s.migrate();
+                return s;
+            }
+        }
+
+        crate::debug!("There is no state record, trying the layout of older bootloaders");
+
+        // The older bootloaders stored the buffer on both pages, so the second one is valid
+        // if the first one got corrupted when a store was interrupted
+        s.buffer.copy_from_slice(pages[0]);
         if !s.is_valid() {
-            s.buffer.copy_from_slice(state_flash_slice_1);
+            crate::debug!("The first state page is not valid, using the second one");
+            s.buffer.copy_from_slice(pages[1]);
+
+            if !s.is_valid() {
+                crate::warn!("Both state pages are not valid");
+            }
         }
 
+        s.migrate();
         s
     }
 
-    /// Stores the bootloader buffer in flash by first erasing the flash and then performing a burn-store
-    pub fn store(&self, flash: &mut impl Flash) {
-        // Erase the first page
-        flash.erase_page(bootloader_state_range().start);
-        // Store the buffer in the first page
-        flash.program_page(bootloader_state_range().start, &self.buffer);
-        // Erase the second page
-        flash.erase_page(bootloader_state_range().start + PAGE_SIZE);
-        // Store the buffer in the second page
-        flash.program_page(bootloader_state_range().start + PAGE_SIZE, &self.buffer);
+    /// Gets the [format version](Self::FORMAT_VERSION) of the buffer
+    pub fn format_version(&self) -> u32 {
+        self.buffer[Self::FORMAT_VERSION_INDEX]
+    }
+
+    /// Brings a loaded buffer to the current [format version](Self::FORMAT_VERSION), keeping its validity.
+    ///
+    /// A buffer with an unknown version is replaced by an invalid buffer of the current version. Its goal is
+    /// [BootloaderGoal::JumpToApplication], and a store doesn't write a record in a layout that it doesn't have.
+    fn migrate(&mut self) {
+        let is_valid = self.is_valid();
+
+        match self.buffer[Self::FORMAT_VERSION_INDEX] {
+            Self::FORMAT_VERSION => return,
+            // The buffers from before the version word, or an erased state. The words they don't have are all ones,
+            // which every getter reads as never set, so only the version is new.
+            0xFFFF_FFFF => {}
+            version => {
+                if is_valid {
+                    crate::warn!(
+                        "The state has the unknown format version {}, ignoring it",
+                        version
+                    );
+                }
+                self.buffer = [0xFFFF_FFFF; 1024];
+                self.buffer[Self::FORMAT_VERSION_INDEX] = Self::FORMAT_VERSION;
+                return;
+            }
+        }
+
+        self.buffer[Self::FORMAT_VERSION_INDEX] = Self::FORMAT_VERSION;
+
+        if is_valid {
+            self.set_valid(is_valid);
+        }
     }
 
-    /// Stores the bootloader buffer in flash, but does not perform an erase and
+    /// Stores the bootloader buffer in flash.
+    ///
+    /// The page states are burned into both state pages and the state is appended to the log as a new record. When
+    /// the page states were reset, so they can't be burned in anymore, both pages are erased and start over with
+    /// the page states and this record instead. The record also goes to the state pages when the log can't take
+    /// it, and then both pages are only erased when their record slots are full.
+    pub fn store(&self, flash: &mut (impl Flash + ?Sized)) -> Result<(), FlashError> {
+        crate::debug!(
+            "Storing the state with goal {:#X}",
+            self.buffer[Self::GOAL_INDEX]
+        );
+
+        let newest_record = Self::find_newest_record(flash, Self::RECORD_WORDS);
+        let newest_log_record = Self::find_newest_log_record(flash);
+        let sequence = newest_record
+            .map(|(_, _, sequence)| sequence)
+            .into_iter()
+            .chain(newest_log_record.map(|(_, sequence)| sequence))
+            .max()
+            .map_or(0, |sequence| sequence.wrapping_add(1));
+
+        // The record goes after every slot that is in use, on both pages
+        let pages = Self::get_state_flash_pages(flash);
+        let next_slot = (0..Self::RECORDS_PER_PAGE)
+            .rev()
+            .find(|slot| {
+                pages.iter().any(|page| {
+                    page[slot * Self::RECORD_WORDS..][..Self::RECORD_WORDS]
+                        .iter()
+                        .any(|word| *word != 0xFFFF_FFFF)
+                })
+            })
+            .map_or(0, |slot| slot + 1);
+        let page_states_can_be_burned = pages.iter().all(|page| {
+            page[Self::CACHED_PAGES_RANGE.start..]
+                .iter()
+                .zip(&self.buffer[Self::CACHED_PAGES_RANGE.start..])
+                .all(|(flash_word, word)| flash_word & word == *word)
+        });
+
+        let has_record = newest_record.is_some() || newest_log_record.is_some();
+        if has_record && page_states_can_be_burned {
+            if let Some(log_slot) = Self::next_log_slot(flash) {
+                // The page states go first, so a record is never there without them
+                self.burn_store(flash)?;
+                return self.append_to_log(flash, log_slot, sequence);
+            }
+        }
+
+        let can_append = newest_record.is_some()
+            && next_slot < Self::RECORDS_PER_PAGE
+            && page_states_can_be_burned;
+        let slot = if can_append { next_slot } else { 0 };
+
+        for page_address in bootloader_state_range().step_by(PAGE_SIZE as usize) {
+            if !can_append {
+                crate::debug!("Erasing the state page at {:#010X}", page_address);
+                flash.erase_page(page_address)?;
+            }
+
+            // The page states go first, so a record is never there without them
+            let mut page = [0xFFFF_FFFF; 1024];
+            page.copy_from_slice(flash.read_u32(page_address..page_address + PAGE_SIZE));
+            page[Self::CACHED_PAGES_RANGE.start..]
+                .copy_from_slice(&self.buffer[Self::CACHED_PAGES_RANGE.start..]);
+            flash.program_page(page_address, &page)?;
+
+            page[slot * Self::RECORD_WORDS..][..Self::RECORD_WORDS]
+                .copy_from_slice(&self.record(sequence));
+            flash.program_page(page_address, &page)?;
+        }
+
+        Ok(())
+    }
+
+    /// Programs the record into the given slot of the log, erasing its page first when the slot isn't erased
+    fn append_to_log(
+        &self,
+        flash: &mut (impl Flash + ?Sized),
+        slot: usize,
+        sequence: u32,
+    ) -> Result<(), FlashError> {
+        let log_page = slot / Self::LOG_RECORDS_PER_PAGE;
+        let page_address = bootloader_state_log_range().start + log_page as u32 * PAGE_SIZE;
+        if !Self::log_slot_is_erased(flash, slot) {
+            crate::debug!("Erasing the state log page at {:#010X}", page_address);
+            flash.erase_page(page_address)?;
+        }
+
+        let record_start = slot % Self::LOG_RECORDS_PER_PAGE * Self::RECORD_WORDS;
+        let mut page = [0xFFFF_FFFF; 1024];
+        page.copy_from_slice(flash.read_u32(page_address..page_address + PAGE_SIZE));
+        page[record_start..][..Self::RECORD_WORDS].copy_from_slice(&self.record(sequence));
+        flash.program_page(page_address, &page[..record_start + Self::RECORD_WORDS])
+    }
+
+    /// Finds the slot of the log for the next record, which is the one after the newest record of the log.
+    ///
+    /// A slot that isn't erased, because a power loss tore a record there, is skipped with the rest of its page.
+    /// The next page is then used, and it has to be erased if it isn't yet. Returns `None` if there is no log, or if
+    /// the page that has to be erased has the newest record of the state.
+    fn next_log_slot(flash: &(impl Flash + ?Sized)) -> Option<usize> {
+        let slots = Self::get_state_log_flash(flash).len() / Self::RECORD_WORDS;
+        if slots == 0 {
+            return None;
+        }
+
+        let newest_log_record = Self::find_newest_log_record(flash);
+        let slot = newest_log_record.map_or(0, |(slot, _)| (slot + 1) % slots);
+        if Self::log_slot_is_erased(flash, slot) {
+            return Some(slot);
+        }
+
+        // The start of the page of the slot, or of the next page
+        let slot = slot.div_ceil(Self::LOG_RECORDS_PER_PAGE) * Self::LOG_RECORDS_PER_PAGE % slots;
+        let erases_the_newest_record =
+            Self::newest_record_in_log(flash).is_some_and(|newest_slot| {
+                newest_slot / Self::LOG_RECORDS_PER_PAGE == slot / Self::LOG_RECORDS_PER_PAGE
+            });
+        (!erases_the_newest_record).then_some(slot)
+    }
+
+    /// Returns true if all words of the given slot of the log are erased
+    fn log_slot_is_erased(flash: &(impl Flash + ?Sized), slot: usize) -> bool {
+        Self::get_state_log_flash(flash)[slot * Self::RECORD_WORDS..][..Self::RECORD_WORDS]
+            .iter()
+            .all(|word| *word == 0xFFFF_FFFF)
+    }
+
+    /// Erases the state pages and the log, which leaves an invalid state.
+    ///
+    /// The pages are erased in an order that keeps the newest record until last, so an erase that is interrupted by
+    /// a reset leaves the newest record or none at all.
+    pub fn erase(flash: &mut (impl Flash + ?Sized)) -> Result<(), FlashError> {
+        let log_range = bootloader_state_log_range();
+        let log_pages = (log_range.end - log_range.start) / PAGE_SIZE;
+        // The log is erased from the page after the one with the newest record, which goes last
+        let first_log_page = Self::find_newest_log_record(flash).map_or(0, |(slot, _)| {
+            (slot / Self::LOG_RECORDS_PER_PAGE) as u32 + 1
+        });
+        let newest_record_in_log = Self::newest_record_in_log(flash).is_some();
+
+        // The state pages and the log, with the one that has the newest record last
+        for erase_log in [!newest_record_in_log, newest_record_in_log] {
+            if erase_log {
+                for log_page in 0..log_pages {
+                    let log_page = (first_log_page + log_page) % log_pages;
+                    flash.erase_page(log_range.start + log_page * PAGE_SIZE)?;
+                }
+            } else {
+                for page_address in bootloader_state_range().step_by(PAGE_SIZE as usize) {
+                    flash.erase_page(page_address)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stores the page states in flash, but does not perform an erase and
     /// only emits word write for words that have changes in them.
     /// Every word may be written to twice.
-    /// The burn store can only change bits from 1 to 0.
-    pub fn burn_store(&self, flash: &mut impl Flash) {
-        flash.program_page(bootloader_state_range().start, &self.buffer);
-        flash.program_page(bootloader_state_range().start + PAGE_SIZE, &self.buffer);
+    /// The burn store can only change bits from 1 to 0, so the rest of the state must be stored with [Self::store].
+    pub fn burn_store(&self, flash: &mut (impl Flash + ?Sized)) -> Result<(), FlashError> {
+        for page_address in bootloader_state_range().step_by(PAGE_SIZE as usize) {
+            let mut page = [0xFFFF_FFFF; 1024];
+            page.copy_from_slice(flash.read_u32(page_address..page_address + PAGE_SIZE));
+            page[Self::CACHED_PAGES_RANGE.start..]
+                .copy_from_slice(&self.buffer[Self::CACHED_PAGES_RANGE.start..]);
+            flash.program_page(page_address, &page)?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates the record of the buffer with the given sequence number
+    fn record(&self, sequence: u32) -> [u32; Self::RECORD_WORDS] {
+        let mut record = [0xFFFF_FFFF; Self::RECORD_WORDS];
+        record[Self::RECORD_MARKER_INDEX] = Self::VALID_WORD;
+        record[Self::RECORD_SEQUENCE_INDEX] = sequence;
+        record[Self::RECORD_HEADER_START..][..Self::HEADER_WORDS]
+            .copy_from_slice(&self.buffer[..Self::HEADER_WORDS]);
+        record[Self::RECORD_CRC_INDEX] = Self::calculate_record_crc(&record);
+        record
+    }
+
+    /// Calculates the crc of a record, over everything but the crc itself
+    fn calculate_record_crc(record: &[u32]) -> u32 {
+        checksum(&record[Self::RECORD_CRC_INDEX + 1..])
+    }
+
+    /// Finds the valid record with the highest sequence number among the records of the given size and returns its
+    /// page, its slot and the sequence number
+    fn find_newest_record(
+        flash: &(impl Flash + ?Sized),
+        record_words: usize,
+    ) -> Option<(usize, usize, u32)> {
+        let pages = Self::get_state_flash_pages(flash);
+        let records_per_page = Self::CACHED_PAGES_RANGE.start / record_words;
+
+        (0..pages.len())
+            .flat_map(|page| (0..records_per_page).map(move |slot| (page, slot)))
+            .filter_map(|(page, slot)| {
+                let record = &pages[page][slot * record_words..][..record_words];
+                Self::record_is_valid(record).then_some((
+                    page,
+                    slot,
+                    record[Self::RECORD_SEQUENCE_INDEX],
+                ))
+            })
+            // On a tie, the first page wins
+            .reduce(|newest, record| if record.2 > newest.2 { record } else { newest })
+    }
+
+    /// Finds the valid record of the log with the highest sequence number and returns its slot and the sequence
+    /// number
+    fn find_newest_log_record(flash: &(impl Flash + ?Sized)) -> Option<(usize, u32)> {
+        Self::get_state_log_flash(flash)
+            .as_chunks::<{ Self::RECORD_WORDS }>()
+            .0
+            .iter()
+            .enumerate()
+            .filter(|(_, record)| Self::record_is_valid(*record))
+            .map(|(slot, record)| (slot, record[Self::RECORD_SEQUENCE_INDEX]))
+            .reduce(|newest, record| if record.1 > newest.1 { record } else { newest })
+    }
+
+    /// Returns the slot of the newest record of the log if it's newer than every record on the state pages
+    fn newest_record_in_log(flash: &(impl Flash + ?Sized)) -> Option<usize> {
+        let (slot, sequence) = Self::find_newest_log_record(flash)?;
+        match Self::find_newest_record(flash, Self::RECORD_WORDS) {
+            Some((_, _, newest_sequence)) if newest_sequence >= sequence => None,
+            _ => Some(slot),
+        }
     }
 
-    unsafe fn get_state_flash_slices<'flash>(flash: &'flash impl Flash) -> (&'flash [u32], &'flash [u32]) {
-        flash.read_u32(bootloader_state_range()).split_at(1024)
+    /// Returns true if the record has the marker and a matching crc
+    fn record_is_valid(record: &[u32]) -> bool {
+        record[Self::RECORD_MARKER_INDEX] == Self::VALID_WORD
+            && record[Self::RECORD_CRC_INDEX] == Self::calculate_record_crc(record)
+    }
+
+    fn get_state_flash_pages(flash: &(impl Flash + ?Sized)) -> [&[u32]; 2] {
+        let (page_0, page_1) = flash.read_u32(bootloader_state_range()).split_at(1024);
+        [page_0, page_1]
+    }
+
+    fn get_state_log_flash(flash: &(impl Flash + ?Sized)) -> &[u32] {
+        flash.read_u32(bootloader_state_log_range())
     }
 }
 
@@ -203,6 +943,143 @@ pub enum BootloaderGoal {
     /// (Internal state only) The bootloader started test swapping and should finish it.
     /// This is only ever relevant when the bootloader was reset in the middle of a test swap.
     FinishTestSwap = 4,
+    /// (Internal state only) The bootloader should erase the device secrets, both program slots, the scratch area
+    /// and finally the state itself, in that order. This goal is only set when the wipe request was authenticated.
+    /// If the bootloader is reset during the wipe, it starts over.
+    Wipe = 5,
+    /// A modem firmware image has been staged and the bootloader should validate it.
+    /// See the [modem_update](crate::modem_update) module.
+    StartModemUpdate = 6,
+    /// The application has finished the modem firmware update
+    FinishModemUpdate = 7,
+    /// A new bootloader has been staged in slot B and should replace the current one.
+    /// See the [bootloader_update](crate::bootloader_update) module.
+    UpdateBootloader = 8,
+    /// The image in slot B should be started in place, at every boot, without copying it to slot A.
+    /// The image must be linked to run from slot B. If slot B has no valid image, the goal is set back to
+    /// [Self::JumpToApplication].
+    BootSlotB = 9,
+    /// The image in slot B should be started in place once. The goal is set back to [Self::JumpToApplication]
+    /// before the jump, so the next boot rolls back to slot A. The application confirms itself by setting
+    /// the goal to [Self::BootSlotB]. This is the direct boot version of [Self::StartTestSwap].
+    TestBootSlotB = 10,
+    /// The golden image should be copied into the primary slot, to go back to the factory firmware.
+    /// The slot layout must have a golden slot with an image that is linked for the primary slot. If it doesn't,
+    /// the goal is set back to [Self::JumpToApplication].
+    FactoryRestore = 11,
+    /// (Internal state only) The bootloader started restoring the golden image and should finish it.
+    /// This is only ever relevant when the bootloader was reset in the middle of the restore.
+    FinishFactoryRestore = 12,
+    /// The image in slot B should be copied over the image in slot A, without keeping the old image.
+    /// There is no rollback, but it takes half the time and wear of a swap. With the `verification` feature of the
+    /// core, slot B must have a valid image or the goal is set back to [Self::JumpToApplication].
+    Overwrite = 13,
+    /// (Internal state only) The bootloader started overwriting slot A and should finish it.
+    /// This is only ever relevant when the bootloader was reset in the middle of the overwrite.
+    FinishOverwrite = 14,
+    /// Both program slots hold an image that is linked to run from its own slot, and the bootloader starts the one
+    /// with the highest version in place, at every boot. The versions come from the
+    /// [image headers](crate::image_header). A new image is written into the slot that isn't running, and it's started
+    /// at the next boot if it's valid. If neither slot has a valid image, the bootloader falls back to the primary slot.
+    DirectXip = 15,
+    /// The image in slot B, which is the previous image after an update, should be swapped back into slot A.
+    /// The state then has [RollbackReason::Requested] as the [rollback reason](BootloaderState::rollback_reason),
+    /// so the old image can tell why it's running. With the `verification` feature of the core, slot B must have an
+    /// image that is linked for slot A or the goal is set back to [Self::JumpToApplication].
+    Revert = 16,
+}
+
+/// The goal wasn't changed, because it isn't the expected one
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct GoalMismatch {
+    /// The goal that is set, or `None` if the stored value is unknown
+    pub current: Option<BootloaderGoal>,
+}
+
+/// Why the goal wasn't changed in flash
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GoalChangeError {
+    /// The goal isn't the expected one
+    Mismatch(GoalMismatch),
+    /// The state could not be stored
+    Flash(FlashError),
+}
+
+impl From<GoalMismatch> for GoalChangeError {
+    fn from(mismatch: GoalMismatch) -> Self {
+        Self::Mismatch(mismatch)
+    }
+}
+
+impl From<FlashError> for GoalChangeError {
+    fn from(error: FlashError) -> Self {
+        Self::Flash(error)
+    }
+}
+
+impl BootloaderGoal {
+    /// Returns true if the application may request the goal through the [mailbox](crate::mailbox) or the
+    /// [secure services](crate::secure_services). The internal goals and the wipe, which needs a token, can't be.
+    pub fn is_requestable(self) -> bool {
+        matches!(
+            self,
+            BootloaderGoal::JumpToApplication
+                | BootloaderGoal::StartSwap
+                | BootloaderGoal::StartTestSwap
+                | BootloaderGoal::StartModemUpdate
+                | BootloaderGoal::FinishModemUpdate
+                | BootloaderGoal::UpdateBootloader
+                | BootloaderGoal::BootSlotB
+                | BootloaderGoal::TestBootSlotB
+                | BootloaderGoal::FactoryRestore
+                | BootloaderGoal::Overwrite
+                | BootloaderGoal::DirectXip
+                | BootloaderGoal::Revert
+        )
+    }
+}
+
+/// What should happen with the old image that a swap left in slot B
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, IntoPrimitive, TryFromPrimitive)]
+pub enum OldImageStatus {
+    /// There is nothing to clean up
+    #[num_enum(alternatives = [0xFFFF_FFFF])]
+    None = 0,
+    /// Slot B has the old image, which should be erased once the new image is confirmed.
+    /// It's only erased if slot B still has the stored CRC, so a newly staged image is left alone.
+    Pending = 1,
+    /// The old image is being erased. The erase starts over if the bootloader is reset.
+    Erasing = 2,
+}
+
+/// Why the image in slot A was last swapped back to the previous one
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, IntoPrimitive, TryFromPrimitive)]
+pub enum RollbackReason {
+    /// The most recent swap installed an update, or the previous image was restored by the swap back of a test swap
+    #[num_enum(alternatives = [0xFFFF])]
+    None = 0,
+    /// The application requested it with the [BootloaderGoal::Revert] goal
+    Requested = 1,
+    /// The application didn't [clear the boot attempts](BootloaderState::clear_boot_attempts) in time
+    BootAttempts = 2,
+}
+
+/// What the most recent swap took.
+///
+/// A swap that was interrupted by a reset only counts what was done after the reset.
+/// The pages that were already swapped before it are counted as skipped.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SwapStatistics {
+    /// The number of pages that were swapped
+    pub pages_copied: u32,
+    /// The number of pages that were already swapped before the reset
+    pub pages_skipped: u32,
+    /// The number of flash page erases
+    pub erases: u32,
+    /// How long the bootloader took, in milliseconds
+    pub duration_ms: u32,
 }
 
 /// The state of a page
@@ -0,0 +1,104 @@
+//! The checksum that tells a valid state from erased flash or random bits
+//!
+//! The [BootloaderState](crate::state::BootloaderState) keeps a checksum of its buffer and one of every record in
+//! flash. Both cover everything but the checksum word itself and the page states, which are burned in bit by bit.
+//! The scheme is picked at build time with a feature of this crate, so a product can use a checksum that its
+//! certification accepts:
+//!
+//! | Feature            | Scheme                                           |
+//! |--------------------|--------------------------------------------------|
+//! | (none)             | CRC-32/MPEG-2, the scheme of older bootloaders   |
+//! | `state-crc32`      | CRC-32/ISO-HDLC, the CRC-32 of zip and Ethernet  |
+//! | `state-crc32c`     | CRC-32C (Castagnoli)                             |
+//! | `state-fletcher32` | Fletcher-32 over the half words                  |
+//! | `state-sha256`     | the first word of a SHA-256 digest               |
+//!
+//! The schemes other than the default also cover their own [SCHEME] before the words, so a state that was stored
+//! with another scheme never passes the check by chance. It's just invalid, like an erased state.
+//!
+//! Stage 0, the bootloader and every application that reads or writes the state must be built with the same scheme.
+
+#[cfg(any(
+    all(feature = "state-crc32", feature = "state-crc32c"),
+    all(feature = "state-crc32", feature = "state-fletcher32"),
+    all(feature = "state-crc32", feature = "state-sha256"),
+    all(feature = "state-crc32c", feature = "state-fletcher32"),
+    all(feature = "state-crc32c", feature = "state-sha256"),
+    all(feature = "state-fletcher32", feature = "state-sha256"),
+))]
+compile_error!("Enable at most one of the state checksum features.");
+
+/// A checksum scheme of the state
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StateChecksumScheme {
+    /// CRC-32/MPEG-2 over the native-endian bytes of the words
+    Crc32Mpeg2 = 0,
+    /// CRC-32/ISO-HDLC over the native-endian bytes of the words
+    Crc32IsoHdlc = 1,
+    /// CRC-32C over the native-endian bytes of the words
+    Crc32C = 2,
+    /// Fletcher-32 over the lower and then the upper half of every word
+    Fletcher32 = 3,
+    /// The first four bytes of the SHA-256 digest of the native-endian bytes of the words, as a big-endian word
+    Sha256 = 4,
+}
+
+/// The scheme this crate was built with
+pub const SCHEME: StateChecksumScheme = if cfg!(feature = "state-crc32") {
+    StateChecksumScheme::Crc32IsoHdlc
+} else if cfg!(feature = "state-crc32c") {
+    StateChecksumScheme::Crc32C
+} else if cfg!(feature = "state-fletcher32") {
+    StateChecksumScheme::Fletcher32
+} else if cfg!(feature = "state-sha256") {
+    StateChecksumScheme::Sha256
+} else {
+    StateChecksumScheme::Crc32Mpeg2
+};
+
+/// Calculates the checksum of the words with the [SCHEME] of this build
+pub fn checksum<'a>(words: impl IntoIterator<Item = &'a u32>) -> u32 {
+    // The default scheme doesn't cover itself, so the states of older bootloaders stay valid
+    let scheme = (SCHEME != StateChecksumScheme::Crc32Mpeg2).then_some(SCHEME as u32);
+    let words = scheme.into_iter().chain(words.into_iter().copied());
+
+    #[cfg(feature = "state-sha256")]
+    {
+        use sha2::Digest;
+
+        let mut hasher = sha2::Sha256::new();
+        for word in words {
+            hasher.update(word.to_ne_bytes());
+        }
+        let digest = hasher.finalize();
+        u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+    }
+
+    #[cfg(feature = "state-fletcher32")]
+    {
+        let (mut sum_1, mut sum_2) = (0u32, 0u32);
+        for word in words {
+            for half in [word & 0xFFFF, word >> 16] {
+                sum_1 = (sum_1 + half) % 0xFFFF;
+                sum_2 = (sum_2 + sum_1) % 0xFFFF;
+            }
+        }
+        sum_2 << 16 | sum_1
+    }
+
+    #[cfg(not(any(feature = "state-sha256", feature = "state-fletcher32")))]
+    {
+        let algorithm = match SCHEME {
+            StateChecksumScheme::Crc32IsoHdlc => &crc::CRC_32_ISO_HDLC,
+            StateChecksumScheme::Crc32C => &crc::CRC_32_ISCSI,
+            _ => &crc::CRC_32_MPEG_2,
+        };
+        let crc = crc::Crc::<u32>::new(algorithm);
+        let mut digest = crc.digest();
+        for word in words {
+            digest.update(&word.to_ne_bytes());
+        }
+        digest.finalize()
+    }
+}
@@ -1,33 +1,120 @@
 //! Helper functions for finding the flash addresses of the memory regions more easily
-//! 
-//! In this std-compat version, the statics must be defined by the program that uses it instead of the linker script
+//!
+//! In this std-compat version, there is no linker script. The addresses come from a [FlashLayout] that can be changed
+//! at runtime with [with_layout], so host tests can run the swap and the state with any slot size, number of scratch
+//! pages or other layout. Outside of [with_layout], the [FlashLayout::NRF9160] layout of the bootloader is used.
+//!
+//! The layout belongs to the thread, so tests with different layouts can run at the same time.
 
-use core::ops::Range;
+use crate::partitions;
+use core::{cell::RefCell, ops::Range};
 
-extern "C" {
-    static _bootloader_flash_start: u32;
-    static _bootloader_flash_end: u32;
-    static _bootloader_scratch_start: u32;
-    static _bootloader_scratch_end: u32;
-    static _bootloader_state_start: u32;
-    static _bootloader_state_end: u32;
+/// The size of a page in bytes
+pub const PAGE_SIZE: u32 = partitions::PAGE_SIZE;
 
-    static _program_slot_a_start: u32;
-    static _program_slot_a_end: u32;
-    static _program_slot_b_start: u32;
-    static _program_slot_b_end: u32;
+/// The address ranges of all memory regions.
+///
+/// The flash regions must be aligned to pages. The RAM regions are only used by the bootloader binary
+/// and the application, so host tests can leave them alone.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FlashLayout {
+    /// The address range of the bootloader's flash
+    pub bootloader_flash: Range<u32>,
+    /// The address range of the descriptor block at the end of the bootloader's flash
+    pub bootloader_descriptor: Range<u32>,
+    /// The address range of the bootloader's scratch area flash
+    pub bootloader_scratch: Range<u32>,
+    /// The address range of the bootloader's state log flash. It may be any number of pages, even none.
+    pub bootloader_state_log: Range<u32>,
+    /// The address range of the bootloader's panic log flash
+    pub bootloader_panic_log: Range<u32>,
+    /// The address range of the key revocation list flash
+    pub bootloader_revocations: Range<u32>,
+    /// The address range of the bootloader's event log flash
+    pub bootloader_event_log: Range<u32>,
+    /// The address range of the bootloader's state flash. It must be two pages.
+    pub bootloader_state: Range<u32>,
+    /// The address range in RAM of the mailbox
+    pub bootloader_mailbox: Range<u32>,
+    /// The address range in RAM of the boot measurements
+    pub bootloader_measurements: Range<u32>,
+    /// The address range in RAM of the boot info block
+    pub bootloader_boot_info: Range<u32>,
+    /// The address range of slot A of the firmware
+    pub program_slot_a: Range<u32>,
+    /// The address range of slot B of the firmware
+    pub program_slot_b: Range<u32>,
+    /// The address range where modem firmware updates are staged
+    pub modem_staging: Range<u32>,
+    /// The address range of the user data, which the bootloader never touches
+    pub user_data: Range<u32>,
+    /// The address range the flash trace is mirrored into
+    pub bootloader_flash_trace: Range<u32>,
 }
 
-/// The size of a page in bytes
-pub const PAGE_SIZE: u32 = 0x0000_1000;
+impl FlashLayout {
+    /// The layout of the bootloader on the nRF9160, with the flash as in `partitions.toml` and the RAM as in its
+    /// `memory.x`
+    pub const NRF9160: Self = Self {
+        bootloader_flash: partitions::BOOTLOADER_FLASH,
+        bootloader_descriptor: partitions::BOOTLOADER_FLASH.end - 256
+            ..partitions::BOOTLOADER_FLASH.end,
+        bootloader_scratch: partitions::BOOTLOADER_SCRATCH,
+        bootloader_state_log: partitions::BOOTLOADER_STATE_LOG,
+        bootloader_panic_log: partitions::BOOTLOADER_PANIC_LOG,
+        bootloader_revocations: partitions::BOOTLOADER_REVOCATIONS,
+        bootloader_event_log: partitions::BOOTLOADER_EVENT_LOG,
+        bootloader_state: partitions::BOOTLOADER_STATE,
+        bootloader_mailbox: 0x2000_FB00..0x2000_FC00,
+        bootloader_measurements: 0x2000_FA00..0x2000_FB00,
+        bootloader_boot_info: 0x2000_F900..0x2000_FA00,
+        program_slot_a: partitions::PROGRAM_SLOT_A,
+        program_slot_b: partitions::PROGRAM_SLOT_B,
+        modem_staging: partitions::PROGRAM_SLOT_B,
+        user_data: partitions::USER_DATA,
+        bootloader_flash_trace: partitions::BOOTLOADER_FLASH_TRACE,
+    };
+}
+
+impl Default for FlashLayout {
+    fn default() -> Self {
+        Self::NRF9160
+    }
+}
+
+std::thread_local! {
+    /// The layout of the current thread
+    static LAYOUT: RefCell<FlashLayout> = const { RefCell::new(FlashLayout::NRF9160) };
+}
+
+/// Runs the closure with the given layout, which all functions in this module return until it's done.
+///
+/// The layout only applies to the current thread, so tests that run at the same time can each have their own.
+/// Calls can be nested, and the previous layout is put back afterwards, even if the closure panics.
+pub fn with_layout<R>(layout: &FlashLayout, f: impl FnOnce() -> R) -> R {
+    /// Puts the previous layout back when it's dropped
+    struct Restore(Option<FlashLayout>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            if let Some(previous) = self.0.take() {
+                LAYOUT.with(|layout| *layout.borrow_mut() = previous);
+            }
+        }
+    }
+
+    let _restore = Restore(Some(LAYOUT.with(|current| current.replace(layout.clone()))));
+    f()
+}
+
+/// Gives the range of the current layout that the closure picks
+fn range(field: impl FnOnce(&FlashLayout) -> &Range<u32>) -> Range<u32> {
+    LAYOUT.with(|layout| field(&layout.borrow()).clone())
+}
 
 /// The address range of the bootloader's flash
 pub fn bootloader_flash_range() -> Range<u32> {
-    unsafe {
-        let start = _bootloader_flash_start;
-        let end = _bootloader_flash_end;
-        start..end
-    }
+    range(|layout| &layout.bootloader_flash)
 }
 
 /// The page range of the bootloader's flash
@@ -36,13 +123,15 @@ pub fn bootloader_flash_page_range() -> Range<u32> {
     address_range.start / PAGE_SIZE..address_range.end / PAGE_SIZE
 }
 
+/// The address range of the descriptor block at the end of the bootloader's flash.
+/// It starts with the [BuildInfo](crate::build_info::BuildInfo) of the bootloader.
+pub fn bootloader_descriptor_range() -> Range<u32> {
+    range(|layout| &layout.bootloader_descriptor)
+}
+
 /// The address range of the bootloader's scratch area flash
 pub fn bootloader_scratch_range() -> Range<u32> {
-    unsafe {
-        let start = _bootloader_scratch_start;
-        let end = _bootloader_scratch_end;
-        start..end
-    }
+    range(|layout| &layout.bootloader_scratch)
 }
 
 /// The page range of the bootloader's scratch area flash
@@ -51,13 +140,33 @@ pub fn bootloader_scratch_page_range() -> Range<u32> {
     address_range.start / PAGE_SIZE..address_range.end / PAGE_SIZE
 }
 
+/// The address range of the bootloader's state log flash.
+/// See [BootloaderState](crate::state::BootloaderState).
+pub fn bootloader_state_log_range() -> Range<u32> {
+    range(|layout| &layout.bootloader_state_log)
+}
+
+/// The address range of the bootloader's panic log flash.
+/// See the [panic_log](crate::panic_log) module.
+pub fn bootloader_panic_log_range() -> Range<u32> {
+    range(|layout| &layout.bootloader_panic_log)
+}
+
+/// The address range of the key revocation list flash.
+/// See the [revocation](crate::revocation) module.
+pub fn bootloader_revocations_range() -> Range<u32> {
+    range(|layout| &layout.bootloader_revocations)
+}
+
+/// The address range of the bootloader's event log flash.
+/// See the [event_log](crate::event_log) module.
+pub fn bootloader_event_log_range() -> Range<u32> {
+    range(|layout| &layout.bootloader_event_log)
+}
+
 /// The address range of the bootloader's state flash
 pub fn bootloader_state_range() -> Range<u32> {
-    unsafe {
-        let start = _bootloader_state_start;
-        let end = _bootloader_state_end;
-        start..end
-    }
+    range(|layout| &layout.bootloader_state)
 }
 
 /// The page range of the bootloader's state flash
@@ -66,13 +175,27 @@ pub fn bootloader_state_page_range() -> Range<u32> {
     address_range.start / PAGE_SIZE..address_range.end / PAGE_SIZE
 }
 
+/// The address range in RAM of the mailbox the application can use to request a goal.
+/// See the [mailbox](crate::mailbox) module.
+pub fn bootloader_mailbox_range() -> Range<u32> {
+    range(|layout| &layout.bootloader_mailbox)
+}
+
+/// The address range in RAM where the bootloader leaves the boot measurements for the application.
+/// See the [measurements](crate::measurements) module.
+pub fn bootloader_measurements_range() -> Range<u32> {
+    range(|layout| &layout.bootloader_measurements)
+}
+
+/// The address range in RAM where the bootloader leaves the boot info block for the application.
+/// See the [boot_info](crate::boot_info) module.
+pub fn bootloader_boot_info_range() -> Range<u32> {
+    range(|layout| &layout.bootloader_boot_info)
+}
+
 /// The address range of slot A of the firmware
 pub fn program_slot_a_range() -> Range<u32> {
-    unsafe {
-        let start = _program_slot_a_start;
-        let end = _program_slot_a_end;
-        start..end
-    }
+    range(|layout| &layout.program_slot_a)
 }
 
 /// The page range of slot A of the firmware
@@ -83,11 +206,7 @@ pub fn program_slot_a_page_range() -> Range<u32> {
 
 /// The address range of slot B of the firmware
 pub fn program_slot_b_range() -> Range<u32> {
-    unsafe {
-        let start = _program_slot_b_start;
-        let end = _program_slot_b_end;
-        start..end
-    }
+    range(|layout| &layout.program_slot_b)
 }
 
 /// The page range of slot B of the firmware
@@ -95,3 +214,27 @@ pub fn program_slot_b_page_range() -> Range<u32> {
     let address_range = program_slot_b_range();
     address_range.start / PAGE_SIZE..address_range.end / PAGE_SIZE
 }
+
+/// The address range where modem firmware updates are staged.
+/// See the [modem_update](crate::modem_update) module.
+pub fn modem_staging_range() -> Range<u32> {
+    range(|layout| &layout.modem_staging)
+}
+
+/// The address range of the user data, which the bootloader never erases or programs.
+/// See the [user_data](crate::user_data) module.
+pub fn user_data_range() -> Range<u32> {
+    range(|layout| &layout.user_data)
+}
+
+/// The page range of the user data
+pub fn user_data_page_range() -> Range<u32> {
+    let address_range = user_data_range();
+    address_range.start / PAGE_SIZE..address_range.end / PAGE_SIZE
+}
+
+/// The address range of the flash the bootloader mirrors its flash trace into.
+/// See the `trace` module of the bootloader core.
+pub fn bootloader_flash_trace_range() -> Range<u32> {
+    range(|layout| &layout.bootloader_flash_trace)
+}
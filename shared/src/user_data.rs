@@ -0,0 +1,50 @@
+//! The user data partition for the calibration and configuration of a product
+//!
+//! The bootloader never erases or programs the user data. It lies outside of the slots, so a swap, a rollback or a
+//! restore leaves it alone, and a [wipe](crate::state::BootloaderGoal::Wipe) keeps it as well, because it belongs to
+//! the device rather than to its user. The partition is laid out in `partitions.toml` like the others.
+//!
+//! The application owns the partition and decides its format. These helpers only locate it, so the application
+//! doesn't have to hard-code the addresses.
+
+use crate::{
+    flash_addresses::{user_data_range, PAGE_SIZE},
+    Flash, FlashError,
+};
+use core::ops::Range;
+
+/// Gets the address range of the user data
+pub fn range() -> Range<u32> {
+    user_data_range()
+}
+
+/// Gets the flash address of the given offset in the user data, or `None` if it lies outside of it
+pub fn address(offset: u32) -> Option<u32> {
+    let range = range();
+    range
+        .start
+        .checked_add(offset)
+        .filter(|address| *address < range.end)
+}
+
+/// Gives the addresses of the pages of the user data
+pub fn page_addresses() -> impl Iterator<Item = u32> {
+    range().step_by(PAGE_SIZE as usize)
+}
+
+/// Reads the whole user data
+pub fn read(flash: &(impl Flash + ?Sized)) -> &[u8] {
+    flash.read_u8(range())
+}
+
+/// Returns true if the address range has an address in common with the user data
+pub fn overlaps(address_range: &Range<u32>) -> bool {
+    let range = range();
+    address_range.start < range.end && range.start < address_range.end
+}
+
+/// Erases the page of the user data with the given offset
+pub fn erase_page(flash: &mut (impl Flash + ?Sized), offset: u32) -> Result<(), FlashError> {
+    let address = address(offset).ok_or(FlashError::OutOfRange)?;
+    flash.erase_page(address - address % PAGE_SIZE)
+}
@@ -0,0 +1,55 @@
+//! XMODEM-1K, the second protocol of the [serial recovery](crate::recovery)
+//!
+//! Terminal programs like minicom and TeraTerm can send a file with XMODEM, so a field technician doesn't need a
+//! custom host tool. The bootloader asks for the CRC variant by sending [CRC_MODE] until the transfer starts.
+//! Every block is sent as:
+//!
+//! | Size         | Field                                                     |
+//! |--------------|-----------------------------------------------------------|
+//! | 1            | [STX] for a block of 1024 bytes, [SOH] for 128 bytes      |
+//! | 1            | the block number, from 1 and wrapping around after 255    |
+//! | 1            | the complement of the block number                        |
+//! | 1024 or 128  | the data                                                  |
+//! | 2            | big-endian CRC-16/XMODEM of the data                      |
+//!
+//! The answers are the [ACK](crate::recovery::ACK) and [NAK](crate::recovery::NAK) of the framed protocol, which are
+//! the same bytes. The sender ends the transfer with [EOT]. The last block is padded, usually with `0x1A`, so the
+//! padding ends up in slot B behind the image. That doesn't matter, because the image has its own length.
+
+/// Starts a block of 128 bytes
+pub const SOH: u8 = 0x01;
+/// Starts a block of 1024 bytes
+pub const STX: u8 = 0x02;
+/// Ends the transfer
+pub const EOT: u8 = 0x04;
+/// Cancels the transfer. The receiver sends it twice to abort.
+pub const CAN: u8 = 0x18;
+/// Asks the sender to start a transfer with CRC-16 instead of a checksum
+pub const CRC_MODE: u8 = b'C';
+
+/// The size of the data of the largest block
+pub const MAX_BLOCK_SIZE: usize = 1024;
+/// The size of everything in a block after the start byte: the block number, its complement and the CRC
+pub const BLOCK_OVERHEAD: usize = 4;
+
+/// Returns the size of the data of the block that starts with the given byte, or `None` if it doesn't start a block
+pub fn block_size(start: u8) -> Option<usize> {
+    match start {
+        SOH => Some(128),
+        STX => Some(MAX_BLOCK_SIZE),
+        _ => None,
+    }
+}
+
+/// Calculates the CRC of the data of a block
+pub fn crc(data: &[u8]) -> u16 {
+    crc::Crc::<u16>::new(&crc::CRC_16_XMODEM).checksum(data)
+}
+
+/// Checks a block that was received after its start byte. Returns the block number and the data if it's intact.
+pub fn parse_block(block: &[u8]) -> Option<(u8, &[u8])> {
+    let (&[number, complement], rest) = block.split_first_chunk::<2>()?;
+    let (data, &crc_bytes) = rest.split_last_chunk::<2>()?;
+
+    (number == !complement && crc(data) == u16::from_be_bytes(crc_bytes)).then_some((number, data))
+}
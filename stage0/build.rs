@@ -0,0 +1,46 @@
+//! Puts `memory.x` and the flash layout in the output directory so the linker can find them, just like the
+//! bootloader does, and builds in the public key that bootloader updates must be signed with
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+#[path = "../shared/partitions.rs"]
+mod partitions;
+#[path = "../shared/signing_key.rs"]
+mod signing_key;
+
+fn main() {
+    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    File::create(out.join("memory.x"))
+        .unwrap()
+        .write_all(include_bytes!("memory.x"))
+        .unwrap();
+    File::create(out.join("partitions.x"))
+        .unwrap()
+        .write_all(
+            partitions::Layout::load()
+                .linker_script("stage0_flash")
+                .as_bytes(),
+        )
+        .unwrap();
+    // `fit.x` checks that stage 0 leaves the page of the stage 1 record free, so it comes after `link.x`
+    File::create(out.join("fit.x"))
+        .unwrap()
+        .write_all(include_bytes!("fit.x"))
+        .unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rustc-link-arg-bins=-Tlink.x");
+    println!("cargo:rustc-link-arg-bins=-Tfit.x");
+
+    println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=fit.x");
+
+    // Stage 0 is the root of trust, so it always has a key of its own
+    println!("cargo:rerun-if-env-changed=SIGNING_PUBLIC_KEY");
+    File::create(out.join("signing_public_key.rs"))
+        .unwrap()
+        .write_all(signing_key::public_key_expression().as_bytes())
+        .unwrap();
+}
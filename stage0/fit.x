@@ -0,0 +1,6 @@
+/* This script is linked after `link.x` of cortex-m-rt, so all section symbols are known here */
+
+/* The flash image ends with the initial values of .data */
+_stage0_image_end = LOADADDR(.data) + SIZEOF(.data);
+
+ASSERT(_stage0_image_end <= _stage1_record_start, "Stage 0 doesn't fit in its flash region. It would overwrite the record of stage 1");
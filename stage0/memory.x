@@ -0,0 +1,25 @@
+/* The flash is laid out in partitions.toml in the root of the workspace, like for the bootloader.
+ * The FLASH region is the stage 0 partition. The bootloader flash is the flash of stage 1,
+ * which is what stage 0 starts and updates. */
+INCLUDE partitions.x
+
+MEMORY
+{
+    /* The same RAM as the bootloader, so the mailbox, the boot info and the measurements stay untouched */
+    RAM   : ORIGIN = 0x20000000, LENGTH = 63K - 768
+}
+
+_bootloader_descriptor_start = _bootloader_flash_end - 256;
+
+/* The last page of the stage 0 flash is the record of the installed stage 1, see the record module */
+_stage1_record_start = _stage0_flash_end - 4K;
+
+_bootloader_mailbox_start = 0x2000FB00;
+_bootloader_mailbox_end = 0x2000FC00;
+_bootloader_measurements_start = 0x2000FA00;
+_bootloader_measurements_end = 0x2000FB00;
+_bootloader_boot_info_start = 0x2000F900;
+_bootloader_boot_info_end = 0x2000FA00;
+
+_modem_staging_start = _program_slot_b_start;
+_modem_staging_end = _program_slot_b_end;
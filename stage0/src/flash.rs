@@ -0,0 +1,136 @@
+//! Implementation of [shared::Flash] for stage 0
+//!
+//! This is a stripped down copy of the driver of the bootloader. Stage 0 can't use that one,
+//! because it depends on the HAL and the board config. It drives the few registers of the NVMC it needs directly,
+//! so it works on every chip without a PAC.
+
+use core::{mem::size_of, ops::Range};
+use shared::{
+    chip,
+    flash_geometry::{self, FlashGeometry},
+    FlashError,
+};
+
+/// The READY register of the NVMC
+const NVMC_READY: *const u32 = (chip::NVMC_ADDRESS + 0x400) as *const u32;
+/// The CONFIG register of the NVMC
+const NVMC_CONFIG: *mut u32 = (chip::NVMC_ADDRESS + 0x504) as *mut u32;
+/// The ERASEPAGE register of the NVMC of the nRF52840
+#[cfg(feature = "chip-nrf52840")]
+const NVMC_ERASEPAGE: *mut u32 = (chip::NVMC_ADDRESS + 0x508) as *mut u32;
+
+/// The values of the WEN field of the CONFIG register: read only, write and erase enabled
+const CONFIG_REN: u32 = 0;
+const CONFIG_WEN: u32 = 1;
+const CONFIG_EEN: u32 = 2;
+
+/// The flash driver of stage 0
+pub struct Flash {
+    /// The geometry of the flash, as the FICR reports it
+    pub geometry: FlashGeometry,
+}
+
+impl Flash {
+    /// Creates the driver and reads the geometry of the flash from the FICR
+    pub fn new() -> Self {
+        let geometry = unsafe {
+            FlashGeometry::from_ficr_words(
+                (flash_geometry::CODE_PAGE_SIZE_ADDRESS as *const u32).read_volatile(),
+                (flash_geometry::CODE_SIZE_ADDRESS as *const u32).read_volatile(),
+            )
+        };
+
+        Self { geometry }
+    }
+
+    /// Sets the WEN field of the CONFIG register
+    fn set_config(&mut self, config: u32) {
+        unsafe { NVMC_CONFIG.write_volatile(config) };
+    }
+
+    /// Waits until the NVMC is done with the erase or write
+    fn wait_until_ready(&self) {
+        while unsafe { NVMC_READY.read_volatile() } & 1 == 0 {}
+    }
+
+    /// Checks that the address is at the start of a flash page
+    fn check_page_address(&self, page_address: u32) -> Result<(), FlashError> {
+        if page_address % self.geometry.page_size != 0 {
+            return Err(FlashError::Alignment);
+        }
+        if page_address >= self.geometry.size {
+            return Err(FlashError::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+impl shared::Flash for Flash {
+    fn erase_page(&mut self, page_address: u32) -> Result<(), FlashError> {
+        self.check_page_address(page_address)?;
+
+        self.set_config(CONFIG_EEN);
+        // The nRF9160 erases the page when all 1's are written to its first word, the nRF52840 has a register for it
+        #[cfg(feature = "chip-nrf9160")]
+        unsafe {
+            (page_address as *mut u32).write_volatile(0xFFFF_FFFF);
+        }
+        #[cfg(feature = "chip-nrf52840")]
+        unsafe {
+            NVMC_ERASEPAGE.write_volatile(page_address);
+        }
+        self.wait_until_ready();
+        self.set_config(CONFIG_REN);
+
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+        Ok(())
+    }
+
+    fn program_page(&mut self, page_address: u32, data: &[u32]) -> Result<(), FlashError> {
+        self.check_page_address(page_address)?;
+        if data.len() > self.geometry.page_size as usize / size_of::<u32>() {
+            return Err(FlashError::OutOfRange);
+        }
+
+        self.set_config(CONFIG_WEN);
+        for (index, data_word) in data.iter().enumerate() {
+            let flash_word = (page_address as *mut u32).wrapping_add(index);
+            if unsafe { flash_word.read_volatile() } != *data_word {
+                unsafe { flash_word.write_volatile(*data_word) };
+                self.wait_until_ready();
+            }
+        }
+        self.set_config(CONFIG_REN);
+
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+        Ok(())
+    }
+
+    fn read_u8(&self, address_range: Range<u32>) -> &[u8] {
+        assert!(
+            address_range.start <= address_range.end && address_range.end <= self.geometry.size
+        );
+        unsafe {
+            core::slice::from_raw_parts(address_range.start as *const u8, address_range.len())
+        }
+    }
+
+    fn read_u32(&self, address_range: Range<u32>) -> &[u32] {
+        assert!(address_range.start % 4 == 0 && address_range.end % 4 == 0);
+        assert!(
+            address_range.start <= address_range.end && address_range.end <= self.geometry.size
+        );
+        unsafe {
+            core::slice::from_raw_parts(
+                address_range.start as *const u32,
+                address_range.len() / size_of::<u32>(),
+            )
+        }
+    }
+
+    fn geometry(&self) -> FlashGeometry {
+        self.geometry
+    }
+}
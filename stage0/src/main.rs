@@ -0,0 +1,123 @@
+//! The immutable first stage of the bootloader
+//!
+//! Stage 0 is the only code that runs from the start of the flash and it's never updated.
+//! It does as little as possible:
+//!
+//! - When a bootloader update is pending, it runs on this hardware revision and it's signed with the key that is
+//!   built into stage 0 (from `SIGNING_PUBLIC_KEY`), it copies the staged image over stage 1 and records its CRC
+//!   (see [record]).
+//!   Because stage 0 itself is not touched, a power loss during the copy just makes it start over at the next boot.
+//! - It checks that stage 1 matches the CRC in the record and has a valid build info and vector table, and starts it.
+//!
+//! The signature is checked by `dis_bootloader_core::secure_boot`, like the bootloader checks the application, but
+//! with the key of stage 0. So a stage 1 without the `secure-boot` feature can't disable the check for its successors.
+//!
+//! Everything else, including finishing the `UpdateBootloader` goal, is done by stage 1 (the `bootloader` crate).
+
+#![no_main]
+#![no_std]
+
+use crate::flash::Flash;
+use cortex_m::peripheral::SCB;
+use dis_bootloader_core::secure_boot;
+use shared::{
+    bootloader_update::{self, is_installed, StagedBootloader},
+    build_info::BuildInfo,
+    flash_addresses::{bootloader_flash_range, PAGE_SIZE},
+    hardware_revision::{self, HARDWARE_REVISION_ADDRESS},
+    state::{BootloaderGoal, BootloaderState},
+    Flash as _, FlashError,
+};
+
+mod flash;
+mod record;
+
+#[cfg(not(any(feature = "chip-nrf9160", feature = "chip-nrf52840")))]
+compile_error!("No chip selected. Enable one of the chip features.");
+#[cfg(all(feature = "chip-nrf9160", feature = "chip-nrf52840"))]
+compile_error!("Enable only one of the chip features.");
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    let mut flash = Flash::new();
+    secure_boot::set_public_key(include!(concat!(env!("OUT_DIR"), "/signing_public_key.rs")));
+
+    let state = BootloaderState::load(&flash);
+    if state.is_valid() && state.goal() == BootloaderGoal::UpdateBootloader {
+        let hardware_revision = hardware_revision::from_uicr_word(unsafe {
+            (HARDWARE_REVISION_ADDRESS as *const u32).read_volatile()
+        });
+
+        let update = bootloader_update::validate(&flash, hardware_revision)
+            .ok()
+            .filter(|update| secure_boot::verify_bootloader_update(&flash, update).is_ok());
+        if let Some(update) = update {
+            // A failed install is tried again after a reset, the goal is still to update the bootloader
+            if !is_installed(&flash, &update) && install(&mut flash, &update).is_err() {
+                SCB::sys_reset();
+            }
+
+            // Stage 1 is now exactly the image that was verified
+            if record::store(&mut flash, record::stage1_crc(&flash)).is_err() {
+                SCB::sys_reset();
+            }
+        }
+    }
+
+    let stage1_address = bootloader_flash_range().start;
+
+    // The stage 1 that was flashed at production has no record yet
+    if record::load(&flash).is_none()
+        && stage1_is_plausible(&flash, stage1_address)
+        && record::store(&mut flash, record::stage1_crc(&flash)).is_err()
+    {
+        SCB::sys_reset();
+    }
+
+    if record::load(&flash) != Some(record::stage1_crc(&flash))
+        || !stage1_is_plausible(&flash, stage1_address)
+    {
+        // Stage 1 is missing or corrupted, so there is nothing we can start and a debugger is needed
+        loop {
+            cortex_m::asm::wfi();
+        }
+    }
+
+    unsafe {
+        (*SCB::PTR).vtor.write(stage1_address);
+        cortex_m::asm::bootload(stage1_address as *const u32)
+    }
+}
+
+/// Copies the staged bootloader over stage 1
+fn install(flash: &mut Flash, update: &StagedBootloader) -> Result<(), FlashError> {
+    for offset in (0..update.header.image_size).step_by(PAGE_SIZE as usize) {
+        let page_address = bootloader_flash_range().start + offset;
+        flash.erase_page(page_address)?;
+
+        let source = update.bootloader_range().start + offset;
+        let data = flash.read_u32(source..source + PAGE_SIZE);
+
+        // The data lives in flash, but in slot B, which is not being written
+        let data = unsafe { core::slice::from_raw_parts(data.as_ptr(), data.len()) };
+        flash.program_page(page_address, data)?;
+    }
+
+    Ok(())
+}
+
+/// Checks that stage 1 has a valid build info and a plausible vector table
+fn stage1_is_plausible(flash: &Flash, stage1_address: u32) -> bool {
+    let vector_table = flash.read_u32(stage1_address..stage1_address + 8);
+    let (initial_stack_pointer, reset_vector) = (vector_table[0], vector_table[1]);
+
+    BuildInfo::load(flash).is_some()
+        && (0x2000_0000..=0x2004_0000).contains(&initial_stack_pointer)
+        && bootloader_flash_range().contains(&reset_vector)
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    // Stage 0 has no way to report anything, so just try again
+    SCB::sys_reset()
+}
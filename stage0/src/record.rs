@@ -0,0 +1,42 @@
+//! The record of the stage 1 that is installed
+//!
+//! The last page of the stage 0 flash holds the CRC-32/MPEG-2 of the bootloader flash, so stage 0 can check the
+//! integrity of stage 1 before it starts it. Stage 0 writes the record when it has installed an update that it
+//! verified the signature of. A stage 1 that was flashed at production has no record yet, so it's trusted at its first
+//! boot and recorded then. Like the code of stage 0, the page can only be written by the secure firmware.
+
+use crate::flash::Flash;
+use shared::{
+    flash_addresses::{bootloader_flash_range, PAGE_SIZE},
+    partitions::STAGE0_FLASH,
+    Flash as _, FlashError,
+};
+
+/// The address of the record, the same as `_stage1_record_start` in `memory.x`
+const RECORD_ADDRESS: u32 = STAGE0_FLASH.end - PAGE_SIZE;
+
+/// The word that marks a valid record
+const MAGIC: u32 = 0x5747_1C4C;
+
+/// Computes the CRC of the bootloader flash, which is what the record keeps
+pub fn stage1_crc(flash: &Flash) -> u32 {
+    crc::Crc::<u32>::new(&crc::CRC_32_MPEG_2).checksum(flash.read_u8(bootloader_flash_range()))
+}
+
+/// Reads the CRC in the record, or returns `None` if there is no record
+pub fn load(flash: &Flash) -> Option<u32> {
+    match flash.read_u32(RECORD_ADDRESS..RECORD_ADDRESS + 8) {
+        [MAGIC, crc] => Some(*crc),
+        _ => None,
+    }
+}
+
+/// Stores the given CRC in the record, unless the record already has it
+pub fn store(flash: &mut Flash, crc: u32) -> Result<(), FlashError> {
+    if load(flash) == Some(crc) {
+        return Ok(());
+    }
+
+    flash.erase_page(RECORD_ADDRESS)?;
+    flash.program_page(RECORD_ADDRESS, &[MAGIC, crc])
+}